@@ -0,0 +1,232 @@
+// auth.rs
+use std::future::Future;
+use std::pin::Pin;
+
+use actix_web::body::{EitherBody, MessageBody};
+use actix_web::dev::{Payload, ServiceRequest, ServiceResponse};
+use actix_web::error::{ErrorForbidden, ErrorInternalServerError, ErrorUnauthorized};
+use actix_web::middleware::Next;
+use actix_web::{web, Error, FromRequest, HttpMessage, HttpRequest};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chrono::{Duration, Utc};
+use hmac::{Hmac, Mac};
+use lazy_static::lazy_static;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use tracing::{error, warn};
+use uuid::Uuid;
+
+use crate::db::Session;
+use crate::store::{Store, StoreError};
+
+// How long a session token stays valid
+const SESSION_HOURS: i64 = 12;
+
+lazy_static! {
+	// Key used to sign session tokens. Set SESSION_SECRET in any deployment with
+	// more than one instance, or tokens will not survive a restart.
+	static ref TOKEN_KEY: Vec<u8> = match std::env::var("SESSION_SECRET") {
+		Ok(secret) if !secret.is_empty() => secret.into_bytes(),
+		_ => {
+			warn!("SESSION_SECRET is not set; using a random per-process token key");
+			let mut key = vec![0u8; 32];
+			rand::thread_rng().fill_bytes(&mut key);
+			key
+		}
+	};
+}
+
+// Signed part of a bearer token
+#[derive(Debug, Serialize, Deserialize)]
+struct Claims {
+	sid: String,
+	sub: String,
+	exp: i64,
+}
+
+// The caller identified by a valid bearer token
+#[derive(Debug, Clone)]
+pub struct AuthenticatedUser {
+	pub user_id: String,
+	pub session_id: String,
+	// Set when the session was opened with the duress PIN. Whoever forced the
+	// login may be holding it, so its writes under `/users/{user_id}` answer as
+	// if they worked but change nothing.
+	pub duress: bool,
+}
+
+fn mac() -> Hmac<Sha256> {
+	Hmac::<Sha256>::new_from_slice(&TOKEN_KEY).expect("HMAC accepts keys of any length")
+}
+
+// HMAC of `data` under the server key. For values derived from public data
+// that outsiders must not be able to compute for themselves.
+pub fn keyed_digest(data: &[u8]) -> Vec<u8> {
+	let mut mac = mac();
+	mac.update(data);
+	mac.finalize().into_bytes().to_vec()
+}
+
+// Encode a session as `<claims>.<signature>`, both base64url
+pub fn sign_token(session: &Session) -> String {
+	let claims = Claims {
+		sid: session.id.clone(),
+		sub: session.user_id.clone(),
+		exp: session.expires_at.timestamp(),
+	};
+	let payload = URL_SAFE_NO_PAD.encode(serde_json::to_vec(&claims).expect("Claims serialize"));
+
+	let mut mac = mac();
+	mac.update(payload.as_bytes());
+	let signature = URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes());
+
+	format!("{}.{}", payload, signature)
+}
+
+// Check a token's signature and expiry, returning its claims
+fn verify_token(token: &str) -> Option<Claims> {
+	let (payload, signature) = token.split_once('.')?;
+	let signature = URL_SAFE_NO_PAD.decode(signature).ok()?;
+
+	let mut mac = mac();
+	mac.update(payload.as_bytes());
+	mac.verify_slice(&signature).ok()?;
+
+	let claims: Claims = serde_json::from_slice(&URL_SAFE_NO_PAD.decode(payload).ok()?).ok()?;
+	if claims.exp <= Utc::now().timestamp() {
+		return None;
+	}
+	Some(claims)
+}
+
+// Create and persist a new session for a user, returning it with its bearer token
+pub async fn issue_session(
+	store: &dyn Store,
+	user_id: &str,
+	duress: bool,
+) -> Result<(Session, String), StoreError> {
+	let now = Utc::now();
+	let session = Session {
+		id: Uuid::new_v4().to_string(),
+		user_id: user_id.to_string(),
+		created_at: now,
+		expires_at: now + Duration::hours(SESSION_HOURS),
+		duress,
+	};
+	store.save_session(&session).await?;
+
+	let token = sign_token(&session);
+	Ok((session, token))
+}
+
+// Resolve a bearer token to its user. The session must still exist in the
+// store, so deleting it revokes the token before it expires.
+async fn authenticate(
+	store: web::Data<dyn Store>,
+	token: &str,
+) -> Result<AuthenticatedUser, Error> {
+	let claims = verify_token(token).ok_or_else(|| ErrorUnauthorized("Unauthorized"))?;
+
+	let session = store.get_session(&claims.sid).await.map_err(|err| {
+		error!("Failed to fetch session: {:?}", err);
+		ErrorInternalServerError(err.to_string())
+	})?;
+
+	match session {
+		Some(session) if session.user_id == claims.sub && session.expires_at > Utc::now() => {
+			Ok(AuthenticatedUser {
+				user_id: session.user_id,
+				session_id: session.id,
+				duress: session.duress,
+			})
+		}
+		_ => Err(ErrorUnauthorized("Unauthorized")),
+	}
+}
+
+impl FromRequest for AuthenticatedUser {
+	type Error = Error;
+	type Future = Pin<Box<dyn Future<Output = Result<Self, Self::Error>>>>;
+
+	fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+		// Already resolved by `require_path_user` for this request
+		if let Some(user) = req.extensions().get::<AuthenticatedUser>() {
+			let user = user.clone();
+			return Box::pin(async move { Ok(user) });
+		}
+
+		let store = req.app_data::<web::Data<dyn Store>>().cloned();
+		let token = req
+			.headers()
+			.get("Authorization")
+			.and_then(|value| value.to_str().ok())
+			.and_then(|value| value.strip_prefix("Bearer "))
+			.map(|token| token.trim().to_string());
+
+		Box::pin(async move {
+			let store = store.ok_or_else(|| ErrorInternalServerError("Store not configured"))?;
+			let token = token.ok_or_else(|| ErrorUnauthorized("Unauthorized"))?;
+			authenticate(store, &token).await
+		})
+	}
+}
+
+// Middleware for the `/users/{user_id}` scope: the bearer token's subject must
+// be the user named in the path.
+pub async fn require_path_user(
+	mut req: ServiceRequest,
+	next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, Error> {
+	let user = match req.extract::<AuthenticatedUser>().await {
+		Ok(user) => user,
+		Err(err) => return Ok(req.error_response(err).map_into_right_body()),
+	};
+
+	if req.match_info().get("user_id") != Some(user.user_id.as_str()) {
+		return Ok(req
+			.error_response(ErrorForbidden("Forbidden"))
+			.map_into_right_body());
+	}
+
+	req.extensions_mut().insert(user);
+	next.call(req)
+		.await
+		.map(ServiceResponse::map_into_left_body)
+}
+
+// Middleware for the `/admin` scope: the bearer token's subject must be an
+// admin.
+pub async fn require_admin(
+	mut req: ServiceRequest,
+	next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, Error> {
+	let user = match req.extract::<AuthenticatedUser>().await {
+		Ok(user) => user,
+		Err(err) => return Ok(req.error_response(err).map_into_right_body()),
+	};
+
+	let store = req.app_data::<web::Data<dyn Store>>().cloned();
+	let admin = match store {
+		Some(store) => store.get_user(&user.user_id).await.map_err(|err| {
+			error!("Failed to fetch user: {:?}", err);
+			ErrorInternalServerError(err.to_string())
+		}),
+		None => Err(ErrorInternalServerError("Store not configured")),
+	};
+	match admin {
+		Ok(Some(admin)) if admin.admin => {}
+		Ok(_) => {
+			return Ok(req
+				.error_response(ErrorForbidden("Forbidden"))
+				.map_into_right_body())
+		}
+		Err(err) => return Ok(req.error_response(err).map_into_right_body()),
+	}
+
+	req.extensions_mut().insert(user);
+	next.call(req)
+		.await
+		.map(ServiceResponse::map_into_left_body)
+}
@@ -0,0 +1,140 @@
+// avatar.rs
+use std::io::Write;
+
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+
+use crate::auth;
+
+// Cells along each side of the grid; the right half mirrors the left
+const GRID: usize = 5;
+
+// Pixels per cell in PNGs, with half a cell of margin around the grid
+const PNG_CELL: usize = 40;
+
+const BACKGROUND: [u8; 3] = [0xf0, 0xf0, 0xf0];
+
+// A symmetric block pattern and colour derived from a user's handle, so
+// members can tell each other apart on the map without photos. The pattern
+// comes from a keyed hash: handles are few enough to try every one, and an
+// unkeyed avatar would give away whose it is.
+pub struct Identicon {
+	cells: [[bool; GRID]; GRID],
+	colour: [u8; 3],
+	// Stable fingerprint of the pattern, for ETags
+	pub digest: String,
+}
+
+impl Identicon {
+	pub fn new(seed: &str) -> Self {
+		let hash = auth::keyed_digest(format!("cherubgyre-avatar:{}", seed).as_bytes());
+
+		let mut cells = [[false; GRID]; GRID];
+		let half = GRID.div_ceil(2);
+		for (row, cells) in cells.iter_mut().enumerate() {
+			for column in 0..half {
+				let on = hash[row * half + column].is_multiple_of(2);
+				cells[column] = on;
+				cells[GRID - 1 - column] = on;
+			}
+		}
+
+		let hue = u16::from_be_bytes([hash[28], hash[29]]) as f64 / 65_536.0 * 360.0;
+		Identicon {
+			cells,
+			colour: hsl_to_rgb(hue, 0.55, 0.5),
+			digest: hash[..8]
+				.iter()
+				.map(|byte| format!("{:02x}", byte))
+				.collect(),
+		}
+	}
+
+	pub fn svg(&self) -> String {
+		let [r, g, b] = self.colour;
+		let mut svg = format!(
+			"<svg xmlns=\"http://www.w3.org/2000/svg\" viewBox=\"0 0 {size} {size}\" \
+			shape-rendering=\"crispEdges\"><rect width=\"{size}\" height=\"{size}\" \
+			fill=\"#{:02x}{:02x}{:02x}\"/><g fill=\"#{:02x}{:02x}{:02x}\">",
+			BACKGROUND[0],
+			BACKGROUND[1],
+			BACKGROUND[2],
+			r,
+			g,
+			b,
+			size = GRID + 1,
+		);
+		for (row, cells) in self.cells.iter().enumerate() {
+			for (column, on) in cells.iter().enumerate() {
+				if *on {
+					svg.push_str(&format!(
+						"<rect x=\"{}.5\" y=\"{}.5\" width=\"1\" height=\"1\"/>",
+						column, row
+					));
+				}
+			}
+		}
+		svg.push_str("</g></svg>");
+		svg
+	}
+
+	pub fn png(&self) -> Vec<u8> {
+		let size = (GRID + 1) * PNG_CELL;
+		let margin = PNG_CELL / 2;
+
+		// Each scanline is a filter type byte (0, none) and RGB pixels
+		let mut pixels = Vec::with_capacity(size * (1 + 3 * size));
+		for y in 0..size {
+			pixels.push(0);
+			for x in 0..size {
+				let cell =
+					|p: usize| (p >= margin && p < size - margin).then(|| (p - margin) / PNG_CELL);
+				let on = matches!((cell(y), cell(x)), (Some(row), Some(column)) if self.cells[row][column]);
+				pixels.extend_from_slice(if on { &self.colour } else { &BACKGROUND });
+			}
+		}
+		let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+		encoder
+			.write_all(&pixels)
+			.expect("writing to a Vec cannot fail");
+		let data = encoder.finish().expect("writing to a Vec cannot fail");
+
+		let mut header = Vec::with_capacity(13);
+		header.extend_from_slice(&(size as u32).to_be_bytes());
+		header.extend_from_slice(&(size as u32).to_be_bytes());
+		// 8 bit RGB, default compression and filtering, not interlaced
+		header.extend_from_slice(&[8, 2, 0, 0, 0]);
+
+		let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+		png_chunk(&mut png, b"IHDR", &header);
+		png_chunk(&mut png, b"IDAT", &data);
+		png_chunk(&mut png, b"IEND", &[]);
+		png
+	}
+}
+
+fn png_chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+	png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+	png.extend_from_slice(kind);
+	png.extend_from_slice(data);
+	let mut crc = crc32fast::Hasher::new();
+	crc.update(kind);
+	crc.update(data);
+	png.extend_from_slice(&crc.finalize().to_be_bytes());
+}
+
+fn hsl_to_rgb(hue: f64, saturation: f64, lightness: f64) -> [u8; 3] {
+	let chroma = (1.0 - (2.0 * lightness - 1.0).abs()) * saturation;
+	let sector = hue / 60.0;
+	let x = chroma * (1.0 - (sector % 2.0 - 1.0).abs());
+	let (r, g, b) = match sector as u32 {
+		0 => (chroma, x, 0.0),
+		1 => (x, chroma, 0.0),
+		2 => (0.0, chroma, x),
+		3 => (0.0, x, chroma),
+		4 => (x, 0.0, chroma),
+		_ => (chroma, 0.0, x),
+	};
+	let m = lightness - chroma / 2.0;
+	[r, g, b].map(|channel| ((channel + m) * 255.0).round() as u8)
+}
@@ -0,0 +1,119 @@
+// block_db.rs
+use aws_sdk_dynamodb::types::AttributeValue;
+use aws_sdk_dynamodb::{Client, Error};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use tracing::info;
+
+// One user blocking another. Only the blocker ever sees it.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Block {
+	pub blocker_id: String,
+	pub blocked_id: String,
+	pub created_at: DateTime<Utc>,
+}
+
+impl Block {
+	pub fn new(blocker_id: &str, blocked_id: &str) -> Self {
+		Block {
+			blocker_id: blocker_id.to_string(),
+			blocked_id: blocked_id.to_string(),
+			created_at: Utc::now(),
+		}
+	}
+}
+
+fn block_from_item(item: &HashMap<String, AttributeValue>) -> Block {
+	let string_attr = |name: &str| {
+		item.get(name)
+			.and_then(|v| v.as_s().ok())
+			.map(|s| s.to_string())
+			.unwrap_or_default()
+	};
+
+	Block {
+		blocker_id: string_attr("blocker_id"),
+		blocked_id: string_attr("blocked_id"),
+		created_at: DateTime::parse_from_rfc3339(&string_attr("created_at"))
+			.map(|v| v.with_timezone(&Utc))
+			.unwrap_or_else(|_| Utc::now()),
+	}
+}
+
+// Write a block to the DynamoDB "Blocks" table, keyed by blocker and blocked
+// user
+pub async fn put_block(client: &Client, block: &Block) -> Result<(), Error> {
+	info!("Writing a block in DynamoDB");
+
+	client
+		.put_item()
+		.table_name("Blocks")
+		.item("blocker_id", AttributeValue::S(block.blocker_id.clone()))
+		.item("blocked_id", AttributeValue::S(block.blocked_id.clone()))
+		.item(
+			"created_at",
+			AttributeValue::S(block.created_at.to_rfc3339()),
+		)
+		.send()
+		.await?;
+
+	Ok(())
+}
+
+pub async fn get_block(
+	client: &Client,
+	blocker_id: &str,
+	blocked_id: &str,
+) -> Result<Option<Block>, Error> {
+	let result = client
+		.get_item()
+		.table_name("Blocks")
+		.key("blocker_id", AttributeValue::S(blocker_id.to_string()))
+		.key("blocked_id", AttributeValue::S(blocked_id.to_string()))
+		.send()
+		.await?;
+
+	Ok(result.item.as_ref().map(block_from_item))
+}
+
+// Every user `blocker_id` has blocked
+pub async fn get_blocks(client: &Client, blocker_id: &str) -> Result<Vec<Block>, Error> {
+	let mut blocks = Vec::new();
+	let mut start_key = None;
+
+	loop {
+		let result = client
+			.query()
+			.table_name("Blocks")
+			.key_condition_expression("blocker_id = :blocker_id")
+			.expression_attribute_values(":blocker_id", AttributeValue::S(blocker_id.to_string()))
+			.set_exclusive_start_key(start_key)
+			.send()
+			.await?;
+
+		blocks.extend(result.items.unwrap_or_default().iter().map(block_from_item));
+		start_key = result.last_evaluated_key;
+		if start_key.is_none() {
+			return Ok(blocks);
+		}
+	}
+}
+
+pub async fn delete_block(
+	client: &Client,
+	blocker_id: &str,
+	blocked_id: &str,
+) -> Result<(), Error> {
+	info!("Deleting a block in DynamoDB");
+
+	client
+		.delete_item()
+		.table_name("Blocks")
+		.key("blocker_id", AttributeValue::S(blocker_id.to_string()))
+		.key("blocked_id", AttributeValue::S(blocked_id.to_string()))
+		.send()
+		.await?;
+
+	Ok(())
+}
@@ -0,0 +1,93 @@
+// block_handlers.rs
+use actix_web::{web, HttpResponse};
+use serde::Deserialize;
+use tracing::{error, info};
+
+use crate::auth::AuthenticatedUser;
+use crate::block_db::Block;
+use crate::store::{Store, StoreError};
+
+#[derive(Debug, Deserialize)]
+pub struct BlockRequest {
+	// ID of the user to block
+	user_id: String,
+}
+
+// POST /users/{user_id}/blocks
+//
+// Ends any follow between the two users, in either direction, and any
+// unanswered request. Nothing tells the blocked user.
+pub async fn block_user(
+	store: web::Data<dyn Store>,
+	user: AuthenticatedUser,
+	path: web::Path<String>,
+	req: web::Json<BlockRequest>,
+) -> HttpResponse {
+	let blocker_id = path.into_inner();
+	let blocked_id = req.user_id.clone();
+	if blocker_id == blocked_id {
+		return HttpResponse::BadRequest().body("Cannot block yourself");
+	}
+
+	let block = Block::new(&blocker_id, &blocked_id);
+	// Blocking followers would stop them hearing about the duress
+	if user.duress {
+		info!("Duress session tried to block a user for {}", blocker_id);
+		return HttpResponse::Ok().json(block);
+	}
+	let result = async {
+		store.save_block(&block).await?;
+		for (follower_id, followed_id) in [(&blocker_id, &blocked_id), (&blocked_id, &blocker_id)] {
+			store.remove_follow(follower_id, followed_id).await?;
+			store
+				.delete_follow_request(follower_id, followed_id)
+				.await?;
+		}
+		Ok::<_, StoreError>(())
+	}
+	.await;
+
+	match result {
+		Ok(_) => HttpResponse::Ok().json(block),
+		Err(err) => {
+			error!("Failed to block user: {:?}", err);
+			HttpResponse::InternalServerError().body(err.to_string())
+		}
+	}
+}
+
+// GET /users/{user_id}/blocks
+pub async fn get_blocks(store: web::Data<dyn Store>, path: web::Path<String>) -> HttpResponse {
+	let blocker_id = path.into_inner();
+
+	match store.get_blocks(&blocker_id).await {
+		Ok(blocks) => HttpResponse::Ok().json(blocks),
+		Err(err) => {
+			error!("Failed to fetch blocks: {:?}", err);
+			HttpResponse::InternalServerError().body(err.to_string())
+		}
+	}
+}
+
+// DELETE /users/{user_id}/blocks/{blocked_id}
+//
+// Unblocking does not bring back follows the block ended.
+pub async fn unblock_user(
+	store: web::Data<dyn Store>,
+	user: AuthenticatedUser,
+	path: web::Path<(String, String)>,
+) -> HttpResponse {
+	let (blocker_id, blocked_id) = path.into_inner();
+	if user.duress {
+		info!("Duress session tried to unblock a user for {}", blocker_id);
+		return HttpResponse::Ok().body("Unblocked successfully");
+	}
+
+	match store.delete_block(&blocker_id, &blocked_id).await {
+		Ok(_) => HttpResponse::Ok().body("Unblocked successfully"),
+		Err(err) => {
+			error!("Failed to unblock user: {:?}", err);
+			HttpResponse::InternalServerError().body(err.to_string())
+		}
+	}
+}
@@ -0,0 +1,126 @@
+// checkin_db.rs
+use aws_sdk_dynamodb::types::AttributeValue;
+use aws_sdk_dynamodb::{Client, Error};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use tracing::info;
+
+use crate::duress_db::{sort_key_time, Location};
+
+// A location reported by a user so the people following them can see where
+// they last were
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Checkin {
+	pub id: String,
+	pub user_id: String,
+	pub location: Location,
+	// Time reported by the client
+	pub timestamp: DateTime<Utc>,
+	// Time the server recorded the check-in
+	pub created_at: DateTime<Utc>,
+}
+
+impl Checkin {
+	// A new check-in recorded now
+	pub fn new(user_id: &str, location: Location, timestamp: DateTime<Utc>) -> Self {
+		Checkin {
+			id: uuid::Uuid::new_v4().to_string(),
+			user_id: user_id.to_string(),
+			location,
+			timestamp,
+			created_at: Utc::now(),
+		}
+	}
+}
+
+fn checkin_from_item(item: &HashMap<String, AttributeValue>) -> Checkin {
+	let string_attr = |name: &str| {
+		item.get(name)
+			.and_then(|v| v.as_s().ok())
+			.map(|s| s.to_string())
+			.unwrap_or_default()
+	};
+	let number_attr = |name: &str| {
+		item.get(name)
+			.and_then(|v| v.as_n().ok())
+			.and_then(|n| n.parse::<f64>().ok())
+	};
+	let time_attr = |name: &str| {
+		DateTime::parse_from_rfc3339(&string_attr(name))
+			.map(|v| v.with_timezone(&Utc))
+			.unwrap_or_else(|_| Utc::now())
+	};
+
+	Checkin {
+		id: string_attr("id"),
+		user_id: string_attr("user_id"),
+		location: Location {
+			latitude: number_attr("latitude").unwrap_or_default(),
+			longitude: number_attr("longitude").unwrap_or_default(),
+			accuracy: number_attr("accuracy"),
+		},
+		timestamp: time_attr("timestamp"),
+		created_at: time_attr("created_at"),
+	}
+}
+
+// Write a check-in to the DynamoDB "Checkin" table, keyed by user and time
+pub async fn put_checkin(client: &Client, checkin: &Checkin) -> Result<(), Error> {
+	info!("Writing a check-in in DynamoDB");
+
+	let mut request = client
+		.put_item()
+		.table_name("Checkin")
+		.item("user_id", AttributeValue::S(checkin.user_id.clone()))
+		.item(
+			"sort_key",
+			AttributeValue::S(format!(
+				"{}#{}",
+				sort_key_time(checkin.created_at),
+				checkin.id
+			)),
+		)
+		.item("id", AttributeValue::S(checkin.id.clone()))
+		.item(
+			"latitude",
+			AttributeValue::N(checkin.location.latitude.to_string()),
+		)
+		.item(
+			"longitude",
+			AttributeValue::N(checkin.location.longitude.to_string()),
+		)
+		.item(
+			"timestamp",
+			AttributeValue::S(checkin.timestamp.to_rfc3339()),
+		)
+		.item(
+			"created_at",
+			AttributeValue::S(checkin.created_at.to_rfc3339()),
+		);
+	if let Some(accuracy) = checkin.location.accuracy {
+		request = request.item("accuracy", AttributeValue::N(accuracy.to_string()));
+	}
+
+	request.send().await?;
+	Ok(())
+}
+
+// Retrieve a user's most recent check-in
+pub async fn get_last_checkin(client: &Client, user_id: &str) -> Result<Option<Checkin>, Error> {
+	let result = client
+		.query()
+		.table_name("Checkin")
+		.key_condition_expression("user_id = :user_id")
+		.expression_attribute_values(":user_id", AttributeValue::S(user_id.to_string()))
+		.scan_index_forward(false)
+		.limit(1)
+		.send()
+		.await?;
+
+	Ok(result
+		.items
+		.unwrap_or_default()
+		.first()
+		.map(checkin_from_item))
+}
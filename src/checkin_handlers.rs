@@ -0,0 +1,129 @@
+// checkin_handlers.rs
+use actix_web::{web, HttpResponse};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tracing::{error, info};
+
+use crate::auth::AuthenticatedUser;
+use crate::checkin_db::Checkin;
+use crate::duress_db::Location;
+use crate::duress_handlers::active_duress_events;
+use crate::location_db::UserLocation;
+use crate::store::{Store, StoreError};
+
+#[derive(Debug, Deserialize)]
+pub struct CheckinRequest {
+	location: Location,
+	timestamp: DateTime<Utc>,
+}
+
+// One followed user as shown on the map
+#[derive(Debug, Serialize)]
+pub struct MapInfo {
+	pub user_id: String,
+	// The user's anonymous handle, if they have one
+	pub username: Option<String>,
+	// Where the user's latest active, real duress event was raised, or else
+	// where they last checked in
+	pub location: Option<Location>,
+	// Whether the user has an active, real duress event
+	pub duress: bool,
+	pub last_checkin: Option<DateTime<Utc>>,
+}
+
+// POST /users/{user_id}/checkin
+pub async fn checkin(
+	store: web::Data<dyn Store>,
+	user: AuthenticatedUser,
+	path: web::Path<String>,
+	req: web::Json<CheckinRequest>,
+) -> HttpResponse {
+	let user_id = path.into_inner();
+	let CheckinRequest {
+		location,
+		timestamp,
+	} = req.into_inner();
+	if !location.is_valid() {
+		return HttpResponse::BadRequest().body("Invalid location");
+	}
+
+	let checkin = Checkin::new(&user_id, location, timestamp);
+	// A made-up location could send followers to the wrong place
+	if user.duress {
+		info!("Duress session tried to check in for {}", user_id);
+		return HttpResponse::Ok().json(checkin);
+	}
+	if let Err(err) = store.save_checkin(&checkin).await {
+		error!("Failed to save check-in: {:?}", err);
+		return HttpResponse::InternalServerError().body(err.to_string());
+	}
+	// Keep the user findable by people raising duress near them
+	match store
+		.save_user_location(&UserLocation::new(&user_id, location))
+		.await
+	{
+		Ok(_) => HttpResponse::Ok().json(checkin),
+		Err(err) => {
+			error!("Failed to save user location: {:?}", err);
+			HttpResponse::InternalServerError().body(err.to_string())
+		}
+	}
+}
+
+// Location and duress status of every user `user_id` follows
+async fn followed_users_map_info(
+	store: &dyn Store,
+	user_id: &str,
+) -> Result<Vec<MapInfo>, StoreError> {
+	let mut map_info = Vec::new();
+
+	for follow in store.get_following(user_id).await? {
+		if store
+			.get_block(&follow.followed_id, user_id)
+			.await?
+			.is_some()
+		{
+			continue;
+		}
+		let last_checkin = store.get_last_checkin(&follow.followed_id).await?;
+		let username = store
+			.get_user(&follow.followed_id)
+			.await?
+			.and_then(|user| user.handle);
+		let real_events: Vec<_> = active_duress_events(store, &follow.followed_id)
+			.await?
+			.into_iter()
+			.filter(|event| !event.test)
+			.collect();
+		// Followers heading to help need where the alarm was raised
+		let duress_location = real_events
+			.iter()
+			.filter_map(|event| event.location.map(|location| (event.created_at, location)))
+			.max_by_key(|(created_at, _)| *created_at)
+			.map(|(_, location)| location);
+
+		map_info.push(MapInfo {
+			user_id: follow.followed_id,
+			username,
+			location: duress_location
+				.or_else(|| last_checkin.as_ref().map(|checkin| checkin.location)),
+			duress: !real_events.is_empty(),
+			last_checkin: last_checkin.map(|checkin| checkin.created_at),
+		});
+	}
+
+	Ok(map_info)
+}
+
+// GET /users/{user_id}/map
+pub async fn get_map_info(store: web::Data<dyn Store>, path: web::Path<String>) -> HttpResponse {
+	let user_id = path.into_inner();
+
+	match followed_users_map_info(store.get_ref(), &user_id).await {
+		Ok(map_info) => HttpResponse::Ok().json(map_info),
+		Err(err) => {
+			error!("Failed to build map info: {:?}", err);
+			HttpResponse::InternalServerError().body(err.to_string())
+		}
+	}
+}
@@ -1,16 +1,25 @@
 use aws_config::meta::region::RegionProviderChain;
-use aws_sdk_dynamodb::types::AttributeValue;
+use aws_config::BehaviorVersion;
+use aws_sdk_dynamodb::operation::transact_write_items::TransactWriteItemsError;
+use aws_sdk_dynamodb::types::{AttributeValue, Put, TransactWriteItem, Update};
 use aws_sdk_dynamodb::{Client, Error};
+use std::collections::HashMap;
+use std::fmt;
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use tracing::info;
 
+use crate::duress_db::sort_key_time;
+
 pub async fn get_dynamodb_client() -> Client {
 	// Set up the region provider
 	let region_provider = RegionProviderChain::default_provider().or_else("eu-north-1");
 
 	// Load the AWS configuration
-	let config = aws_config::from_env().region(region_provider).load().await;
+	let config = aws_config::defaults(BehaviorVersion::latest())
+		.region(region_provider)
+		.load()
+		.await;
 
 	// Create DynamoDB client from the configuration
 	Client::new(&config)
@@ -20,8 +29,22 @@ pub async fn get_dynamodb_client() -> Client {
 pub struct User {
 	pub id: String,
 	pub invite_code: String,
-	pub normal_pin: String,
-	pub duress_pin: String,
+	// Argon2id PHC strings; never serialized into API responses
+	#[serde(skip_serializing)]
+	pub normal_pin_hash: String,
+	#[serde(skip_serializing)]
+	pub duress_pin_hash: String,
+	// Anonymous name other members know the user by. Users who registered
+	// before handles existed have none until they re-roll.
+	#[serde(default)]
+	pub handle: Option<String>,
+	// May see any user's lineage and suspend their subtree
+	#[serde(default)]
+	pub admin: bool,
+	// Set when an admin suspends the user, who then cannot log in or use
+	// their sessions
+	#[serde(default)]
+	pub suspended_at: Option<DateTime<Utc>>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -33,6 +56,136 @@ pub struct Invite {
 	pub invite_count: u32,
 	// Date when invite was created
 	pub created_at: DateTime<Utc>,
+	// When the code stops working. Invites made before expiry existed never do.
+	#[serde(default)]
+	pub expires_at: Option<DateTime<Utc>>,
+	// Registrations the code allows, unlimited if absent
+	#[serde(default)]
+	pub max_uses: Option<u32>,
+	// Withdrawn by the invitor
+	#[serde(default)]
+	pub revoked: bool,
+}
+
+// Why an invite can no longer be used
+#[derive(Debug, PartialEq, Eq)]
+pub enum InviteError {
+	Expired,
+	Revoked,
+	UsedUp,
+}
+
+impl fmt::Display for InviteError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			InviteError::Expired => write!(f, "Invite has expired"),
+			InviteError::Revoked => write!(f, "Invite has been revoked"),
+			InviteError::UsedUp => write!(f, "Invite has been used up"),
+		}
+	}
+}
+
+impl std::error::Error for InviteError {}
+
+impl Invite {
+	// Whether someone could register with the invite at `now`
+	pub fn check(&self, now: DateTime<Utc>) -> Result<(), InviteError> {
+		if self.revoked {
+			return Err(InviteError::Revoked);
+		}
+		if self.expires_at.is_some_and(|expires_at| expires_at <= now) {
+			return Err(InviteError::Expired);
+		}
+		if self
+			.max_uses
+			.is_some_and(|max_uses| self.invite_count >= max_uses)
+		{
+			return Err(InviteError::UsedUp);
+		}
+		Ok(())
+	}
+}
+
+// A logged-in session. Sessions opened with the duress PIN look exactly like
+// normal ones to the client; only the server knows about the `duress` flag.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Session {
+	// Session ID carried inside the signed bearer token
+	pub id: String,
+	pub user_id: String,
+	pub created_at: DateTime<Utc>,
+	pub expires_at: DateTime<Utc>,
+	#[serde(skip_serializing)]
+	pub duress: bool,
+}
+
+// Read a string attribute from a DynamoDB item, defaulting to empty
+fn string_attr(item: &HashMap<String, AttributeValue>, name: &str) -> String {
+	item.get(name)
+		.and_then(|v| v.as_s().ok())
+		.map(|s| s.to_string())
+		.unwrap_or_default()
+}
+
+// Read an RFC 3339 timestamp attribute from a DynamoDB item
+fn time_attr(item: &HashMap<String, AttributeValue>, name: &str) -> DateTime<Utc> {
+	item.get(name)
+		.and_then(|v| v.as_s().ok())
+		.and_then(|v| DateTime::parse_from_rfc3339(v).ok())
+		.map(|v| v.with_timezone(&Utc))
+		.unwrap_or_else(Utc::now)
+}
+
+fn user_item(user: &User) -> HashMap<String, AttributeValue> {
+	let mut item = HashMap::from([
+		("id".to_string(), AttributeValue::S(user.id.clone())),
+		(
+			"invite_code".to_string(),
+			AttributeValue::S(user.invite_code.clone()),
+		),
+		(
+			"normal_pin_hash".to_string(),
+			AttributeValue::S(user.normal_pin_hash.clone()),
+		),
+		(
+			"duress_pin_hash".to_string(),
+			AttributeValue::S(user.duress_pin_hash.clone()),
+		),
+	]);
+	if let Some(handle) = &user.handle {
+		item.insert("handle".to_string(), AttributeValue::S(handle.clone()));
+	}
+	if user.admin {
+		item.insert("admin".to_string(), AttributeValue::Bool(true));
+	}
+	if let Some(suspended_at) = user.suspended_at {
+		item.insert(
+			"suspended_at".to_string(),
+			AttributeValue::S(suspended_at.to_rfc3339()),
+		);
+	}
+	item
+}
+
+fn user_from_item(item: &HashMap<String, AttributeValue>) -> User {
+	User {
+		id: string_attr(item, "id"),
+		invite_code: string_attr(item, "invite_code"),
+		normal_pin_hash: string_attr(item, "normal_pin_hash"),
+		duress_pin_hash: string_attr(item, "duress_pin_hash"),
+		handle: item
+			.get("handle")
+			.and_then(|v| v.as_s().ok())
+			.map(|s| s.to_string()),
+		admin: item
+			.get("admin")
+			.and_then(|v| v.as_bool().ok())
+			.copied()
+			.unwrap_or(false),
+		suspended_at: item
+			.get("suspended_at")
+			.map(|_| time_attr(item, "suspended_at")),
+	}
 }
 
 pub async fn save_user(client: &Client, user: &User) -> Result<(), Error> {
@@ -40,18 +193,191 @@ pub async fn save_user(client: &Client, user: &User) -> Result<(), Error> {
 	client
 		.put_item()
 		.table_name("User")
-		.item("id", AttributeValue::S(user.id.clone()))
-		.item("invite_code", AttributeValue::S(user.invite_code.clone()))
-		.item("normal_pin", AttributeValue::S(user.normal_pin.clone()))
-		.item("duress_pin", AttributeValue::S(user.duress_pin.clone()))
+		.set_item(Some(user_item(user)))
+		.send()
+		.await?;
+	Ok(())
+}
+
+pub async fn get_user(client: &Client, user_id: &str) -> Result<Option<User>, Error> {
+	let result = client
+		.get_item()
+		.table_name("User")
+		.key("id", AttributeValue::S(user_id.to_string()))
+		.send()
+		.await?;
+
+	Ok(result.item.as_ref().map(user_from_item))
+}
+
+// Mark a user suspended, unless they are missing or already suspended.
+// Returns whether they were.
+pub async fn suspend_user(
+	client: &Client,
+	user_id: &str,
+	suspended_at: DateTime<Utc>,
+) -> Result<bool, Error> {
+	let result = client
+		.update_item()
+		.table_name("User")
+		.key("id", AttributeValue::S(user_id.to_string()))
+		.update_expression("SET suspended_at = :at")
+		.condition_expression("attribute_exists(id) AND attribute_not_exists(suspended_at)")
+		.expression_attribute_values(":at", AttributeValue::S(suspended_at.to_rfc3339()))
+		.send()
+		.await;
+
+	match result {
+		Ok(_) => Ok(true),
+		Err(err)
+			if err
+				.as_service_error()
+				.is_some_and(|err| err.is_conditional_check_failed_exception()) =>
+		{
+			Ok(false)
+		}
+		Err(err) => Err(err.into()),
+	}
+}
+
+// Lift a user's suspension. Returns false if there is no such user.
+pub async fn unsuspend_user(client: &Client, user_id: &str) -> Result<bool, Error> {
+	let result = client
+		.update_item()
+		.table_name("User")
+		.key("id", AttributeValue::S(user_id.to_string()))
+		.update_expression("REMOVE suspended_at")
+		.condition_expression("attribute_exists(id)")
+		.send()
+		.await;
+
+	match result {
+		Ok(_) => Ok(true),
+		Err(err)
+			if err
+				.as_service_error()
+				.is_some_and(|err| err.is_conditional_check_failed_exception()) =>
+		{
+			Ok(false)
+		}
+		Err(err) => Err(err.into()),
+	}
+}
+
+// Users who registered with invite `code`, from the "invite-code-index"
+// global secondary index on the "User" table
+pub async fn get_users_by_invite(client: &Client, code: &str) -> Result<Vec<User>, Error> {
+	let mut users = Vec::new();
+	let mut start_key = None;
+	loop {
+		let result = client
+			.query()
+			.table_name("User")
+			.index_name("invite-code-index")
+			.key_condition_expression("invite_code = :code")
+			.expression_attribute_values(":code", AttributeValue::S(code.to_string()))
+			.set_exclusive_start_key(start_key)
+			.send()
+			.await?;
+
+		users.extend(result.items.unwrap_or_default().iter().map(user_from_item));
+		start_key = result.last_evaluated_key;
+		if start_key.is_none() {
+			break;
+		}
+	}
+
+	Ok(users)
+}
+
+pub async fn save_session(client: &Client, session: &Session) -> Result<(), Error> {
+	client
+		.put_item()
+		.table_name("Session")
+		.item("id", AttributeValue::S(session.id.clone()))
+		.item("user_id", AttributeValue::S(session.user_id.clone()))
+		.item(
+			"created_at",
+			AttributeValue::S(session.created_at.to_rfc3339()),
+		)
+		.item(
+			"expires_at",
+			AttributeValue::S(session.expires_at.to_rfc3339()),
+		)
+		// DynamoDB TTL attribute so expired sessions are purged automatically
+		.item(
+			"ttl",
+			AttributeValue::N(session.expires_at.timestamp().to_string()),
+		)
+		.item("duress", AttributeValue::Bool(session.duress))
+		.send()
+		.await?;
+
+	Ok(())
+}
+
+pub async fn get_session(client: &Client, session_id: &str) -> Result<Option<Session>, Error> {
+	let result = client
+		.get_item()
+		.table_name("Session")
+		.key("id", AttributeValue::S(session_id.to_string()))
 		.send()
 		.await?;
+
+	Ok(result.item.map(|item| Session {
+		id: string_attr(&item, "id"),
+		user_id: string_attr(&item, "user_id"),
+		created_at: time_attr(&item, "created_at"),
+		expires_at: time_attr(&item, "expires_at"),
+		duress: item
+			.get("duress")
+			.and_then(|v| v.as_bool().ok())
+			.copied()
+			.unwrap_or(false),
+	}))
+}
+
+pub async fn delete_session(client: &Client, session_id: &str) -> Result<(), Error> {
+	client
+		.delete_item()
+		.table_name("Session")
+		.key("id", AttributeValue::S(session_id.to_string()))
+		.send()
+		.await?;
+
+	Ok(())
+}
+
+// Delete every session of a user, found through the "user_id-index" global
+// secondary index on the "Session" table
+pub async fn delete_user_sessions(client: &Client, user_id: &str) -> Result<(), Error> {
+	let mut start_key = None;
+	loop {
+		let result = client
+			.query()
+			.table_name("Session")
+			.index_name("user_id-index")
+			.key_condition_expression("user_id = :id")
+			.expression_attribute_values(":id", AttributeValue::S(user_id.to_string()))
+			.set_exclusive_start_key(start_key)
+			.send()
+			.await?;
+
+		for item in result.items.unwrap_or_default() {
+			delete_session(client, &string_attr(&item, "id")).await?;
+		}
+		start_key = result.last_evaluated_key;
+		if start_key.is_none() {
+			break;
+		}
+	}
+
 	Ok(())
 }
 
 pub async fn save_invite(client: &Client, invite: &Invite) -> Result<(), Error> {
 	info!("here i am");
-	client
+	let mut request = client
 		.put_item()
 		.table_name("Invite")
 		.item("code", AttributeValue::S(invite.code.clone()))
@@ -64,12 +390,44 @@ pub async fn save_invite(client: &Client, invite: &Invite) -> Result<(), Error>
 			"created_at",
 			AttributeValue::S(invite.created_at.to_rfc3339()),
 		)
-		.send()
-		.await?;
+		.item("revoked", AttributeValue::Bool(invite.revoked));
+	// Fixed width, so `create_invited_user` can compare it with the time as a
+	// string
+	if let Some(expires_at) = invite.expires_at {
+		request = request.item("expires_at", AttributeValue::S(sort_key_time(expires_at)));
+	}
+	if let Some(max_uses) = invite.max_uses {
+		request = request.item("max_uses", AttributeValue::N(max_uses.to_string()));
+	}
+	request.send().await?;
 
 	Ok(())
 }
 
+fn invite_from_item(item: &HashMap<String, AttributeValue>) -> Invite {
+	let number_attr = |name: &str| {
+		item.get(name)
+			.and_then(|v| v.as_n().ok())
+			.and_then(|n| n.parse::<u32>().ok())
+	};
+
+	Invite {
+		code: string_attr(item, "code"),
+		invitor_id: string_attr(item, "invitor_id"),
+		invite_count: number_attr("invite_count").unwrap_or(0),
+		created_at: time_attr(item, "created_at"),
+		expires_at: item
+			.get("expires_at")
+			.map(|_| time_attr(item, "expires_at")),
+		max_uses: number_attr("max_uses"),
+		revoked: item
+			.get("revoked")
+			.and_then(|v| v.as_bool().ok())
+			.copied()
+			.unwrap_or(false),
+	}
+}
+
 pub async fn get_invite(client: &Client, code: &str) -> Result<Option<Invite>, Error> {
 	let result = client
 		.get_item()
@@ -78,94 +436,164 @@ pub async fn get_invite(client: &Client, code: &str) -> Result<Option<Invite>, E
 		.send()
 		.await?;
 
-	if let Some(item) = result.item {
-		let invite = Invite {
-			code: item
-				.get("code")
-				.and_then(|v| v.as_s().ok())
-				.map(|s| s.to_string())
-				.unwrap_or_else(String::new),
-			invitor_id: item
-				.get("invitor_id")
-				.and_then(|v| v.as_s().ok())
-				.map(|s| s.to_string())
-				.unwrap_or_else(String::new),
-			invite_count: item
-				.get("invite_count")
-				.and_then(|v| v.as_n().ok())
-				.and_then(|n| n.parse::<u32>().ok())
-				.unwrap_or(0),
-			created_at: item
-				.get("created_at")
-				.and_then(|v| v.as_s().ok())
-				.map(|v| DateTime::parse_from_rfc3339(v).unwrap().with_timezone(&Utc))
-				.unwrap_or(Utc::now()),
-		};
-		Ok(Some(invite))
-	} else {
-		Ok(None)
-	}
+	Ok(result.item.as_ref().map(invite_from_item))
 }
 
+// A user's invites, from the "invitor_id-index" global secondary index on
+// the "Invite" table
 pub async fn get_user_invites(client: &Client, user_id: &str) -> Result<Vec<Invite>, Error> {
-	let result = client
-		.scan()
-		.table_name("Invite")
-		.filter_expression("invitor_id = :id")
-		.expression_attribute_values(":id", AttributeValue::S(user_id.to_string()))
-		.send()
-		.await?;
+	let mut invites = Vec::new();
+	let mut start_key = None;
+	loop {
+		let result = client
+			.query()
+			.table_name("Invite")
+			.index_name("invitor_id-index")
+			.key_condition_expression("invitor_id = :id")
+			.expression_attribute_values(":id", AttributeValue::S(user_id.to_string()))
+			.set_exclusive_start_key(start_key)
+			.send()
+			.await?;
 
-	let invites = result
-		.items
-		.unwrap_or_default()
-		.into_iter()
-		.map(|item| Invite {
-			code: item
-				.get("code")
-				.and_then(|v| v.as_s().ok())
-				.map(|s| s.to_string())
-				.unwrap_or_else(String::new),
-			invitor_id: item
-				.get("invitor_id")
-				.and_then(|v| v.as_s().ok())
-				.map(|s| s.to_string())
-				.unwrap_or_else(String::new),
-			invite_count: item
-				.get("invite_count")
-				.and_then(|v| v.as_n().ok())
-				.and_then(|n| n.parse::<u32>().ok())
-				.unwrap_or(0),
-			created_at: item
-				.get("created_at")
-				.and_then(|v| v.as_s().ok())
-				.map(|v| DateTime::parse_from_rfc3339(v).unwrap().with_timezone(&Utc))
-				.unwrap_or(Utc::now()),
-		})
-		.collect();
+		invites.extend(
+			result
+				.items
+				.unwrap_or_default()
+				.iter()
+				.map(invite_from_item),
+		);
+		start_key = result.last_evaluated_key;
+		if start_key.is_none() {
+			break;
+		}
+	}
 
 	Ok(invites)
 }
 
 pub async fn update_invite(client: &Client, invite: &Invite) -> Result<(), Error> {
-	client
+	let mut set = vec![
+		"invitor_id = :invitor_id",
+		"created_at = :created_at",
+		"revoked = :revoked",
+	];
+	let mut remove = Vec::new();
+	let mut request = client
 		.update_item()
 		.table_name("Invite")
 		.key("code", AttributeValue::S(invite.code.clone()))
-		.update_expression(
-			"SET invitor_id = :invitor_id, invite_count = :invite_count, created_at = :created_at",
-		)
 		.expression_attribute_values(":invitor_id", AttributeValue::S(invite.invitor_id.clone()))
-		.expression_attribute_values(
-			":invite_count",
-			AttributeValue::N(invite.invite_count.to_string()),
-		)
 		.expression_attribute_values(
 			":created_at",
 			AttributeValue::S(invite.created_at.to_rfc3339()),
 		)
-		.send()
-		.await?;
+		.expression_attribute_values(":revoked", AttributeValue::Bool(invite.revoked));
+	match invite.expires_at {
+		Some(expires_at) => {
+			set.push("expires_at = :expires_at");
+			request = request.expression_attribute_values(
+				":expires_at",
+				AttributeValue::S(sort_key_time(expires_at)),
+			);
+		}
+		None => remove.push("expires_at"),
+	}
+	match invite.max_uses {
+		Some(max_uses) => {
+			set.push("max_uses = :max_uses");
+			request = request
+				.expression_attribute_values(":max_uses", AttributeValue::N(max_uses.to_string()));
+		}
+		None => remove.push("max_uses"),
+	}
+
+	// `invite_count` is only ever incremented, by `create_invited_user`, so
+	// writing back a stale count cannot undo a registration
+	let mut expression = format!("SET {}", set.join(", "));
+	if !remove.is_empty() {
+		expression.push_str(&format!(" REMOVE {}", remove.join(", ")));
+	}
+	request.update_expression(expression).send().await?;
 
 	Ok(())
 }
+
+// Set only `revoked`, so a registration counted meanwhile is kept
+pub async fn revoke_invite(client: &Client, code: &str) -> Result<(), Error> {
+	let result = client
+		.update_item()
+		.table_name("Invite")
+		.key("code", AttributeValue::S(code.to_string()))
+		.update_expression("SET revoked = :true")
+		// Don't create an invite that isn't there
+		.condition_expression("attribute_exists(code)")
+		.expression_attribute_values(":true", AttributeValue::Bool(true))
+		.send()
+		.await;
+
+	match result {
+		Ok(_) => Ok(()),
+		Err(err)
+			if err
+				.as_service_error()
+				.is_some_and(|err| err.is_conditional_check_failed_exception()) =>
+		{
+			Ok(())
+		}
+		Err(err) => Err(err.into()),
+	}
+}
+
+// Create `user` and count a registration against their invite in one
+// transaction, so a failed write neither burns an invite use nor leaves a user
+// nobody invited. The invite must still be usable at `now`, checked as part of
+// the same write, so two registrations cannot both take its last use. Returns
+// false, writing nothing, if it is not.
+pub async fn create_invited_user(
+	client: &Client,
+	user: &User,
+	now: DateTime<Utc>,
+) -> Result<bool, Error> {
+	let consume_invite = Update::builder()
+		.table_name("Invite")
+		.key("code", AttributeValue::S(user.invite_code.clone()))
+		.update_expression("SET invite_count = invite_count + :one")
+		.condition_expression(
+			"attribute_exists(code) \
+			AND (attribute_not_exists(revoked) OR revoked = :false) \
+			AND (attribute_not_exists(expires_at) OR expires_at > :now) \
+			AND (attribute_not_exists(max_uses) OR invite_count < max_uses)",
+		)
+		.expression_attribute_values(":one", AttributeValue::N("1".to_string()))
+		.expression_attribute_values(":false", AttributeValue::Bool(false))
+		.expression_attribute_values(":now", AttributeValue::S(sort_key_time(now)))
+		.build()?;
+	let create_user = Put::builder()
+		.table_name("User")
+		.set_item(Some(user_item(user)))
+		.condition_expression("attribute_not_exists(id)")
+		.build()?;
+
+	let result = client
+		.transact_write_items()
+		.transact_items(TransactWriteItem::builder().update(consume_invite).build())
+		.transact_items(TransactWriteItem::builder().put(create_user).build())
+		.send()
+		.await;
+
+	match result {
+		Ok(_) => Ok(true),
+		// Only the invite's condition failing means the invite was the problem
+		Err(err)
+			if matches!(
+				err.as_service_error(),
+				Some(TransactWriteItemsError::TransactionCanceledException(canceled))
+					if canceled.cancellation_reasons().first().and_then(|reason| reason.code())
+						== Some("ConditionalCheckFailed")
+			) =>
+		{
+			Ok(false)
+		}
+		Err(err) => Err(err.into()),
+	}
+}
@@ -0,0 +1,401 @@
+// delivery_db.rs
+use aws_sdk_dynamodb::types::AttributeValue;
+use aws_sdk_dynamodb::{Client, Error};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::duress_db::sort_key_time;
+use crate::notify::Alert;
+
+// Whether an alert announces a new duress event or the end of one
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AlertKind {
+	Raised,
+	Ended,
+}
+
+impl AlertKind {
+	pub fn as_str(&self) -> &'static str {
+		match self {
+			AlertKind::Raised => "raised",
+			AlertKind::Ended => "ended",
+		}
+	}
+
+	pub fn parse(value: &str) -> Option<AlertKind> {
+		match value {
+			"raised" => Some(AlertKind::Raised),
+			"ended" => Some(AlertKind::Ended),
+			_ => None,
+		}
+	}
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DeliveryStatus {
+	// Waiting in the job queue, possibly after failed attempts
+	Queued,
+	Sent,
+	Failed,
+	// Dropped, never to be sent, because of the recipient's quiet hours
+	Suppressed,
+	// Never sent, as no backend for the channel is configured
+	Unavailable,
+}
+
+impl DeliveryStatus {
+	pub fn as_str(&self) -> &'static str {
+		match self {
+			DeliveryStatus::Queued => "queued",
+			DeliveryStatus::Sent => "sent",
+			DeliveryStatus::Failed => "failed",
+			DeliveryStatus::Suppressed => "suppressed",
+			DeliveryStatus::Unavailable => "unavailable",
+		}
+	}
+
+	pub fn parse(value: &str) -> Option<DeliveryStatus> {
+		match value {
+			"queued" => Some(DeliveryStatus::Queued),
+			"sent" => Some(DeliveryStatus::Sent),
+			"failed" => Some(DeliveryStatus::Failed),
+			"suppressed" => Some(DeliveryStatus::Suppressed),
+			"unavailable" => Some(DeliveryStatus::Unavailable),
+			_ => None,
+		}
+	}
+}
+
+// The outcome of sending one alert to one recipient over one channel
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Delivery {
+	pub id: String,
+	pub event_id: String,
+	pub recipient_id: String,
+	pub channel: String,
+	pub kind: AlertKind,
+	pub status: DeliveryStatus,
+	// Why the delivery failed, if it did
+	pub error: Option<String>,
+	pub created_at: DateTime<Utc>,
+}
+
+// A queued delivery. Jobs are keyed by (event, kind, recipient, channel), so
+// fanning the same alert out twice sends it once. The key doubles as the ID of
+// the delivery record the job reports through.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Job {
+	pub key: String,
+	pub recipient_id: String,
+	pub channel: String,
+	pub alert: Alert,
+	pub attempts: u32,
+	pub next_attempt_at: DateTime<Utc>,
+	pub last_error: Option<String>,
+	// Gave up after too many attempts; kept for the dead-letter list
+	pub dead: bool,
+	pub created_at: DateTime<Utc>,
+}
+
+impl Job {
+	pub fn key_for(alert: &Alert, recipient_id: &str, channel: &str) -> String {
+		format!(
+			"{}:{}:{}:{}",
+			alert.event_id,
+			alert.kind.as_str(),
+			recipient_id,
+			channel
+		)
+	}
+
+	// The delivery record this job reports its outcome through
+	pub fn delivery(&self, status: DeliveryStatus) -> Delivery {
+		Delivery {
+			id: self.key.clone(),
+			event_id: self.alert.event_id.clone(),
+			recipient_id: self.recipient_id.clone(),
+			channel: self.channel.clone(),
+			kind: self.alert.kind,
+			status,
+			error: self.last_error.clone(),
+			created_at: self.created_at,
+		}
+	}
+}
+
+fn delivery_from_item(item: &HashMap<String, AttributeValue>) -> Delivery {
+	let string_attr = |name: &str| {
+		item.get(name)
+			.and_then(|v| v.as_s().ok())
+			.map(|s| s.to_string())
+	};
+
+	Delivery {
+		id: string_attr("id").unwrap_or_default(),
+		event_id: string_attr("event_id").unwrap_or_default(),
+		recipient_id: string_attr("recipient_id").unwrap_or_default(),
+		channel: string_attr("channel").unwrap_or_default(),
+		kind: string_attr("kind")
+			.and_then(|v| AlertKind::parse(&v))
+			.unwrap_or(AlertKind::Raised),
+		status: string_attr("status")
+			.and_then(|v| DeliveryStatus::parse(&v))
+			.unwrap_or(DeliveryStatus::Failed),
+		error: string_attr("error"),
+		created_at: string_attr("created_at")
+			.and_then(|v| DateTime::parse_from_rfc3339(&v).ok())
+			.map(|v| v.with_timezone(&Utc))
+			.unwrap_or_else(Utc::now),
+	}
+}
+
+// Record a delivery in the DynamoDB "Delivery" table, keyed by event
+pub async fn put_delivery(client: &Client, delivery: &Delivery) -> Result<(), Error> {
+	let mut request = client
+		.put_item()
+		.table_name("Delivery")
+		.item("event_id", AttributeValue::S(delivery.event_id.clone()))
+		.item("id", AttributeValue::S(delivery.id.clone()))
+		.item(
+			"recipient_id",
+			AttributeValue::S(delivery.recipient_id.clone()),
+		)
+		.item("channel", AttributeValue::S(delivery.channel.clone()))
+		.item(
+			"kind",
+			AttributeValue::S(delivery.kind.as_str().to_string()),
+		)
+		.item(
+			"status",
+			AttributeValue::S(delivery.status.as_str().to_string()),
+		)
+		.item(
+			"created_at",
+			AttributeValue::S(delivery.created_at.to_rfc3339()),
+		);
+	if let Some(error) = &delivery.error {
+		request = request.item("error", AttributeValue::S(error.clone()));
+	}
+
+	request.send().await?;
+	Ok(())
+}
+
+// Retrieve every delivery recorded for a duress event
+pub async fn get_event_deliveries(client: &Client, event_id: &str) -> Result<Vec<Delivery>, Error> {
+	let mut deliveries = Vec::new();
+	let mut start_key = None;
+	loop {
+		let result = client
+			.query()
+			.table_name("Delivery")
+			.key_condition_expression("event_id = :event_id")
+			.expression_attribute_values(":event_id", AttributeValue::S(event_id.to_string()))
+			.set_exclusive_start_key(start_key)
+			.send()
+			.await?;
+		deliveries.extend(
+			result
+				.items
+				.unwrap_or_default()
+				.iter()
+				.map(delivery_from_item),
+		);
+
+		start_key = result.last_evaluated_key;
+		if start_key.is_none() {
+			break;
+		}
+	}
+
+	Ok(deliveries)
+}
+
+fn job_from_item(item: &HashMap<String, AttributeValue>) -> Option<Job> {
+	let string_attr = |name: &str| {
+		item.get(name)
+			.and_then(|v| v.as_s().ok())
+			.map(|s| s.to_string())
+	};
+	let time_attr = |name: &str| {
+		string_attr(name)
+			.and_then(|v| DateTime::parse_from_rfc3339(&v).ok())
+			.map(|v| v.with_timezone(&Utc))
+			.unwrap_or_else(Utc::now)
+	};
+
+	Some(Job {
+		key: string_attr("key")?,
+		recipient_id: string_attr("recipient_id")?,
+		channel: string_attr("channel")?,
+		alert: serde_json::from_str(&string_attr("alert")?).ok()?,
+		attempts: item
+			.get("attempts")
+			.and_then(|v| v.as_n().ok())
+			.and_then(|n| n.parse().ok())
+			.unwrap_or(0),
+		next_attempt_at: time_attr("next_attempt_at"),
+		last_error: string_attr("last_error"),
+		dead: string_attr("queue").as_deref() == Some(DEAD_QUEUE),
+		created_at: time_attr("created_at"),
+	})
+}
+
+// Partitions of the `queue-index` global secondary index on "NotificationJob".
+// Dead jobs sit in their own partition so polling for due jobs never reads them.
+const PENDING_QUEUE: &str = "pending";
+const DEAD_QUEUE: &str = "dead";
+
+fn queue_name(dead: bool) -> &'static str {
+	if dead {
+		DEAD_QUEUE
+	} else {
+		PENDING_QUEUE
+	}
+}
+
+fn job_request(
+	client: &Client,
+	job: &Job,
+) -> aws_sdk_dynamodb::operation::put_item::builders::PutItemFluentBuilder {
+	let mut request = client
+		.put_item()
+		.table_name("NotificationJob")
+		.item("key", AttributeValue::S(job.key.clone()))
+		.item("recipient_id", AttributeValue::S(job.recipient_id.clone()))
+		.item("channel", AttributeValue::S(job.channel.clone()))
+		.item(
+			"alert",
+			AttributeValue::S(serde_json::to_string(&job.alert).unwrap_or_default()),
+		)
+		.item("attempts", AttributeValue::N(job.attempts.to_string()))
+		// Fixed width so `<=` compares times
+		.item(
+			"next_attempt_at",
+			AttributeValue::S(sort_key_time(job.next_attempt_at)),
+		)
+		.item("queue", AttributeValue::S(queue_name(job.dead).to_string()))
+		.item("created_at", AttributeValue::S(job.created_at.to_rfc3339()));
+	if let Some(last_error) = &job.last_error {
+		request = request.item("last_error", AttributeValue::S(last_error.clone()));
+	}
+	request
+}
+
+// Add a job to the DynamoDB "NotificationJob" table unless one with the same
+// key exists. Returns whether it was added.
+pub async fn insert_job(client: &Client, job: &Job) -> Result<bool, Error> {
+	let result = job_request(client, job)
+		.condition_expression("attribute_not_exists(#key)")
+		.expression_attribute_names("#key", "key")
+		.send()
+		.await;
+
+	match result {
+		Ok(_) => Ok(true),
+		Err(err)
+			if err
+				.as_service_error()
+				.is_some_and(|err| err.is_conditional_check_failed_exception()) =>
+		{
+			Ok(false)
+		}
+		Err(err) => Err(err.into()),
+	}
+}
+
+pub async fn put_job(client: &Client, job: &Job) -> Result<(), Error> {
+	job_request(client, job).send().await?;
+	Ok(())
+}
+
+pub async fn delete_job(client: &Client, key: &str) -> Result<(), Error> {
+	client
+		.delete_item()
+		.table_name("NotificationJob")
+		.key("key", AttributeValue::S(key.to_string()))
+		.send()
+		.await?;
+	Ok(())
+}
+
+// Move a job's next attempt from `expected` to `until`, only if no other
+// worker has done so first. Returns whether this caller won the job.
+pub async fn claim_job(
+	client: &Client,
+	key: &str,
+	expected: DateTime<Utc>,
+	until: DateTime<Utc>,
+) -> Result<bool, Error> {
+	let result = client
+		.update_item()
+		.table_name("NotificationJob")
+		.key("key", AttributeValue::S(key.to_string()))
+		.update_expression("SET next_attempt_at = :until")
+		.condition_expression("next_attempt_at = :expected")
+		.expression_attribute_values(":expected", AttributeValue::S(sort_key_time(expected)))
+		.expression_attribute_values(":until", AttributeValue::S(sort_key_time(until)))
+		.send()
+		.await;
+
+	match result {
+		Ok(_) => Ok(true),
+		Err(err)
+			if err
+				.as_service_error()
+				.is_some_and(|err| err.is_conditional_check_failed_exception()) =>
+		{
+			Ok(false)
+		}
+		Err(err) => Err(err.into()),
+	}
+}
+
+// Query the `queue-index` for live jobs due by `now`, soonest first (`dead`
+// false), or for dead jobs (`dead` true, `now` ignored), up to `limit` of them
+pub async fn query_jobs(
+	client: &Client,
+	dead: bool,
+	now: DateTime<Utc>,
+	limit: usize,
+) -> Result<Vec<Job>, Error> {
+	let mut jobs = Vec::new();
+	let mut start_key = None;
+	while jobs.len() < limit {
+		let mut query = client
+			.query()
+			.table_name("NotificationJob")
+			.index_name("queue-index")
+			.expression_attribute_names("#queue", "queue")
+			.expression_attribute_values(":queue", AttributeValue::S(queue_name(dead).to_string()))
+			.limit((limit - jobs.len()) as i32)
+			.set_exclusive_start_key(start_key);
+		query = if dead {
+			query.key_condition_expression("#queue = :queue")
+		} else {
+			query
+				.key_condition_expression("#queue = :queue AND next_attempt_at <= :now")
+				.expression_attribute_values(":now", AttributeValue::S(sort_key_time(now)))
+		};
+
+		let result = query.send().await?;
+		jobs.extend(
+			result
+				.items
+				.unwrap_or_default()
+				.iter()
+				.filter_map(job_from_item),
+		);
+
+		start_key = result.last_evaluated_key;
+		if start_key.is_none() {
+			break;
+		}
+	}
+
+	jobs.truncate(limit);
+	Ok(jobs)
+}
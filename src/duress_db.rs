@@ -1,111 +1,837 @@
 // duress_db.rs
-use std::fs::{OpenOptions};
-use std::io::{Write, BufRead, BufReader};
-use serde::{Serialize, Deserialize};
-use tokio::sync::Mutex;
-use lazy_static::lazy_static;
-use std::io::Error;
-// duress_db.rs
-use crate::duress_handlers::MapInfo;
+use aws_sdk_dynamodb::types::AttributeValue;
+use aws_sdk_dynamodb::{Client, Error};
+use chrono::{DateTime, Duration, FixedOffset, NaiveTime, SecondsFormat, Utc};
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::HashMap;
+use tracing::info;
 
-lazy_static! {
-	static ref FILE_MUTEX: Mutex<()> = Mutex::new(());
-}
+// Current shape of `UserPreferences`. Version 1 had only the two broadcast
+// flags and version 2 had no contacts; anything stored before a field existed
+// reads back with its default.
+pub const PREFERENCES_VERSION: u32 = 3;
 
-static DURESS_FILE_PATH: &str = "duress_db.txt";
-static PREFERENCES_FILE_PATH: &str = "preferences_db.txt";
+// Bounds for the proximity radius and the check-in interval
+pub(crate) const MAX_PROXIMITY_RADIUS_M: u32 = 50_000;
+const MIN_CHECKIN_INTERVAL_MINUTES: u32 = 5;
+const MAX_CHECKIN_INTERVAL_MINUTES: u32 = 24 * 60;
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub struct UserPreferences {
+	#[serde(default = "legacy_preferences_version")]
+	pub version: u32,
 	pub broadcast_duress: bool,
 	pub receive_duress_broadcasts: bool,
+	#[serde(default)]
+	pub channels: NotificationChannels,
+	#[serde(default)]
+	pub contacts: NotificationContacts,
+	#[serde(default)]
+	pub quiet_hours: Option<QuietHours>,
+	// How far away, in metres, nearby users' duress alerts reach this user
+	#[serde(default = "default_proximity_radius_m")]
+	pub proximity_radius_m: u32,
+	// How often the client should check in when nothing else happens
+	#[serde(default = "default_checkin_interval_minutes")]
+	pub checkin_interval_minutes: u32,
 }
 
-// Log a duress event
-pub async fn log_duress_event(
-	user_id: &str,
-	duress_type: &str,
-	message: &str,
-	timestamp: &str,
-) -> Result<(), Error> {
-	let _guard = FILE_MUTEX.lock().await;
-	let mut file = OpenOptions::new()
-		.create(true)
-		.append(true)
-		.open(DURESS_FILE_PATH)?;
-	let duress_entry = format!("{}|{}|{}|{}\n", user_id, duress_type, message, timestamp);
-	writeln!(file, "{}", duress_entry)?;
-	Ok(())
+fn legacy_preferences_version() -> u32 {
+	1
 }
 
-// Cancel a duress event
-pub async fn cancel_duress(user_id: &str) -> Result<(), Error> {
-	let _guard = FILE_MUTEX.lock().await;
+fn default_proximity_radius_m() -> u32 {
+	1_000
+}
 
-	// Placeholder: Logic to cancel duress for the user
-	// TODO: Adjust behavior based on your application's needs
-	Ok(())
+fn default_checkin_interval_minutes() -> u32 {
+	60
 }
 
-// Enable test mode for duress
-pub async fn enable_test_mode(user_id: &str) -> Result<(), Error> {
-	let _guard = FILE_MUTEX.lock().await;
+impl Default for UserPreferences {
+	fn default() -> Self {
+		UserPreferences {
+			version: PREFERENCES_VERSION,
+			broadcast_duress: true,
+			receive_duress_broadcasts: true,
+			channels: NotificationChannels::default(),
+			contacts: NotificationContacts::default(),
+			quiet_hours: None,
+			proximity_radius_m: default_proximity_radius_m(),
+			checkin_interval_minutes: default_checkin_interval_minutes(),
+		}
+	}
+}
 
-	// Placeholder: Logic to enable test mode
-	Ok(())
+impl UserPreferences {
+	pub fn validate(&self) -> Result<(), String> {
+		if !(1..=MAX_PROXIMITY_RADIUS_M).contains(&self.proximity_radius_m) {
+			return Err(format!(
+				"proximity_radius_m must be between 1 and {}",
+				MAX_PROXIMITY_RADIUS_M
+			));
+		}
+		if !(MIN_CHECKIN_INTERVAL_MINUTES..=MAX_CHECKIN_INTERVAL_MINUTES)
+			.contains(&self.checkin_interval_minutes)
+		{
+			return Err(format!(
+				"checkin_interval_minutes must be between {} and {}",
+				MIN_CHECKIN_INTERVAL_MINUTES, MAX_CHECKIN_INTERVAL_MINUTES
+			));
+		}
+		if let Some(quiet_hours) = &self.quiet_hours {
+			quiet_hours.validate()?;
+		}
+		self.contacts.validate()
+	}
+}
+
+// Which channels a user wants alerts delivered on
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub struct NotificationChannels {
+	pub push: bool,
+	pub sms: bool,
+	pub email: bool,
+	pub webhook: bool,
+}
+
+impl NotificationChannels {
+	// Names of the channels opted into, as `Notifier::channel` spells them
+	pub fn enabled(&self) -> Vec<&'static str> {
+		[
+			("push", self.push),
+			("sms", self.sms),
+			("email", self.email),
+			("webhook", self.webhook),
+		]
+		.into_iter()
+		.filter_map(|(channel, on)| on.then_some(channel))
+		.collect()
+	}
+}
+
+impl Default for NotificationChannels {
+	fn default() -> Self {
+		NotificationChannels {
+			push: true,
+			sms: false,
+			email: false,
+			webhook: false,
+		}
+	}
+}
+
+// Where the email and webhook channels deliver to. Only the user can read these.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
+pub struct NotificationContacts {
+	pub email: Option<String>,
+	pub webhook_url: Option<String>,
+}
+
+impl NotificationContacts {
+	fn validate(&self) -> Result<(), String> {
+		if self
+			.email
+			.as_ref()
+			.is_some_and(|email| email.parse::<lettre::Address>().is_err())
+		{
+			return Err("contacts email is not a valid address".to_string());
+		}
+		if self.webhook_url.as_ref().is_some_and(|url| {
+			reqwest::Url::parse(url).map_or(true, |url| !matches!(url.scheme(), "http" | "https"))
+		}) {
+			return Err("contacts webhook_url must be an http(s) URL".to_string());
+		}
+		Ok(())
+	}
+}
+
+// A daily window in the user's local time during which alerts are dropped.
+// The window may wrap past midnight.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub struct QuietHours {
+	pub start: NaiveTime,
+	pub end: NaiveTime,
+	// The user's offset from UTC, in minutes
+	#[serde(default)]
+	pub utc_offset_minutes: i32,
+	// Duress alerts are delivered even during quiet hours
+	#[serde(default = "default_duress_overrides")]
+	pub duress_overrides: bool,
+}
+
+fn default_duress_overrides() -> bool {
+	true
+}
+
+impl QuietHours {
+	fn validate(&self) -> Result<(), String> {
+		if self.start == self.end {
+			return Err("quiet_hours start and end must differ".to_string());
+		}
+		if !(-12 * 60..=14 * 60).contains(&self.utc_offset_minutes) {
+			return Err("quiet_hours utc_offset_minutes is out of range".to_string());
+		}
+		Ok(())
+	}
+
+	pub fn contains(&self, time: DateTime<Utc>) -> bool {
+		let local = match FixedOffset::east_opt(self.utc_offset_minutes * 60) {
+			Some(offset) => time.with_timezone(&offset).time(),
+			None => time.time(),
+		};
+
+		if self.start < self.end {
+			self.start <= local && local < self.end
+		} else {
+			local >= self.start || local < self.end
+		}
+	}
+}
+
+// A partial update to a user's preferences. Fields left out keep their
+// current values; `quiet_hours: null` turns quiet hours off.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UserPreferencesPatch {
+	pub broadcast_duress: Option<bool>,
+	pub receive_duress_broadcasts: Option<bool>,
+	pub channels: Option<NotificationChannelsPatch>,
+	pub contacts: Option<NotificationContactsPatch>,
+	#[serde(default, deserialize_with = "present")]
+	pub quiet_hours: Option<Option<QuietHours>>,
+	pub proximity_radius_m: Option<u32>,
+	pub checkin_interval_minutes: Option<u32>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NotificationChannelsPatch {
+	pub push: Option<bool>,
+	pub sms: Option<bool>,
+	pub email: Option<bool>,
+	pub webhook: Option<bool>,
+}
+
+// `null` clears a contact
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NotificationContactsPatch {
+	#[serde(default, deserialize_with = "present")]
+	pub email: Option<Option<String>>,
+	#[serde(default, deserialize_with = "present")]
+	pub webhook_url: Option<Option<String>>,
+}
+
+// Tell a field that is present but null apart from one that is missing
+fn present<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+	D: Deserializer<'de>,
+	T: Deserialize<'de>,
+{
+	T::deserialize(deserializer).map(Some)
 }
 
-// Retrieve map information for followed users
-pub async fn get_followed_users_map_info(user_id: &str) -> Result<Vec<MapInfo>, Error> {
-	// Placeholder: Retrieve last check-in locations and duress status
-	// TODO: Integrate with real map data storage
-	let map_info = vec![
-		MapInfo {
-			user_id: "follower1".to_string(),
-			username: "Follower One".to_string(),
-			location: "Location1".to_string(),
-			duress: false,
-			last_checkin: "2024-10-05T11:57:33.803Z".to_string(),
-		},
-		// Additional entries as necessary
-	];
-	Ok(map_info)
-}
-
-// Get user preferences
-pub async fn get_user_preferences(user_id: &str) -> Result<UserPreferences, Error> {
-	let _guard = FILE_MUTEX.lock().await;
-
-	let file = OpenOptions::new().read(true).open(PREFERENCES_FILE_PATH)?;
-	let reader = BufReader::new(file);
-
-	for line in reader.lines() {
-		if let Ok(preference_json) = line {
-			if let Ok(preference) = serde_json::from_str::<UserPreferences>(&preference_json) {
-				return Ok(preference);
+impl UserPreferencesPatch {
+	pub fn apply(&self, preferences: &mut UserPreferences) {
+		if let Some(broadcast_duress) = self.broadcast_duress {
+			preferences.broadcast_duress = broadcast_duress;
+		}
+		if let Some(receive_duress_broadcasts) = self.receive_duress_broadcasts {
+			preferences.receive_duress_broadcasts = receive_duress_broadcasts;
+		}
+		if let Some(channels) = &self.channels {
+			let current = &mut preferences.channels;
+			current.push = channels.push.unwrap_or(current.push);
+			current.sms = channels.sms.unwrap_or(current.sms);
+			current.email = channels.email.unwrap_or(current.email);
+			current.webhook = channels.webhook.unwrap_or(current.webhook);
+		}
+		if let Some(contacts) = &self.contacts {
+			if let Some(email) = &contacts.email {
+				preferences.contacts.email = email.clone();
 			}
+			if let Some(webhook_url) = &contacts.webhook_url {
+				preferences.contacts.webhook_url = webhook_url.clone();
+			}
+		}
+		if let Some(quiet_hours) = self.quiet_hours {
+			preferences.quiet_hours = quiet_hours;
+		}
+		if let Some(proximity_radius_m) = self.proximity_radius_m {
+			preferences.proximity_radius_m = proximity_radius_m;
+		}
+		if let Some(checkin_interval_minutes) = self.checkin_interval_minutes {
+			preferences.checkin_interval_minutes = checkin_interval_minutes;
+		}
+		preferences.version = PREFERENCES_VERSION;
+	}
+}
+
+// Active events that nobody cancels or resolves expire after this long
+const DURESS_EXPIRY_HOURS: i64 = 24;
+
+// Lifecycle of a duress event. Only active events can change state.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DuressStatus {
+	Active,
+	Cancelled,
+	Expired,
+	Resolved,
+}
+
+impl DuressStatus {
+	pub fn as_str(&self) -> &'static str {
+		match self {
+			DuressStatus::Active => "active",
+			DuressStatus::Cancelled => "cancelled",
+			DuressStatus::Expired => "expired",
+			DuressStatus::Resolved => "resolved",
+		}
+	}
+
+	pub fn parse(value: &str) -> Option<DuressStatus> {
+		match value {
+			"active" => Some(DuressStatus::Active),
+			"cancelled" => Some(DuressStatus::Cancelled),
+			"expired" => Some(DuressStatus::Expired),
+			"resolved" => Some(DuressStatus::Resolved),
+			_ => None,
+		}
+	}
+
+	pub fn can_transition_to(&self, next: DuressStatus) -> bool {
+		*self == DuressStatus::Active && next != DuressStatus::Active
+	}
+}
+
+// What kind of emergency a duress event reports. Unrecognised values from
+// newer clients are kept as `Other`.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DuressType {
+	Assault,
+	Harassment,
+	Abduction,
+	Medical,
+	Fire,
+	// Raised by logging in with the duress PIN
+	DuressPin,
+	#[serde(other)]
+	Other,
+}
+
+impl DuressType {
+	pub fn as_str(&self) -> &'static str {
+		match self {
+			DuressType::Assault => "assault",
+			DuressType::Harassment => "harassment",
+			DuressType::Abduction => "abduction",
+			DuressType::Medical => "medical",
+			DuressType::Fire => "fire",
+			DuressType::DuressPin => "duress_pin",
+			DuressType::Other => "other",
+		}
+	}
+
+	pub fn parse(value: &str) -> DuressType {
+		match value {
+			"assault" => DuressType::Assault,
+			"harassment" => DuressType::Harassment,
+			"abduction" => DuressType::Abduction,
+			"medical" => DuressType::Medical,
+			"fire" => DuressType::Fire,
+			"duress_pin" => DuressType::DuressPin,
+			_ => DuressType::Other,
+		}
+	}
+}
+
+// A point reported by a client, in WGS84 degrees
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub struct Location {
+	pub latitude: f64,
+	pub longitude: f64,
+	// Radius of uncertainty in metres, if the client knows it
+	pub accuracy: Option<f64>,
+}
+
+impl Location {
+	pub fn is_valid(&self) -> bool {
+		(-90.0..=90.0).contains(&self.latitude)
+			&& (-180.0..=180.0).contains(&self.longitude)
+			&& self.accuracy.is_none_or(|accuracy| accuracy >= 0.0)
+	}
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DuressEvent {
+	pub id: String,
+	pub user_id: String,
+	pub duress_type: DuressType,
+	pub message: String,
+	// Time reported by the client
+	pub timestamp: DateTime<Utc>,
+	pub location: Option<Location>,
+	// Free-form data from the client, stored as given
+	pub additional_data: serde_json::Value,
+	pub status: DuressStatus,
+	// Raised while the user had test mode on; followers see a test alert
+	pub test: bool,
+	// Time the server recorded the event
+	pub created_at: DateTime<Utc>,
+}
+
+impl DuressEvent {
+	// A new active event recorded now
+	pub fn new(
+		user_id: &str,
+		duress_type: DuressType,
+		message: &str,
+		timestamp: DateTime<Utc>,
+	) -> Self {
+		DuressEvent {
+			id: uuid::Uuid::new_v4().to_string(),
+			user_id: user_id.to_string(),
+			duress_type,
+			message: message.to_string(),
+			timestamp,
+			location: None,
+			additional_data: serde_json::Value::Null,
+			status: DuressStatus::Active,
+			test: false,
+			created_at: Utc::now(),
+		}
+	}
+
+	// Move the event to `next` if the lifecycle allows it
+	pub fn transition(&mut self, next: DuressStatus) -> bool {
+		if self.status.can_transition_to(next) {
+			self.status = next;
+			true
+		} else {
+			false
+		}
+	}
+
+	pub fn is_stale(&self, now: DateTime<Utc>) -> bool {
+		self.status == DuressStatus::Active
+			&& now - self.created_at > Duration::hours(DURESS_EXPIRY_HOURS)
+	}
+}
+
+// Who receives the alerts raised while test mode is on
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum TestAudience {
+	// Followers get alerts clearly labelled as tests
+	#[default]
+	Followers,
+	// Only the user themselves is notified
+	SelfOnly,
+}
+
+impl TestAudience {
+	pub fn as_str(&self) -> &'static str {
+		match self {
+			TestAudience::Followers => "followers",
+			TestAudience::SelfOnly => "self_only",
+		}
+	}
+
+	pub fn parse(value: &str) -> Option<TestAudience> {
+		match value {
+			"followers" => Some(TestAudience::Followers),
+			"self_only" => Some(TestAudience::SelfOnly),
+			_ => None,
+		}
+	}
+}
+
+// A window during which duress triggered by the user is treated as a test
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TestMode {
+	pub user_id: String,
+	pub audience: TestAudience,
+	pub started_at: DateTime<Utc>,
+	pub expires_at: DateTime<Utc>,
+}
+
+impl TestMode {
+	pub fn is_active(&self, now: DateTime<Utc>) -> bool {
+		now < self.expires_at
+	}
+}
+
+// Fixed-width UTC timestamp, so sort keys compare in time order as strings
+pub(crate) fn sort_key_time(time: DateTime<Utc>) -> String {
+	time.to_rfc3339_opts(SecondsFormat::Micros, true)
+}
+
+// Sort key for the "DuressEvent" table, ordering a user's events by time
+fn duress_sort_key(event: &DuressEvent) -> String {
+	format!("{}#{}", sort_key_time(event.created_at), event.id)
+}
+
+fn duress_event_from_item(item: &HashMap<String, AttributeValue>) -> DuressEvent {
+	let string_attr = |name: &str| {
+		item.get(name)
+			.and_then(|v| v.as_s().ok())
+			.map(|s| s.to_string())
+			.unwrap_or_default()
+	};
+
+	let number_attr = |name: &str| {
+		item.get(name)
+			.and_then(|v| v.as_n().ok())
+			.and_then(|n| n.parse::<f64>().ok())
+	};
+	let time_attr = |name: &str| {
+		DateTime::parse_from_rfc3339(&string_attr(name))
+			.map(|v| v.with_timezone(&Utc))
+			.unwrap_or_else(|_| Utc::now())
+	};
+	let location = match (number_attr("latitude"), number_attr("longitude")) {
+		(Some(latitude), Some(longitude)) => Some(Location {
+			latitude,
+			longitude,
+			accuracy: number_attr("accuracy"),
+		}),
+		_ => None,
+	};
+
+	DuressEvent {
+		id: string_attr("id"),
+		user_id: string_attr("user_id"),
+		duress_type: DuressType::parse(&string_attr("duress_type")),
+		message: string_attr("message"),
+		timestamp: time_attr("timestamp"),
+		location,
+		additional_data: serde_json::from_str(&string_attr("additional_data"))
+			.unwrap_or(serde_json::Value::Null),
+		status: DuressStatus::parse(&string_attr("status")).unwrap_or(DuressStatus::Active),
+		test: item
+			.get("test")
+			.and_then(|v| v.as_bool().ok())
+			.copied()
+			.unwrap_or(false),
+		created_at: time_attr("created_at"),
+	}
+}
+
+// Write a duress event to the DynamoDB "DuressEvent" table, keyed by user and
+// time. Used both to log new events and to record status changes.
+pub async fn put_duress_event(client: &Client, event: &DuressEvent) -> Result<(), Error> {
+	info!("Writing a duress event in DynamoDB");
+
+	let mut request = client
+		.put_item()
+		.table_name("DuressEvent")
+		.item("user_id", AttributeValue::S(event.user_id.clone()))
+		.item("sort_key", AttributeValue::S(duress_sort_key(event)))
+		.item("id", AttributeValue::S(event.id.clone()))
+		.item("timestamp", AttributeValue::S(event.timestamp.to_rfc3339()))
+		.item(
+			"duress_type",
+			AttributeValue::S(event.duress_type.as_str().to_string()),
+		)
+		.item("message", AttributeValue::S(event.message.clone()))
+		.item(
+			"additional_data",
+			AttributeValue::S(event.additional_data.to_string()),
+		)
+		.item(
+			"status",
+			AttributeValue::S(event.status.as_str().to_string()),
+		)
+		.item("test", AttributeValue::Bool(event.test))
+		.item(
+			"created_at",
+			AttributeValue::S(event.created_at.to_rfc3339()),
+		);
+
+	if let Some(location) = &event.location {
+		request = request
+			.item("latitude", AttributeValue::N(location.latitude.to_string()))
+			.item(
+				"longitude",
+				AttributeValue::N(location.longitude.to_string()),
+			);
+		if let Some(accuracy) = location.accuracy {
+			request = request.item("accuracy", AttributeValue::N(accuracy.to_string()));
 		}
 	}
 
-	Ok(UserPreferences {
-		broadcast_duress: true,
-		receive_duress_broadcasts: true,
-	})
+	request.send().await?;
+	Ok(())
 }
 
-// Update user preferences
+// Retrieve a user's duress events recorded between `from` and `to` (either
+// bound optional), oldest first
+pub async fn get_user_duress_events(
+	client: &Client,
+	user_id: &str,
+	from: Option<DateTime<Utc>>,
+	to: Option<DateTime<Utc>>,
+) -> Result<Vec<DuressEvent>, Error> {
+	// '#' sorts before any ID character and '~' after, so these bracket every
+	// event recorded in the range
+	let lower = from.map(|from| format!("{}#", sort_key_time(from)));
+	let upper = to.map(|to| format!("{}~", sort_key_time(to)));
+
+	let condition = match (&lower, &upper) {
+		(Some(_), Some(_)) => "user_id = :user_id AND sort_key BETWEEN :from AND :to",
+		(Some(_), None) => "user_id = :user_id AND sort_key >= :from",
+		(None, Some(_)) => "user_id = :user_id AND sort_key <= :to",
+		(None, None) => "user_id = :user_id",
+	};
+
+	let mut events = Vec::new();
+	let mut start_key = None;
+	loop {
+		let mut query = client
+			.query()
+			.table_name("DuressEvent")
+			.key_condition_expression(condition)
+			.expression_attribute_values(":user_id", AttributeValue::S(user_id.to_string()))
+			.set_exclusive_start_key(start_key);
+		if let Some(lower) = &lower {
+			query = query.expression_attribute_values(":from", AttributeValue::S(lower.clone()));
+		}
+		if let Some(upper) = &upper {
+			query = query.expression_attribute_values(":to", AttributeValue::S(upper.clone()));
+		}
+
+		let result = query.send().await?;
+		events.extend(
+			result
+				.items
+				.unwrap_or_default()
+				.iter()
+				.map(duress_event_from_item),
+		);
+
+		start_key = result.last_evaluated_key;
+		if start_key.is_none() {
+			break;
+		}
+	}
+
+	Ok(events)
+}
+
+// Save a user's test mode window to the DynamoDB "TestMode" table
+pub async fn put_test_mode(client: &Client, test_mode: &TestMode) -> Result<(), Error> {
+	client
+		.put_item()
+		.table_name("TestMode")
+		.item("user_id", AttributeValue::S(test_mode.user_id.clone()))
+		.item(
+			"audience",
+			AttributeValue::S(test_mode.audience.as_str().to_string()),
+		)
+		.item(
+			"started_at",
+			AttributeValue::S(test_mode.started_at.to_rfc3339()),
+		)
+		.item(
+			"expires_at",
+			AttributeValue::S(test_mode.expires_at.to_rfc3339()),
+		)
+		// DynamoDB TTL attribute so finished windows are purged automatically
+		.item(
+			"ttl",
+			AttributeValue::N(test_mode.expires_at.timestamp().to_string()),
+		)
+		.send()
+		.await?;
+
+	Ok(())
+}
+
+pub async fn get_test_mode(client: &Client, user_id: &str) -> Result<Option<TestMode>, Error> {
+	let result = client
+		.get_item()
+		.table_name("TestMode")
+		.key("user_id", AttributeValue::S(user_id.to_string()))
+		.send()
+		.await?;
+
+	Ok(result.item.map(|item| {
+		let string_attr = |name: &str| {
+			item.get(name)
+				.and_then(|v| v.as_s().ok())
+				.map(|s| s.to_string())
+				.unwrap_or_default()
+		};
+		let time_attr = |name: &str| {
+			DateTime::parse_from_rfc3339(&string_attr(name))
+				.map(|v| v.with_timezone(&Utc))
+				.unwrap_or_else(|_| Utc::now())
+		};
+
+		TestMode {
+			user_id: string_attr("user_id"),
+			audience: TestAudience::parse(&string_attr("audience")).unwrap_or_default(),
+			started_at: time_attr("started_at"),
+			expires_at: time_attr("expires_at"),
+		}
+	}))
+}
+
+pub async fn delete_test_mode(client: &Client, user_id: &str) -> Result<(), Error> {
+	client
+		.delete_item()
+		.table_name("TestMode")
+		.key("user_id", AttributeValue::S(user_id.to_string()))
+		.send()
+		.await?;
+
+	Ok(())
+}
+
+// Get user preferences from the DynamoDB "UserPreferences" table
+pub async fn get_user_preferences(
+	client: &Client,
+	user_id: &str,
+) -> Result<Option<UserPreferences>, Error> {
+	let result = client
+		.get_item()
+		.table_name("UserPreferences")
+		.key("user_id", AttributeValue::S(user_id.to_string()))
+		.send()
+		.await?;
+
+	Ok(result.item.map(|item| {
+		let bool_attr = |name: &str, default: bool| {
+			item.get(name)
+				.and_then(|v| v.as_bool().ok())
+				.copied()
+				.unwrap_or(default)
+		};
+		let number_attr = |name: &str| {
+			item.get(name)
+				.and_then(|v| v.as_n().ok())
+				.and_then(|n| n.parse::<i64>().ok())
+		};
+		let string_attr = |name: &str| {
+			item.get(name)
+				.and_then(|v| v.as_s().ok())
+				.map(|s| s.to_string())
+		};
+		let time_attr = |name: &str| string_attr(name).and_then(|v| v.parse::<NaiveTime>().ok());
+
+		let defaults = UserPreferences::default();
+		let quiet_hours = match (time_attr("quiet_start"), time_attr("quiet_end")) {
+			(Some(start), Some(end)) => Some(QuietHours {
+				start,
+				end,
+				utc_offset_minutes: number_attr("quiet_utc_offset_minutes").unwrap_or(0) as i32,
+				duress_overrides: bool_attr("quiet_duress_overrides", true),
+			}),
+			_ => None,
+		};
+
+		UserPreferences {
+			version: number_attr("version").unwrap_or(1) as u32,
+			broadcast_duress: bool_attr("broadcast_duress", true),
+			receive_duress_broadcasts: bool_attr("receive_duress_broadcasts", true),
+			channels: NotificationChannels {
+				push: bool_attr("channel_push", defaults.channels.push),
+				sms: bool_attr("channel_sms", defaults.channels.sms),
+				email: bool_attr("channel_email", defaults.channels.email),
+				webhook: bool_attr("channel_webhook", defaults.channels.webhook),
+			},
+			contacts: NotificationContacts {
+				email: string_attr("contact_email"),
+				webhook_url: string_attr("contact_webhook_url"),
+			},
+			quiet_hours,
+			proximity_radius_m: number_attr("proximity_radius_m")
+				.map(|n| n as u32)
+				.unwrap_or(defaults.proximity_radius_m),
+			checkin_interval_minutes: number_attr("checkin_interval_minutes")
+				.map(|n| n as u32)
+				.unwrap_or(defaults.checkin_interval_minutes),
+		}
+	}))
+}
+
+// Update user preferences in the DynamoDB "UserPreferences" table
 pub async fn update_user_preferences(
+	client: &Client,
 	user_id: &str,
-	preferences: UserPreferences,
+	preferences: &UserPreferences,
 ) -> Result<(), Error> {
-	let _guard = FILE_MUTEX.lock().await;
-
-	// Placeholder: Logic to update preferences in a real storage
-	let mut file = OpenOptions::new()
-		.create(true)
-		.append(true)
-		.open(PREFERENCES_FILE_PATH)?;
-	let preferences_json = serde_json::to_string(&preferences)?;
-	writeln!(file, "{}", preferences_json)?;
+	let mut request = client
+		.put_item()
+		.table_name("UserPreferences")
+		.item("user_id", AttributeValue::S(user_id.to_string()))
+		.item(
+			"version",
+			AttributeValue::N(preferences.version.to_string()),
+		)
+		.item(
+			"broadcast_duress",
+			AttributeValue::Bool(preferences.broadcast_duress),
+		)
+		.item(
+			"receive_duress_broadcasts",
+			AttributeValue::Bool(preferences.receive_duress_broadcasts),
+		)
+		.item(
+			"channel_push",
+			AttributeValue::Bool(preferences.channels.push),
+		)
+		.item(
+			"channel_sms",
+			AttributeValue::Bool(preferences.channels.sms),
+		)
+		.item(
+			"channel_email",
+			AttributeValue::Bool(preferences.channels.email),
+		)
+		.item(
+			"channel_webhook",
+			AttributeValue::Bool(preferences.channels.webhook),
+		)
+		.item(
+			"proximity_radius_m",
+			AttributeValue::N(preferences.proximity_radius_m.to_string()),
+		)
+		.item(
+			"checkin_interval_minutes",
+			AttributeValue::N(preferences.checkin_interval_minutes.to_string()),
+		);
+
+	if let Some(email) = &preferences.contacts.email {
+		request = request.item("contact_email", AttributeValue::S(email.clone()));
+	}
+	if let Some(webhook_url) = &preferences.contacts.webhook_url {
+		request = request.item(
+			"contact_webhook_url",
+			AttributeValue::S(webhook_url.clone()),
+		);
+	}
+	if let Some(quiet_hours) = &preferences.quiet_hours {
+		request = request
+			.item(
+				"quiet_start",
+				AttributeValue::S(quiet_hours.start.to_string()),
+			)
+			.item("quiet_end", AttributeValue::S(quiet_hours.end.to_string()))
+			.item(
+				"quiet_utc_offset_minutes",
+				AttributeValue::N(quiet_hours.utc_offset_minutes.to_string()),
+			)
+			.item(
+				"quiet_duress_overrides",
+				AttributeValue::Bool(quiet_hours.duress_overrides),
+			);
+	}
+
+	request.send().await?;
+
 	Ok(())
 }
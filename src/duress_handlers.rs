@@ -1,102 +1,575 @@
 // duress_handlers.rs
 use actix_web::{web, HttpResponse};
+use chrono::{DateTime, Duration, Utc};
 use serde::{Deserialize, Serialize};
-use crate::duress_db::{self, UserPreferences};
+use tracing::{error, info};
+
+use crate::duress_db::{
+	DuressEvent, DuressStatus, DuressType, Location, TestAudience, TestMode, UserPreferencesPatch,
+};
+use crate::auth::AuthenticatedUser;
+use crate::delivery_db::AlertKind;
+use crate::location_db::UserLocation;
+use crate::notify::{self, Notifiers};
+use crate::outbound;
+use crate::pin::{self, PinMatch};
+use crate::store::{Store, StoreError};
 
 #[derive(Debug, Deserialize)]
 pub struct DuressRequest {
-	duress_type: String,
+	duress_type: DuressType,
 	message: String,
-	timestamp: String,
+	timestamp: DateTime<Utc>,
+	location: Option<Location>,
+	#[serde(default)]
 	additional_data: serde_json::Value,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Deserialize)]
 pub struct CancelDuressRequest {
 	normal_pin: String,
 	confirm: bool,
 }
 
+#[derive(Deserialize)]
+pub struct ResolveDuressRequest {
+	normal_pin: String,
+}
+
+// Default and maximum length of a test mode window
+const TEST_MODE_MINUTES: i64 = 5;
+const MAX_TEST_MODE_MINUTES: i64 = 60;
+
+#[derive(Debug, Deserialize)]
+pub struct TestModeRequest {
+	minutes: Option<i64>,
+	audience: Option<TestAudience>,
+}
+
 #[derive(Debug, Serialize)]
-pub struct MapInfo {
-	pub user_id: String,
-	pub username: String,
-	pub location: String,
-	pub duress: bool,
-	pub last_checkin: String,
+pub struct TestModeStatus {
+	active: bool,
+	audience: Option<TestAudience>,
+	expires_at: Option<DateTime<Utc>>,
+}
+
+// Default and maximum number of events in a page of duress history
+const HISTORY_PAGE_SIZE: usize = 50;
+const MAX_HISTORY_PAGE_SIZE: usize = 200;
+
+#[derive(Debug, Deserialize)]
+pub struct HistoryQuery {
+	status: Option<DuressStatus>,
+	duress_type: Option<DuressType>,
+	from: Option<DateTime<Utc>>,
+	to: Option<DateTime<Utc>>,
+	limit: Option<usize>,
+	#[serde(default)]
+	offset: usize,
+}
+
+impl HistoryQuery {
+	fn matches(&self, event: &DuressEvent) -> bool {
+		self.status.is_none_or(|status| event.status == status)
+			&& self
+				.duress_type
+				.is_none_or(|duress_type| event.duress_type == duress_type)
+	}
+
+	// Filter the events and return the requested page, newest first
+	fn page(&self, events: Vec<DuressEvent>) -> DuressHistory {
+		let limit = self.limit.unwrap_or(HISTORY_PAGE_SIZE);
+		let mut events: Vec<_> = events.into_iter().filter(|e| self.matches(e)).collect();
+		events.sort_by(|a, b| {
+			b.created_at
+				.cmp(&a.created_at)
+				.then_with(|| a.id.cmp(&b.id))
+		});
+
+		// The offset comes straight from the query string
+		let end = self.offset.saturating_add(limit);
+		let next_offset = (events.len() > end).then_some(end);
+		DuressHistory {
+			events: events.into_iter().skip(self.offset).take(limit).collect(),
+			next_offset,
+		}
+	}
+
+	fn validate(&self) -> Result<(), String> {
+		if self
+			.limit
+			.is_some_and(|limit| !(1..=MAX_HISTORY_PAGE_SIZE).contains(&limit))
+		{
+			return Err(format!(
+				"limit must be between 1 and {}",
+				MAX_HISTORY_PAGE_SIZE
+			));
+		}
+		if let (Some(from), Some(to)) = (self.from, self.to) {
+			if from > to {
+				return Err("from must not be after to".to_string());
+			}
+		}
+		Ok(())
+	}
+}
+
+#[derive(Debug, Serialize)]
+pub struct DuressHistory {
+	events: Vec<DuressEvent>,
+	// Offset of the next page, absent on the last page
+	next_offset: Option<usize>,
 }
 
 // POST /users/{user_id}/duress
 pub async fn trigger_duress(
+	store: web::Data<dyn Store>,
+	notifiers: web::Data<Notifiers>,
+	user: AuthenticatedUser,
 	path: web::Path<String>,
 	req: web::Json<DuressRequest>,
 ) -> HttpResponse {
 	let user_id = path.into_inner();
+	let req = req.into_inner();
+	if req.location.is_some_and(|location| !location.is_valid()) {
+		return HttpResponse::BadRequest().body("Invalid location");
+	}
 
-	// Placeholder: Notify followers and nearby users
-	// TODO: Integrate with actual notification and location service
-	duress_db::log_duress_event(&user_id, &req.duress_type, &req.message, &req.timestamp).await;
+	let mut event = DuressEvent::new(&user_id, req.duress_type, &req.message, req.timestamp);
+	event.location = req.location;
+	event.additional_data = req.additional_data;
 
-	HttpResponse::Ok().body("Duress notification triggered")
+	// While test mode is on, the event is recorded and announced as a test
+	match store.get_test_mode(&user_id).await {
+		Ok(test_mode) => {
+			event.test = test_mode.is_some_and(|mode| mode.is_active(event.created_at));
+		}
+		Err(err) => {
+			error!("Failed to fetch test mode: {:?}", err);
+			return HttpResponse::InternalServerError().body(err.to_string());
+		}
+	}
+	let triggered = if event.test {
+		"Test duress notification triggered"
+	} else {
+		"Duress notification triggered"
+	};
+	// The login already raised the alarm. Anything sent from this session may
+	// come from whoever forced it, down to the location.
+	if user.duress {
+		info!("Duress session tried to trigger duress for {}", user_id);
+		return HttpResponse::Ok().body(triggered);
+	}
+
+	if let Some(location) = event.location {
+		if let Err(err) = store
+			.save_user_location(&UserLocation::new(&user_id, location))
+			.await
+		{
+			error!("Failed to save user location: {:?}", err);
+			return HttpResponse::InternalServerError().body(err.to_string());
+		}
+	}
+
+	match raise_duress(store.get_ref(), &notifiers, &event).await {
+		Ok(_) => HttpResponse::Ok().body(triggered),
+		Err(err) => {
+			error!("Failed to log duress event: {:?}", err);
+			HttpResponse::InternalServerError().body(err.to_string())
+		}
+	}
+}
+
+// Record a duress event and alert the user's followers. Shared by the
+// explicit duress endpoint and by logins made with the duress PIN; only the
+// former honours test mode, so a coerced login always raises a real alert.
+pub async fn raise_duress(
+	store: &dyn Store,
+	notifiers: &Notifiers,
+	event: &DuressEvent,
+) -> Result<(), StoreError> {
+	store.log_duress_event(event).await?;
+
+	// Test events go only to the test mode audience, labelled as tests
+	let deliveries = notify::fan_out(store, notifiers, event, AlertKind::Raised).await?;
+	info!(
+		"Duress event {} raised with {} deliveries queued",
+		event.id,
+		deliveries.len()
+	);
+	Ok(())
+}
+
+// Tell everyone who was alerted that a duress situation has ended
+async fn announce_duress_ended(
+	store: &dyn Store,
+	notifiers: &Notifiers,
+	event: &DuressEvent,
+) -> Result<(), StoreError> {
+	info!("Duress event {} is now {}", event.id, event.status.as_str());
+	notify::fan_out(store, notifiers, event, AlertKind::Ended).await?;
+	Ok(())
+}
+
+// Move events that have been active for too long to expired
+async fn expire_stale_events(
+	store: &dyn Store,
+	mut events: Vec<DuressEvent>,
+) -> Result<Vec<DuressEvent>, StoreError> {
+	let now = Utc::now();
+	for event in events.iter_mut().filter(|event| event.is_stale(now)) {
+		event.transition(DuressStatus::Expired);
+		store.update_duress_event(event).await?;
+	}
+	Ok(events)
+}
+
+// A user's active duress events, expiring stale ones along the way
+pub(crate) async fn active_duress_events(
+	store: &dyn Store,
+	user_id: &str,
+) -> Result<Vec<DuressEvent>, StoreError> {
+	let events = store.get_user_duress_events(user_id, None, None).await?;
+	Ok(expire_stale_events(store, events)
+		.await?
+		.into_iter()
+		.filter(|event| event.status == DuressStatus::Active)
+		.collect())
+}
+
+// GET /users/{user_id}/duress
+pub async fn get_duress_history(
+	store: web::Data<dyn Store>,
+	user: AuthenticatedUser,
+	query: web::Query<HistoryQuery>,
+) -> HttpResponse {
+	if let Err(message) = query.validate() {
+		return HttpResponse::BadRequest().body(message);
+	}
+
+	let events = match store
+		.get_user_duress_events(&user.user_id, query.from, query.to)
+		.await
+	{
+		Ok(events) => events,
+		Err(err) => {
+			error!("Failed to fetch duress history: {:?}", err);
+			return HttpResponse::InternalServerError().body(err.to_string());
+		}
+	};
+
+	match expire_stale_events(store.get_ref(), events).await {
+		Ok(mut events) => {
+			// Whoever forced the duress PIN login may be reading this session,
+			// so it must not show the alert that login raised.
+			if user.duress {
+				events.retain(|event| event.duress_type != DuressType::DuressPin);
+			}
+			HttpResponse::Ok().json(query.page(events))
+		}
+		Err(err) => {
+			error!("Failed to expire duress events: {:?}", err);
+			HttpResponse::InternalServerError().body(err.to_string())
+		}
+	}
+}
+
+// GET /users/{user_id}/following/duress
+pub async fn get_following_duress_history(
+	store: web::Data<dyn Store>,
+	path: web::Path<String>,
+	query: web::Query<HistoryQuery>,
+) -> HttpResponse {
+	let user_id = path.into_inner();
+	if let Err(message) = query.validate() {
+		return HttpResponse::BadRequest().body(message);
+	}
+
+	let result = async {
+		let mut events = Vec::new();
+		for follow in store.get_following(&user_id).await? {
+			let followed_events = store
+				.get_user_duress_events(&follow.followed_id, query.from, query.to)
+				.await?;
+			events.extend(expire_stale_events(store.get_ref(), followed_events).await?);
+		}
+		Ok::<_, StoreError>(events)
+	}
+	.await;
+
+	match result {
+		// Test alerts are rehearsals, not incidents to review
+		Ok(events) => HttpResponse::Ok()
+			.json(query.page(events.into_iter().filter(|event| !event.test).collect())),
+		Err(err) => {
+			error!("Failed to fetch followed users' duress history: {:?}", err);
+			HttpResponse::InternalServerError().body(err.to_string())
+		}
+	}
+}
+
+// Move the selected active events to `next` and tell followers
+async fn end_duress_events(
+	store: &dyn Store,
+	notifiers: &Notifiers,
+	events: Vec<DuressEvent>,
+	next: DuressStatus,
+) -> Result<(), StoreError> {
+	for mut event in events {
+		if event.transition(next) {
+			store.update_duress_event(&event).await?;
+			announce_duress_ended(store, notifiers, &event).await?;
+		}
+	}
+	Ok(())
+}
+
+// End the events in the background. Cancelling or resolving with the duress
+// PIN reads the same events and changes nothing, so the response to either PIN
+// takes the same time and cannot give the duress PIN away.
+fn spawn_end_duress_events(
+	store: &web::Data<dyn Store>,
+	notifiers: &web::Data<Notifiers>,
+	events: Vec<DuressEvent>,
+	next: DuressStatus,
+) {
+	let store = store.clone();
+	let notifiers = notifiers.clone();
+	actix_web::rt::spawn(async move {
+		if let Err(err) = end_duress_events(store.get_ref(), &notifiers, events, next).await {
+			error!("Failed to end duress events: {:?}", err);
+		}
+	});
 }
 
 // POST /users/{user_id}/duress/cancel
 pub async fn cancel_duress(
+	store: web::Data<dyn Store>,
+	notifiers: web::Data<Notifiers>,
+	user: AuthenticatedUser,
 	path: web::Path<String>,
 	req: web::Json<CancelDuressRequest>,
 ) -> HttpResponse {
 	let user_id = path.into_inner();
+	let CancelDuressRequest {
+		normal_pin,
+		confirm,
+	} = req.into_inner();
 
-	if req.confirm {
-		// Placeholder: Validate normal_pin
-		duress_db::cancel_duress(&user_id).await;
+	if !confirm {
+		return HttpResponse::BadRequest().body("Confirmation required to cancel duress");
+	}
 
-		HttpResponse::Ok().body("Duress notification canceled")
-	} else {
-		HttpResponse::BadRequest().body("Confirmation required to cancel duress")
+	let result = async {
+		let pin_match = pin::check_user_pin(store.get_ref(), &user_id, normal_pin).await?;
+		if pin_match == PinMatch::Wrong {
+			return Ok(None);
+		}
+		let events = active_duress_events(store.get_ref(), &user_id).await?;
+		Ok::<_, StoreError>(Some((pin_match, events)))
+	}
+	.await;
+
+	match result {
+		Ok(Some((pin_match, events))) => {
+			if pin_match == PinMatch::Normal && !user.duress {
+				spawn_end_duress_events(&store, &notifiers, events, DuressStatus::Cancelled);
+			} else {
+				// Someone may be forcing the user to cancel, by the duress PIN
+				// or from the session it opened: look like it worked, change
+				// nothing
+				info!("Duress PIN used to cancel duress for user {}", user_id);
+			}
+			HttpResponse::Ok().body("Duress notification canceled")
+		}
+		Ok(None) => HttpResponse::Unauthorized().body("Invalid credentials"),
+		Err(err) => {
+			error!("Failed to cancel duress: {:?}", err);
+			HttpResponse::InternalServerError().body(err.to_string())
+		}
+	}
+}
+
+// POST /users/{user_id}/duress/{event_id}/resolve
+pub async fn resolve_duress(
+	store: web::Data<dyn Store>,
+	notifiers: web::Data<Notifiers>,
+	user: AuthenticatedUser,
+	path: web::Path<(String, String)>,
+	req: web::Json<ResolveDuressRequest>,
+) -> HttpResponse {
+	let (user_id, event_id) = path.into_inner();
+	let normal_pin = req.into_inner().normal_pin;
+
+	let result = async {
+		let pin_match = pin::check_user_pin(store.get_ref(), &user_id, normal_pin).await?;
+		if pin_match == PinMatch::Wrong {
+			return Ok(None);
+		}
+		let events: Vec<_> = active_duress_events(store.get_ref(), &user_id)
+			.await?
+			.into_iter()
+			.filter(|event| event.id == event_id)
+			.collect();
+		Ok::<_, StoreError>(Some((pin_match, events)))
+	}
+	.await;
+
+	match result {
+		Ok(Some((_, events))) if events.is_empty() => {
+			HttpResponse::NotFound().body("No active duress event with that ID")
+		}
+		Ok(Some((pin_match, events))) => {
+			if pin_match == PinMatch::Normal && !user.duress {
+				spawn_end_duress_events(&store, &notifiers, events, DuressStatus::Resolved);
+			} else {
+				// As with cancellation, the duress PIN appears to work but
+				// changes nothing
+				info!("Duress PIN used to resolve duress for user {}", user_id);
+			}
+			HttpResponse::Ok().body("Duress event resolved")
+		}
+		Ok(None) => HttpResponse::Unauthorized().body("Invalid credentials"),
+		Err(err) => {
+			error!("Failed to resolve duress: {:?}", err);
+			HttpResponse::InternalServerError().body(err.to_string())
+		}
 	}
 }
 
 // POST /users/{user_id}/test-mode
-pub async fn enable_test_mode(path: web::Path<String>) -> HttpResponse {
+pub async fn enable_test_mode(
+	store: web::Data<dyn Store>,
+	user: AuthenticatedUser,
+	path: web::Path<String>,
+	req: Option<web::Json<TestModeRequest>>,
+) -> HttpResponse {
 	let user_id = path.into_inner();
+	let (minutes, audience) = match req {
+		Some(req) => (
+			req.minutes.unwrap_or(TEST_MODE_MINUTES),
+			req.audience.unwrap_or_default(),
+		),
+		None => (TEST_MODE_MINUTES, TestAudience::default()),
+	};
+	if !(1..=MAX_TEST_MODE_MINUTES).contains(&minutes) {
+		return HttpResponse::BadRequest().body(format!(
+			"Test mode must last between 1 and {} minutes",
+			MAX_TEST_MODE_MINUTES
+		));
+	}
+
+	let enabled = format!("Test mode enabled for {} minutes", minutes);
+	// Later alerts would go out as tests
+	if user.duress {
+		info!("Duress session tried to enable test mode for {}", user_id);
+		return HttpResponse::Ok().body(enabled);
+	}
+
+	let now = Utc::now();
+	let test_mode = TestMode {
+		user_id,
+		audience,
+		started_at: now,
+		expires_at: now + Duration::minutes(minutes),
+	};
+
+	match store.save_test_mode(&test_mode).await {
+		Ok(_) => HttpResponse::Ok().body(enabled),
+		Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+	}
+}
 
-	// Placeholder: Enable test mode for 5 minutes
-	duress_db::enable_test_mode(&user_id).await;
+// GET /users/{user_id}/test-mode
+pub async fn get_test_mode(store: web::Data<dyn Store>, path: web::Path<String>) -> HttpResponse {
+	let user_id = path.into_inner();
 
-	HttpResponse::Ok().body("Test mode enabled for 5 minutes")
+	match store.get_test_mode(&user_id).await {
+		Ok(test_mode) => {
+			let active = test_mode.filter(|mode| mode.is_active(Utc::now()));
+			HttpResponse::Ok().json(TestModeStatus {
+				active: active.is_some(),
+				audience: active.as_ref().map(|mode| mode.audience),
+				expires_at: active.as_ref().map(|mode| mode.expires_at),
+			})
+		}
+		Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+	}
 }
 
-// GET /users/{user_id}/map
-pub async fn get_map_info(path: web::Path<String>) -> HttpResponse {
+// DELETE /users/{user_id}/test-mode
+pub async fn end_test_mode(
+	store: web::Data<dyn Store>,
+	user: AuthenticatedUser,
+	path: web::Path<String>,
+) -> HttpResponse {
 	let user_id = path.into_inner();
+	if user.duress {
+		info!("Duress session tried to end test mode for {}", user_id);
+		return HttpResponse::Ok().body("Test mode ended");
+	}
 
-	// Placeholder: Retrieve location and duress status of all followed users
-	match duress_db::get_followed_users_map_info(&user_id).await {
-		Ok(map_info) => HttpResponse::Ok().json(map_info),
+	match store.delete_test_mode(&user_id).await {
+		Ok(_) => HttpResponse::Ok().body("Test mode ended"),
 		Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
 	}
 }
 
 // GET /users/{user_id}/preferences
-pub async fn get_preferences(path: web::Path<String>) -> HttpResponse {
+pub async fn get_preferences(store: web::Data<dyn Store>, path: web::Path<String>) -> HttpResponse {
 	let user_id = path.into_inner();
 
-	match duress_db::get_user_preferences(&user_id).await {
-		Ok(preferences) => HttpResponse::Ok().json(preferences),
+	match store.get_user_preferences(&user_id).await {
+		Ok(preferences) => HttpResponse::Ok().json(preferences.unwrap_or_default()),
 		Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
 	}
 }
 
 // PATCH /users/{user_id}/preferences
 pub async fn update_preferences(
+	store: web::Data<dyn Store>,
+	user: AuthenticatedUser,
 	path: web::Path<String>,
-	req: web::Json<UserPreferences>,
+	req: web::Json<UserPreferencesPatch>,
 ) -> HttpResponse {
 	let user_id = path.into_inner();
 
-	match duress_db::update_user_preferences(&user_id, req.into_inner()).await {
-		Ok(_) => HttpResponse::Ok().body("Preferences updated"),
-		Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+	let mut preferences = match store.get_user_preferences(&user_id).await {
+		Ok(preferences) => preferences.unwrap_or_default(),
+		Err(err) => {
+			error!("Failed to fetch preferences: {:?}", err);
+			return HttpResponse::InternalServerError().body(err.to_string());
+		}
+	};
+	let previous_webhook_url = preferences.contacts.webhook_url.clone();
+	req.apply(&mut preferences);
+	if let Err(message) = preferences.validate() {
+		return HttpResponse::BadRequest().body(message);
+	}
+	// The notifier checks the address again before every delivery
+	if let Some(url) = preferences
+		.contacts
+		.webhook_url
+		.as_ref()
+		.filter(|url| previous_webhook_url.as_ref() != Some(*url))
+	{
+		if let Err(err) = outbound::check_url(url, false).await {
+			return HttpResponse::BadRequest()
+				.body(format!("contacts webhook_url refused: {}", err));
+		}
+	}
+
+	// Turning off broadcasts or channels would quiet the alarm
+	if user.duress {
+		info!("Duress session tried to update preferences for {}", user_id);
+		return HttpResponse::Ok().json(preferences);
+	}
+
+	match store.update_user_preferences(&user_id, &preferences).await {
+		Ok(_) => HttpResponse::Ok().json(preferences),
+		Err(err) => {
+			error!("Failed to update preferences: {:?}", err);
+			HttpResponse::InternalServerError().body(err.to_string())
+		}
 	}
 }
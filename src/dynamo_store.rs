@@ -0,0 +1,337 @@
+// dynamo_store.rs
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use aws_sdk_dynamodb::Client;
+
+use crate::block_db::{self, Block};
+use crate::checkin_db::{self, Checkin};
+use crate::db::{self, Invite, Session, User};
+use crate::delivery_db::{self, Delivery, Job};
+use crate::duress_db::{self, DuressEvent, TestMode, UserPreferences};
+use crate::follow_db::{self, Follow, FollowCursor, FollowPage, FollowRequest};
+use crate::handle_db;
+use crate::location_db::{self, UserLocation};
+use crate::login_db::{self, LoginAttempts};
+use crate::push_db::{self, PushSubscription};
+use crate::store::{Store, StoreError};
+
+// Store backed by the DynamoDB tables used in the AWS deployment.
+pub struct DynamoStore {
+	client: Client,
+}
+
+impl DynamoStore {
+	pub fn new(client: Client) -> Self {
+		Self { client }
+	}
+
+	pub async fn from_env() -> Self {
+		Self::new(db::get_dynamodb_client().await)
+	}
+}
+
+#[async_trait]
+impl Store for DynamoStore {
+	async fn save_user(&self, user: &User) -> Result<(), StoreError> {
+		Ok(db::save_user(&self.client, user).await?)
+	}
+
+	async fn get_user(&self, user_id: &str) -> Result<Option<User>, StoreError> {
+		Ok(db::get_user(&self.client, user_id).await?)
+	}
+
+	async fn get_users_by_invite(&self, code: &str) -> Result<Vec<User>, StoreError> {
+		Ok(db::get_users_by_invite(&self.client, code).await?)
+	}
+
+	async fn suspend_user(
+		&self,
+		user_id: &str,
+		suspended_at: DateTime<Utc>,
+	) -> Result<bool, StoreError> {
+		Ok(db::suspend_user(&self.client, user_id, suspended_at).await?)
+	}
+
+	async fn unsuspend_user(&self, user_id: &str) -> Result<bool, StoreError> {
+		Ok(db::unsuspend_user(&self.client, user_id).await?)
+	}
+
+	async fn reserve_handle(&self, handle: &str, user_id: &str) -> Result<bool, StoreError> {
+		Ok(handle_db::reserve_handle(&self.client, handle, user_id).await?)
+	}
+
+	async fn release_handle(&self, handle: &str) -> Result<(), StoreError> {
+		Ok(handle_db::release_handle(&self.client, handle).await?)
+	}
+
+	async fn save_session(&self, session: &Session) -> Result<(), StoreError> {
+		Ok(db::save_session(&self.client, session).await?)
+	}
+
+	async fn get_session(&self, session_id: &str) -> Result<Option<Session>, StoreError> {
+		Ok(db::get_session(&self.client, session_id).await?)
+	}
+
+	async fn delete_session(&self, session_id: &str) -> Result<(), StoreError> {
+		Ok(db::delete_session(&self.client, session_id).await?)
+	}
+
+	async fn delete_user_sessions(&self, user_id: &str) -> Result<(), StoreError> {
+		Ok(db::delete_user_sessions(&self.client, user_id).await?)
+	}
+
+	async fn save_invite(&self, invite: &Invite) -> Result<(), StoreError> {
+		Ok(db::save_invite(&self.client, invite).await?)
+	}
+
+	async fn get_invite(&self, code: &str) -> Result<Option<Invite>, StoreError> {
+		Ok(db::get_invite(&self.client, code).await?)
+	}
+
+	async fn get_user_invites(&self, user_id: &str) -> Result<Vec<Invite>, StoreError> {
+		Ok(db::get_user_invites(&self.client, user_id).await?)
+	}
+
+	async fn update_invite(&self, invite: &Invite) -> Result<(), StoreError> {
+		Ok(db::update_invite(&self.client, invite).await?)
+	}
+
+	async fn revoke_invite(&self, code: &str) -> Result<(), StoreError> {
+		Ok(db::revoke_invite(&self.client, code).await?)
+	}
+
+	async fn create_invited_user(
+		&self,
+		user: &User,
+		now: DateTime<Utc>,
+	) -> Result<bool, StoreError> {
+		Ok(db::create_invited_user(&self.client, user, now).await?)
+	}
+
+	async fn add_follow(&self, follower_id: &str, followed_id: &str) -> Result<(), StoreError> {
+		Ok(follow_db::add_follow(&self.client, follower_id, followed_id).await?)
+	}
+
+	async fn remove_follow(&self, follower_id: &str, followed_id: &str) -> Result<(), StoreError> {
+		Ok(follow_db::remove_follow(&self.client, follower_id, followed_id).await?)
+	}
+
+	async fn get_follows(&self, followed_id: &str) -> Result<Vec<Follow>, StoreError> {
+		Ok(follow_db::get_follows(&self.client, followed_id).await?)
+	}
+
+	async fn get_following(&self, follower_id: &str) -> Result<Vec<Follow>, StoreError> {
+		Ok(follow_db::get_following(&self.client, follower_id).await?)
+	}
+
+	async fn get_followers_page(
+		&self,
+		followed_id: &str,
+		after: Option<&FollowCursor>,
+		limit: usize,
+	) -> Result<FollowPage, StoreError> {
+		Ok(follow_db::get_followers_page(&self.client, followed_id, after, limit).await?)
+	}
+
+	async fn get_following_page(
+		&self,
+		follower_id: &str,
+		after: Option<&FollowCursor>,
+		limit: usize,
+	) -> Result<FollowPage, StoreError> {
+		Ok(follow_db::get_following_page(&self.client, follower_id, after, limit).await?)
+	}
+
+	async fn count_followers(&self, followed_id: &str) -> Result<usize, StoreError> {
+		Ok(follow_db::count_followers(&self.client, followed_id).await?)
+	}
+
+	async fn count_following(&self, follower_id: &str) -> Result<usize, StoreError> {
+		Ok(follow_db::count_following(&self.client, follower_id).await?)
+	}
+
+	async fn save_follow_request(&self, request: &FollowRequest) -> Result<(), StoreError> {
+		Ok(follow_db::put_follow_request(&self.client, request).await?)
+	}
+
+	async fn get_follow_request(
+		&self,
+		follower_id: &str,
+		followed_id: &str,
+	) -> Result<Option<FollowRequest>, StoreError> {
+		Ok(follow_db::get_follow_request(&self.client, follower_id, followed_id).await?)
+	}
+
+	async fn get_follow_requests(
+		&self,
+		followed_id: &str,
+	) -> Result<Vec<FollowRequest>, StoreError> {
+		Ok(follow_db::get_follow_requests(&self.client, followed_id).await?)
+	}
+
+	async fn delete_follow_request(
+		&self,
+		follower_id: &str,
+		followed_id: &str,
+	) -> Result<(), StoreError> {
+		Ok(follow_db::delete_follow_request(&self.client, follower_id, followed_id).await?)
+	}
+
+	async fn save_block(&self, block: &Block) -> Result<(), StoreError> {
+		Ok(block_db::put_block(&self.client, block).await?)
+	}
+
+	async fn get_block(
+		&self,
+		blocker_id: &str,
+		blocked_id: &str,
+	) -> Result<Option<Block>, StoreError> {
+		Ok(block_db::get_block(&self.client, blocker_id, blocked_id).await?)
+	}
+
+	async fn get_blocks(&self, blocker_id: &str) -> Result<Vec<Block>, StoreError> {
+		Ok(block_db::get_blocks(&self.client, blocker_id).await?)
+	}
+
+	async fn delete_block(&self, blocker_id: &str, blocked_id: &str) -> Result<(), StoreError> {
+		Ok(block_db::delete_block(&self.client, blocker_id, blocked_id).await?)
+	}
+
+	async fn get_login_attempts(&self, user_id: &str) -> Result<Option<LoginAttempts>, StoreError> {
+		Ok(login_db::get_login_attempts(&self.client, user_id).await?)
+	}
+
+	async fn record_login_failure(
+		&self,
+		user_id: &str,
+		now: DateTime<Utc>,
+	) -> Result<LoginAttempts, StoreError> {
+		Ok(login_db::record_login_failure(&self.client, user_id, now).await?)
+	}
+
+	async fn clear_login_attempts(&self, user_id: &str) -> Result<(), StoreError> {
+		Ok(login_db::delete_login_attempts(&self.client, user_id).await?)
+	}
+
+	async fn log_duress_event(&self, event: &DuressEvent) -> Result<(), StoreError> {
+		Ok(duress_db::put_duress_event(&self.client, event).await?)
+	}
+
+	async fn update_duress_event(&self, event: &DuressEvent) -> Result<(), StoreError> {
+		Ok(duress_db::put_duress_event(&self.client, event).await?)
+	}
+
+	async fn get_user_duress_events(
+		&self,
+		user_id: &str,
+		from: Option<DateTime<Utc>>,
+		to: Option<DateTime<Utc>>,
+	) -> Result<Vec<DuressEvent>, StoreError> {
+		Ok(duress_db::get_user_duress_events(&self.client, user_id, from, to).await?)
+	}
+
+	async fn save_delivery(&self, delivery: &Delivery) -> Result<(), StoreError> {
+		Ok(delivery_db::put_delivery(&self.client, delivery).await?)
+	}
+
+	async fn get_event_deliveries(&self, event_id: &str) -> Result<Vec<Delivery>, StoreError> {
+		Ok(delivery_db::get_event_deliveries(&self.client, event_id).await?)
+	}
+
+	async fn enqueue_job(&self, job: &Job) -> Result<bool, StoreError> {
+		Ok(delivery_db::insert_job(&self.client, job).await?)
+	}
+
+	async fn get_due_jobs(&self, now: DateTime<Utc>, limit: usize) -> Result<Vec<Job>, StoreError> {
+		Ok(delivery_db::query_jobs(&self.client, false, now, limit).await?)
+	}
+
+	async fn claim_job(
+		&self,
+		key: &str,
+		expected: DateTime<Utc>,
+		until: DateTime<Utc>,
+	) -> Result<bool, StoreError> {
+		Ok(delivery_db::claim_job(&self.client, key, expected, until).await?)
+	}
+
+	async fn update_job(&self, job: &Job) -> Result<(), StoreError> {
+		Ok(delivery_db::put_job(&self.client, job).await?)
+	}
+
+	async fn delete_job(&self, key: &str) -> Result<(), StoreError> {
+		Ok(delivery_db::delete_job(&self.client, key).await?)
+	}
+
+	async fn get_dead_jobs(&self, limit: usize) -> Result<Vec<Job>, StoreError> {
+		Ok(delivery_db::query_jobs(&self.client, true, Utc::now(), limit).await?)
+	}
+
+	async fn save_push_subscription(
+		&self,
+		subscription: &PushSubscription,
+	) -> Result<(), StoreError> {
+		Ok(push_db::put_push_subscription(&self.client, subscription).await?)
+	}
+
+	async fn get_push_subscriptions(
+		&self,
+		user_id: &str,
+	) -> Result<Vec<PushSubscription>, StoreError> {
+		Ok(push_db::get_push_subscriptions(&self.client, user_id).await?)
+	}
+
+	async fn delete_push_subscription(&self, user_id: &str, id: &str) -> Result<(), StoreError> {
+		Ok(push_db::delete_push_subscription(&self.client, user_id, id).await?)
+	}
+
+	async fn save_checkin(&self, checkin: &Checkin) -> Result<(), StoreError> {
+		Ok(checkin_db::put_checkin(&self.client, checkin).await?)
+	}
+
+	async fn get_last_checkin(&self, user_id: &str) -> Result<Option<Checkin>, StoreError> {
+		Ok(checkin_db::get_last_checkin(&self.client, user_id).await?)
+	}
+
+	async fn save_user_location(&self, location: &UserLocation) -> Result<(), StoreError> {
+		Ok(location_db::put_user_location(&self.client, location).await?)
+	}
+
+	async fn get_user_location(&self, user_id: &str) -> Result<Option<UserLocation>, StoreError> {
+		Ok(location_db::get_user_location(&self.client, user_id).await?)
+	}
+
+	async fn get_user_locations_in_cells(
+		&self,
+		cells: &[String],
+	) -> Result<Vec<UserLocation>, StoreError> {
+		Ok(location_db::get_user_locations_in_cells(&self.client, cells).await?)
+	}
+
+	async fn save_test_mode(&self, test_mode: &TestMode) -> Result<(), StoreError> {
+		Ok(duress_db::put_test_mode(&self.client, test_mode).await?)
+	}
+
+	async fn get_test_mode(&self, user_id: &str) -> Result<Option<TestMode>, StoreError> {
+		Ok(duress_db::get_test_mode(&self.client, user_id).await?)
+	}
+
+	async fn delete_test_mode(&self, user_id: &str) -> Result<(), StoreError> {
+		Ok(duress_db::delete_test_mode(&self.client, user_id).await?)
+	}
+
+	async fn get_user_preferences(
+		&self,
+		user_id: &str,
+	) -> Result<Option<UserPreferences>, StoreError> {
+		Ok(duress_db::get_user_preferences(&self.client, user_id).await?)
+	}
+
+	async fn update_user_preferences(
+		&self,
+		user_id: &str,
+		preferences: &UserPreferences,
+	) -> Result<(), StoreError> {
+		Ok(duress_db::update_user_preferences(&self.client, user_id, preferences).await?)
+	}
+}
@@ -1,8 +1,26 @@
+use std::collections::HashMap;
+
 use aws_sdk_dynamodb::{Client, Error};
-use aws_sdk_dynamodb::types::AttributeValue;
+use aws_sdk_dynamodb::types::{AttributeValue, Select};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chrono::{DateTime, Utc};
 use serde::{Serialize, Deserialize};
 use tracing::info;
-use uuid::Uuid;
+
+// Follows are keyed by (follower_id, followed_id), so following someone twice
+// is a no-op and the users someone follows are a Query on the table. The
+// "followed_id-index" GSI keys the same rows the other way round for looking
+// up followers.
+const FOLLOW_TABLE: &str = "Follows";
+const FOLLOWED_INDEX: &str = "followed_id-index";
+
+// The original table, whose rows were keyed by a random `id`
+const LEGACY_FOLLOW_TABLE: &str = "Follow";
+
+// Requests waiting for the followed user's approval, keyed by (followed_id,
+// follower_id) so a user's pending requests are a Query
+const FOLLOW_REQUEST_TABLE: &str = "FollowRequests";
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Follow {
@@ -10,7 +28,90 @@ pub struct Follow {
 	pub followed_id: String,
 }
 
-// Adds a new follow relationship to the DynamoDB "Follow" table
+// Where a page of follows ended: the last follow on it. Handed to clients as
+// an opaque string and given back to fetch the next page.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct FollowCursor {
+	pub follower_id: String,
+	pub followed_id: String,
+}
+
+impl FollowCursor {
+	pub fn after(follow: &Follow) -> Self {
+		FollowCursor {
+			follower_id: follow.follower_id.clone(),
+			followed_id: follow.followed_id.clone(),
+		}
+	}
+
+	pub fn encode(&self) -> String {
+		URL_SAFE_NO_PAD.encode(serde_json::to_vec(self).expect("cursors serialize"))
+	}
+
+	pub fn decode(cursor: &str) -> Option<Self> {
+		serde_json::from_slice(&URL_SAFE_NO_PAD.decode(cursor).ok()?).ok()
+	}
+
+	fn from_key(key: &HashMap<String, AttributeValue>) -> Self {
+		FollowCursor::after(&follow_from_item(key))
+	}
+
+	fn to_key(&self) -> HashMap<String, AttributeValue> {
+		HashMap::from([
+			(
+				"follower_id".to_string(),
+				AttributeValue::S(self.follower_id.clone()),
+			),
+			(
+				"followed_id".to_string(),
+				AttributeValue::S(self.followed_id.clone()),
+			),
+		])
+	}
+}
+
+// Some of a user's followers or followings, in a stable order
+#[derive(Debug, Clone)]
+pub struct FollowPage {
+	pub follows: Vec<Follow>,
+	// Where the next page starts, absent on the last page
+	pub next: Option<FollowCursor>,
+}
+
+// A request to follow someone. It becomes a follow only once the followed
+// user approves it, so nobody sees a user's location without their say-so.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct FollowRequest {
+	pub follower_id: String,
+	pub followed_id: String,
+	pub requested_at: DateTime<Utc>,
+}
+
+impl FollowRequest {
+	pub fn new(follower_id: &str, followed_id: &str) -> Self {
+		FollowRequest {
+			follower_id: follower_id.to_string(),
+			followed_id: followed_id.to_string(),
+			requested_at: Utc::now(),
+		}
+	}
+}
+
+fn follow_from_item(item: &HashMap<String, AttributeValue>) -> Follow {
+	let string_attr = |name: &str| {
+		item.get(name)
+			.and_then(|v| v.as_s().ok())
+			.map(|s| s.to_string())
+			.unwrap_or_default()
+	};
+
+	Follow {
+		follower_id: string_attr("follower_id"),
+		followed_id: string_attr("followed_id"),
+	}
+}
+
+// Adds a new follow relationship to the DynamoDB "Follows" table
 pub async fn add_follow(
 	client: &Client,
 	follower_id: &str,
@@ -20,8 +121,7 @@ pub async fn add_follow(
 
 	client
 		.put_item()
-		.table_name("Follow")
-		.item("id", AttributeValue::S(Uuid::new_v4().to_string()))
+		.table_name(FOLLOW_TABLE)
 		.item("follower_id", AttributeValue::S(follower_id.to_string()))
 		.item("followed_id", AttributeValue::S(followed_id.to_string()))
 		.send()
@@ -30,7 +130,7 @@ pub async fn add_follow(
 	Ok(())
 }
 
-// Removes a follow relationship from the DynamoDB "Follow" table
+// Removes a follow relationship from the DynamoDB "Follows" table
 pub async fn remove_follow(
 	client: &Client,
 	follower_id: &str,
@@ -40,7 +140,7 @@ pub async fn remove_follow(
 
 	client
 		.delete_item()
-		.table_name("Follow")
+		.table_name(FOLLOW_TABLE)
 		.key("follower_id", AttributeValue::S(follower_id.to_string()))
 		.key("followed_id", AttributeValue::S(followed_id.to_string()))
 		.send()
@@ -49,35 +149,335 @@ pub async fn remove_follow(
 	Ok(())
 }
 
-// Retrieves all follows for a given follower_id
+// Every follow whose `key` attribute is `value`, from the table or `index`
+async fn query_follows(
+	client: &Client,
+	index: Option<&str>,
+	key: &str,
+	value: &str,
+) -> Result<Vec<Follow>, Error> {
+	let mut follows = Vec::new();
+	let mut start_key = None;
+
+	loop {
+		let result = client
+			.query()
+			.table_name(FOLLOW_TABLE)
+			.set_index_name(index.map(str::to_string))
+			.key_condition_expression("#key = :value")
+			.expression_attribute_names("#key", key)
+			.expression_attribute_values(":value", AttributeValue::S(value.to_string()))
+			.set_exclusive_start_key(start_key)
+			.send()
+			.await?;
+
+		follows.extend(
+			result
+				.items
+				.unwrap_or_default()
+				.iter()
+				.map(follow_from_item),
+		);
+		start_key = result.last_evaluated_key;
+		if start_key.is_none() {
+			return Ok(follows);
+		}
+	}
+}
+
+// Retrieves the followers of `followed_id`
 pub async fn get_follows(client: &Client, followed_id: &str) -> Result<Vec<Follow>, Error> {
 	info!("Fetching follows for a given followed_id");
+	query_follows(client, Some(FOLLOWED_INDEX), "followed_id", followed_id).await
+}
 
+// Retrieves all follows made by a given follower_id
+pub async fn get_following(client: &Client, follower_id: &str) -> Result<Vec<Follow>, Error> {
+	info!("Fetching follows for a given follower_id");
+	query_follows(client, None, "follower_id", follower_id).await
+}
+
+// Up to `limit` follows whose `key` attribute is `value`, after `after`
+async fn query_follows_page(
+	client: &Client,
+	index: Option<&str>,
+	key: &str,
+	value: &str,
+	after: Option<&FollowCursor>,
+	limit: usize,
+) -> Result<FollowPage, Error> {
 	let result = client
-		.scan()
-		.table_name("Follow")
-		.filter_expression("followed_id = :followed_id")
-		.expression_attribute_values(":followed_id", AttributeValue::S(followed_id.to_string()))
+		.query()
+		.table_name(FOLLOW_TABLE)
+		.set_index_name(index.map(str::to_string))
+		.key_condition_expression("#key = :value")
+		.expression_attribute_names("#key", key)
+		.expression_attribute_values(":value", AttributeValue::S(value.to_string()))
+		.set_exclusive_start_key(after.map(FollowCursor::to_key))
+		.limit(limit as i32)
 		.send()
 		.await?;
 
-	let follows = result
-		.items
-		.unwrap_or_default()
-		.into_iter()
-		.map(|item| Follow {
-			followed_id: item
-				.get("followed_id")
-				.and_then(|v| v.as_s().ok())
-				.map(|s| s.to_string())
-				.unwrap_or_else(String::new),
-			follower_id: item
-				.get("follower`1   _id")
-				.and_then(|v| v.as_s().ok())
-				.map(|s| s.to_string())
-				.unwrap_or_else(String::new),
-		})
-		.collect();
-
-	Ok(follows)
+	Ok(FollowPage {
+		follows: result
+			.items
+			.unwrap_or_default()
+			.iter()
+			.map(follow_from_item)
+			.collect(),
+		next: result
+			.last_evaluated_key
+			.as_ref()
+			.map(FollowCursor::from_key),
+	})
+}
+
+// Number of follows whose `key` attribute is `value`
+async fn count_follows(
+	client: &Client,
+	index: Option<&str>,
+	key: &str,
+	value: &str,
+) -> Result<usize, Error> {
+	let mut count = 0;
+	let mut start_key = None;
+
+	loop {
+		let result = client
+			.query()
+			.table_name(FOLLOW_TABLE)
+			.set_index_name(index.map(str::to_string))
+			.key_condition_expression("#key = :value")
+			.expression_attribute_names("#key", key)
+			.expression_attribute_values(":value", AttributeValue::S(value.to_string()))
+			.select(Select::Count)
+			.set_exclusive_start_key(start_key)
+			.send()
+			.await?;
+
+		count += result.count as usize;
+		start_key = result.last_evaluated_key;
+		if start_key.is_none() {
+			return Ok(count);
+		}
+	}
+}
+
+// A page of the followers of `followed_id`, ordered by follower
+pub async fn get_followers_page(
+	client: &Client,
+	followed_id: &str,
+	after: Option<&FollowCursor>,
+	limit: usize,
+) -> Result<FollowPage, Error> {
+	query_follows_page(
+		client,
+		Some(FOLLOWED_INDEX),
+		"followed_id",
+		followed_id,
+		after,
+		limit,
+	)
+	.await
+}
+
+// A page of the users `follower_id` follows, ordered by followed user
+pub async fn get_following_page(
+	client: &Client,
+	follower_id: &str,
+	after: Option<&FollowCursor>,
+	limit: usize,
+) -> Result<FollowPage, Error> {
+	query_follows_page(client, None, "follower_id", follower_id, after, limit).await
+}
+
+pub async fn count_followers(client: &Client, followed_id: &str) -> Result<usize, Error> {
+	count_follows(client, Some(FOLLOWED_INDEX), "followed_id", followed_id).await
+}
+
+pub async fn count_following(client: &Client, follower_id: &str) -> Result<usize, Error> {
+	count_follows(client, None, "follower_id", follower_id).await
+}
+
+fn follow_request_from_item(item: &HashMap<String, AttributeValue>) -> FollowRequest {
+	let follow = follow_from_item(item);
+	FollowRequest {
+		follower_id: follow.follower_id,
+		followed_id: follow.followed_id,
+		requested_at: item
+			.get("requested_at")
+			.and_then(|v| v.as_s().ok())
+			.and_then(|v| DateTime::parse_from_rfc3339(v).ok())
+			.map(|v| v.with_timezone(&Utc))
+			.unwrap_or_else(Utc::now),
+	}
+}
+
+// Writes a pending request to the DynamoDB "FollowRequests" table
+pub async fn put_follow_request(client: &Client, request: &FollowRequest) -> Result<(), Error> {
+	info!("Writing a follow request in DynamoDB");
+
+	client
+		.put_item()
+		.table_name(FOLLOW_REQUEST_TABLE)
+		.item(
+			"followed_id",
+			AttributeValue::S(request.followed_id.clone()),
+		)
+		.item(
+			"follower_id",
+			AttributeValue::S(request.follower_id.clone()),
+		)
+		.item(
+			"requested_at",
+			AttributeValue::S(request.requested_at.to_rfc3339()),
+		)
+		.send()
+		.await?;
+
+	Ok(())
+}
+
+pub async fn get_follow_request(
+	client: &Client,
+	follower_id: &str,
+	followed_id: &str,
+) -> Result<Option<FollowRequest>, Error> {
+	let result = client
+		.get_item()
+		.table_name(FOLLOW_REQUEST_TABLE)
+		.key("followed_id", AttributeValue::S(followed_id.to_string()))
+		.key("follower_id", AttributeValue::S(follower_id.to_string()))
+		.send()
+		.await?;
+
+	Ok(result.item.as_ref().map(follow_request_from_item))
+}
+
+// Pending requests to follow `followed_id`
+pub async fn get_follow_requests(
+	client: &Client,
+	followed_id: &str,
+) -> Result<Vec<FollowRequest>, Error> {
+	let mut requests = Vec::new();
+	let mut start_key = None;
+
+	loop {
+		let result = client
+			.query()
+			.table_name(FOLLOW_REQUEST_TABLE)
+			.key_condition_expression("followed_id = :followed_id")
+			.expression_attribute_values(":followed_id", AttributeValue::S(followed_id.to_string()))
+			.set_exclusive_start_key(start_key)
+			.send()
+			.await?;
+
+		requests.extend(
+			result
+				.items
+				.unwrap_or_default()
+				.iter()
+				.map(follow_request_from_item),
+		);
+		start_key = result.last_evaluated_key;
+		if start_key.is_none() {
+			return Ok(requests);
+		}
+	}
+}
+
+pub async fn delete_follow_request(
+	client: &Client,
+	follower_id: &str,
+	followed_id: &str,
+) -> Result<(), Error> {
+	info!("Deleting a follow request in DynamoDB");
+
+	client
+		.delete_item()
+		.table_name(FOLLOW_REQUEST_TABLE)
+		.key("followed_id", AttributeValue::S(followed_id.to_string()))
+		.key("follower_id", AttributeValue::S(follower_id.to_string()))
+		.send()
+		.await?;
+
+	Ok(())
+}
+
+// A row of the legacy "Follow" table, with the random `id` it was keyed by.
+// Some rows were read back under a mistyped attribute name, so that spelling
+// is accepted for the follower too.
+fn legacy_follow_from_item(item: &HashMap<String, AttributeValue>) -> Option<(String, Follow)> {
+	let string_attr = |name: &str| {
+		item.get(name)
+			.and_then(|v| v.as_s().ok())
+			.filter(|s| !s.is_empty())
+			.map(|s| s.to_string())
+	};
+
+	Some((
+		string_attr("id")?,
+		Follow {
+			follower_id: string_attr("follower_id").or_else(|| string_attr("follower`1   _id"))?,
+			followed_id: string_attr("followed_id")?,
+		},
+	))
+}
+
+// Copy every row of the legacy "Follow" table into "Follows", dropping the
+// random IDs. Copied rows are marked with `migrated_at` and skipped on later
+// runs, so running it again does not bring back follows removed since.
+// Returns how many rows were copied.
+pub async fn migrate_legacy_follows(client: &Client) -> Result<usize, Error> {
+	info!(
+		"Migrating follows from {} to {}",
+		LEGACY_FOLLOW_TABLE, FOLLOW_TABLE
+	);
+	let mut copied = 0;
+	let mut start_key = None;
+
+	loop {
+		let result = match client
+			.scan()
+			.table_name(LEGACY_FOLLOW_TABLE)
+			.filter_expression("attribute_not_exists(migrated_at)")
+			.set_exclusive_start_key(start_key)
+			.send()
+			.await
+		{
+			Ok(result) => result,
+			Err(err) => {
+				let err = Error::from(err);
+				if matches!(err, Error::ResourceNotFoundException(_)) {
+					info!("No {} table to migrate", LEGACY_FOLLOW_TABLE);
+					return Ok(copied);
+				}
+				return Err(err);
+			}
+		};
+
+		for (id, follow) in result
+			.items
+			.unwrap_or_default()
+			.iter()
+			.filter_map(legacy_follow_from_item)
+		{
+			add_follow(client, &follow.follower_id, &follow.followed_id).await?;
+			client
+				.update_item()
+				.table_name(LEGACY_FOLLOW_TABLE)
+				.key("id", AttributeValue::S(id))
+				.update_expression("SET migrated_at = :now")
+				.expression_attribute_values(":now", AttributeValue::S(Utc::now().to_rfc3339()))
+				.send()
+				.await?;
+			copied += 1;
+		}
+
+		start_key = result.last_evaluated_key;
+		if start_key.is_none() {
+			info!("Migrated {} follows", copied);
+			return Ok(copied);
+		}
+	}
 }
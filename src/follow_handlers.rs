@@ -1,102 +1,343 @@
 use actix_web::{web, HttpResponse};
-use aws_sdk_dynamodb::Client;
-use serde::Deserialize;
-// Import the DynamoDB client
-use crate::follow_db;
-use tracing::error;
+use serde::{Deserialize, Serialize};
+use crate::auth::AuthenticatedUser;
+use crate::follow_db::{Follow, FollowCursor, FollowPage, FollowRequest};
+use crate::store::{Store, StoreError};
+use tracing::{error, info};
+
+// Default and maximum number of follows in a page of followers or following
+const FOLLOW_PAGE_SIZE: usize = 50;
+const MAX_FOLLOW_PAGE_SIZE: usize = 200;
 
 #[derive(Debug, Deserialize)]
-pub struct FollowRequest {
+pub struct FollowUserRequest {
 	// ID of the user to follow or unfollow
 	user_id: String,
 }
 
+enum FollowOutcome {
+	Requested,
+	AlreadyFollowing,
+	BlockedByFollower,
+}
+
 // POST /users/{user_id}/follow
+//
+// Asks to follow a user. The follow only starts once they approve it.
 pub async fn follow_user(
-	// Access the DynamoDB client from the app state
-	client: web::Data<Client>,
+	// Access the configured store from the app state
+	store: web::Data<dyn Store>,
+	user: AuthenticatedUser,
 	path: web::Path<String>,
-	req: web::Json<FollowRequest>,
+	req: web::Json<FollowUserRequest>,
 ) -> HttpResponse {
 	let follower_id = path.into_inner();
 	let followed_id = req.user_id.clone();
+	if user.duress {
+		info!("Duress session tried to follow a user for {}", follower_id);
+		return HttpResponse::Accepted().body("Follow request sent");
+	}
 
-	match follow_db::add_follow(&client, &follower_id, &followed_id).await {
-		Ok(_) => HttpResponse::Ok().body("Followed successfully"),
+	let result = async {
+		if store.get_block(&follower_id, &followed_id).await?.is_some() {
+			return Ok(FollowOutcome::BlockedByFollower);
+		}
+		// Looks like any other request to someone who has blocked the follower
+		if store.get_block(&followed_id, &follower_id).await?.is_some() {
+			return Ok(FollowOutcome::Requested);
+		}
+		let following = store
+			.get_following(&follower_id)
+			.await?
+			.iter()
+			.any(|follow| follow.followed_id == followed_id);
+		if following {
+			return Ok(FollowOutcome::AlreadyFollowing);
+		}
+		// Asking again leaves the original request in place
+		if store
+			.get_follow_request(&follower_id, &followed_id)
+			.await?
+			.is_none()
+		{
+			store
+				.save_follow_request(&FollowRequest::new(&follower_id, &followed_id))
+				.await?;
+		}
+		Ok::<_, StoreError>(FollowOutcome::Requested)
+	}
+	.await;
 
+	match result {
+		Ok(FollowOutcome::Requested) => HttpResponse::Accepted().body("Follow request sent"),
+		Ok(FollowOutcome::AlreadyFollowing) => HttpResponse::Ok().body("Already following"),
+		Ok(FollowOutcome::BlockedByFollower) => {
+			HttpResponse::Conflict().body("Unblock this user before following them")
+		}
 		Err(err) => {
-			error!("Failed to add follower :{:?}", err);
-			return HttpResponse::InternalServerError().body(err.to_string());
+			error!("Failed to request follow :{:?}", err);
+			HttpResponse::InternalServerError().body(err.to_string())
 		}
 	}
 }
 
 // POST /users/{user_id}/unfollow
 pub async fn unfollow_user(
-	// Access the DynamoDB client from the app state
-	client: web::Data<Client>,
+	// Access the configured store from the app state
+	store: web::Data<dyn Store>,
+	user: AuthenticatedUser,
 	path: web::Path<String>,
-	req: web::Json<FollowRequest>,
+	req: web::Json<FollowUserRequest>,
 ) -> HttpResponse {
 	let follower_id = path.into_inner();
 	let followed_id = req.user_id.clone();
+	if user.duress {
+		info!(
+			"Duress session tried to unfollow a user for {}",
+			follower_id
+		);
+		return HttpResponse::Ok().body("Unfollowed successfully");
+	}
+
+	// Also withdraws a request that has not been answered yet
+	let result = async {
+		store
+			.delete_follow_request(&follower_id, &followed_id)
+			.await?;
+		store.remove_follow(&follower_id, &followed_id).await
+	}
+	.await;
 
-	match follow_db::remove_follow(&client, &follower_id, &followed_id).await {
+	match result {
 		Ok(_) => HttpResponse::Ok().body("Unfollowed successfully"),
 		Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
 	}
 }
 
-// GET /users/{user_id}/follows
-pub async fn get_user_follows(
-	// Access the DynamoDB client from the app state
-	client: web::Data<Client>,
+#[derive(Debug, Deserialize)]
+pub struct FollowListQuery {
+	// Opaque `next_cursor` from the previous page
+	cursor: Option<String>,
+	limit: Option<usize>,
+}
+
+impl FollowListQuery {
+	fn validate(&self) -> Result<(Option<FollowCursor>, usize), String> {
+		let limit = self.limit.unwrap_or(FOLLOW_PAGE_SIZE);
+		if !(1..=MAX_FOLLOW_PAGE_SIZE).contains(&limit) {
+			return Err(format!(
+				"limit must be between 1 and {}",
+				MAX_FOLLOW_PAGE_SIZE
+			));
+		}
+		let cursor = match &self.cursor {
+			Some(cursor) => Some(FollowCursor::decode(cursor).ok_or("Invalid cursor")?),
+			None => None,
+		};
+		Ok((cursor, limit))
+	}
+}
+
+#[derive(Debug, Serialize)]
+pub struct FollowList {
+	follows: Vec<Follow>,
+	// Total across every page
+	count: usize,
+	next_cursor: Option<String>,
+}
+
+impl FollowList {
+	fn new(page: FollowPage, count: usize) -> Self {
+		FollowList {
+			follows: page.follows,
+			count,
+			next_cursor: page.next.as_ref().map(FollowCursor::encode),
+		}
+	}
+}
+
+// GET /users/{user_id}/following
+pub async fn get_following(
+	// Access the configured store from the app state
+	store: web::Data<dyn Store>,
 	path: web::Path<String>,
+	query: web::Query<FollowListQuery>,
 ) -> HttpResponse {
 	let follower_id = path.into_inner();
+	let (cursor, limit) = match query.validate() {
+		Ok(page) => page,
+		Err(message) => return HttpResponse::BadRequest().body(message),
+	};
 
-	match follow_db::get_follows(&client, &follower_id).await {
-		Ok(follows) => HttpResponse::Ok().json(follows),
+	let result = async {
+		let page = store
+			.get_following_page(&follower_id, cursor.as_ref(), limit)
+			.await?;
+		let count = store.count_following(&follower_id).await?;
+		Ok::<_, StoreError>(FollowList::new(page, count))
+	}
+	.await;
+
+	match result {
+		Ok(following) => HttpResponse::Ok().json(following),
 		Err(err) => {
-			error!("Failed to add follower :{:?}", err);
-			return HttpResponse::InternalServerError().body(err.to_string());
+			error!("Failed to fetch followed users: {:?}", err);
+			HttpResponse::InternalServerError().body(err.to_string())
 		}
 	}
 }
 
-// POST /users/{user_id}/delete_follower
+// DELETE /users/{user_id}/followers/{follower_id}
+//
+// Stops someone following the user. They can ask to follow again.
 pub async fn delete_follower(
-	// Access the DynamoDB client from the app state
-	client: web::Data<Client>,
-	path: web::Path<String>,
-	req: web::Json<FollowRequest>,
+	// Access the configured store from the app state
+	store: web::Data<dyn Store>,
+	user: AuthenticatedUser,
+	path: web::Path<(String, String)>,
 ) -> HttpResponse {
-	// This is the user who is followed
-	let followed_id = path.into_inner();
-	// This is the follower to be removed
-	let follower_id = req.user_id.clone();
+	let (followed_id, follower_id) = path.into_inner();
+	// Removing a follower would stop them hearing about the duress
+	if user.duress {
+		info!(
+			"Duress session tried to remove a follower of {}",
+			followed_id
+		);
+		return HttpResponse::Ok().body("Follower removed successfully");
+	}
 
-	// Match the result of removing the follow relationship
-	match follow_db::remove_follow(&client, &follower_id, &followed_id).await {
+	match store.remove_follow(&follower_id, &followed_id).await {
 		Ok(_) => HttpResponse::Ok().body("Follower removed successfully"),
-		Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+		Err(err) => {
+			error!("Failed to remove follower: {:?}", err);
+			HttpResponse::InternalServerError().body(err.to_string())
+		}
 	}
 }
 
 // GET /users/{user_id}/followers
 pub async fn get_followers(
-	// Access the DynamoDB client from the app state
-	client: web::Data<Client>,
+	// Access the configured store from the app state
+	store: web::Data<dyn Store>,
 	path: web::Path<String>,
+	query: web::Query<FollowListQuery>,
 ) -> HttpResponse {
 	let followed_id = path.into_inner();
+	let (cursor, limit) = match query.validate() {
+		Ok(page) => page,
+		Err(message) => return HttpResponse::BadRequest().body(message),
+	};
 
-	// Query the Follow table to find all users following the given `followed_id`
-	match follow_db::get_follows(&client, &followed_id).await {
+	let result = async {
+		let page = store
+			.get_followers_page(&followed_id, cursor.as_ref(), limit)
+			.await?;
+		let count = store.count_followers(&followed_id).await?;
+		Ok::<_, StoreError>(FollowList::new(page, count))
+	}
+	.await;
+
+	match result {
 		Ok(followers) => HttpResponse::Ok().json(followers),
 		Err(err) => {
-			error!("Failed to add follower :{:?}", err);
-			return HttpResponse::InternalServerError().body(err.to_string());
+			error!("Failed to fetch followers: {:?}", err);
+			HttpResponse::InternalServerError().body(err.to_string())
+		}
+	}
+}
+
+// GET /users/{user_id}/follow-requests
+pub async fn get_follow_requests(
+	store: web::Data<dyn Store>,
+	path: web::Path<String>,
+) -> HttpResponse {
+	let followed_id = path.into_inner();
+
+	match store.get_follow_requests(&followed_id).await {
+		Ok(requests) => HttpResponse::Ok().json(requests),
+		Err(err) => {
+			error!("Failed to fetch follow requests: {:?}", err);
+			HttpResponse::InternalServerError().body(err.to_string())
+		}
+	}
+}
+
+// POST /users/{user_id}/follow-requests/{follower_id}/approve
+pub async fn approve_follow_request(
+	store: web::Data<dyn Store>,
+	user: AuthenticatedUser,
+	path: web::Path<(String, String)>,
+) -> HttpResponse {
+	let (followed_id, follower_id) = path.into_inner();
+
+	let result = async {
+		if store
+			.get_follow_request(&follower_id, &followed_id)
+			.await?
+			.is_none()
+		{
+			return Ok(false);
+		}
+		// Whoever forced the login could otherwise let themselves in as a
+		// follower and watch the map
+		if user.duress {
+			info!(
+				"Duress session tried to approve a follower of {}",
+				followed_id
+			);
+			return Ok(true);
+		}
+		store.add_follow(&follower_id, &followed_id).await?;
+		store
+			.delete_follow_request(&follower_id, &followed_id)
+			.await?;
+		Ok::<_, StoreError>(true)
+	}
+	.await;
+
+	match result {
+		Ok(true) => HttpResponse::Ok().body("Follow request approved"),
+		Ok(false) => HttpResponse::NotFound().body("Follow request not found"),
+		Err(err) => {
+			error!("Failed to approve follow request: {:?}", err);
+			HttpResponse::InternalServerError().body(err.to_string())
+		}
+	}
+}
+
+// POST /users/{user_id}/follow-requests/{follower_id}/deny
+pub async fn deny_follow_request(
+	store: web::Data<dyn Store>,
+	user: AuthenticatedUser,
+	path: web::Path<(String, String)>,
+) -> HttpResponse {
+	let (followed_id, follower_id) = path.into_inner();
+
+	let result = async {
+		if store
+			.get_follow_request(&follower_id, &followed_id)
+			.await?
+			.is_none()
+		{
+			return Ok(false);
+		}
+		if user.duress {
+			info!("Duress session tried to deny a follower of {}", followed_id);
+			return Ok(true);
+		}
+		store
+			.delete_follow_request(&follower_id, &followed_id)
+			.await?;
+		Ok::<_, StoreError>(true)
+	}
+	.await;
+
+	match result {
+		Ok(true) => HttpResponse::Ok().body("Follow request denied"),
+		Ok(false) => HttpResponse::NotFound().body("Follow request not found"),
+		Err(err) => {
+			error!("Failed to deny follow request: {:?}", err);
+			HttpResponse::InternalServerError().body(err.to_string())
 		}
 	}
 }
@@ -0,0 +1,85 @@
+// handle_db.rs
+use aws_sdk_dynamodb::types::AttributeValue;
+use aws_sdk_dynamodb::{Client, Error};
+use chrono::Utc;
+use lazy_static::lazy_static;
+use rand::seq::SliceRandom;
+use rand::Rng;
+use tracing::info;
+
+// Handles are an angel, a city and a curl, e.g. "seraph-lagos-spiral": easy
+// to recognise and say, and nothing to do with who the user is
+lazy_static! {
+	static ref ANGELS: Vec<&'static str> = words(include_str!("../lists/angels.txt"));
+	static ref CITIES: Vec<&'static str> = words(include_str!("../lists/cities.txt"));
+	static ref CURLS: Vec<&'static str> = words(include_str!("../lists/curls.txt"));
+}
+
+// One word per line; the lists repeat a few
+fn words(list: &'static str) -> Vec<&'static str> {
+	let mut words: Vec<_> = list
+		.lines()
+		.map(str::trim)
+		.filter(|word| !word.is_empty())
+		.collect();
+	words.sort_unstable();
+	words.dedup();
+	words
+}
+
+// A random handle. Only unique once reserved.
+pub fn generate() -> String {
+	let mut rng = rand::thread_rng();
+	let words: Vec<&str> = [&*ANGELS, &*CITIES, &*CURLS]
+		.iter()
+		.map(|list| *list.choose(&mut rng).expect("word lists are not empty"))
+		.collect();
+	words.join("-")
+}
+
+// A random handle with a number on the end, for when plain ones keep
+// colliding
+pub fn generate_numbered() -> String {
+	format!("{}-{}", generate(), rand::thread_rng().gen_range(2..10_000))
+}
+
+// Claim `handle` for `user_id` in the DynamoDB "HandleReservation" table,
+// keyed by handle. Returns false if someone already holds it.
+pub async fn reserve_handle(client: &Client, handle: &str, user_id: &str) -> Result<bool, Error> {
+	info!("Reserving a handle in DynamoDB");
+
+	let result = client
+		.put_item()
+		.table_name("HandleReservation")
+		.item("handle", AttributeValue::S(handle.to_string()))
+		.item("user_id", AttributeValue::S(user_id.to_string()))
+		.item("reserved_at", AttributeValue::S(Utc::now().to_rfc3339()))
+		.condition_expression("attribute_not_exists(handle)")
+		.send()
+		.await;
+
+	match result {
+		Ok(_) => Ok(true),
+		Err(err)
+			if err
+				.as_service_error()
+				.is_some_and(|err| err.is_conditional_check_failed_exception()) =>
+		{
+			Ok(false)
+		}
+		Err(err) => Err(err.into()),
+	}
+}
+
+pub async fn release_handle(client: &Client, handle: &str) -> Result<(), Error> {
+	info!("Releasing a handle in DynamoDB");
+
+	client
+		.delete_item()
+		.table_name("HandleReservation")
+		.key("handle", AttributeValue::S(handle.to_string()))
+		.send()
+		.await?;
+
+	Ok(())
+}
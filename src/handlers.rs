@@ -1,27 +1,120 @@
-use actix_web::{web, HttpResponse};
+use actix_web::http::header;
+use actix_web::{web, HttpRequest, HttpResponse};
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
-use chrono::{Duration, Utc}; // You may leave `Duration` in case it's used for time-based invite restrictions
-use aws_sdk_dynamodb::Client;
+use chrono::{DateTime, Duration, Utc};
 use tracing::{info, error};
 
-use crate::db;
+use crate::auth::{self, AuthenticatedUser};
+use crate::avatar::Identicon;
+use crate::db::{self, InviteError};
+use crate::duress_db::{DuressEvent, DuressType};
+use crate::duress_handlers::raise_duress;
+use crate::handle_db;
+use crate::notify::Notifiers;
+use crate::pin::{self, PinMatch};
+use crate::store::{Store, StoreError};
 
-#[derive(Debug, Deserialize)]
+#[derive(Deserialize)]
 pub struct RegisterRequest {
 	invite_code: String,
 	normal_pin: String,
 	duress_pin: String,
 }
 
+// PINs are redacted so request logging never records them
+impl std::fmt::Debug for RegisterRequest {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_struct("RegisterRequest")
+			.field("invite_code", &self.invite_code)
+			.field("normal_pin", &"<redacted>")
+			.field("duress_pin", &"<redacted>")
+			.finish()
+	}
+}
+
+#[derive(Deserialize)]
+pub struct LoginRequest {
+	user_id: String,
+	pin: String,
+}
+
+impl std::fmt::Debug for LoginRequest {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_struct("LoginRequest")
+			.field("user_id", &self.user_id)
+			.field("pin", &"<redacted>")
+			.finish()
+	}
+}
+
+// Default and longest lifetime of a new invite
+const DEFAULT_INVITE_HOURS: i64 = 168;
+const MAX_INVITE_HOURS: i64 = 720;
+
+// How long clients may cache an avatar. Short, as re-rolling a handle changes it.
+const AVATAR_MAX_AGE_SECONDS: u32 = 3600;
+
+// Plain handles to try before adding a number. The lists make well over a
+// hundred thousand, so collisions stay rare for a long while.
+const PLAIN_HANDLE_ATTEMPTS: usize = 8;
+const MAX_HANDLE_ATTEMPTS: usize = 32;
+
+#[derive(Debug, Serialize)]
+pub struct RegisterResponse {
+	id: String,
+	handle: String,
+	invite_code: String,
+	token: String,
+	expires_at: DateTime<Utc>,
+}
+
+// Identical for normal and duress logins
+#[derive(Debug, Serialize)]
+pub struct LoginResponse {
+	user_id: String,
+	token: String,
+	expires_at: DateTime<Utc>,
+}
+
 #[derive(Debug, Deserialize)]
 pub struct InviteRequest {
 	user_id: String, // ID of the user generating the invite
+	// Hours until the code stops working
+	expires_in_hours: Option<i64>,
+	// Registrations the code allows, unlimited if absent
+	max_uses: Option<u32>,
+	// Shorthand for `max_uses: 1`
+	#[serde(default)]
+	single_use: bool,
+}
+
+impl InviteRequest {
+	fn validate(&self) -> Result<(i64, Option<u32>), String> {
+		let hours = self.expires_in_hours.unwrap_or(DEFAULT_INVITE_HOURS);
+		if !(1..=MAX_INVITE_HOURS).contains(&hours) {
+			return Err(format!(
+				"expires_in_hours must be between 1 and {}",
+				MAX_INVITE_HOURS
+			));
+		}
+		let max_uses = match (self.single_use, self.max_uses) {
+			(_, Some(0)) => return Err("max_uses must be at least 1".to_string()),
+			(true, Some(max_uses)) if max_uses != 1 => {
+				return Err("single_use invites allow exactly one use".to_string())
+			}
+			(true, _) => Some(1),
+			(false, max_uses) => max_uses,
+		};
+		Ok((hours, max_uses))
+	}
 }
 
 #[derive(Debug, Serialize)]
 pub struct InviteResponse {
 	invite_code: String,
+	expires_at: Option<chrono::DateTime<Utc>>,
+	max_uses: Option<u32>,
 }
 
 fn log_error_chain(error: &dyn std::error::Error) {
@@ -32,59 +125,305 @@ fn log_error_chain(error: &dyn std::error::Error) {
 	}
 }
 
+// Reserve a random handle for `user_id`, or None if every attempt collided
+async fn reserve_new_handle(
+	store: &dyn Store,
+	user_id: &str,
+) -> Result<Option<String>, StoreError> {
+	for attempt in 0..MAX_HANDLE_ATTEMPTS {
+		let handle = if attempt < PLAIN_HANDLE_ATTEMPTS {
+			handle_db::generate()
+		} else {
+			handle_db::generate_numbered()
+		};
+		if store.reserve_handle(&handle, user_id).await? {
+			return Ok(Some(handle));
+		}
+	}
+	Ok(None)
+}
+
 pub async fn register_user(
-	client: web::Data<Client>, // Access the DynamoDB client from the app state
+	store: web::Data<dyn Store>, // Access the configured store from the app state
+	// Set when an existing member registers again while signed in
+	registrant: Option<AuthenticatedUser>,
 	req: web::Json<RegisterRequest>,
 ) -> HttpResponse {
-	// Get the invite from DynamoDB using the provided invite code
 	info!("Received register request: {:?}", req);
-	match db::get_invite(&client, &req.invite_code).await {
-		Ok(Some(mut invite)) => {
-			invite.invite_count += 1;
-			if let Err(err) = db::update_invite(&client, &invite).await {
-				error!("Failed to update invite: {:?}", err);
-				return HttpResponse::InternalServerError().body(err.to_string());
+	let req = req.into_inner();
+	if req.normal_pin.is_empty() || req.duress_pin.is_empty() {
+		return HttpResponse::BadRequest().body("Both PINs are required");
+	}
+	if req.normal_pin == req.duress_pin {
+		return HttpResponse::BadRequest().body("Normal and duress PINs must differ");
+	}
+
+	// Hash both PINs off the async executor before touching the invite
+	let pins = web::block(move || -> Result<_, argon2::password_hash::Error> {
+		Ok((
+			pin::hash_pin(&req.normal_pin)?,
+			pin::hash_pin(&req.duress_pin)?,
+			req.invite_code,
+		))
+	})
+	.await;
+	let (normal_pin_hash, duress_pin_hash, invite_code) = match pins {
+		Ok(Ok(pins)) => pins,
+		Ok(Err(err)) => {
+			error!("Failed to hash PINs: {:?}", err);
+			return HttpResponse::InternalServerError().body("Failed to hash PINs");
+		}
+		Err(err) => {
+			error!("Failed to hash PINs: {:?}", err);
+			return HttpResponse::InternalServerError().body("Failed to hash PINs");
+		}
+	};
+
+	// Get the invite from the store using the provided invite code
+	let now = Utc::now();
+	match store.get_invite(&invite_code).await {
+		Ok(Some(invite)) => {
+			if let Err(err) = invite.check(now) {
+				return HttpResponse::BadRequest().body(err.to_string());
+			}
+			// Someone the invitor has blocked cannot come back in on their
+			// code. Answered like an unknown code, as blocks are never shown.
+			if let Some(registrant) = &registrant {
+				match store
+					.get_block(&invite.invitor_id, &registrant.user_id)
+					.await
+				{
+					Ok(Some(_)) => {
+						info!(
+							"Blocked user {} tried to register with invite code {}",
+							registrant.user_id, invite_code
+						);
+						return HttpResponse::BadRequest().body("Invalid invite code");
+					}
+					Ok(None) => {}
+					Err(err) => {
+						error!("Failed to fetch block: {:?}", err);
+						return HttpResponse::InternalServerError().body(err.to_string());
+					}
+				}
 			}
 		}
 		Ok(None) => {
-			error!("Invalid invite code provided: {}", req.invite_code);
+			error!("Invalid invite code provided: {}", invite_code);
 			return HttpResponse::BadRequest().body("Invalid invite code");
 		}
 		Err(err) => {
-			error!("Failed to fetch invite from DynamoDB: {:?}", err);
+			error!("Failed to fetch invite: {:?}", err);
 			return HttpResponse::InternalServerError().body(err.to_string());
 		}
 	}
 
 	let user_id = Uuid::new_v4().to_string();
+	let handle = match reserve_new_handle(store.get_ref(), &user_id).await {
+		Ok(Some(handle)) => handle,
+		Ok(None) => {
+			error!("Failed to find a free handle");
+			return HttpResponse::ServiceUnavailable().body("No free handle, try again");
+		}
+		Err(err) => {
+			error!("Failed to reserve handle: {:?}", err);
+			return HttpResponse::InternalServerError().body(err.to_string());
+		}
+	};
 	let user = db::User {
 		id: user_id.clone(),
-		invite_code: req.invite_code.clone(),
-		normal_pin: req.normal_pin.clone(),
-		duress_pin: req.duress_pin.clone(),
+		invite_code,
+		normal_pin_hash,
+		duress_pin_hash,
+		handle: Some(handle.clone()),
+		admin: false,
+		suspended_at: None,
 	};
 
-	match db::save_user(&client, &user).await {
-		Ok(_) => {
-			info!("Successfully registered user: {}", user_id);
-			HttpResponse::Ok().json(&user)
+	// Checked again as the use is counted, in case another registration took
+	// the last use in the meantime. Either the user is saved and the use
+	// counted, or neither.
+	let created = store.create_invited_user(&user, now).await;
+	if !matches!(created, Ok(true)) {
+		if let Err(err) = store.release_handle(&handle).await {
+			error!("Failed to release handle: {:?}", err);
+		}
+	}
+	match created {
+		Ok(true) => {}
+		Ok(false) => {
+			// Say what changed since the invite was checked above
+			let reason = match store.get_invite(&user.invite_code).await {
+				Ok(Some(invite)) => invite.check(now).err(),
+				Ok(None) => None,
+				Err(err) => {
+					error!("Failed to fetch invite: {:?}", err);
+					None
+				}
+			};
+			return HttpResponse::BadRequest()
+				.body(reason.unwrap_or(InviteError::UsedUp).to_string());
 		}
 		Err(err) => {
-			error!("Failed to save user to DynamoDB: {:?}", err);
+			error!("Failed to save user");
+			log_error_chain(&err);
+			return HttpResponse::InternalServerError().body(err.to_string());
+		}
+	}
+	info!("Successfully registered user: {}", user_id);
 
+	match auth::issue_session(store.get_ref(), &user_id, false).await {
+		Ok((session, token)) => HttpResponse::Ok().json(RegisterResponse {
+			id: user.id,
+			handle,
+			invite_code: user.invite_code,
+			token,
+			expires_at: session.expires_at,
+		}),
+		Err(err) => {
+			error!("Failed to save session: {:?}", err);
 			HttpResponse::InternalServerError().body(err.to_string())
 		}
 	}
 }
 
+// Raise the alarm for a duress PIN login in the background, so the response
+// looks and takes the same as a normal login to anyone watching the client.
+fn spawn_login_duress(
+	store: &web::Data<dyn Store>,
+	notifiers: &web::Data<Notifiers>,
+	user_id: &str,
+	at: DateTime<Utc>,
+) {
+	let store = store.clone();
+	let notifiers = notifiers.clone();
+	let event = DuressEvent::new(
+		user_id,
+		DuressType::DuressPin,
+		"Duress PIN entered at login",
+		at,
+	);
+	actix_web::rt::spawn(async move {
+		if let Err(err) = raise_duress(store.get_ref(), &notifiers, &event).await {
+			error!("Failed to raise duress from login: {:?}", err);
+		}
+	});
+}
+
+// POST /login
+pub async fn login(
+	store: web::Data<dyn Store>, // Access the configured store from the app state
+	notifiers: web::Data<Notifiers>,
+	req: web::Json<LoginRequest>,
+) -> HttpResponse {
+	info!("Received login request: {:?}", req);
+	let LoginRequest { user_id, pin } = req.into_inner();
+
+	let user = match store.get_user(&user_id).await {
+		Ok(user) => user,
+		Err(err) => {
+			error!("Failed to fetch user: {:?}", err);
+			return HttpResponse::InternalServerError().body(err.to_string());
+		}
+	};
+	let suspended = user
+		.as_ref()
+		.is_some_and(|user| user.suspended_at.is_some());
+	let known = user.is_some();
+	let pin_match = pin::check_pin(user, pin).await;
+
+	let now = Utc::now();
+	let attempts = match store.get_login_attempts(&user_id).await {
+		Ok(attempts) => attempts,
+		Err(err) => {
+			error!("Failed to fetch login attempts: {:?}", err);
+			return HttpResponse::InternalServerError().body(err.to_string());
+		}
+	};
+	if let Some(attempts) = attempts.as_ref().filter(|attempts| attempts.locked(now)) {
+		// Someone forced to log in while locked out must still be able to
+		// raise the alarm, but every PIN gets the same answer.
+		if pin_match == PinMatch::Duress {
+			spawn_login_duress(&store, &notifiers, &user_id, now);
+		}
+		let retry_after = (attempts.window_end() - now).num_seconds().max(1);
+		return HttpResponse::TooManyRequests()
+			.insert_header((header::RETRY_AFTER, retry_after.to_string()))
+			.body("Too many failed logins");
+	}
+
+	let duress = match pin_match {
+		PinMatch::Normal => false,
+		PinMatch::Duress => true,
+		PinMatch::Wrong => {
+			// Nobody to lock out, and no row left behind for every ID tried
+			if known {
+				if let Err(err) = store.record_login_failure(&user_id, now).await {
+					error!("Failed to record login failure: {:?}", err);
+					return HttpResponse::InternalServerError().body(err.to_string());
+				}
+			}
+			return HttpResponse::Unauthorized().body("Invalid credentials");
+		}
+	};
+	if attempts.is_some() {
+		if let Err(err) = store.clear_login_attempts(&user_id).await {
+			error!("Failed to clear login attempts: {:?}", err);
+			return HttpResponse::InternalServerError().body(err.to_string());
+		}
+	}
+	// As when locked out: no session, but the duress PIN still raises the
+	// alarm and gets the same answer as the normal one
+	if suspended {
+		if duress {
+			spawn_login_duress(&store, &notifiers, &user_id, now);
+		}
+		return HttpResponse::Forbidden().body("Account suspended");
+	}
+
+	let (session, token) = match auth::issue_session(store.get_ref(), &user_id, duress).await {
+		Ok(issued) => issued,
+		Err(err) => {
+			error!("Failed to save session: {:?}", err);
+			return HttpResponse::InternalServerError().body(err.to_string());
+		}
+	};
+
+	if duress {
+		spawn_login_duress(&store, &notifiers, &user_id, session.created_at);
+	}
+
+	HttpResponse::Ok().json(LoginResponse {
+		user_id,
+		token,
+		expires_at: session.expires_at,
+	})
+}
+
+// POST /logout
+pub async fn logout(store: web::Data<dyn Store>, user: AuthenticatedUser) -> HttpResponse {
+	match store.delete_session(&user.session_id).await {
+		Ok(_) => HttpResponse::Ok().body("Logged out"),
+		Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+	}
+}
+
 pub async fn create_invite(
-	client: web::Data<Client>, // Access the DynamoDB client from the app state
+	store: web::Data<dyn Store>, // Access the configured store from the app state
+	user: AuthenticatedUser,
 	req: web::Json<InviteRequest>,
 ) -> HttpResponse {
 	let user_id = req.user_id.clone();
+	if user_id != user.user_id {
+		return HttpResponse::Forbidden().body("Forbidden");
+	}
+	let (expires_in_hours, max_uses) = match req.validate() {
+		Ok(limits) => limits,
+		Err(message) => return HttpResponse::BadRequest().body(message),
+	};
 
 	// Fetch the user's invites within the past 168 hours (7 days)
-	match db::get_user_invites(&client, &user_id).await {
+	match store.get_user_invites(&user_id).await {
 		Ok(invites) => {
 			let recent_invites: Vec<_> = invites
 				.iter()
@@ -99,18 +438,207 @@ pub async fn create_invite(
 			// Generate a unique invite code
 			let invite_code = Uuid::new_v4().to_string();
 
+			let now = Utc::now();
 			let invite = db::Invite {
 				code: invite_code.clone(),
 				invitor_id: user_id,
 				invite_count: 0,
-				created_at: Utc::now(),
+				created_at: now,
+				expires_at: Some(now + Duration::hours(expires_in_hours)),
+				max_uses,
+				revoked: false,
 			};
 
-			match db::save_invite(&client, &invite).await {
-				Ok(_) => HttpResponse::Ok().json(InviteResponse { invite_code }),
+			match store.save_invite(&invite).await {
+				Ok(_) => HttpResponse::Ok().json(InviteResponse {
+					invite_code,
+					expires_at: invite.expires_at,
+					max_uses,
+				}),
 				Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
 			}
 		}
 		Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
 	}
 }
+
+// GET /users/{user_id}/invites
+//
+// The user's invites, newest first, with how much each has been used
+pub async fn get_user_invites(
+	store: web::Data<dyn Store>,
+	path: web::Path<String>,
+) -> HttpResponse {
+	let user_id = path.into_inner();
+
+	match store.get_user_invites(&user_id).await {
+		Ok(mut invites) => {
+			invites.sort_by_key(|invite| std::cmp::Reverse(invite.created_at));
+			HttpResponse::Ok().json(invites)
+		}
+		Err(err) => {
+			error!("Failed to fetch invites: {:?}", err);
+			HttpResponse::InternalServerError().body(err.to_string())
+		}
+	}
+}
+
+// DELETE /invites/{code}
+//
+// Revoke an invite so nobody else can register with it. Only its invitor may.
+pub async fn revoke_invite(
+	store: web::Data<dyn Store>,
+	user: AuthenticatedUser,
+	path: web::Path<String>,
+) -> HttpResponse {
+	let code = path.into_inner();
+
+	let invite = match store.get_invite(&code).await {
+		// Someone else's invite looks the same as a missing one
+		Ok(Some(invite)) if invite.invitor_id == user.user_id => invite,
+		Ok(_) => return HttpResponse::NotFound().body("Invite not found"),
+		Err(err) => {
+			error!("Failed to fetch invite: {:?}", err);
+			return HttpResponse::InternalServerError().body(err.to_string());
+		}
+	};
+
+	match store.revoke_invite(&invite.code).await {
+		Ok(_) => HttpResponse::Ok().json(db::Invite {
+			revoked: true,
+			..invite
+		}),
+		Err(err) => {
+			error!("Failed to revoke invite: {:?}", err);
+			HttpResponse::InternalServerError().body(err.to_string())
+		}
+	}
+}
+
+#[derive(Debug, Serialize)]
+pub struct HandleResponse {
+	handle: String,
+}
+
+// POST /users/{user_id}/handle
+//
+// Swap the user's handle for a new random one and free the old one
+pub async fn reroll_handle(store: web::Data<dyn Store>, user: AuthenticatedUser) -> HttpResponse {
+	let mut stored = match store.get_user(&user.user_id).await {
+		Ok(Some(stored)) => stored,
+		Ok(None) => return HttpResponse::NotFound().body("User not found"),
+		Err(err) => {
+			error!("Failed to fetch user: {:?}", err);
+			return HttpResponse::InternalServerError().body(err.to_string());
+		}
+	};
+
+	// Followers would no longer recognise the user on their map
+	if user.duress {
+		info!(
+			"Duress session tried to re-roll the handle of {}",
+			stored.id
+		);
+		return HttpResponse::Ok().json(HandleResponse {
+			handle: handle_db::generate(),
+		});
+	}
+
+	let result = async {
+		let Some(handle) = reserve_new_handle(store.get_ref(), &stored.id).await? else {
+			return Ok(None);
+		};
+		let old = stored.handle.replace(handle.clone());
+		if let Err(err) = store.save_user(&stored).await {
+			// Nobody holds the new handle after all
+			if let Err(err) = store.release_handle(&handle).await {
+				error!("Failed to release handle: {:?}", err);
+			}
+			return Err(err);
+		}
+		if let Some(old) = old {
+			store.release_handle(&old).await?;
+		}
+		Ok::<_, StoreError>(Some(handle))
+	}
+	.await;
+
+	match result {
+		Ok(Some(handle)) => HttpResponse::Ok().json(HandleResponse { handle }),
+		Ok(None) => HttpResponse::ServiceUnavailable().body("No free handle, try again"),
+		Err(err) => {
+			error!("Failed to re-roll handle: {:?}", err);
+			HttpResponse::InternalServerError().body(err.to_string())
+		}
+	}
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum AvatarFormat {
+	#[default]
+	Svg,
+	Png,
+}
+
+impl AvatarFormat {
+	fn extension(self) -> &'static str {
+		match self {
+			AvatarFormat::Svg => "svg",
+			AvatarFormat::Png => "png",
+		}
+	}
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AvatarQuery {
+	#[serde(default)]
+	format: AvatarFormat,
+}
+
+// GET /users/{user_id}/avatar
+//
+// Public, so other members' apps can show it. Derived from the handle rather
+// than the user ID, so a re-rolled handle also gets a new face.
+pub async fn get_avatar(
+	store: web::Data<dyn Store>,
+	path: web::Path<String>,
+	query: web::Query<AvatarQuery>,
+	req: HttpRequest,
+) -> HttpResponse {
+	let user_id = path.into_inner();
+	// Unknown IDs get an avatar of their own, so this does not tell anyone
+	// which IDs exist
+	let seed = match store.get_user(&user_id).await {
+		Ok(Some(user)) => user.handle.unwrap_or(user.id),
+		Ok(None) => user_id,
+		Err(err) => {
+			error!("Failed to fetch user: {:?}", err);
+			return HttpResponse::InternalServerError().body(err.to_string());
+		}
+	};
+
+	let identicon = Identicon::new(&seed);
+	let etag = format!("\"{}.{}\"", identicon.digest, query.format.extension());
+	let cache_control = format!("public, max-age={}", AVATAR_MAX_AGE_SECONDS);
+	let unchanged = req
+		.headers()
+		.get(header::IF_NONE_MATCH)
+		.and_then(|value| value.to_str().ok())
+		.is_some_and(|value| value.split(',').any(|tag| tag.trim() == etag));
+	if unchanged {
+		return HttpResponse::NotModified()
+			.insert_header((header::ETAG, etag))
+			.insert_header((header::CACHE_CONTROL, cache_control))
+			.finish();
+	}
+
+	let mut response = HttpResponse::Ok();
+	response
+		.insert_header((header::ETAG, etag))
+		.insert_header((header::CACHE_CONTROL, cache_control));
+	match query.format {
+		AvatarFormat::Svg => response.content_type("image/svg+xml").body(identicon.svg()),
+		AvatarFormat::Png => response.content_type("image/png").body(identicon.png()),
+	}
+}
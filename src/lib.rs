@@ -1 +1,130 @@
+use actix_web::middleware::from_fn;
+use actix_web::web;
 
+use handlers::{
+	register_user, login, logout, create_invite, get_user_invites, revoke_invite, reroll_handle,
+	get_avatar,
+};
+use checkin_handlers::{checkin, get_map_info};
+use push_handlers::{
+	get_vapid_public_key, add_push_subscription, get_push_subscriptions, delete_push_subscription,
+};
+use block_handlers::{block_user, get_blocks, unblock_user};
+use lineage_handlers::{get_invitees, get_lineage, admin_get_lineage, suspend_subtree, unsuspend_user};
+use follow_handlers::{
+	follow_user, unfollow_user, get_followers, get_following, delete_follower, get_follow_requests,
+	approve_follow_request, deny_follow_request,
+};
+use duress_handlers::{
+	trigger_duress, get_duress_history, get_following_duress_history, cancel_duress,
+	resolve_duress, enable_test_mode, get_test_mode, end_test_mode, get_preferences,
+	update_preferences,
+};
+
+pub mod auth;
+pub mod avatar;
+pub mod block_db;
+pub mod block_handlers;
+pub mod checkin_db;
+pub mod checkin_handlers;
+pub mod db;
+pub mod delivery_db;
+pub mod duress_db;
+pub mod duress_handlers;
+pub mod dynamo_store;
+pub mod follow_db;
+pub mod follow_handlers;
+pub mod handle_db;
+pub mod handlers;
+pub mod lineage_handlers;
+pub mod location_db;
+pub mod login_db;
+pub mod memory_store;
+pub mod notify;
+pub mod notify_backends;
+pub mod outbound;
+pub mod pin;
+pub mod push_db;
+pub mod push_handlers;
+pub mod queue;
+pub mod sqlite_store;
+pub mod store;
+pub mod webpush;
+
+// Register every API route. Callers provide the `web::Data<dyn Store>` and
+// `web::Data<Notifiers>` app data.
+pub fn configure(cfg: &mut web::ServiceConfig) {
+	cfg.route("/health", web::get().to(|| async { "System is Live" }))
+		.route("/register", web::post().to(register_user))
+		.route("/login", web::post().to(login))
+		.route("/logout", web::post().to(logout))
+		.route("/invite", web::post().to(create_invite))
+		.route("/invites/{code}", web::delete().to(revoke_invite))
+		.route(
+			"/push/vapid-public-key",
+			web::get().to(get_vapid_public_key),
+		)
+		// Outside the scope below, so anyone can fetch a member's avatar
+		.route("/users/{user_id}/avatar", web::get().to(get_avatar))
+		.service(
+			// Every route below requires a bearer token for `{user_id}`
+			web::scope("/users/{user_id}")
+				.wrap(from_fn(auth::require_path_user))
+				.route("/follow", web::post().to(follow_user))
+				.route("/unfollow", web::post().to(unfollow_user))
+				.route("/followers", web::get().to(get_followers))
+				.route("/following", web::get().to(get_following))
+				.route("/follow-requests", web::get().to(get_follow_requests))
+				.route(
+					"/follow-requests/{follower_id}/approve",
+					web::post().to(approve_follow_request),
+				)
+				.route(
+					"/follow-requests/{follower_id}/deny",
+					web::post().to(deny_follow_request),
+				)
+				.route(
+					"/followers/{follower_id}",
+					web::delete().to(delete_follower),
+				)
+				.route("/handle", web::post().to(reroll_handle))
+				.route("/invites", web::get().to(get_user_invites))
+				.route("/invitees", web::get().to(get_invitees))
+				.route("/lineage", web::get().to(get_lineage))
+				.route("/blocks", web::post().to(block_user))
+				.route("/blocks", web::get().to(get_blocks))
+				.route("/blocks/{blocked_id}", web::delete().to(unblock_user))
+				.route("/duress", web::post().to(trigger_duress))
+				.route("/duress", web::get().to(get_duress_history))
+				.route("/duress/cancel", web::post().to(cancel_duress))
+				.route("/duress/{event_id}/resolve", web::post().to(resolve_duress))
+				.route(
+					"/following/duress",
+					web::get().to(get_following_duress_history),
+				)
+				.route("/test-mode", web::post().to(enable_test_mode))
+				.route("/test-mode", web::get().to(get_test_mode))
+				.route("/test-mode", web::delete().to(end_test_mode))
+				.route("/checkin", web::post().to(checkin))
+				.route("/map", web::get().to(get_map_info))
+				.route("/preferences", web::get().to(get_preferences))
+				.route("/preferences", web::patch().to(update_preferences))
+				.route("/push-subscriptions", web::post().to(add_push_subscription))
+				.route("/push-subscriptions", web::get().to(get_push_subscriptions))
+				.route(
+					"/push-subscriptions/{subscription_id}",
+					web::delete().to(delete_push_subscription),
+				),
+		)
+		.service(
+			// Every route below requires a bearer token for an admin
+			web::scope("/admin")
+				.wrap(from_fn(auth::require_admin))
+				.route("/users/{user_id}/lineage", web::get().to(admin_get_lineage))
+				.route(
+					"/users/{user_id}/suspend-subtree",
+					web::post().to(suspend_subtree),
+				)
+				.route("/users/{user_id}/unsuspend", web::post().to(unsuspend_user)),
+		);
+}
@@ -0,0 +1,214 @@
+// lineage_handlers.rs
+use std::collections::{HashSet, VecDeque};
+
+use actix_web::{web, HttpResponse};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use tracing::{error, info};
+
+use crate::db::User;
+use crate::store::{Store, StoreError};
+
+// A member of someone's invite tree, as shown to them or an admin
+#[derive(Debug, Serialize)]
+pub struct LineageMember {
+	pub id: String,
+	pub handle: Option<String>,
+	pub suspended: bool,
+}
+
+impl From<&User> for LineageMember {
+	fn from(user: &User) -> Self {
+		LineageMember {
+			id: user.id.clone(),
+			handle: user.handle.clone(),
+			suspended: user.suspended_at.is_some(),
+		}
+	}
+}
+
+#[derive(Debug, Serialize)]
+pub struct Lineage {
+	pub user_id: String,
+	// Who invited the user, then who invited them, up to the first member
+	pub ancestors: Vec<LineageMember>,
+	// Users who registered with one of the user's invites
+	pub invitee_count: usize,
+	// Everyone the user vouched for, directly or through their invitees
+	pub subtree_size: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SuspendResponse {
+	// Users suspended by this request, the named user first
+	pub suspended: Vec<String>,
+	pub suspended_at: DateTime<Utc>,
+}
+
+// Users who registered with any of `user_id`'s invite codes
+async fn invitees(store: &dyn Store, user_id: &str) -> Result<Vec<User>, StoreError> {
+	let mut invitees = Vec::new();
+	for invite in store.get_user_invites(user_id).await? {
+		invitees.extend(store.get_users_by_invite(&invite.code).await?);
+	}
+	Ok(invitees)
+}
+
+// The chain of invitors above `user`, nearest first. It ends at an invitor
+// who is not a user, such as the seed invite's.
+async fn ancestors(store: &dyn Store, user: &User) -> Result<Vec<User>, StoreError> {
+	let mut seen = HashSet::from([user.id.clone()]);
+	let mut ancestors = Vec::new();
+	let mut invite_code = user.invite_code.clone();
+	while let Some(invite) = store.get_invite(&invite_code).await? {
+		// Guards against a cycle in hand-edited data
+		if !seen.insert(invite.invitor_id.clone()) {
+			break;
+		}
+		let Some(invitor) = store.get_user(&invite.invitor_id).await? else {
+			break;
+		};
+		invite_code = invitor.invite_code.clone();
+		ancestors.push(invitor);
+	}
+	Ok(ancestors)
+}
+
+// Everyone below `user_id` in the invite tree, breadth first
+async fn subtree(store: &dyn Store, user_id: &str) -> Result<Vec<User>, StoreError> {
+	let mut seen = HashSet::from([user_id.to_string()]);
+	let mut queue = VecDeque::from([user_id.to_string()]);
+	let mut members = Vec::new();
+	while let Some(id) = queue.pop_front() {
+		for invitee in invitees(store, &id).await? {
+			if seen.insert(invitee.id.clone()) {
+				queue.push_back(invitee.id.clone());
+				members.push(invitee);
+			}
+		}
+	}
+	Ok(members)
+}
+
+async fn lineage(store: &dyn Store, user: &User) -> Result<Lineage, StoreError> {
+	Ok(Lineage {
+		user_id: user.id.clone(),
+		ancestors: ancestors(store, user)
+			.await?
+			.iter()
+			.map(LineageMember::from)
+			.collect(),
+		invitee_count: invitees(store, &user.id).await?.len(),
+		subtree_size: subtree(store, &user.id).await?.len(),
+	})
+}
+
+async fn lineage_response(store: &dyn Store, user_id: &str) -> HttpResponse {
+	let result = async {
+		match store.get_user(user_id).await? {
+			Some(user) => Ok(Some(lineage(store, &user).await?)),
+			None => Ok::<_, StoreError>(None),
+		}
+	}
+	.await;
+
+	match result {
+		Ok(Some(lineage)) => HttpResponse::Ok().json(lineage),
+		Ok(None) => HttpResponse::NotFound().body("User not found"),
+		Err(err) => {
+			error!("Failed to fetch lineage: {:?}", err);
+			HttpResponse::InternalServerError().body(err.to_string())
+		}
+	}
+}
+
+// GET /users/{user_id}/invitees
+pub async fn get_invitees(store: web::Data<dyn Store>, path: web::Path<String>) -> HttpResponse {
+	let user_id = path.into_inner();
+
+	match invitees(store.get_ref(), &user_id).await {
+		Ok(invitees) => {
+			HttpResponse::Ok().json(invitees.iter().map(LineageMember::from).collect::<Vec<_>>())
+		}
+		Err(err) => {
+			error!("Failed to fetch invitees: {:?}", err);
+			HttpResponse::InternalServerError().body(err.to_string())
+		}
+	}
+}
+
+// GET /users/{user_id}/lineage
+pub async fn get_lineage(store: web::Data<dyn Store>, path: web::Path<String>) -> HttpResponse {
+	lineage_response(store.get_ref(), &path.into_inner()).await
+}
+
+// GET /admin/users/{user_id}/lineage
+pub async fn admin_get_lineage(
+	store: web::Data<dyn Store>,
+	path: web::Path<String>,
+) -> HttpResponse {
+	lineage_response(store.get_ref(), &path.into_inner()).await
+}
+
+// POST /admin/users/{user_id}/suspend-subtree
+//
+// Suspend a user and everyone they vouched for, directly or not, log them
+// out, and revoke their invites so the subtree cannot grow back. Users
+// already suspended keep their original suspension time.
+pub async fn suspend_subtree(store: web::Data<dyn Store>, path: web::Path<String>) -> HttpResponse {
+	let user_id = path.into_inner();
+	let now = Utc::now();
+
+	let result = async {
+		let Some(root) = store.get_user(&user_id).await? else {
+			return Ok(None);
+		};
+		let mut suspended = Vec::new();
+		for member in std::iter::once(root).chain(subtree(store.get_ref(), &user_id).await?) {
+			for invite in store.get_user_invites(&member.id).await? {
+				if !invite.revoked {
+					store.revoke_invite(&invite.code).await?;
+				}
+			}
+			if store.suspend_user(&member.id, now).await? {
+				suspended.push(member.id.clone());
+			}
+			// Also catches a login that raced an earlier suspension
+			store.delete_user_sessions(&member.id).await?;
+		}
+		Ok::<_, StoreError>(Some(suspended))
+	}
+	.await;
+
+	match result {
+		Ok(Some(suspended)) => {
+			info!("Suspended {} users under {}", suspended.len(), user_id);
+			HttpResponse::Ok().json(SuspendResponse {
+				suspended,
+				suspended_at: now,
+			})
+		}
+		Ok(None) => HttpResponse::NotFound().body("User not found"),
+		Err(err) => {
+			error!("Failed to suspend subtree: {:?}", err);
+			HttpResponse::InternalServerError().body(err.to_string())
+		}
+	}
+}
+
+// POST /admin/users/{user_id}/unsuspend
+//
+// Lift one user's suspension. Their invitees stay suspended and their
+// invites stay revoked.
+pub async fn unsuspend_user(store: web::Data<dyn Store>, path: web::Path<String>) -> HttpResponse {
+	let user_id = path.into_inner();
+
+	match store.unsuspend_user(&user_id).await {
+		Ok(true) => HttpResponse::Ok().body("Suspension lifted"),
+		Ok(false) => HttpResponse::NotFound().body("User not found"),
+		Err(err) => {
+			error!("Failed to lift suspension: {:?}", err);
+			HttpResponse::InternalServerError().body(err.to_string())
+		}
+	}
+}
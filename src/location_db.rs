@@ -0,0 +1,250 @@
+// location_db.rs
+use aws_sdk_dynamodb::types::AttributeValue;
+use aws_sdk_dynamodb::{Client, Error};
+use chrono::{DateTime, Duration, Utc};
+use geohash::Coord;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use tracing::info;
+
+use crate::duress_db::Location;
+
+// Geohash length of the index cells, about 39 by 20 km at the equator, so a
+// search over the largest proximity radius touches a few dozen cells
+pub const CELL_PRECISION: usize = 4;
+
+// Geohash length locations are rounded to before strangers see them, about
+// 150 m across
+pub const COARSE_PRECISION: usize = 7;
+
+const EARTH_RADIUS_M: f64 = 6_371_000.0;
+
+// Where a user last reported being, indexed by geohash cell so the users near
+// a point can be found without scanning everyone
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct UserLocation {
+	pub user_id: String,
+	pub location: Location,
+	pub cell: String,
+	pub updated_at: DateTime<Utc>,
+}
+
+impl UserLocation {
+	// The user's location as of now
+	pub fn new(user_id: &str, location: Location) -> Self {
+		UserLocation {
+			user_id: user_id.to_string(),
+			location,
+			cell: cell_of(&location, CELL_PRECISION),
+			updated_at: Utc::now(),
+		}
+	}
+
+	// Whether the location is recent enough to act on. Clients check in every
+	// `checkin_interval_minutes`, so anything older than two intervals is a
+	// user who has stopped reporting.
+	pub fn is_current(&self, checkin_interval_minutes: u32, now: DateTime<Utc>) -> bool {
+		now - self.updated_at <= Duration::minutes(2 * checkin_interval_minutes as i64)
+	}
+}
+
+fn coord(location: &Location) -> Coord<f64> {
+	Coord {
+		x: location.longitude,
+		y: location.latitude,
+	}
+}
+
+// Geohash cell of the given length containing `location`
+pub fn cell_of(location: &Location, precision: usize) -> String {
+	geohash::encode(coord(location), precision).expect("valid locations have a geohash")
+}
+
+// `location` moved to the centre of its geohash cell at `precision`
+pub fn coarsen(location: &Location, precision: usize) -> Location {
+	let (centre, lon_err, lat_err) =
+		geohash::decode(&cell_of(location, precision)).expect("encoded geohashes decode");
+	Location {
+		latitude: centre.y,
+		longitude: centre.x,
+		// Anywhere in the cell
+		accuracy: Some(distance_m(
+			&Location {
+				latitude: centre.y,
+				longitude: centre.x,
+				accuracy: None,
+			},
+			&Location {
+				latitude: centre.y + lat_err,
+				longitude: centre.x + lon_err,
+				accuracy: None,
+			},
+		)),
+	}
+}
+
+// Great-circle distance between two locations
+pub fn distance_m(a: &Location, b: &Location) -> f64 {
+	let (lat_a, lat_b) = (a.latitude.to_radians(), b.latitude.to_radians());
+	let d_lat = lat_b - lat_a;
+	let d_lon = (b.longitude - a.longitude).to_radians();
+	let h = (d_lat / 2.0).sin().powi(2) + lat_a.cos() * lat_b.cos() * (d_lon / 2.0).sin().powi(2);
+	2.0 * EARTH_RADIUS_M * h.sqrt().asin()
+}
+
+// Index cells that together cover every point within `radius_m` of `centre`
+pub fn cells_within(centre: &Location, radius_m: f64) -> Vec<String> {
+	let lat_delta = (radius_m / EARTH_RADIUS_M).to_degrees();
+	let min_lat = (centre.latitude - lat_delta).max(-90.0);
+	let max_lat = (centre.latitude + lat_delta).min(90.0);
+	// Near the poles a circle can span every longitude
+	let widest = min_lat.abs().max(max_lat.abs()).to_radians().cos();
+	let lon_delta = if widest > 0.0 {
+		(lat_delta / widest).min(180.0)
+	} else {
+		180.0
+	};
+
+	// Step by the cell size so every cell the box overlaps gets sampled
+	let (_, lon_err, lat_err) =
+		geohash::decode(&cell_of(centre, CELL_PRECISION)).expect("encoded geohashes decode");
+	let (cell_width, cell_height) = (2.0 * lon_err, 2.0 * lat_err);
+
+	let mut cells = Vec::new();
+	let mut lat = min_lat;
+	loop {
+		let mut lon = centre.longitude - lon_delta;
+		loop {
+			// Wrap across the antimeridian
+			let wrapped = (lon + 540.0).rem_euclid(360.0) - 180.0;
+			cells.push(cell_of(
+				&Location {
+					latitude: lat,
+					longitude: wrapped,
+					accuracy: None,
+				},
+				CELL_PRECISION,
+			));
+			if lon >= centre.longitude + lon_delta {
+				break;
+			}
+			lon = (lon + cell_width).min(centre.longitude + lon_delta);
+		}
+		if lat >= max_lat {
+			break;
+		}
+		lat = (lat + cell_height).min(max_lat);
+	}
+
+	cells.sort();
+	cells.dedup();
+	cells
+}
+
+fn user_location_from_item(item: &HashMap<String, AttributeValue>) -> UserLocation {
+	let string_attr = |name: &str| {
+		item.get(name)
+			.and_then(|v| v.as_s().ok())
+			.map(|s| s.to_string())
+			.unwrap_or_default()
+	};
+	let number_attr = |name: &str| {
+		item.get(name)
+			.and_then(|v| v.as_n().ok())
+			.and_then(|n| n.parse::<f64>().ok())
+	};
+
+	UserLocation {
+		user_id: string_attr("user_id"),
+		location: Location {
+			latitude: number_attr("latitude").unwrap_or_default(),
+			longitude: number_attr("longitude").unwrap_or_default(),
+			accuracy: number_attr("accuracy"),
+		},
+		cell: string_attr("cell"),
+		updated_at: DateTime::parse_from_rfc3339(&string_attr("updated_at"))
+			.map(|v| v.with_timezone(&Utc))
+			.unwrap_or_else(|_| Utc::now()),
+	}
+}
+
+// Write a user's location to the DynamoDB "UserLocation" table, keyed by user
+// with a "cell-index" global secondary index on the cell
+pub async fn put_user_location(client: &Client, location: &UserLocation) -> Result<(), Error> {
+	info!("Writing a user location in DynamoDB");
+
+	let mut request = client
+		.put_item()
+		.table_name("UserLocation")
+		.item("user_id", AttributeValue::S(location.user_id.clone()))
+		.item("cell", AttributeValue::S(location.cell.clone()))
+		.item(
+			"latitude",
+			AttributeValue::N(location.location.latitude.to_string()),
+		)
+		.item(
+			"longitude",
+			AttributeValue::N(location.location.longitude.to_string()),
+		)
+		.item(
+			"updated_at",
+			AttributeValue::S(location.updated_at.to_rfc3339()),
+		);
+	if let Some(accuracy) = location.location.accuracy {
+		request = request.item("accuracy", AttributeValue::N(accuracy.to_string()));
+	}
+
+	request.send().await?;
+	Ok(())
+}
+
+pub async fn get_user_location(
+	client: &Client,
+	user_id: &str,
+) -> Result<Option<UserLocation>, Error> {
+	let result = client
+		.get_item()
+		.table_name("UserLocation")
+		.key("user_id", AttributeValue::S(user_id.to_string()))
+		.send()
+		.await?;
+
+	Ok(result.item.as_ref().map(user_location_from_item))
+}
+
+// Every user whose location falls in one of `cells`
+pub async fn get_user_locations_in_cells(
+	client: &Client,
+	cells: &[String],
+) -> Result<Vec<UserLocation>, Error> {
+	let mut locations = Vec::new();
+
+	for cell in cells {
+		let mut start_key = None;
+		loop {
+			let result = client
+				.query()
+				.table_name("UserLocation")
+				.index_name("cell-index")
+				.key_condition_expression("cell = :cell")
+				.expression_attribute_values(":cell", AttributeValue::S(cell.clone()))
+				.set_exclusive_start_key(start_key)
+				.send()
+				.await?;
+
+			locations.extend(
+				result
+					.items
+					.unwrap_or_default()
+					.iter()
+					.map(user_location_from_item),
+			);
+			start_key = result.last_evaluated_key;
+			if start_key.is_none() {
+				break;
+			}
+		}
+	}
+
+	Ok(locations)
+}
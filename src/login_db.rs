@@ -0,0 +1,145 @@
+// login_db.rs
+use aws_sdk_dynamodb::types::{AttributeValue, ReturnValue};
+use aws_sdk_dynamodb::{Client, Error};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::duress_db::sort_key_time;
+
+// Wrong PINs allowed per user in a window before logins are refused
+pub const MAX_LOGIN_FAILURES: u32 = 5;
+
+// How long a window of failed logins lasts, counted from the first failure
+pub const LOGIN_WINDOW_MINUTES: i64 = 15;
+
+// A user's recent failed logins. PINs are short, so without a limit anyone who
+// knows a user ID could guess them.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct LoginAttempts {
+	pub user_id: String,
+	pub failures: u32,
+	pub window_start: DateTime<Utc>,
+}
+
+impl LoginAttempts {
+	pub fn window_end(&self) -> DateTime<Utc> {
+		self.window_start + Duration::minutes(LOGIN_WINDOW_MINUTES)
+	}
+
+	// Whether logins are refused at `now`
+	pub fn locked(&self, now: DateTime<Utc>) -> bool {
+		self.failures >= MAX_LOGIN_FAILURES && now < self.window_end()
+	}
+}
+
+fn login_attempts_from_item(item: &HashMap<String, AttributeValue>) -> LoginAttempts {
+	LoginAttempts {
+		user_id: item
+			.get("user_id")
+			.and_then(|v| v.as_s().ok())
+			.cloned()
+			.unwrap_or_default(),
+		failures: item
+			.get("failures")
+			.and_then(|v| v.as_n().ok())
+			.and_then(|n| n.parse().ok())
+			.unwrap_or(0),
+		window_start: item
+			.get("window_start")
+			.and_then(|v| v.as_s().ok())
+			.and_then(|v| DateTime::parse_from_rfc3339(v).ok())
+			.map(|v| v.with_timezone(&Utc))
+			.unwrap_or_else(Utc::now),
+	}
+}
+
+pub async fn get_login_attempts(
+	client: &Client,
+	user_id: &str,
+) -> Result<Option<LoginAttempts>, Error> {
+	let result = client
+		.get_item()
+		.table_name("LoginAttempts")
+		.key("user_id", AttributeValue::S(user_id.to_string()))
+		.send()
+		.await?;
+
+	Ok(result.item.as_ref().map(login_attempts_from_item))
+}
+
+// Count a failed login in the DynamoDB "LoginAttempts" table, keyed by user.
+// The count is added to atomically while the window is open; once it has
+// ended a new window starts at `now`. Returns the updated attempts.
+pub async fn record_login_failure(
+	client: &Client,
+	user_id: &str,
+	now: DateTime<Utc>,
+) -> Result<LoginAttempts, Error> {
+	let cutoff = now - Duration::minutes(LOGIN_WINDOW_MINUTES);
+	let result = client
+		.update_item()
+		.table_name("LoginAttempts")
+		.key("user_id", AttributeValue::S(user_id.to_string()))
+		.update_expression("ADD failures :one")
+		.condition_expression("window_start > :cutoff")
+		.expression_attribute_values(":one", AttributeValue::N("1".to_string()))
+		.expression_attribute_values(":cutoff", AttributeValue::S(sort_key_time(cutoff)))
+		.return_values(ReturnValue::AllNew)
+		.send()
+		.await;
+
+	match result {
+		Ok(output) => Ok(output
+			.attributes
+			.as_ref()
+			.map(login_attempts_from_item)
+			.unwrap_or_else(|| LoginAttempts {
+				user_id: user_id.to_string(),
+				failures: 1,
+				window_start: now,
+			})),
+		// No open window
+		Err(err)
+			if err
+				.as_service_error()
+				.is_some_and(|err| err.is_conditional_check_failed_exception()) =>
+		{
+			let attempts = LoginAttempts {
+				user_id: user_id.to_string(),
+				failures: 1,
+				window_start: now,
+			};
+			client
+				.put_item()
+				.table_name("LoginAttempts")
+				.item("user_id", AttributeValue::S(attempts.user_id.clone()))
+				.item("failures", AttributeValue::N("1".to_string()))
+				// Fixed width, so the condition above can compare it as a string
+				.item(
+					"window_start",
+					AttributeValue::S(sort_key_time(attempts.window_start)),
+				)
+				// DynamoDB TTL attribute so old windows are purged automatically
+				.item(
+					"ttl",
+					AttributeValue::N(attempts.window_end().timestamp().to_string()),
+				)
+				.send()
+				.await?;
+			Ok(attempts)
+		}
+		Err(err) => Err(err.into()),
+	}
+}
+
+pub async fn delete_login_attempts(client: &Client, user_id: &str) -> Result<(), Error> {
+	client
+		.delete_item()
+		.table_name("LoginAttempts")
+		.key("user_id", AttributeValue::S(user_id.to_string()))
+		.send()
+		.await?;
+
+	Ok(())
+}
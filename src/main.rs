@@ -1,37 +1,74 @@
 use actix_web::{web, App, HttpServer};
-use handlers::{register_user, create_invite};
-use follow_handlers::{follow_user, unfollow_user, get_followers, delete_follower};
-use duress_handlers::{trigger_duress, cancel_duress, enable_test_mode, get_map_info, get_preferences, update_preferences};
-
-mod handlers;
-mod db;
-mod follow_handlers;
-mod duress_db;
-mod duress_handlers;
-mod follow_db;
+use cherubgyre::{configure, db, follow_db, notify, queue, store};
 
 #[actix_web::main]
 async fn main() -> Result<(), std::io::Error> {
-    HttpServer::new(|| {
-        App::new()
-            .route("/health", web::get().to(|| async { "System is Live" }))
-            .route("/register", web::post().to(register_user))
-            .route("/invite", web::post().to(create_invite))
-            .service(
-                web::scope("/users")
-                    .route("/{user_id}/follow", web::post().to(follow_user))
-                    .route("/{user_id}/unfollow", web::post().to(unfollow_user))
-                    .route("/{user_id}/followers", web::get().to(get_followers))
-                    .route("/{user_id}/followers/{follower_id}", web::delete().to(delete_follower))
-                    .route("/{user_id}/duress", web::post().to(trigger_duress))
-                    .route("/{user_id}/duress/cancel", web::post().to(cancel_duress))
-                    .route("/{user_id}/test-mode", web::post().to(enable_test_mode))
-                    .route("/{user_id}/map", web::get().to(get_map_info))
-                    .route("/{user_id}/preferences", web::get().to(get_preferences))
-                    .route("/{user_id}/preferences", web::patch().to(update_preferences)),
-            )
-    })
-    .bind("127.0.0.1:8080")?
-    .run()
-    .await
+	dotenv::dotenv().ok();
+
+	// Make a user an admin, run as `cherubgyre grant-admin <user_id>`
+	if std::env::args().nth(1).as_deref() == Some("grant-admin") {
+		return grant_admin(std::env::args().nth(2)).await;
+	}
+
+	// One-off DynamoDB data migrations, run as `cherubgyre <migration>`
+	if let Some(migration) = std::env::args().nth(1) {
+		return run_migration(&migration).await;
+	}
+
+	let store = web::Data::from(store::from_env().await);
+	let notifiers = web::Data::new(notify::from_env(store.clone().into_inner()));
+
+	// Alerts are queued by the handlers and sent from here
+	actix_web::rt::spawn(queue::run_worker(store.clone(), notifiers.clone()));
+
+	HttpServer::new(move || {
+		App::new()
+			.app_data(store.clone())
+			.app_data(notifiers.clone())
+			.configure(configure)
+	})
+	.bind("127.0.0.1:8080")?
+	.run()
+	.await
+}
+
+async fn run_migration(migration: &str) -> Result<(), std::io::Error> {
+	let client = db::get_dynamodb_client().await;
+	let result = match migration {
+		"migrate-follows" => follow_db::migrate_legacy_follows(&client).await,
+		other => {
+			return Err(std::io::Error::new(
+				std::io::ErrorKind::InvalidInput,
+				format!("Unknown migration: {}", other),
+			))
+		}
+	};
+	result
+		.map(|_| ())
+		.map_err(|err| std::io::Error::other(err.to_string()))
+}
+
+async fn grant_admin(user_id: Option<String>) -> Result<(), std::io::Error> {
+	let user_id = user_id.ok_or_else(|| {
+		std::io::Error::new(
+			std::io::ErrorKind::InvalidInput,
+			"Usage: cherubgyre grant-admin <user_id>",
+		)
+	})?;
+	let store = store::from_env().await;
+	let mut user = store
+		.get_user(&user_id)
+		.await
+		.map_err(|err| std::io::Error::other(err.to_string()))?
+		.ok_or_else(|| {
+			std::io::Error::new(
+				std::io::ErrorKind::NotFound,
+				format!("Unknown user: {}", user_id),
+			)
+		})?;
+	user.admin = true;
+	store
+		.save_user(&user)
+		.await
+		.map_err(|err| std::io::Error::other(err.to_string()))
 }
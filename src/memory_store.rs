@@ -0,0 +1,634 @@
+// memory_store.rs
+use std::collections::HashMap;
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use tokio::sync::Mutex;
+
+use crate::block_db::Block;
+use crate::checkin_db::Checkin;
+use crate::db::{Invite, Session, User};
+use crate::delivery_db::{Delivery, Job};
+use crate::duress_db::{DuressEvent, TestMode, UserPreferences};
+use crate::follow_db::{Follow, FollowCursor, FollowPage, FollowRequest};
+use crate::location_db::UserLocation;
+use crate::login_db::LoginAttempts;
+use crate::push_db::PushSubscription;
+use crate::store::{Store, StoreError};
+
+#[derive(Default)]
+struct MemoryData {
+	users: HashMap<String, User>,
+	sessions: HashMap<String, Session>,
+	// Reserved handle to the user holding it
+	handles: HashMap<String, String>,
+	invites: HashMap<String, Invite>,
+	follows: Vec<Follow>,
+	// Keyed by (follower_id, followed_id)
+	follow_requests: HashMap<(String, String), FollowRequest>,
+	// Keyed by (blocker_id, blocked_id)
+	blocks: HashMap<(String, String), Block>,
+	login_attempts: HashMap<String, LoginAttempts>,
+	duress_events: Vec<DuressEvent>,
+	checkins: Vec<Checkin>,
+	locations: HashMap<String, UserLocation>,
+	push_subscriptions: Vec<PushSubscription>,
+	deliveries: Vec<Delivery>,
+	jobs: HashMap<String, Job>,
+	test_modes: HashMap<String, TestMode>,
+	preferences: HashMap<String, UserPreferences>,
+}
+
+// In-process store for local development and tests. Nothing survives a restart.
+#[derive(Default)]
+pub struct MemoryStore {
+	data: Mutex<MemoryData>,
+}
+
+impl MemoryStore {
+	pub fn new() -> Self {
+		Self::default()
+	}
+}
+
+// Up to `limit` of `follows` after `after`, ordered by `key`
+fn follow_page(
+	mut follows: Vec<Follow>,
+	key: fn(&Follow) -> &str,
+	after: Option<&FollowCursor>,
+	limit: usize,
+) -> FollowPage {
+	follows.sort_by(|a, b| key(a).cmp(key(b)));
+	let after = after.map(|cursor| {
+		key(&Follow {
+			follower_id: cursor.follower_id.clone(),
+			followed_id: cursor.followed_id.clone(),
+		})
+		.to_string()
+	});
+	let mut page: Vec<Follow> = follows
+		.into_iter()
+		.filter(|f| after.as_deref().is_none_or(|after| key(f) > after))
+		.take(limit + 1)
+		.collect();
+	let more = page.len() > limit;
+	page.truncate(limit);
+	FollowPage {
+		next: page.last().filter(|_| more).map(FollowCursor::after),
+		follows: page,
+	}
+}
+
+#[async_trait]
+impl Store for MemoryStore {
+	async fn save_user(&self, user: &User) -> Result<(), StoreError> {
+		let mut data = self.data.lock().await;
+		data.users.insert(user.id.clone(), user.clone());
+		Ok(())
+	}
+
+	async fn get_user(&self, user_id: &str) -> Result<Option<User>, StoreError> {
+		let data = self.data.lock().await;
+		Ok(data.users.get(user_id).cloned())
+	}
+
+	async fn get_users_by_invite(&self, code: &str) -> Result<Vec<User>, StoreError> {
+		let data = self.data.lock().await;
+		let mut users: Vec<User> = data
+			.users
+			.values()
+			.filter(|user| user.invite_code == code)
+			.cloned()
+			.collect();
+		users.sort_by(|a, b| a.id.cmp(&b.id));
+		Ok(users)
+	}
+
+	async fn suspend_user(
+		&self,
+		user_id: &str,
+		suspended_at: DateTime<Utc>,
+	) -> Result<bool, StoreError> {
+		let mut data = self.data.lock().await;
+		match data.users.get_mut(user_id) {
+			Some(user) if user.suspended_at.is_none() => {
+				user.suspended_at = Some(suspended_at);
+				Ok(true)
+			}
+			_ => Ok(false),
+		}
+	}
+
+	async fn unsuspend_user(&self, user_id: &str) -> Result<bool, StoreError> {
+		let mut data = self.data.lock().await;
+		match data.users.get_mut(user_id) {
+			Some(user) => {
+				user.suspended_at = None;
+				Ok(true)
+			}
+			None => Ok(false),
+		}
+	}
+
+	async fn reserve_handle(&self, handle: &str, user_id: &str) -> Result<bool, StoreError> {
+		let mut data = self.data.lock().await;
+		if data.handles.contains_key(handle) {
+			return Ok(false);
+		}
+		data.handles.insert(handle.to_string(), user_id.to_string());
+		Ok(true)
+	}
+
+	async fn release_handle(&self, handle: &str) -> Result<(), StoreError> {
+		let mut data = self.data.lock().await;
+		data.handles.remove(handle);
+		Ok(())
+	}
+
+	async fn save_session(&self, session: &Session) -> Result<(), StoreError> {
+		let mut data = self.data.lock().await;
+		data.sessions.insert(session.id.clone(), session.clone());
+		Ok(())
+	}
+
+	async fn get_session(&self, session_id: &str) -> Result<Option<Session>, StoreError> {
+		let data = self.data.lock().await;
+		Ok(data.sessions.get(session_id).cloned())
+	}
+
+	async fn delete_session(&self, session_id: &str) -> Result<(), StoreError> {
+		let mut data = self.data.lock().await;
+		data.sessions.remove(session_id);
+		Ok(())
+	}
+
+	async fn delete_user_sessions(&self, user_id: &str) -> Result<(), StoreError> {
+		let mut data = self.data.lock().await;
+		data.sessions
+			.retain(|_, session| session.user_id != user_id);
+		Ok(())
+	}
+
+	async fn save_invite(&self, invite: &Invite) -> Result<(), StoreError> {
+		let mut data = self.data.lock().await;
+		data.invites.insert(invite.code.clone(), invite.clone());
+		Ok(())
+	}
+
+	async fn get_invite(&self, code: &str) -> Result<Option<Invite>, StoreError> {
+		let data = self.data.lock().await;
+		Ok(data.invites.get(code).cloned())
+	}
+
+	async fn get_user_invites(&self, user_id: &str) -> Result<Vec<Invite>, StoreError> {
+		let data = self.data.lock().await;
+		Ok(data
+			.invites
+			.values()
+			.filter(|invite| invite.invitor_id == user_id)
+			.cloned()
+			.collect())
+	}
+
+	async fn update_invite(&self, invite: &Invite) -> Result<(), StoreError> {
+		let mut data = self.data.lock().await;
+		if let Some(stored) = data.invites.get_mut(&invite.code) {
+			*stored = Invite {
+				invite_count: stored.invite_count,
+				..invite.clone()
+			};
+		}
+		Ok(())
+	}
+
+	async fn revoke_invite(&self, code: &str) -> Result<(), StoreError> {
+		let mut data = self.data.lock().await;
+		if let Some(invite) = data.invites.get_mut(code) {
+			invite.revoked = true;
+		}
+		Ok(())
+	}
+
+	async fn create_invited_user(
+		&self,
+		user: &User,
+		now: DateTime<Utc>,
+	) -> Result<bool, StoreError> {
+		let mut data = self.data.lock().await;
+		match data.invites.get_mut(&user.invite_code) {
+			Some(invite) if invite.check(now).is_ok() => {
+				invite.invite_count += 1;
+				data.users.insert(user.id.clone(), user.clone());
+				Ok(true)
+			}
+			_ => Ok(false),
+		}
+	}
+
+	async fn add_follow(&self, follower_id: &str, followed_id: &str) -> Result<(), StoreError> {
+		let mut data = self.data.lock().await;
+		let exists = data
+			.follows
+			.iter()
+			.any(|f| f.follower_id == follower_id && f.followed_id == followed_id);
+		if !exists {
+			data.follows.push(Follow {
+				follower_id: follower_id.to_string(),
+				followed_id: followed_id.to_string(),
+			});
+		}
+		Ok(())
+	}
+
+	async fn remove_follow(&self, follower_id: &str, followed_id: &str) -> Result<(), StoreError> {
+		let mut data = self.data.lock().await;
+		data.follows
+			.retain(|f| !(f.follower_id == follower_id && f.followed_id == followed_id));
+		Ok(())
+	}
+
+	async fn get_follows(&self, followed_id: &str) -> Result<Vec<Follow>, StoreError> {
+		let data = self.data.lock().await;
+		Ok(data
+			.follows
+			.iter()
+			.filter(|f| f.followed_id == followed_id)
+			.cloned()
+			.collect())
+	}
+
+	async fn get_following(&self, follower_id: &str) -> Result<Vec<Follow>, StoreError> {
+		let data = self.data.lock().await;
+		Ok(data
+			.follows
+			.iter()
+			.filter(|f| f.follower_id == follower_id)
+			.cloned()
+			.collect())
+	}
+
+	async fn get_followers_page(
+		&self,
+		followed_id: &str,
+		after: Option<&FollowCursor>,
+		limit: usize,
+	) -> Result<FollowPage, StoreError> {
+		let follows = self.get_follows(followed_id).await?;
+		Ok(follow_page(follows, |f| &f.follower_id, after, limit))
+	}
+
+	async fn get_following_page(
+		&self,
+		follower_id: &str,
+		after: Option<&FollowCursor>,
+		limit: usize,
+	) -> Result<FollowPage, StoreError> {
+		let follows = self.get_following(follower_id).await?;
+		Ok(follow_page(follows, |f| &f.followed_id, after, limit))
+	}
+
+	async fn count_followers(&self, followed_id: &str) -> Result<usize, StoreError> {
+		Ok(self.get_follows(followed_id).await?.len())
+	}
+
+	async fn count_following(&self, follower_id: &str) -> Result<usize, StoreError> {
+		Ok(self.get_following(follower_id).await?.len())
+	}
+
+	async fn save_follow_request(&self, request: &FollowRequest) -> Result<(), StoreError> {
+		let mut data = self.data.lock().await;
+		data.follow_requests.insert(
+			(request.follower_id.clone(), request.followed_id.clone()),
+			request.clone(),
+		);
+		Ok(())
+	}
+
+	async fn get_follow_request(
+		&self,
+		follower_id: &str,
+		followed_id: &str,
+	) -> Result<Option<FollowRequest>, StoreError> {
+		let data = self.data.lock().await;
+		Ok(data
+			.follow_requests
+			.get(&(follower_id.to_string(), followed_id.to_string()))
+			.cloned())
+	}
+
+	async fn get_follow_requests(
+		&self,
+		followed_id: &str,
+	) -> Result<Vec<FollowRequest>, StoreError> {
+		let data = self.data.lock().await;
+		let mut requests: Vec<_> = data
+			.follow_requests
+			.values()
+			.filter(|request| request.followed_id == followed_id)
+			.cloned()
+			.collect();
+		requests.sort_by_key(|request| request.requested_at);
+		Ok(requests)
+	}
+
+	async fn delete_follow_request(
+		&self,
+		follower_id: &str,
+		followed_id: &str,
+	) -> Result<(), StoreError> {
+		let mut data = self.data.lock().await;
+		data.follow_requests
+			.remove(&(follower_id.to_string(), followed_id.to_string()));
+		Ok(())
+	}
+
+	async fn save_block(&self, block: &Block) -> Result<(), StoreError> {
+		let mut data = self.data.lock().await;
+		data.blocks.insert(
+			(block.blocker_id.clone(), block.blocked_id.clone()),
+			block.clone(),
+		);
+		Ok(())
+	}
+
+	async fn get_block(
+		&self,
+		blocker_id: &str,
+		blocked_id: &str,
+	) -> Result<Option<Block>, StoreError> {
+		let data = self.data.lock().await;
+		Ok(data
+			.blocks
+			.get(&(blocker_id.to_string(), blocked_id.to_string()))
+			.cloned())
+	}
+
+	async fn get_blocks(&self, blocker_id: &str) -> Result<Vec<Block>, StoreError> {
+		let data = self.data.lock().await;
+		let mut blocks: Vec<_> = data
+			.blocks
+			.values()
+			.filter(|block| block.blocker_id == blocker_id)
+			.cloned()
+			.collect();
+		blocks.sort_by_key(|block| block.created_at);
+		Ok(blocks)
+	}
+
+	async fn delete_block(&self, blocker_id: &str, blocked_id: &str) -> Result<(), StoreError> {
+		let mut data = self.data.lock().await;
+		data.blocks
+			.remove(&(blocker_id.to_string(), blocked_id.to_string()));
+		Ok(())
+	}
+
+	async fn get_login_attempts(&self, user_id: &str) -> Result<Option<LoginAttempts>, StoreError> {
+		let data = self.data.lock().await;
+		Ok(data.login_attempts.get(user_id).cloned())
+	}
+
+	async fn record_login_failure(
+		&self,
+		user_id: &str,
+		now: DateTime<Utc>,
+	) -> Result<LoginAttempts, StoreError> {
+		let mut data = self.data.lock().await;
+		let attempts = data
+			.login_attempts
+			.entry(user_id.to_string())
+			.or_insert_with(|| LoginAttempts {
+				user_id: user_id.to_string(),
+				failures: 0,
+				window_start: now,
+			});
+		if attempts.window_end() <= now {
+			attempts.failures = 0;
+			attempts.window_start = now;
+		}
+		attempts.failures += 1;
+		Ok(attempts.clone())
+	}
+
+	async fn clear_login_attempts(&self, user_id: &str) -> Result<(), StoreError> {
+		let mut data = self.data.lock().await;
+		data.login_attempts.remove(user_id);
+		Ok(())
+	}
+
+	async fn log_duress_event(&self, event: &DuressEvent) -> Result<(), StoreError> {
+		let mut data = self.data.lock().await;
+		data.duress_events.push(event.clone());
+		Ok(())
+	}
+
+	async fn update_duress_event(&self, event: &DuressEvent) -> Result<(), StoreError> {
+		let mut data = self.data.lock().await;
+		if let Some(existing) = data.duress_events.iter_mut().find(|e| e.id == event.id) {
+			*existing = event.clone();
+		}
+		Ok(())
+	}
+
+	async fn get_user_duress_events(
+		&self,
+		user_id: &str,
+		from: Option<DateTime<Utc>>,
+		to: Option<DateTime<Utc>>,
+	) -> Result<Vec<DuressEvent>, StoreError> {
+		let data = self.data.lock().await;
+		Ok(data
+			.duress_events
+			.iter()
+			.filter(|e| e.user_id == user_id)
+			.filter(|e| from.is_none_or(|from| e.created_at >= from))
+			.filter(|e| to.is_none_or(|to| e.created_at <= to))
+			.cloned()
+			.collect())
+	}
+
+	async fn save_delivery(&self, delivery: &Delivery) -> Result<(), StoreError> {
+		let mut data = self.data.lock().await;
+		match data.deliveries.iter_mut().find(|d| d.id == delivery.id) {
+			Some(existing) => *existing = delivery.clone(),
+			None => data.deliveries.push(delivery.clone()),
+		}
+		Ok(())
+	}
+
+	async fn get_event_deliveries(&self, event_id: &str) -> Result<Vec<Delivery>, StoreError> {
+		let data = self.data.lock().await;
+		Ok(data
+			.deliveries
+			.iter()
+			.filter(|d| d.event_id == event_id)
+			.cloned()
+			.collect())
+	}
+
+	async fn enqueue_job(&self, job: &Job) -> Result<bool, StoreError> {
+		let mut data = self.data.lock().await;
+		if data.jobs.contains_key(&job.key) {
+			return Ok(false);
+		}
+		data.jobs.insert(job.key.clone(), job.clone());
+		Ok(true)
+	}
+
+	async fn get_due_jobs(&self, now: DateTime<Utc>, limit: usize) -> Result<Vec<Job>, StoreError> {
+		let data = self.data.lock().await;
+		let mut jobs: Vec<Job> = data
+			.jobs
+			.values()
+			.filter(|job| !job.dead && job.next_attempt_at <= now)
+			.cloned()
+			.collect();
+		jobs.sort_by_key(|job| job.next_attempt_at);
+		jobs.truncate(limit);
+		Ok(jobs)
+	}
+
+	async fn claim_job(
+		&self,
+		key: &str,
+		expected: DateTime<Utc>,
+		until: DateTime<Utc>,
+	) -> Result<bool, StoreError> {
+		let mut data = self.data.lock().await;
+		match data.jobs.get_mut(key) {
+			Some(job) if job.next_attempt_at == expected => {
+				job.next_attempt_at = until;
+				Ok(true)
+			}
+			_ => Ok(false),
+		}
+	}
+
+	async fn update_job(&self, job: &Job) -> Result<(), StoreError> {
+		let mut data = self.data.lock().await;
+		data.jobs.insert(job.key.clone(), job.clone());
+		Ok(())
+	}
+
+	async fn delete_job(&self, key: &str) -> Result<(), StoreError> {
+		let mut data = self.data.lock().await;
+		data.jobs.remove(key);
+		Ok(())
+	}
+
+	async fn get_dead_jobs(&self, limit: usize) -> Result<Vec<Job>, StoreError> {
+		let data = self.data.lock().await;
+		let mut jobs: Vec<Job> = data.jobs.values().filter(|job| job.dead).cloned().collect();
+		jobs.sort_by_key(|job| job.created_at);
+		jobs.truncate(limit);
+		Ok(jobs)
+	}
+
+	async fn save_push_subscription(
+		&self,
+		subscription: &PushSubscription,
+	) -> Result<(), StoreError> {
+		let mut data = self.data.lock().await;
+		data.push_subscriptions
+			.retain(|s| !(s.user_id == subscription.user_id && s.id == subscription.id));
+		data.push_subscriptions.push(subscription.clone());
+		Ok(())
+	}
+
+	async fn get_push_subscriptions(
+		&self,
+		user_id: &str,
+	) -> Result<Vec<PushSubscription>, StoreError> {
+		let data = self.data.lock().await;
+		Ok(data
+			.push_subscriptions
+			.iter()
+			.filter(|s| s.user_id == user_id)
+			.cloned()
+			.collect())
+	}
+
+	async fn delete_push_subscription(&self, user_id: &str, id: &str) -> Result<(), StoreError> {
+		let mut data = self.data.lock().await;
+		data.push_subscriptions
+			.retain(|s| !(s.user_id == user_id && s.id == id));
+		Ok(())
+	}
+
+	async fn save_checkin(&self, checkin: &Checkin) -> Result<(), StoreError> {
+		let mut data = self.data.lock().await;
+		data.checkins.push(checkin.clone());
+		Ok(())
+	}
+
+	async fn get_last_checkin(&self, user_id: &str) -> Result<Option<Checkin>, StoreError> {
+		let data = self.data.lock().await;
+		Ok(data
+			.checkins
+			.iter()
+			.filter(|c| c.user_id == user_id)
+			.max_by_key(|c| c.created_at)
+			.cloned())
+	}
+
+	async fn save_user_location(&self, location: &UserLocation) -> Result<(), StoreError> {
+		let mut data = self.data.lock().await;
+		data.locations
+			.insert(location.user_id.clone(), location.clone());
+		Ok(())
+	}
+
+	async fn get_user_location(&self, user_id: &str) -> Result<Option<UserLocation>, StoreError> {
+		let data = self.data.lock().await;
+		Ok(data.locations.get(user_id).cloned())
+	}
+
+	async fn get_user_locations_in_cells(
+		&self,
+		cells: &[String],
+	) -> Result<Vec<UserLocation>, StoreError> {
+		let data = self.data.lock().await;
+		Ok(data
+			.locations
+			.values()
+			.filter(|l| cells.contains(&l.cell))
+			.cloned()
+			.collect())
+	}
+
+	async fn save_test_mode(&self, test_mode: &TestMode) -> Result<(), StoreError> {
+		let mut data = self.data.lock().await;
+		data.test_modes
+			.insert(test_mode.user_id.clone(), test_mode.clone());
+		Ok(())
+	}
+
+	async fn get_test_mode(&self, user_id: &str) -> Result<Option<TestMode>, StoreError> {
+		let data = self.data.lock().await;
+		Ok(data.test_modes.get(user_id).cloned())
+	}
+
+	async fn delete_test_mode(&self, user_id: &str) -> Result<(), StoreError> {
+		let mut data = self.data.lock().await;
+		data.test_modes.remove(user_id);
+		Ok(())
+	}
+
+	async fn get_user_preferences(
+		&self,
+		user_id: &str,
+	) -> Result<Option<UserPreferences>, StoreError> {
+		let data = self.data.lock().await;
+		Ok(data.preferences.get(user_id).cloned())
+	}
+
+	async fn update_user_preferences(
+		&self,
+		user_id: &str,
+		preferences: &UserPreferences,
+	) -> Result<(), StoreError> {
+		let mut data = self.data.lock().await;
+		data.preferences
+			.insert(user_id.to_string(), preferences.clone());
+		Ok(())
+	}
+}
@@ -0,0 +1,424 @@
+// notify.rs
+use std::fmt;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::delivery_db::{AlertKind, Delivery, DeliveryStatus, Job};
+use crate::duress_db::{
+	DuressEvent, DuressStatus, DuressType, Location, TestAudience, UserPreferences,
+	MAX_PROXIMITY_RADIUS_M,
+};
+use crate::location_db::{cells_within, coarsen, distance_m, COARSE_PRECISION};
+use crate::notify_backends::{EmailNotifier, PushNotifier, SinkNotifier, WebhookNotifier};
+use crate::push_db::PushSubscription;
+use crate::store::{Store, StoreError};
+use crate::webpush;
+
+// What recipients are told about a duress event
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Alert {
+	pub kind: AlertKind,
+	pub event_id: String,
+	// The user in duress. Withheld from nearby strangers.
+	pub user_id: Option<String>,
+	pub duress_type: DuressType,
+	pub status: DuressStatus,
+	pub message: String,
+	pub location: Option<Location>,
+	// Raised in test mode; clients must present it as a test
+	pub test: bool,
+	// Sent to someone near the user rather than someone who knows them
+	#[serde(default)]
+	pub nearby: bool,
+	pub created_at: DateTime<Utc>,
+}
+
+impl Alert {
+	pub fn new(event: &DuressEvent, kind: AlertKind) -> Self {
+		Alert {
+			kind,
+			event_id: event.id.clone(),
+			user_id: Some(event.user_id.clone()),
+			duress_type: event.duress_type,
+			status: event.status,
+			message: event.message.clone(),
+			location: event.location,
+			test: event.test,
+			nearby: false,
+			created_at: event.created_at,
+		}
+	}
+
+	// The alert as told to a stranger nearby: who raised it and what they said
+	// are withheld, and `location` is only given to the nearest cell
+	pub fn for_nearby(&self, location: Option<Location>) -> Self {
+		Alert {
+			user_id: None,
+			message: String::new(),
+			location: location.map(|location| coarsen(&location, COARSE_PRECISION)),
+			nearby: true,
+			..self.clone()
+		}
+	}
+
+	// One-line summary for channels with a subject or title
+	pub fn subject(&self) -> String {
+		let prefix = if self.test { "[TEST] " } else { "" };
+		match self.kind {
+			AlertKind::Raised => format!("{}Duress alert: {}", prefix, self.duress_type.as_str()),
+			AlertKind::Ended => format!("{}Duress alert {}", prefix, self.status.as_str()),
+		}
+	}
+
+	// Plain text body for channels without structure
+	pub fn text(&self) -> String {
+		let mut text = format!("{}\n\n", self.subject());
+		match &self.user_id {
+			Some(user_id) => text.push_str(&format!("User: {}\n", user_id)),
+			None => text.push_str("Someone near you needs help\n"),
+		}
+		if !self.message.is_empty() {
+			text.push_str(&format!("Message: {}\n", self.message));
+		}
+		if let Some(location) = &self.location {
+			text.push_str(&format!(
+				"Location: {}, {}\n",
+				location.latitude, location.longitude
+			));
+		}
+		text.push_str(&format!("Raised at: {}\n", self.created_at.to_rfc3339()));
+		text
+	}
+}
+
+// Someone an alert is being delivered to
+#[derive(Debug, Clone)]
+pub struct Recipient {
+	pub user_id: String,
+	pub preferences: UserPreferences,
+	pub push_subscriptions: Vec<PushSubscription>,
+}
+
+impl Recipient {
+	// Look up everything the notifiers need to reach `user_id`
+	pub async fn load(store: &dyn Store, user_id: &str) -> Result<Self, StoreError> {
+		Ok(Recipient {
+			user_id: user_id.to_string(),
+			preferences: store
+				.get_user_preferences(user_id)
+				.await?
+				.unwrap_or_default(),
+			push_subscriptions: store.get_push_subscriptions(user_id).await?,
+		})
+	}
+}
+
+#[derive(Debug)]
+pub struct NotifyError(pub String);
+
+impl fmt::Display for NotifyError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{}", self.0)
+	}
+}
+
+impl std::error::Error for NotifyError {}
+
+// A way of delivering alerts, such as email or webhooks
+#[async_trait]
+pub trait Notifier: Send + Sync {
+	// Channel name recorded against each delivery
+	fn channel(&self) -> &'static str;
+	// Whether the recipient has opted into this channel and can be reached on it
+	fn reaches(&self, recipient: &Recipient) -> bool;
+	async fn send(&self, recipient: &Recipient, alert: &Alert) -> Result<(), NotifyError>;
+}
+
+// The delivery backends alerts fan out to. Injected as `web::Data<Notifiers>`
+// next to the store.
+#[derive(Default)]
+pub struct Notifiers {
+	backends: Vec<Box<dyn Notifier>>,
+}
+
+impl Notifiers {
+	pub fn new(backends: Vec<Box<dyn Notifier>>) -> Self {
+		Self { backends }
+	}
+
+	pub fn get(&self, channel: &str) -> Option<&dyn Notifier> {
+		self.backends
+			.iter()
+			.find(|backend| backend.channel() == channel)
+			.map(|backend| backend.as_ref())
+	}
+}
+
+// Build the notifiers from the environment. Web Push and webhooks are always
+// available (VAPID_SUBJECT sets the push contact); SMTP_HOST enables email and
+// NOTIFY_SINK ("stdout" or a file path) copies every alert to a local sink for
+// testing.
+pub fn from_env(store: Arc<dyn Store>) -> Notifiers {
+	let mut backends: Vec<Box<dyn Notifier>> = vec![
+		Box::new(PushNotifier::new(
+			store,
+			&webpush::VAPID_KEY,
+			std::env::var("VAPID_SUBJECT").ok(),
+		)),
+		Box::new(WebhookNotifier::new()),
+	];
+
+	if let Ok(host) = std::env::var("SMTP_HOST") {
+		match EmailNotifier::from_env(&host) {
+			Ok(notifier) => backends.push(Box::new(notifier)),
+			Err(err) => warn!("Email alerts disabled: {}", err),
+		}
+	}
+
+	match std::env::var("NOTIFY_SINK").as_deref() {
+		Ok("stdout") => backends.push(Box::new(SinkNotifier::stdout())),
+		Ok(path) if !path.is_empty() => backends.push(Box::new(SinkNotifier::file(path))),
+		_ => {}
+	}
+
+	info!(
+		"Alert channels: {}",
+		backends
+			.iter()
+			.map(|backend| backend.channel())
+			.collect::<Vec<_>>()
+			.join(", ")
+	);
+	Notifiers::new(backends)
+}
+
+// Where the user in duress is: where they raised the alert from or, for
+// alerts without a location such as a duress PIN login, where they last
+// checked in if that is still current
+async fn duress_location(
+	store: &dyn Store,
+	event: &DuressEvent,
+) -> Result<Option<Location>, StoreError> {
+	if event.location.is_some() {
+		return Ok(event.location);
+	}
+	let Some(last) = store.get_user_location(&event.user_id).await? else {
+		return Ok(None);
+	};
+	let preferences = store
+		.get_user_preferences(&event.user_id)
+		.await?
+		.unwrap_or_default();
+	Ok(last
+		.is_current(preferences.checkin_interval_minutes, Utc::now())
+		.then_some(last.location))
+}
+
+// Users other than `known` whose current location is within their own
+// proximity radius of `location`
+async fn nearby_ids(
+	store: &dyn Store,
+	event: &DuressEvent,
+	location: &Location,
+	known: &[String],
+) -> Result<Vec<String>, StoreError> {
+	let now = Utc::now();
+	let cells = cells_within(location, MAX_PROXIMITY_RADIUS_M as f64);
+	let mut ids = Vec::new();
+
+	for candidate in store.get_user_locations_in_cells(&cells).await? {
+		if candidate.user_id == event.user_id || known.contains(&candidate.user_id) {
+			continue;
+		}
+		let preferences = store
+			.get_user_preferences(&candidate.user_id)
+			.await?
+			.unwrap_or_default();
+		if candidate.is_current(preferences.checkin_interval_minutes, now)
+			&& distance_m(location, &candidate.location) <= preferences.proximity_radius_m as f64
+		{
+			ids.push(candidate.user_id);
+		}
+	}
+
+	Ok(ids)
+}
+
+// Who should hear about `event`, and whether each is a stranger nearby. New
+// alerts go to the user's followers, or only to the user for tests with a
+// self-only audience. Real alerts from users who broadcast their duress also
+// go to nearby users at `location`. Endings go to whoever was told about the
+// alert. Users the user has blocked are left out.
+async fn recipient_ids(
+	store: &dyn Store,
+	event: &DuressEvent,
+	kind: AlertKind,
+	location: Option<&Location>,
+) -> Result<Vec<(String, bool)>, StoreError> {
+	let self_only = event.test
+		&& store
+			.get_test_mode(&event.user_id)
+			.await?
+			.is_some_and(|mode| mode.audience == TestAudience::SelfOnly);
+	let mut known: Vec<String> = if self_only {
+		vec![event.user_id.clone()]
+	} else {
+		store
+			.get_follows(&event.user_id)
+			.await?
+			.into_iter()
+			.map(|follow| follow.follower_id)
+			.collect()
+	};
+	known.push(event.user_id.clone());
+
+	let mut ids: Vec<(String, bool)> = match kind {
+		AlertKind::Raised => {
+			let mut ids: Vec<(String, bool)> = known
+				.iter()
+				.filter(|id| self_only || **id != event.user_id)
+				.map(|id| (id.clone(), false))
+				.collect();
+			let broadcast = !event.test
+				&& store
+					.get_user_preferences(&event.user_id)
+					.await?
+					.unwrap_or_default()
+					.broadcast_duress;
+			if let (true, Some(location)) = (broadcast, location) {
+				ids.extend(
+					nearby_ids(store, event, location, &known)
+						.await?
+						.into_iter()
+						.map(|id| (id, true)),
+				);
+			}
+			ids
+		}
+		// Anyone who is not, or is no longer, a follower is treated as a stranger
+		AlertKind::Ended => store
+			.get_event_deliveries(&event.id)
+			.await?
+			.into_iter()
+			.filter(|d| {
+				d.kind == AlertKind::Raised
+					&& matches!(d.status, DeliveryStatus::Queued | DeliveryStatus::Sent)
+			})
+			.map(|d| {
+				let nearby = !known.contains(&d.recipient_id);
+				(d.recipient_id, nearby)
+			})
+			.collect(),
+	};
+	// Never anyone the user has blocked, however they would have heard
+	let blocked: Vec<String> = store
+		.get_blocks(&event.user_id)
+		.await?
+		.into_iter()
+		.map(|block| block.blocked_id)
+		.collect();
+	ids.retain(|(id, _)| !blocked.contains(id));
+	ids.sort();
+	ids.dedup();
+	Ok(ids)
+}
+
+// Queue an alert about `event` for everyone who should receive it, on each
+// channel that reaches them, and record a delivery for each. The queue worker
+// does the sending, so this never waits on a slow or failing backend.
+pub async fn fan_out(
+	store: &dyn Store,
+	notifiers: &Notifiers,
+	event: &DuressEvent,
+	kind: AlertKind,
+) -> Result<Vec<Delivery>, StoreError> {
+	let location = duress_location(store, event).await?;
+	let alert = Alert::new(event, kind);
+	let nearby_alert = alert.for_nearby(location);
+	let now = Utc::now();
+	let mut deliveries = Vec::new();
+	// Unavailable channels are never queued, so this is how a second fan-out
+	// knows they were already recorded
+	let recorded: Vec<String> = store
+		.get_event_deliveries(&event.id)
+		.await?
+		.into_iter()
+		.map(|delivery| delivery.id)
+		.collect();
+
+	for (user_id, nearby) in recipient_ids(store, event, kind, location.as_ref()).await? {
+		let alert = if nearby { &nearby_alert } else { &alert };
+		let recipient = Recipient::load(store, &user_id).await?;
+		let preferences = &recipient.preferences;
+		// Opting out covers other people's alerts, not your own tests
+		if !preferences.receive_duress_broadcasts && user_id != event.user_id {
+			continue;
+		}
+
+		// Only a real duress alert can break through quiet hours
+		let urgent = kind == AlertKind::Raised && !event.test;
+		let quiet = preferences.quiet_hours.is_some_and(|quiet_hours| {
+			quiet_hours.contains(now) && !(urgent && quiet_hours.duress_overrides)
+		});
+
+		for notifier in notifiers
+			.backends
+			.iter()
+			.filter(|notifier| notifier.reaches(&recipient))
+		{
+			let job = Job {
+				key: Job::key_for(alert, &recipient.user_id, notifier.channel()),
+				recipient_id: recipient.user_id.clone(),
+				channel: notifier.channel().to_string(),
+				alert: alert.clone(),
+				attempts: 0,
+				next_attempt_at: now,
+				last_error: None,
+				dead: false,
+				created_at: now,
+			};
+
+			let delivery = if quiet {
+				job.delivery(DeliveryStatus::Suppressed)
+			} else if store.enqueue_job(&job).await? {
+				job.delivery(DeliveryStatus::Queued)
+			} else {
+				// Already queued by an earlier fan-out of the same alert
+				continue;
+			};
+			store.save_delivery(&delivery).await?;
+			deliveries.push(delivery);
+		}
+
+		// Channels opted into that this server cannot send on, such as SMS
+		for channel in preferences
+			.channels
+			.enabled()
+			.into_iter()
+			.filter(|channel| notifiers.get(channel).is_none())
+		{
+			let id = Job::key_for(alert, &recipient.user_id, channel);
+			if recorded.contains(&id) {
+				continue;
+			}
+			let delivery = Delivery {
+				id,
+				event_id: alert.event_id.clone(),
+				recipient_id: recipient.user_id.clone(),
+				channel: channel.to_string(),
+				kind: alert.kind,
+				status: DeliveryStatus::Unavailable,
+				error: Some(format!("No {} backend is configured", channel)),
+				created_at: now,
+			};
+			store.save_delivery(&delivery).await?;
+			deliveries.push(delivery);
+		}
+	}
+
+	Ok(deliveries)
+}
@@ -0,0 +1,369 @@
+// notify_backends.rs
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use lettre::message::Mailbox;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use chrono::Utc;
+use reqwest::StatusCode;
+use serde_json::json;
+use tracing::info;
+
+use crate::delivery_db::AlertKind;
+use crate::notify::{Alert, Notifier, NotifyError, Recipient};
+use crate::outbound;
+use crate::push_db::PushSubscription;
+use crate::store::Store;
+use crate::webpush::{self, SubscriptionKeys, VapidKey};
+
+// How long a single webhook or push service call may take
+const WEBHOOK_TIMEOUT_SECONDS: u64 = 10;
+
+// How long a push service holds an alert for an offline browser
+const PUSH_TTL_SECONDS: u32 = 24 * 60 * 60;
+
+// POSTs the alert as JSON to the recipient's webhook URL
+pub struct WebhookNotifier {
+	client: reqwest::Client,
+	allow_private: bool,
+}
+
+impl WebhookNotifier {
+	pub fn new() -> Self {
+		Self::with_private_addresses(false)
+	}
+
+	// Also deliver to loopback and private addresses, for local development.
+	// Never use this where users can set webhook URLs.
+	pub fn allowing_private_addresses() -> Self {
+		Self::with_private_addresses(true)
+	}
+
+	fn with_private_addresses(allow_private: bool) -> Self {
+		Self {
+			client: outbound::client(Duration::from_secs(WEBHOOK_TIMEOUT_SECONDS), allow_private),
+			allow_private,
+		}
+	}
+}
+
+impl Default for WebhookNotifier {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+#[async_trait]
+impl Notifier for WebhookNotifier {
+	fn channel(&self) -> &'static str {
+		"webhook"
+	}
+
+	fn reaches(&self, recipient: &Recipient) -> bool {
+		recipient.preferences.channels.webhook
+			&& recipient.preferences.contacts.webhook_url.is_some()
+	}
+
+	async fn send(&self, recipient: &Recipient, alert: &Alert) -> Result<(), NotifyError> {
+		let url = recipient
+			.preferences
+			.contacts
+			.webhook_url
+			.as_deref()
+			.ok_or_else(|| NotifyError("No webhook URL".to_string()))?;
+		// Checked again here, as what the host resolves to may have changed
+		// since the URL was saved
+		if !self.allow_private {
+			outbound::check_url(url, false)
+				.await
+				.map_err(|err| NotifyError(format!("Webhook URL refused: {}", err)))?;
+		}
+
+		self.client
+			.post(url)
+			.json(alert)
+			.send()
+			.await
+			.and_then(|response| response.error_for_status())
+			.map_err(|err| NotifyError(err.to_string()))?;
+		Ok(())
+	}
+}
+
+// Sends the alert as an encrypted Web Push message to each of the
+// recipient's browser subscriptions, dropping any the push service has expired
+pub struct PushNotifier {
+	client: reqwest::Client,
+	store: Arc<dyn Store>,
+	key: &'static VapidKey,
+	// Contact for push service operators, e.g. "mailto:ops@example.com"
+	subject: Option<String>,
+	allow_private: bool,
+}
+
+impl PushNotifier {
+	pub fn new(store: Arc<dyn Store>, key: &'static VapidKey, subject: Option<String>) -> Self {
+		Self {
+			client: outbound::client(Duration::from_secs(WEBHOOK_TIMEOUT_SECONDS), false),
+			store,
+			key,
+			subject,
+			allow_private: false,
+		}
+	}
+
+	// Also push to plain http, loopback and private addresses, for testing
+	// against a local push service. Never use this where users can register
+	// subscriptions.
+	pub fn allowing_private_addresses(self) -> Self {
+		Self {
+			client: outbound::client(Duration::from_secs(WEBHOOK_TIMEOUT_SECONDS), true),
+			allow_private: true,
+			..self
+		}
+	}
+
+	// Deliver to one subscription. Returns false if the push service says the
+	// subscription no longer exists.
+	async fn push(
+		&self,
+		subscription: &PushSubscription,
+		payload: &[u8],
+		urgent: bool,
+	) -> Result<bool, NotifyError> {
+		let endpoint = if self.allow_private {
+			reqwest::Url::parse(&subscription.endpoint)
+				.map_err(|err| NotifyError(err.to_string()))?
+		} else {
+			outbound::check_url(&subscription.endpoint, true)
+				.await
+				.map_err(|err| NotifyError(format!("Push endpoint refused: {}", err)))?
+		};
+		let keys = SubscriptionKeys::from_base64(&subscription.p256dh, &subscription.auth)
+			.map_err(|err| NotifyError(err.to_string()))?;
+
+		let response = self
+			.client
+			.post(endpoint.clone())
+			.header(
+				"Authorization",
+				self.key
+					.authorization(&endpoint, self.subject.as_deref(), Utc::now()),
+			)
+			.header("TTL", PUSH_TTL_SECONDS.to_string())
+			.header("Urgency", if urgent { "high" } else { "normal" })
+			.header("Content-Encoding", "aes128gcm")
+			.header("Content-Type", "application/octet-stream")
+			.body(webpush::encrypt(&keys, payload))
+			.send()
+			.await
+			.map_err(|err| NotifyError(err.to_string()))?;
+
+		match response.status() {
+			StatusCode::NOT_FOUND | StatusCode::GONE => Ok(false),
+			status if status.is_success() => Ok(true),
+			status => Err(NotifyError(format!("Push service returned {}", status))),
+		}
+	}
+}
+
+// The alert as JSON, with its message cut short if that is what it takes to
+// fit in a push message. Quotes and control characters grow when escaped, so
+// it is the JSON that is measured.
+fn push_payload(alert: &Alert) -> Result<Vec<u8>, NotifyError> {
+	let mut alert = alert.clone();
+	loop {
+		let payload = serde_json::to_vec(&alert).map_err(|err| NotifyError(err.to_string()))?;
+		if payload.len() <= webpush::MAX_PAYLOAD_BYTES {
+			return Ok(payload);
+		}
+		if alert.message.is_empty() {
+			return Err(NotifyError("Alert is too large to push".to_string()));
+		}
+		// Every byte cut saves at least one byte of JSON
+		let mut end = alert
+			.message
+			.len()
+			.saturating_sub(payload.len() - webpush::MAX_PAYLOAD_BYTES);
+		while !alert.message.is_char_boundary(end) {
+			end -= 1;
+		}
+		alert.message.truncate(end);
+	}
+}
+
+#[async_trait]
+impl Notifier for PushNotifier {
+	fn channel(&self) -> &'static str {
+		"push"
+	}
+
+	fn reaches(&self, recipient: &Recipient) -> bool {
+		recipient.preferences.channels.push && !recipient.push_subscriptions.is_empty()
+	}
+
+	async fn send(&self, recipient: &Recipient, alert: &Alert) -> Result<(), NotifyError> {
+		let payload = push_payload(alert)?;
+		let urgent = alert.kind == AlertKind::Raised && !alert.test;
+
+		// Succeed if any browser got the alert, so a retry does not repeat it
+		// on the others
+		let mut delivered = false;
+		let mut errors = Vec::new();
+		for subscription in &recipient.push_subscriptions {
+			match self.push(subscription, &payload, urgent).await {
+				Ok(true) => delivered = true,
+				Ok(false) => {
+					info!("Removing expired push subscription {}", subscription.id);
+					if let Err(err) = self
+						.store
+						.delete_push_subscription(&subscription.user_id, &subscription.id)
+						.await
+					{
+						errors.push(err.to_string());
+					}
+				}
+				Err(err) => errors.push(err.0),
+			}
+		}
+
+		if delivered {
+			Ok(())
+		} else if errors.is_empty() {
+			Err(NotifyError(
+				"Every push subscription has expired".to_string(),
+			))
+		} else {
+			Err(NotifyError(errors.join("; ")))
+		}
+	}
+}
+
+// Sends the alert as a plain text email through an SMTP relay
+pub struct EmailNotifier {
+	transport: AsyncSmtpTransport<Tokio1Executor>,
+	from: Mailbox,
+}
+
+impl EmailNotifier {
+	// Connect to the relay at `host` over TLS. SMTP_FROM is required;
+	// SMTP_PORT, SMTP_USERNAME and SMTP_PASSWORD are optional.
+	pub fn from_env(host: &str) -> Result<Self, NotifyError> {
+		let from = std::env::var("SMTP_FROM")
+			.map_err(|_| NotifyError("SMTP_FROM is not set".to_string()))?
+			.parse::<Mailbox>()
+			.map_err(|err| NotifyError(format!("Invalid SMTP_FROM: {}", err)))?;
+
+		let mut builder = AsyncSmtpTransport::<Tokio1Executor>::relay(host)
+			.map_err(|err| NotifyError(err.to_string()))?;
+		if let Some(port) = std::env::var("SMTP_PORT")
+			.ok()
+			.and_then(|port| port.parse().ok())
+		{
+			builder = builder.port(port);
+		}
+		if let (Ok(username), Ok(password)) = (
+			std::env::var("SMTP_USERNAME"),
+			std::env::var("SMTP_PASSWORD"),
+		) {
+			builder = builder.credentials(Credentials::new(username, password));
+		}
+
+		Ok(Self {
+			transport: builder.build(),
+			from,
+		})
+	}
+}
+
+#[async_trait]
+impl Notifier for EmailNotifier {
+	fn channel(&self) -> &'static str {
+		"email"
+	}
+
+	fn reaches(&self, recipient: &Recipient) -> bool {
+		recipient.preferences.channels.email && recipient.preferences.contacts.email.is_some()
+	}
+
+	async fn send(&self, recipient: &Recipient, alert: &Alert) -> Result<(), NotifyError> {
+		let to = recipient
+			.preferences
+			.contacts
+			.email
+			.as_deref()
+			.ok_or_else(|| NotifyError("No email address".to_string()))?
+			.parse::<Mailbox>()
+			.map_err(|err| NotifyError(err.to_string()))?;
+
+		let message = Message::builder()
+			.from(self.from.clone())
+			.to(to)
+			.subject(alert.subject())
+			.body(alert.text())
+			.map_err(|err| NotifyError(err.to_string()))?;
+
+		self.transport
+			.send(message)
+			.await
+			.map_err(|err| NotifyError(err.to_string()))?;
+		Ok(())
+	}
+}
+
+enum SinkTarget {
+	Stdout,
+	File(PathBuf),
+}
+
+// Writes every alert as a JSON line to stdout or a file, whatever the
+// recipient's channels. For local development and tests.
+pub struct SinkNotifier {
+	target: SinkTarget,
+}
+
+impl SinkNotifier {
+	pub fn stdout() -> Self {
+		Self {
+			target: SinkTarget::Stdout,
+		}
+	}
+
+	pub fn file(path: impl Into<PathBuf>) -> Self {
+		Self {
+			target: SinkTarget::File(path.into()),
+		}
+	}
+}
+
+#[async_trait]
+impl Notifier for SinkNotifier {
+	fn channel(&self) -> &'static str {
+		"sink"
+	}
+
+	fn reaches(&self, _recipient: &Recipient) -> bool {
+		true
+	}
+
+	async fn send(&self, recipient: &Recipient, alert: &Alert) -> Result<(), NotifyError> {
+		let line = format!(
+			"{}\n",
+			json!({"recipient_id": recipient.user_id, "alert": alert})
+		);
+
+		let result = match &self.target {
+			SinkTarget::Stdout => std::io::stdout().write_all(line.as_bytes()),
+			SinkTarget::File(path) => std::fs::OpenOptions::new()
+				.create(true)
+				.append(true)
+				.open(path)
+				.and_then(|mut file| file.write_all(line.as_bytes())),
+		};
+		result.map_err(|err| NotifyError(err.to_string()))
+	}
+}
@@ -0,0 +1,167 @@
+// outbound.rs
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use reqwest::redirect::Policy;
+use reqwest::Url;
+
+// Webhooks and Web Push endpoints are URLs users give us. Requests to them
+// must not reach our own network: loopback, private ranges, or link-local
+// addresses such as the cloud metadata service at 169.254.169.254.
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum OutboundError {
+	// Not a URL with a host
+	Url,
+	// Not http(s), or not https where that is required
+	Scheme,
+	// The host does not resolve
+	Unresolved,
+	// The host resolves to an address that is not on the public internet
+	Address,
+}
+
+impl std::fmt::Display for OutboundError {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			OutboundError::Url => write!(f, "not a valid URL"),
+			OutboundError::Scheme => write!(f, "URL scheme is not allowed"),
+			OutboundError::Unresolved => write!(f, "host does not resolve"),
+			OutboundError::Address => write!(f, "host is not a public address"),
+		}
+	}
+}
+
+impl std::error::Error for OutboundError {}
+
+// Whether `ip` is an address on the public internet
+pub fn is_public(ip: IpAddr) -> bool {
+	match ip {
+		IpAddr::V4(ip) => is_public_v4(ip),
+		IpAddr::V6(ip) => is_public_v6(ip),
+	}
+}
+
+fn is_public_v4(ip: Ipv4Addr) -> bool {
+	let [a, b, c, _] = ip.octets();
+	!(ip.is_unspecified()
+		|| ip.is_loopback()
+		|| ip.is_private()
+		|| ip.is_link_local()
+		|| ip.is_broadcast()
+		|| ip.is_multicast()
+		|| ip.is_documentation()
+		// 0.0.0.0/8 "this network"
+		|| a == 0
+		// 100.64.0.0/10 carrier-grade NAT, home of some clouds' metadata services
+		|| (a == 100 && (b & 0xc0) == 64)
+		// 192.0.0.0/24 protocol assignments
+		|| (a == 192 && b == 0 && c == 0)
+		// 198.18.0.0/15 benchmarking
+		|| (a == 198 && (b & 0xfe) == 18)
+		// 240.0.0.0/4 reserved
+		|| a >= 240)
+}
+
+fn is_public_v6(ip: Ipv6Addr) -> bool {
+	// IPv4 addresses in IPv6 clothing are judged as IPv4
+	if let Some(v4) = ip.to_ipv4_mapped() {
+		return is_public_v4(v4);
+	}
+	let segments = ip.segments();
+	// 64:ff9b::/96 NAT64
+	if segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0] {
+		return is_public_v4(Ipv4Addr::new(
+			(segments[6] >> 8) as u8,
+			segments[6] as u8,
+			(segments[7] >> 8) as u8,
+			segments[7] as u8,
+		));
+	}
+	!(ip.is_unspecified()
+		|| ip.is_loopback()
+		|| ip.is_multicast()
+		// fc00::/7 unique local, including fd00:ec2::254 metadata
+		|| (segments[0] & 0xfe00) == 0xfc00
+		// fe80::/10 link-local and fec0::/10 site-local
+		|| (segments[0] & 0xffc0) == 0xfe80
+		|| (segments[0] & 0xffc0) == 0xfec0
+		// 2001:db8::/32 documentation
+		|| (segments[0] == 0x2001 && segments[1] == 0xdb8))
+}
+
+async fn resolve(host: &str, port: u16) -> Result<Vec<SocketAddr>, OutboundError> {
+	let addrs: Vec<_> = tokio::net::lookup_host((host, port))
+		.await
+		.map_err(|_| OutboundError::Unresolved)?
+		.collect();
+	if addrs.is_empty() {
+		return Err(OutboundError::Unresolved);
+	}
+	Ok(addrs)
+}
+
+// Parse a user-supplied URL and check that every address its host resolves
+// to is public. Plain http is refused when `https_only` is set.
+pub async fn check_url(url: &str, https_only: bool) -> Result<Url, OutboundError> {
+	let url = Url::parse(url).map_err(|_| OutboundError::Url)?;
+	match url.scheme() {
+		"https" => {}
+		"http" if !https_only => {}
+		_ => return Err(OutboundError::Scheme),
+	}
+	// IPv6 literals come bracketed
+	let host = url
+		.host_str()
+		.ok_or(OutboundError::Url)?
+		.trim_start_matches('[')
+		.trim_end_matches(']');
+	let port = url.port_or_known_default().ok_or(OutboundError::Url)?;
+
+	if resolve(host, port)
+		.await?
+		.iter()
+		.any(|addr| !is_public(addr.ip()))
+	{
+		return Err(OutboundError::Address);
+	}
+	Ok(url)
+}
+
+// Resolves names to their public addresses only, so a host that passed
+// `check_url` cannot be pointed somewhere private before we connect
+struct PublicResolver;
+
+impl Resolve for PublicResolver {
+	fn resolve(&self, name: Name) -> Resolving {
+		Box::pin(async move {
+			let addrs: Vec<_> = resolve(name.as_str(), 0)
+				.await?
+				.into_iter()
+				.filter(|addr| is_public(addr.ip()))
+				.collect();
+			if addrs.is_empty() {
+				return Err(Box::new(OutboundError::Address) as _);
+			}
+			Ok(Box::new(addrs.into_iter()) as Addrs)
+		})
+	}
+}
+
+// HTTP client for user-supplied URLs. It connects only to public addresses
+// and does not follow redirects, which could lead anywhere. With
+// `allow_private` it is an ordinary client, for local development against
+// services on the same machine.
+pub fn client(timeout: Duration, allow_private: bool) -> reqwest::Client {
+	let builder = reqwest::Client::builder()
+		.timeout(timeout)
+		.redirect(Policy::none());
+	let builder = if allow_private {
+		builder
+	} else {
+		builder.dns_resolver(Arc::new(PublicResolver))
+	};
+	builder.build().expect("Failed to build HTTP client")
+}
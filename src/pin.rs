@@ -0,0 +1,80 @@
+// pin.rs
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{self, PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use actix_web::web;
+use argon2::Argon2;
+use lazy_static::lazy_static;
+
+use crate::db::User;
+use crate::store::{Store, StoreError};
+
+lazy_static! {
+	// Hash checked against when the user does not exist, so an unknown user
+	// costs the same as a wrong PIN.
+	static ref DUMMY_PIN_HASH: String = hash_pin("dummy-pin").expect("Failed to hash dummy PIN");
+}
+
+// Outcome of checking a PIN against a user's stored hashes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PinMatch {
+	Normal,
+	Duress,
+	Wrong,
+}
+
+// Hash a PIN with Argon2id and a random salt, returning a PHC string
+pub fn hash_pin(pin: &str) -> Result<String, password_hash::Error> {
+	let salt = SaltString::generate(&mut OsRng);
+	let hash = Argon2::default().hash_password(pin.as_bytes(), &salt)?;
+	Ok(hash.to_string())
+}
+
+// Check a PIN against both of the user's hashes. Both hashes are always
+// verified so the time taken does not reveal which PIN (if any) matched.
+pub fn verify_pin(user: &User, pin: &str) -> PinMatch {
+	let normal = matches_hash(&user.normal_pin_hash, pin);
+	let duress = matches_hash(&user.duress_pin_hash, pin);
+
+	match (normal, duress) {
+		(true, _) => PinMatch::Normal,
+		(false, true) => PinMatch::Duress,
+		(false, false) => PinMatch::Wrong,
+	}
+}
+
+// Spend the same effort as `verify_pin` when there is no user to check against.
+pub fn verify_unknown_user(pin: &str) -> PinMatch {
+	let _ = matches_hash(&DUMMY_PIN_HASH, pin);
+	let _ = matches_hash(&DUMMY_PIN_HASH, pin);
+	PinMatch::Wrong
+}
+
+// Look up a user and check a PIN against it, off the async executor since
+// Argon2 is CPU bound. Unknown users cost the same as a wrong PIN.
+pub async fn check_user_pin(
+	store: &dyn Store,
+	user_id: &str,
+	pin: String,
+) -> Result<PinMatch, StoreError> {
+	let user = store.get_user(user_id).await?;
+	Ok(check_pin(user, pin).await)
+}
+
+// `check_user_pin` for a user already fetched, or None if there is no such user
+pub async fn check_pin(user: Option<User>, pin: String) -> PinMatch {
+	web::block(move || match user {
+		Some(user) => verify_pin(&user, &pin),
+		None => verify_unknown_user(&pin),
+	})
+	.await
+	.unwrap_or(PinMatch::Wrong)
+}
+
+fn matches_hash(hash: &str, pin: &str) -> bool {
+	match PasswordHash::new(hash) {
+		Ok(parsed) => Argon2::default()
+			.verify_password(pin.as_bytes(), &parsed)
+			.is_ok(),
+		Err(_) => false,
+	}
+}
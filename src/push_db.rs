@@ -0,0 +1,123 @@
+// push_db.rs
+use aws_sdk_dynamodb::types::AttributeValue;
+use aws_sdk_dynamodb::{Client, Error};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use tracing::info;
+
+// A browser push subscription, as returned by `PushSubscription.toJSON()`
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PushSubscription {
+	// Derived from the endpoint, so subscribing twice updates one record
+	pub id: String,
+	pub user_id: String,
+	// Push service URL the browser handed out
+	pub endpoint: String,
+	// The browser's P-256 public key, base64url
+	pub p256dh: String,
+	// The browser's auth secret, base64url
+	pub auth: String,
+	pub created_at: DateTime<Utc>,
+}
+
+impl PushSubscription {
+	pub fn new(user_id: &str, endpoint: &str, p256dh: &str, auth: &str) -> Self {
+		PushSubscription {
+			id: Self::id_for(endpoint),
+			user_id: user_id.to_string(),
+			endpoint: endpoint.to_string(),
+			p256dh: p256dh.to_string(),
+			auth: auth.to_string(),
+			created_at: Utc::now(),
+		}
+	}
+
+	pub fn id_for(endpoint: &str) -> String {
+		Sha256::digest(endpoint.as_bytes())[..16]
+			.iter()
+			.map(|byte| format!("{:02x}", byte))
+			.collect()
+	}
+}
+
+fn subscription_from_item(item: &HashMap<String, AttributeValue>) -> PushSubscription {
+	let string_attr = |name: &str| {
+		item.get(name)
+			.and_then(|v| v.as_s().ok())
+			.map(|s| s.to_string())
+			.unwrap_or_default()
+	};
+
+	PushSubscription {
+		id: string_attr("id"),
+		user_id: string_attr("user_id"),
+		endpoint: string_attr("endpoint"),
+		p256dh: string_attr("p256dh"),
+		auth: string_attr("auth"),
+		created_at: DateTime::parse_from_rfc3339(&string_attr("created_at"))
+			.map(|v| v.with_timezone(&Utc))
+			.unwrap_or_else(|_| Utc::now()),
+	}
+}
+
+// Write a subscription to the DynamoDB "PushSubscription" table, keyed by
+// user and subscription ID
+pub async fn put_push_subscription(
+	client: &Client,
+	subscription: &PushSubscription,
+) -> Result<(), Error> {
+	info!("Writing a push subscription in DynamoDB");
+
+	client
+		.put_item()
+		.table_name("PushSubscription")
+		.item("user_id", AttributeValue::S(subscription.user_id.clone()))
+		.item("id", AttributeValue::S(subscription.id.clone()))
+		.item("endpoint", AttributeValue::S(subscription.endpoint.clone()))
+		.item("p256dh", AttributeValue::S(subscription.p256dh.clone()))
+		.item("auth", AttributeValue::S(subscription.auth.clone()))
+		.item(
+			"created_at",
+			AttributeValue::S(subscription.created_at.to_rfc3339()),
+		)
+		.send()
+		.await?;
+	Ok(())
+}
+
+pub async fn get_push_subscriptions(
+	client: &Client,
+	user_id: &str,
+) -> Result<Vec<PushSubscription>, Error> {
+	let result = client
+		.query()
+		.table_name("PushSubscription")
+		.key_condition_expression("user_id = :user_id")
+		.expression_attribute_values(":user_id", AttributeValue::S(user_id.to_string()))
+		.send()
+		.await?;
+
+	Ok(result
+		.items
+		.unwrap_or_default()
+		.iter()
+		.map(subscription_from_item)
+		.collect())
+}
+
+pub async fn delete_push_subscription(
+	client: &Client,
+	user_id: &str,
+	id: &str,
+) -> Result<(), Error> {
+	client
+		.delete_item()
+		.table_name("PushSubscription")
+		.key("user_id", AttributeValue::S(user_id.to_string()))
+		.key("id", AttributeValue::S(id.to_string()))
+		.send()
+		.await?;
+	Ok(())
+}
@@ -0,0 +1,116 @@
+// push_handlers.rs
+use actix_web::{web, HttpResponse};
+use serde::{Deserialize, Serialize};
+use tracing::{error, info};
+
+use crate::auth::AuthenticatedUser;
+use crate::outbound;
+use crate::push_db::PushSubscription;
+use crate::store::Store;
+use crate::webpush::{SubscriptionKeys, VAPID_KEY};
+
+#[derive(Debug, Deserialize)]
+pub struct PushSubscriptionKeys {
+	p256dh: String,
+	auth: String,
+}
+
+// Body of `PushSubscription.toJSON()` in the browser
+#[derive(Debug, Deserialize)]
+pub struct PushSubscriptionRequest {
+	endpoint: String,
+	keys: PushSubscriptionKeys,
+}
+
+#[derive(Debug, Serialize)]
+pub struct VapidPublicKeyResponse {
+	public_key: String,
+}
+
+// GET /push/vapid-public-key
+// The `applicationServerKey` clients subscribe with
+pub async fn get_vapid_public_key() -> HttpResponse {
+	HttpResponse::Ok().json(VapidPublicKeyResponse {
+		public_key: VAPID_KEY.public_key(),
+	})
+}
+
+// POST /users/{user_id}/push-subscriptions
+pub async fn add_push_subscription(
+	store: web::Data<dyn Store>,
+	user: AuthenticatedUser,
+	path: web::Path<String>,
+	req: web::Json<PushSubscriptionRequest>,
+) -> HttpResponse {
+	let user_id = path.into_inner();
+	let PushSubscriptionRequest { endpoint, keys } = req.into_inner();
+
+	if let Err(err) = SubscriptionKeys::from_base64(&keys.p256dh, &keys.auth) {
+		return HttpResponse::BadRequest().body(format!("Invalid subscription keys: {}", err));
+	}
+	// Push services are public https endpoints. Anything else would have us
+	// POST to wherever the user likes.
+	if let Err(err) = outbound::check_url(&endpoint, true).await {
+		return HttpResponse::BadRequest().body(format!("endpoint refused: {}", err));
+	}
+
+	let subscription = PushSubscription::new(&user_id, &endpoint, &keys.p256dh, &keys.auth);
+	if user.duress {
+		info!(
+			"Duress session tried to add a push subscription for {}",
+			user_id
+		);
+		return HttpResponse::Ok().json(subscription);
+	}
+	match store.save_push_subscription(&subscription).await {
+		Ok(_) => HttpResponse::Ok().json(subscription),
+		Err(err) => {
+			error!("Failed to save push subscription: {:?}", err);
+			HttpResponse::InternalServerError().body(err.to_string())
+		}
+	}
+}
+
+// GET /users/{user_id}/push-subscriptions
+pub async fn get_push_subscriptions(
+	store: web::Data<dyn Store>,
+	path: web::Path<String>,
+) -> HttpResponse {
+	let user_id = path.into_inner();
+
+	match store.get_push_subscriptions(&user_id).await {
+		Ok(subscriptions) => HttpResponse::Ok().json(subscriptions),
+		Err(err) => {
+			error!("Failed to fetch push subscriptions: {:?}", err);
+			HttpResponse::InternalServerError().body(err.to_string())
+		}
+	}
+}
+
+// DELETE /users/{user_id}/push-subscriptions/{subscription_id}
+pub async fn delete_push_subscription(
+	store: web::Data<dyn Store>,
+	user: AuthenticatedUser,
+	path: web::Path<(String, String)>,
+) -> HttpResponse {
+	let (user_id, subscription_id) = path.into_inner();
+	// The user's own devices keep getting their alerts
+	if user.duress {
+		info!(
+			"Duress session tried to remove a push subscription for {}",
+			user_id
+		);
+		return HttpResponse::Ok().body("Push subscription removed");
+	}
+
+	match store
+		.delete_push_subscription(&user_id, &subscription_id)
+		.await
+	{
+		Ok(_) => HttpResponse::Ok().body("Push subscription removed"),
+		Err(err) => {
+			error!("Failed to delete push subscription: {:?}", err);
+			HttpResponse::InternalServerError().body(err.to_string())
+		}
+	}
+}
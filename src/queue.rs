@@ -0,0 +1,121 @@
+// queue.rs
+use std::time::Duration as StdDuration;
+
+use actix_web::web;
+use chrono::{DateTime, Duration, Utc};
+use tracing::{error, info, warn};
+
+use crate::delivery_db::{DeliveryStatus, Job};
+use crate::notify::{Notifiers, NotifyError, Recipient};
+use crate::store::{Store, StoreError};
+
+// How often the worker looks for due jobs, and how many it takes at a time
+const POLL_SECONDS: u64 = 2;
+const BATCH_SIZE: usize = 50;
+
+// How long a claimed job is hidden from other workers while it is sent
+const LEASE_SECONDS: i64 = 60;
+
+// Retry with exponential backoff from BASE up to MAX, then give up and move
+// the job to the dead-letter list
+const RETRY_BASE_SECONDS: i64 = 5;
+const RETRY_MAX_SECONDS: i64 = 60 * 60;
+pub const MAX_ATTEMPTS: u32 = 8;
+
+// Delay before the next attempt after `attempts` failures
+pub fn backoff(attempts: u32) -> Duration {
+	let seconds = RETRY_BASE_SECONDS.saturating_mul(1 << attempts.saturating_sub(1).min(20));
+	Duration::seconds(seconds.min(RETRY_MAX_SECONDS))
+}
+
+// Try one claimed job, then delete it, schedule a retry or bury it
+async fn run_job(
+	store: &dyn Store,
+	notifiers: &Notifiers,
+	mut job: Job,
+	now: DateTime<Utc>,
+) -> Result<(), StoreError> {
+	let recipient = Recipient::load(store, &job.recipient_id).await?;
+
+	let result = match notifiers.get(&job.channel) {
+		Some(notifier) => notifier.send(&recipient, &job.alert).await,
+		None => Err(NotifyError(format!(
+			"No {} backend is configured",
+			job.channel
+		))),
+	};
+
+	match result {
+		Ok(_) => {
+			job.last_error = None;
+			store
+				.save_delivery(&job.delivery(DeliveryStatus::Sent))
+				.await?;
+			store.delete_job(&job.key).await?;
+		}
+		Err(err) => {
+			job.attempts += 1;
+			job.last_error = Some(err.to_string());
+			if job.attempts >= MAX_ATTEMPTS {
+				warn!(
+					"Giving up on job {} after {} attempts: {}",
+					job.key, job.attempts, err
+				);
+				job.dead = true;
+				store
+					.save_delivery(&job.delivery(DeliveryStatus::Failed))
+					.await?;
+			} else {
+				job.next_attempt_at = now + backoff(job.attempts);
+				store
+					.save_delivery(&job.delivery(DeliveryStatus::Queued))
+					.await?;
+			}
+			store.update_job(&job).await?;
+		}
+	}
+	Ok(())
+}
+
+// Run every job due by `now` that this worker manages to claim. Returns how
+// many were run.
+pub async fn process_due_jobs(
+	store: &dyn Store,
+	notifiers: &Notifiers,
+	now: DateTime<Utc>,
+) -> Result<usize, StoreError> {
+	let mut processed = 0;
+
+	// One job failing to save must not hold up the rest of the batch. A job
+	// left claimed is picked up again once its lease runs out.
+	for job in store.get_due_jobs(now, BATCH_SIZE).await? {
+		let lease = now + Duration::seconds(LEASE_SECONDS);
+		match store.claim_job(&job.key, job.next_attempt_at, lease).await {
+			Ok(true) => {}
+			Ok(false) => continue,
+			Err(err) => {
+				error!("Failed to claim job {}: {:?}", job.key, err);
+				continue;
+			}
+		}
+		let key = job.key.clone();
+		if let Err(err) = run_job(store, notifiers, job, now).await {
+			error!("Failed to run job {}: {:?}", key, err);
+			continue;
+		}
+		processed += 1;
+	}
+
+	Ok(processed)
+}
+
+// Deliver queued alerts until the process exits. Spawned once from main.
+pub async fn run_worker(store: web::Data<dyn Store>, notifiers: web::Data<Notifiers>) {
+	info!("Alert delivery worker started");
+	loop {
+		if let Err(err) = process_due_jobs(store.get_ref(), &notifiers, Utc::now()).await {
+			error!("Alert delivery worker failed: {:?}", err);
+		}
+		actix_web::rt::time::sleep(StdDuration::from_secs(POLL_SECONDS)).await;
+	}
+}
@@ -0,0 +1,1523 @@
+// sqlite_store.rs
+use std::sync::{Arc, Mutex, PoisonError};
+
+use actix_web::web;
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, NaiveTime, Utc};
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use tracing::info;
+
+use crate::block_db::Block;
+use crate::checkin_db::Checkin;
+use crate::db::{Invite, Session, User};
+use crate::delivery_db::{AlertKind, Delivery, DeliveryStatus, Job};
+use crate::duress_db::{
+	DuressEvent, DuressStatus, DuressType, Location, NotificationChannels, NotificationContacts,
+	QuietHours, TestAudience, TestMode, UserPreferences,
+};
+use crate::follow_db::{Follow, FollowCursor, FollowPage, FollowRequest};
+use crate::location_db::UserLocation;
+use crate::login_db::{LoginAttempts, LOGIN_WINDOW_MINUTES};
+use crate::push_db::PushSubscription;
+use crate::store::{Store, StoreError};
+
+// Schema migrations, applied in order. The index of the last applied migration
+// (plus one) is kept in SQLite's `user_version` pragma. Never edit an entry
+// once it has shipped; append a new one instead.
+const MIGRATIONS: &[&str] = &[
+	// 1: initial data model
+	"CREATE TABLE users (
+		id TEXT PRIMARY KEY,
+		invite_code TEXT NOT NULL,
+		normal_pin TEXT NOT NULL,
+		duress_pin TEXT NOT NULL
+	);
+	CREATE INDEX users_invite_code ON users (invite_code);
+
+	CREATE TABLE invites (
+		code TEXT PRIMARY KEY,
+		invitor_id TEXT NOT NULL,
+		invite_count INTEGER NOT NULL DEFAULT 0,
+		created_at TEXT NOT NULL
+	);
+	CREATE INDEX invites_invitor_id ON invites (invitor_id);
+
+	CREATE TABLE follows (
+		follower_id TEXT NOT NULL,
+		followed_id TEXT NOT NULL,
+		PRIMARY KEY (follower_id, followed_id)
+	);
+	CREATE INDEX follows_followed_id ON follows (followed_id);
+
+	CREATE TABLE duress_events (
+		id INTEGER PRIMARY KEY AUTOINCREMENT,
+		user_id TEXT NOT NULL,
+		duress_type TEXT NOT NULL,
+		message TEXT NOT NULL,
+		timestamp TEXT NOT NULL
+	);
+	CREATE INDEX duress_events_user_id ON duress_events (user_id, timestamp);
+
+	CREATE TABLE user_preferences (
+		user_id TEXT PRIMARY KEY,
+		broadcast_duress INTEGER NOT NULL,
+		receive_duress_broadcasts INTEGER NOT NULL
+	);",
+	// 2: PINs are stored as Argon2id hashes
+	"ALTER TABLE users RENAME COLUMN normal_pin TO normal_pin_hash;
+	ALTER TABLE users RENAME COLUMN duress_pin TO duress_pin_hash;",
+	// 3: login sessions
+	"CREATE TABLE sessions (
+		token TEXT PRIMARY KEY,
+		user_id TEXT NOT NULL,
+		created_at TEXT NOT NULL,
+		expires_at TEXT NOT NULL,
+		duress INTEGER NOT NULL DEFAULT 0
+	);
+	CREATE INDEX sessions_user_id ON sessions (user_id);",
+	// 4: sessions are keyed by an ID carried in a signed token
+	"ALTER TABLE sessions RENAME COLUMN token TO id;",
+	// 5: duress events get string IDs and a lifecycle status
+	"CREATE TABLE duress_events_v5 (
+		id TEXT PRIMARY KEY,
+		user_id TEXT NOT NULL,
+		duress_type TEXT NOT NULL,
+		message TEXT NOT NULL,
+		timestamp TEXT NOT NULL,
+		status TEXT NOT NULL DEFAULT 'active',
+		created_at TEXT NOT NULL
+	);
+	INSERT INTO duress_events_v5 (id, user_id, duress_type, message, timestamp, created_at)
+		SELECT CAST(id AS TEXT), user_id, duress_type, message, timestamp,
+			COALESCE(
+				strftime('%Y-%m-%dT%H:%M:%SZ', timestamp),
+				strftime('%Y-%m-%dT%H:%M:%SZ', 'now')
+			)
+		FROM duress_events;
+	DROP TABLE duress_events;
+	ALTER TABLE duress_events_v5 RENAME TO duress_events;
+	CREATE INDEX duress_events_user_id ON duress_events (user_id, created_at);",
+	// 6: test mode windows and test duress events
+	"CREATE TABLE test_modes (
+		user_id TEXT PRIMARY KEY,
+		audience TEXT NOT NULL,
+		started_at TEXT NOT NULL,
+		expires_at TEXT NOT NULL
+	);
+	ALTER TABLE duress_events ADD COLUMN test INTEGER NOT NULL DEFAULT 0;",
+	// 7: structured duress events with location and client data
+	"ALTER TABLE duress_events ADD COLUMN latitude REAL;
+	ALTER TABLE duress_events ADD COLUMN longitude REAL;
+	ALTER TABLE duress_events ADD COLUMN accuracy REAL;
+	ALTER TABLE duress_events ADD COLUMN additional_data TEXT NOT NULL DEFAULT 'null';
+	UPDATE duress_events SET timestamp = created_at
+		WHERE strftime('%s', timestamp) IS NULL;",
+	// 8: location check-ins
+	"CREATE TABLE checkins (
+		id TEXT PRIMARY KEY,
+		user_id TEXT NOT NULL,
+		latitude REAL NOT NULL,
+		longitude REAL NOT NULL,
+		accuracy REAL,
+		timestamp TEXT NOT NULL,
+		created_at TEXT NOT NULL
+	);
+	CREATE INDEX checkins_user_id ON checkins (user_id, created_at);",
+	// 9: notification channels, quiet hours, radius and check-in interval.
+	// Existing rows stay at version 1 until they are next written.
+	"ALTER TABLE user_preferences ADD COLUMN version INTEGER NOT NULL DEFAULT 1;
+	ALTER TABLE user_preferences ADD COLUMN channel_push INTEGER NOT NULL DEFAULT 1;
+	ALTER TABLE user_preferences ADD COLUMN channel_sms INTEGER NOT NULL DEFAULT 0;
+	ALTER TABLE user_preferences ADD COLUMN channel_email INTEGER NOT NULL DEFAULT 0;
+	ALTER TABLE user_preferences ADD COLUMN channel_webhook INTEGER NOT NULL DEFAULT 0;
+	ALTER TABLE user_preferences ADD COLUMN quiet_start TEXT;
+	ALTER TABLE user_preferences ADD COLUMN quiet_end TEXT;
+	ALTER TABLE user_preferences ADD COLUMN quiet_utc_offset_minutes INTEGER NOT NULL DEFAULT 0;
+	ALTER TABLE user_preferences ADD COLUMN quiet_duress_overrides INTEGER NOT NULL DEFAULT 1;
+	ALTER TABLE user_preferences ADD COLUMN proximity_radius_m INTEGER NOT NULL DEFAULT 1000;
+	ALTER TABLE user_preferences
+		ADD COLUMN checkin_interval_minutes INTEGER NOT NULL DEFAULT 60;",
+	// 10: alert contacts and per-recipient delivery records
+	"ALTER TABLE user_preferences ADD COLUMN contact_email TEXT;
+	ALTER TABLE user_preferences ADD COLUMN contact_webhook_url TEXT;
+
+	CREATE TABLE deliveries (
+		id TEXT PRIMARY KEY,
+		event_id TEXT NOT NULL,
+		recipient_id TEXT NOT NULL,
+		channel TEXT NOT NULL,
+		kind TEXT NOT NULL,
+		status TEXT NOT NULL,
+		error TEXT,
+		created_at TEXT NOT NULL
+	);
+	CREATE INDEX deliveries_event_id ON deliveries (event_id);",
+	// 11: durable delivery job queue
+	"CREATE TABLE jobs (
+		key TEXT PRIMARY KEY,
+		recipient_id TEXT NOT NULL,
+		channel TEXT NOT NULL,
+		alert TEXT NOT NULL,
+		attempts INTEGER NOT NULL DEFAULT 0,
+		next_attempt_at TEXT NOT NULL,
+		last_error TEXT,
+		dead INTEGER NOT NULL DEFAULT 0,
+		created_at TEXT NOT NULL
+	);
+	CREATE INDEX jobs_due ON jobs (dead, next_attempt_at);",
+	// 12: browser push subscriptions
+	"CREATE TABLE push_subscriptions (
+		user_id TEXT NOT NULL,
+		id TEXT NOT NULL,
+		endpoint TEXT NOT NULL,
+		p256dh TEXT NOT NULL,
+		auth TEXT NOT NULL,
+		created_at TEXT NOT NULL,
+		PRIMARY KEY (user_id, id)
+	);",
+	// 13: last known location of each user, by geohash cell
+	"CREATE TABLE user_locations (
+		user_id TEXT PRIMARY KEY,
+		cell TEXT NOT NULL,
+		latitude REAL NOT NULL,
+		longitude REAL NOT NULL,
+		accuracy REAL,
+		updated_at TEXT NOT NULL
+	);
+	CREATE INDEX user_locations_cell ON user_locations (cell);",
+	// 14: follow requests awaiting approval
+	"CREATE TABLE follow_requests (
+		follower_id TEXT NOT NULL,
+		followed_id TEXT NOT NULL,
+		requested_at TEXT NOT NULL,
+		PRIMARY KEY (followed_id, follower_id)
+	);",
+	// 15: users each user has blocked
+	"CREATE TABLE blocks (
+		blocker_id TEXT NOT NULL,
+		blocked_id TEXT NOT NULL,
+		created_at TEXT NOT NULL,
+		PRIMARY KEY (blocker_id, blocked_id)
+	);",
+	// 16: anonymous handles, each held by one user
+	"ALTER TABLE users ADD COLUMN handle TEXT;
+	CREATE TABLE handle_reservations (
+		handle TEXT PRIMARY KEY,
+		user_id TEXT NOT NULL,
+		reserved_at TEXT NOT NULL
+	);",
+	// 17: invite expiry, use limits and revocation
+	"ALTER TABLE invites ADD COLUMN expires_at TEXT;
+	ALTER TABLE invites ADD COLUMN max_uses INTEGER;
+	ALTER TABLE invites ADD COLUMN revoked INTEGER NOT NULL DEFAULT 0;",
+	// 18: admins and suspensions
+	"ALTER TABLE users ADD COLUMN admin INTEGER NOT NULL DEFAULT 0;
+	ALTER TABLE users ADD COLUMN suspended_at TEXT;",
+	// 19: failed logins per user
+	"CREATE TABLE login_attempts (
+		user_id TEXT PRIMARY KEY,
+		failures INTEGER NOT NULL,
+		window_start TEXT NOT NULL
+	);",
+];
+
+// Store backed by a single SQLite database file, for self-hosted deployments.
+// rusqlite is synchronous, so every call runs on the blocking thread pool.
+pub struct SqliteStore {
+	conn: Arc<Mutex<Connection>>,
+}
+
+impl SqliteStore {
+	pub fn open(path: &str) -> Result<Self, StoreError> {
+		Self::from_connection(Connection::open(path)?)
+	}
+
+	pub fn open_in_memory() -> Result<Self, StoreError> {
+		Self::from_connection(Connection::open_in_memory()?)
+	}
+
+	fn from_connection(mut conn: Connection) -> Result<Self, StoreError> {
+		conn.pragma_update(None, "foreign_keys", true)?;
+		migrate(&mut conn)?;
+		Ok(Self {
+			conn: Arc::new(Mutex::new(conn)),
+		})
+	}
+
+	// Run `f` with the connection on the blocking thread pool. A panic inside
+	// `f` rolls back any open transaction while unwinding, so a poisoned lock
+	// still holds a usable connection.
+	async fn call<T, F>(&self, f: F) -> Result<T, StoreError>
+	where
+		T: Send + 'static,
+		F: FnOnce(&mut Connection) -> Result<T, StoreError> + Send + 'static,
+	{
+		let conn = self.conn.clone();
+		web::block(move || {
+			let mut conn = conn.lock().unwrap_or_else(PoisonError::into_inner);
+			f(&mut conn)
+		})
+		.await?
+	}
+}
+
+// Bring the schema up to date, one transaction per migration.
+fn migrate(conn: &mut Connection) -> Result<(), rusqlite::Error> {
+	let version: usize = conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+
+	for (index, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+		info!("Applying SQLite migration {}", index + 1);
+		let tx = conn.transaction()?;
+		tx.execute_batch(migration)?;
+		tx.pragma_update(None, "user_version", index + 1)?;
+		tx.commit()?;
+	}
+
+	Ok(())
+}
+
+fn user_from_row(row: &Row) -> Result<User, rusqlite::Error> {
+	Ok(User {
+		id: row.get("id")?,
+		invite_code: row.get("invite_code")?,
+		normal_pin_hash: row.get("normal_pin_hash")?,
+		duress_pin_hash: row.get("duress_pin_hash")?,
+		handle: row.get("handle")?,
+		admin: row.get("admin")?,
+		suspended_at: row.get("suspended_at")?,
+	})
+}
+
+fn invite_from_row(row: &Row) -> Result<Invite, rusqlite::Error> {
+	Ok(Invite {
+		code: row.get("code")?,
+		invitor_id: row.get("invitor_id")?,
+		invite_count: row.get("invite_count")?,
+		created_at: row.get("created_at")?,
+		expires_at: row.get("expires_at")?,
+		max_uses: row.get("max_uses")?,
+		revoked: row.get("revoked")?,
+	})
+}
+
+fn duress_event_from_row(row: &Row) -> Result<DuressEvent, rusqlite::Error> {
+	let status: String = row.get("status")?;
+	let duress_type: String = row.get("duress_type")?;
+	let latitude: Option<f64> = row.get("latitude")?;
+	let longitude: Option<f64> = row.get("longitude")?;
+	let location = match (latitude, longitude) {
+		(Some(latitude), Some(longitude)) => Some(Location {
+			latitude,
+			longitude,
+			accuracy: row.get("accuracy")?,
+		}),
+		_ => None,
+	};
+	let additional_data: String = row.get("additional_data")?;
+
+	Ok(DuressEvent {
+		id: row.get("id")?,
+		user_id: row.get("user_id")?,
+		duress_type: DuressType::parse(&duress_type),
+		message: row.get("message")?,
+		timestamp: row.get("timestamp")?,
+		location,
+		additional_data: serde_json::from_str(&additional_data).unwrap_or_default(),
+		status: DuressStatus::parse(&status).unwrap_or(DuressStatus::Active),
+		test: row.get("test")?,
+		created_at: row.get("created_at")?,
+	})
+}
+
+fn checkin_from_row(row: &Row) -> Result<Checkin, rusqlite::Error> {
+	Ok(Checkin {
+		id: row.get("id")?,
+		user_id: row.get("user_id")?,
+		location: Location {
+			latitude: row.get("latitude")?,
+			longitude: row.get("longitude")?,
+			accuracy: row.get("accuracy")?,
+		},
+		timestamp: row.get("timestamp")?,
+		created_at: row.get("created_at")?,
+	})
+}
+
+// Turn up to `limit + 1` rows into a page of `limit`, with a cursor if there
+// was more
+fn follow_page(mut follows: Vec<Follow>, limit: usize) -> FollowPage {
+	let more = follows.len() > limit;
+	follows.truncate(limit);
+	FollowPage {
+		next: follows.last().filter(|_| more).map(FollowCursor::after),
+		follows,
+	}
+}
+
+fn user_location_from_row(row: &Row) -> Result<UserLocation, rusqlite::Error> {
+	Ok(UserLocation {
+		user_id: row.get("user_id")?,
+		location: Location {
+			latitude: row.get("latitude")?,
+			longitude: row.get("longitude")?,
+			accuracy: row.get("accuracy")?,
+		},
+		cell: row.get("cell")?,
+		updated_at: row.get("updated_at")?,
+	})
+}
+
+fn push_subscription_from_row(row: &Row) -> Result<PushSubscription, rusqlite::Error> {
+	Ok(PushSubscription {
+		id: row.get("id")?,
+		user_id: row.get("user_id")?,
+		endpoint: row.get("endpoint")?,
+		p256dh: row.get("p256dh")?,
+		auth: row.get("auth")?,
+		created_at: row.get("created_at")?,
+	})
+}
+
+fn preferences_from_row(row: &Row) -> Result<UserPreferences, rusqlite::Error> {
+	let quiet_start: Option<NaiveTime> = row.get("quiet_start")?;
+	let quiet_end: Option<NaiveTime> = row.get("quiet_end")?;
+	let quiet_hours = match (quiet_start, quiet_end) {
+		(Some(start), Some(end)) => Some(QuietHours {
+			start,
+			end,
+			utc_offset_minutes: row.get("quiet_utc_offset_minutes")?,
+			duress_overrides: row.get("quiet_duress_overrides")?,
+		}),
+		_ => None,
+	};
+
+	Ok(UserPreferences {
+		version: row.get("version")?,
+		broadcast_duress: row.get("broadcast_duress")?,
+		receive_duress_broadcasts: row.get("receive_duress_broadcasts")?,
+		channels: NotificationChannels {
+			push: row.get("channel_push")?,
+			sms: row.get("channel_sms")?,
+			email: row.get("channel_email")?,
+			webhook: row.get("channel_webhook")?,
+		},
+		contacts: NotificationContacts {
+			email: row.get("contact_email")?,
+			webhook_url: row.get("contact_webhook_url")?,
+		},
+		quiet_hours,
+		proximity_radius_m: row.get("proximity_radius_m")?,
+		checkin_interval_minutes: row.get("checkin_interval_minutes")?,
+	})
+}
+
+fn delivery_from_row(row: &Row) -> Result<Delivery, rusqlite::Error> {
+	let kind: String = row.get("kind")?;
+	let status: String = row.get("status")?;
+	Ok(Delivery {
+		id: row.get("id")?,
+		event_id: row.get("event_id")?,
+		recipient_id: row.get("recipient_id")?,
+		channel: row.get("channel")?,
+		kind: AlertKind::parse(&kind).unwrap_or(AlertKind::Raised),
+		status: DeliveryStatus::parse(&status).unwrap_or(DeliveryStatus::Failed),
+		error: row.get("error")?,
+		created_at: row.get("created_at")?,
+	})
+}
+
+fn job_from_row(row: &Row) -> Result<Job, rusqlite::Error> {
+	let alert: String = row.get("alert")?;
+	Ok(Job {
+		key: row.get("key")?,
+		recipient_id: row.get("recipient_id")?,
+		channel: row.get("channel")?,
+		alert: serde_json::from_str(&alert).map_err(|err| {
+			rusqlite::Error::FromSqlConversionFailure(0, rusqlite::types::Type::Text, Box::new(err))
+		})?,
+		attempts: row.get("attempts")?,
+		next_attempt_at: row.get("next_attempt_at")?,
+		last_error: row.get("last_error")?,
+		dead: row.get("dead")?,
+		created_at: row.get("created_at")?,
+	})
+}
+
+fn follow_from_row(row: &Row) -> Result<Follow, rusqlite::Error> {
+	Ok(Follow {
+		follower_id: row.get("follower_id")?,
+		followed_id: row.get("followed_id")?,
+	})
+}
+
+fn follow_request_from_row(row: &Row) -> Result<FollowRequest, rusqlite::Error> {
+	Ok(FollowRequest {
+		follower_id: row.get("follower_id")?,
+		followed_id: row.get("followed_id")?,
+		requested_at: row.get("requested_at")?,
+	})
+}
+
+fn login_attempts_from_row(row: &Row) -> Result<LoginAttempts, rusqlite::Error> {
+	Ok(LoginAttempts {
+		user_id: row.get("user_id")?,
+		failures: row.get("failures")?,
+		window_start: row.get("window_start")?,
+	})
+}
+
+fn block_from_row(row: &Row) -> Result<Block, rusqlite::Error> {
+	Ok(Block {
+		blocker_id: row.get("blocker_id")?,
+		blocked_id: row.get("blocked_id")?,
+		created_at: row.get("created_at")?,
+	})
+}
+
+#[async_trait]
+impl Store for SqliteStore {
+	async fn save_user(&self, user: &User) -> Result<(), StoreError> {
+		let user = user.clone();
+		self.call(move |conn| {
+			conn.execute(
+				"INSERT OR REPLACE INTO users
+				(id, invite_code, normal_pin_hash, duress_pin_hash, handle, admin, suspended_at)
+				VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+				params![
+					user.id,
+					user.invite_code,
+					user.normal_pin_hash,
+					user.duress_pin_hash,
+					user.handle,
+					user.admin,
+					user.suspended_at
+				],
+			)?;
+			Ok(())
+		})
+		.await
+	}
+
+	async fn get_user(&self, user_id: &str) -> Result<Option<User>, StoreError> {
+		let user_id = user_id.to_string();
+		self.call(move |conn| {
+			let user = conn
+				.query_row(
+					"SELECT * FROM users WHERE id = ?1",
+					[user_id],
+					user_from_row,
+				)
+				.optional()?;
+			Ok(user)
+		})
+		.await
+	}
+
+	async fn suspend_user(
+		&self,
+		user_id: &str,
+		suspended_at: DateTime<Utc>,
+	) -> Result<bool, StoreError> {
+		let user_id = user_id.to_string();
+		self.call(move |conn| {
+			let updated = conn.execute(
+				"UPDATE users SET suspended_at = ?2 WHERE id = ?1 AND suspended_at IS NULL",
+				params![user_id, suspended_at],
+			)?;
+			Ok(updated == 1)
+		})
+		.await
+	}
+
+	async fn unsuspend_user(&self, user_id: &str) -> Result<bool, StoreError> {
+		let user_id = user_id.to_string();
+		self.call(move |conn| {
+			let updated = conn.execute(
+				"UPDATE users SET suspended_at = NULL WHERE id = ?1",
+				[user_id],
+			)?;
+			Ok(updated == 1)
+		})
+		.await
+	}
+
+	async fn get_users_by_invite(&self, code: &str) -> Result<Vec<User>, StoreError> {
+		let code = code.to_string();
+		self.call(move |conn| {
+			let mut stmt =
+				conn.prepare("SELECT * FROM users WHERE invite_code = ?1 ORDER BY id")?;
+			let users = stmt
+				.query_map([code], user_from_row)?
+				.collect::<Result<Vec<_>, _>>()?;
+			Ok(users)
+		})
+		.await
+	}
+
+	async fn reserve_handle(&self, handle: &str, user_id: &str) -> Result<bool, StoreError> {
+		let handle = handle.to_string();
+		let user_id = user_id.to_string();
+		self.call(move |conn| {
+			let inserted = conn.execute(
+				"INSERT OR IGNORE INTO handle_reservations (handle, user_id, reserved_at)
+				VALUES (?1, ?2, ?3)",
+				params![handle, user_id, Utc::now()],
+			)?;
+			Ok(inserted == 1)
+		})
+		.await
+	}
+
+	async fn release_handle(&self, handle: &str) -> Result<(), StoreError> {
+		let handle = handle.to_string();
+		self.call(move |conn| {
+			conn.execute(
+				"DELETE FROM handle_reservations WHERE handle = ?1",
+				[handle],
+			)?;
+			Ok(())
+		})
+		.await
+	}
+
+	async fn save_session(&self, session: &Session) -> Result<(), StoreError> {
+		let session = session.clone();
+		self.call(move |conn| {
+			conn.execute(
+				"INSERT INTO sessions (id, user_id, created_at, expires_at, duress)
+				VALUES (?1, ?2, ?3, ?4, ?5)",
+				params![
+					session.id,
+					session.user_id,
+					session.created_at,
+					session.expires_at,
+					session.duress
+				],
+			)?;
+			Ok(())
+		})
+		.await
+	}
+
+	async fn get_session(&self, session_id: &str) -> Result<Option<Session>, StoreError> {
+		let session_id = session_id.to_string();
+		self.call(move |conn| {
+			let session = conn
+				.query_row(
+					"SELECT * FROM sessions WHERE id = ?1",
+					[session_id],
+					|row| {
+						Ok(Session {
+							id: row.get("id")?,
+							user_id: row.get("user_id")?,
+							created_at: row.get("created_at")?,
+							expires_at: row.get("expires_at")?,
+							duress: row.get("duress")?,
+						})
+					},
+				)
+				.optional()?;
+			Ok(session)
+		})
+		.await
+	}
+
+	async fn delete_session(&self, session_id: &str) -> Result<(), StoreError> {
+		let session_id = session_id.to_string();
+		self.call(move |conn| {
+			conn.execute("DELETE FROM sessions WHERE id = ?1", [session_id])?;
+			Ok(())
+		})
+		.await
+	}
+
+	async fn delete_user_sessions(&self, user_id: &str) -> Result<(), StoreError> {
+		let user_id = user_id.to_string();
+		self.call(move |conn| {
+			conn.execute("DELETE FROM sessions WHERE user_id = ?1", [user_id])?;
+			Ok(())
+		})
+		.await
+	}
+
+	async fn save_invite(&self, invite: &Invite) -> Result<(), StoreError> {
+		let invite = invite.clone();
+		self.call(move |conn| {
+			conn.execute(
+				"INSERT OR REPLACE INTO invites
+				(code, invitor_id, invite_count, created_at, expires_at, max_uses, revoked)
+				VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+				params![
+					invite.code,
+					invite.invitor_id,
+					invite.invite_count,
+					invite.created_at,
+					invite.expires_at,
+					invite.max_uses,
+					invite.revoked
+				],
+			)?;
+			Ok(())
+		})
+		.await
+	}
+
+	async fn get_invite(&self, code: &str) -> Result<Option<Invite>, StoreError> {
+		let code = code.to_string();
+		self.call(move |conn| {
+			let invite = conn
+				.query_row(
+					"SELECT * FROM invites WHERE code = ?1",
+					[code],
+					invite_from_row,
+				)
+				.optional()?;
+			Ok(invite)
+		})
+		.await
+	}
+
+	async fn get_user_invites(&self, user_id: &str) -> Result<Vec<Invite>, StoreError> {
+		let user_id = user_id.to_string();
+		self.call(move |conn| {
+			let mut stmt = conn.prepare("SELECT * FROM invites WHERE invitor_id = ?1")?;
+			let invites = stmt
+				.query_map([user_id], invite_from_row)?
+				.collect::<Result<Vec<_>, _>>()?;
+			Ok(invites)
+		})
+		.await
+	}
+
+	async fn update_invite(&self, invite: &Invite) -> Result<(), StoreError> {
+		let invite = invite.clone();
+		self.call(move |conn| {
+			conn.execute(
+				"UPDATE invites SET invitor_id = ?2, created_at = ?3, revoked = ?4, expires_at = ?5,
+				max_uses = ?6
+				WHERE code = ?1",
+				params![
+					invite.code,
+					invite.invitor_id,
+					invite.created_at,
+					invite.revoked,
+					invite.expires_at,
+					invite.max_uses
+				],
+			)?;
+			Ok(())
+		})
+		.await
+	}
+
+	async fn revoke_invite(&self, code: &str) -> Result<(), StoreError> {
+		let code = code.to_string();
+		self.call(move |conn| {
+			conn.execute("UPDATE invites SET revoked = 1 WHERE code = ?1", [code])?;
+			Ok(())
+		})
+		.await
+	}
+
+	async fn create_invited_user(
+		&self,
+		user: &User,
+		now: DateTime<Utc>,
+	) -> Result<bool, StoreError> {
+		let user = user.clone();
+		self.call(move |conn| {
+			// Rolled back if dropped before the commit
+			let tx = conn.transaction()?;
+			let updated = tx.execute(
+				"UPDATE invites SET invite_count = invite_count + 1
+				WHERE code = ?1 AND NOT revoked
+				AND (expires_at IS NULL OR expires_at > ?2)
+				AND (max_uses IS NULL OR invite_count < max_uses)",
+				params![user.invite_code, now],
+			)?;
+			if updated == 0 {
+				return Ok(false);
+			}
+			tx.execute(
+				"INSERT INTO users
+				(id, invite_code, normal_pin_hash, duress_pin_hash, handle, admin, suspended_at)
+				VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+				params![
+					user.id,
+					user.invite_code,
+					user.normal_pin_hash,
+					user.duress_pin_hash,
+					user.handle,
+					user.admin,
+					user.suspended_at
+				],
+			)?;
+			tx.commit()?;
+			Ok(true)
+		})
+		.await
+	}
+
+	async fn add_follow(&self, follower_id: &str, followed_id: &str) -> Result<(), StoreError> {
+		let follower_id = follower_id.to_string();
+		let followed_id = followed_id.to_string();
+		self.call(move |conn| {
+			conn.execute(
+				"INSERT OR IGNORE INTO follows (follower_id, followed_id) VALUES (?1, ?2)",
+				[follower_id, followed_id],
+			)?;
+			Ok(())
+		})
+		.await
+	}
+
+	async fn remove_follow(&self, follower_id: &str, followed_id: &str) -> Result<(), StoreError> {
+		let follower_id = follower_id.to_string();
+		let followed_id = followed_id.to_string();
+		self.call(move |conn| {
+			conn.execute(
+				"DELETE FROM follows WHERE follower_id = ?1 AND followed_id = ?2",
+				[follower_id, followed_id],
+			)?;
+			Ok(())
+		})
+		.await
+	}
+
+	async fn get_follows(&self, followed_id: &str) -> Result<Vec<Follow>, StoreError> {
+		let followed_id = followed_id.to_string();
+		self.call(move |conn| {
+			let mut stmt = conn.prepare("SELECT * FROM follows WHERE followed_id = ?1")?;
+			let follows = stmt
+				.query_map([followed_id], follow_from_row)?
+				.collect::<Result<Vec<_>, _>>()?;
+			Ok(follows)
+		})
+		.await
+	}
+
+	async fn get_following(&self, follower_id: &str) -> Result<Vec<Follow>, StoreError> {
+		let follower_id = follower_id.to_string();
+		self.call(move |conn| {
+			let mut stmt = conn.prepare("SELECT * FROM follows WHERE follower_id = ?1")?;
+			let follows = stmt
+				.query_map([follower_id], follow_from_row)?
+				.collect::<Result<Vec<_>, _>>()?;
+			Ok(follows)
+		})
+		.await
+	}
+
+	async fn get_followers_page(
+		&self,
+		followed_id: &str,
+		after: Option<&FollowCursor>,
+		limit: usize,
+	) -> Result<FollowPage, StoreError> {
+		let followed_id = followed_id.to_string();
+		let after = after.cloned();
+		self.call(move |conn| {
+			let mut stmt = conn.prepare(
+				"SELECT * FROM follows WHERE followed_id = ?1 AND follower_id > ?2
+				ORDER BY follower_id LIMIT ?3",
+			)?;
+			let follows = stmt
+				.query_map(
+					params![
+						followed_id,
+						after
+							.as_ref()
+							.map_or("", |cursor| cursor.follower_id.as_str()),
+						limit + 1
+					],
+					follow_from_row,
+				)?
+				.collect::<Result<Vec<_>, _>>()?;
+			Ok(follow_page(follows, limit))
+		})
+		.await
+	}
+
+	async fn get_following_page(
+		&self,
+		follower_id: &str,
+		after: Option<&FollowCursor>,
+		limit: usize,
+	) -> Result<FollowPage, StoreError> {
+		let follower_id = follower_id.to_string();
+		let after = after.cloned();
+		self.call(move |conn| {
+			let mut stmt = conn.prepare(
+				"SELECT * FROM follows WHERE follower_id = ?1 AND followed_id > ?2
+				ORDER BY followed_id LIMIT ?3",
+			)?;
+			let follows = stmt
+				.query_map(
+					params![
+						follower_id,
+						after
+							.as_ref()
+							.map_or("", |cursor| cursor.followed_id.as_str()),
+						limit + 1
+					],
+					follow_from_row,
+				)?
+				.collect::<Result<Vec<_>, _>>()?;
+			Ok(follow_page(follows, limit))
+		})
+		.await
+	}
+
+	async fn count_followers(&self, followed_id: &str) -> Result<usize, StoreError> {
+		let followed_id = followed_id.to_string();
+		self.call(move |conn| {
+			let count = conn.query_row(
+				"SELECT COUNT(*) FROM follows WHERE followed_id = ?1",
+				[followed_id],
+				|row| row.get(0),
+			)?;
+			Ok(count)
+		})
+		.await
+	}
+
+	async fn count_following(&self, follower_id: &str) -> Result<usize, StoreError> {
+		let follower_id = follower_id.to_string();
+		self.call(move |conn| {
+			let count = conn.query_row(
+				"SELECT COUNT(*) FROM follows WHERE follower_id = ?1",
+				[follower_id],
+				|row| row.get(0),
+			)?;
+			Ok(count)
+		})
+		.await
+	}
+
+	async fn save_follow_request(&self, request: &FollowRequest) -> Result<(), StoreError> {
+		let request = request.clone();
+		self.call(move |conn| {
+			conn.execute(
+				"INSERT OR REPLACE INTO follow_requests (follower_id, followed_id, requested_at)
+				VALUES (?1, ?2, ?3)",
+				params![
+					request.follower_id,
+					request.followed_id,
+					request.requested_at
+				],
+			)?;
+			Ok(())
+		})
+		.await
+	}
+
+	async fn get_follow_request(
+		&self,
+		follower_id: &str,
+		followed_id: &str,
+	) -> Result<Option<FollowRequest>, StoreError> {
+		let follower_id = follower_id.to_string();
+		let followed_id = followed_id.to_string();
+		self.call(move |conn| {
+			let request = conn
+				.query_row(
+					"SELECT * FROM follow_requests WHERE follower_id = ?1 AND followed_id = ?2",
+					[follower_id, followed_id],
+					follow_request_from_row,
+				)
+				.optional()?;
+			Ok(request)
+		})
+		.await
+	}
+
+	async fn get_follow_requests(
+		&self,
+		followed_id: &str,
+	) -> Result<Vec<FollowRequest>, StoreError> {
+		let followed_id = followed_id.to_string();
+		self.call(move |conn| {
+			let mut stmt = conn.prepare(
+				"SELECT * FROM follow_requests WHERE followed_id = ?1 ORDER BY requested_at",
+			)?;
+			let requests = stmt
+				.query_map([followed_id], follow_request_from_row)?
+				.collect::<Result<Vec<_>, _>>()?;
+			Ok(requests)
+		})
+		.await
+	}
+
+	async fn delete_follow_request(
+		&self,
+		follower_id: &str,
+		followed_id: &str,
+	) -> Result<(), StoreError> {
+		let follower_id = follower_id.to_string();
+		let followed_id = followed_id.to_string();
+		self.call(move |conn| {
+			conn.execute(
+				"DELETE FROM follow_requests WHERE follower_id = ?1 AND followed_id = ?2",
+				[follower_id, followed_id],
+			)?;
+			Ok(())
+		})
+		.await
+	}
+
+	async fn save_block(&self, block: &Block) -> Result<(), StoreError> {
+		let block = block.clone();
+		self.call(move |conn| {
+			conn.execute(
+				"INSERT OR REPLACE INTO blocks (blocker_id, blocked_id, created_at)
+				VALUES (?1, ?2, ?3)",
+				params![block.blocker_id, block.blocked_id, block.created_at],
+			)?;
+			Ok(())
+		})
+		.await
+	}
+
+	async fn get_block(
+		&self,
+		blocker_id: &str,
+		blocked_id: &str,
+	) -> Result<Option<Block>, StoreError> {
+		let blocker_id = blocker_id.to_string();
+		let blocked_id = blocked_id.to_string();
+		self.call(move |conn| {
+			let block = conn
+				.query_row(
+					"SELECT * FROM blocks WHERE blocker_id = ?1 AND blocked_id = ?2",
+					[blocker_id, blocked_id],
+					block_from_row,
+				)
+				.optional()?;
+			Ok(block)
+		})
+		.await
+	}
+
+	async fn get_blocks(&self, blocker_id: &str) -> Result<Vec<Block>, StoreError> {
+		let blocker_id = blocker_id.to_string();
+		self.call(move |conn| {
+			let mut stmt =
+				conn.prepare("SELECT * FROM blocks WHERE blocker_id = ?1 ORDER BY created_at")?;
+			let blocks = stmt
+				.query_map([blocker_id], block_from_row)?
+				.collect::<Result<Vec<_>, _>>()?;
+			Ok(blocks)
+		})
+		.await
+	}
+
+	async fn delete_block(&self, blocker_id: &str, blocked_id: &str) -> Result<(), StoreError> {
+		let blocker_id = blocker_id.to_string();
+		let blocked_id = blocked_id.to_string();
+		self.call(move |conn| {
+			conn.execute(
+				"DELETE FROM blocks WHERE blocker_id = ?1 AND blocked_id = ?2",
+				[blocker_id, blocked_id],
+			)?;
+			Ok(())
+		})
+		.await
+	}
+
+	async fn get_login_attempts(&self, user_id: &str) -> Result<Option<LoginAttempts>, StoreError> {
+		let user_id = user_id.to_string();
+		self.call(move |conn| {
+			let attempts = conn
+				.query_row(
+					"SELECT * FROM login_attempts WHERE user_id = ?1",
+					[user_id],
+					login_attempts_from_row,
+				)
+				.optional()?;
+			Ok(attempts)
+		})
+		.await
+	}
+
+	async fn record_login_failure(
+		&self,
+		user_id: &str,
+		now: DateTime<Utc>,
+	) -> Result<LoginAttempts, StoreError> {
+		let user_id = user_id.to_string();
+		self.call(move |conn| {
+			let cutoff = now - Duration::minutes(LOGIN_WINDOW_MINUTES);
+			// A window that has ended starts over at `now`
+			let attempts = conn.query_row(
+				"INSERT INTO login_attempts (user_id, failures, window_start) VALUES (?1, 1, ?2)
+				ON CONFLICT (user_id) DO UPDATE SET
+					failures = CASE WHEN window_start > ?3 THEN failures + 1 ELSE 1 END,
+					window_start = CASE WHEN window_start > ?3 THEN window_start ELSE ?2 END
+				RETURNING *",
+				params![user_id, now, cutoff],
+				login_attempts_from_row,
+			)?;
+			Ok(attempts)
+		})
+		.await
+	}
+
+	async fn clear_login_attempts(&self, user_id: &str) -> Result<(), StoreError> {
+		let user_id = user_id.to_string();
+		self.call(move |conn| {
+			conn.execute("DELETE FROM login_attempts WHERE user_id = ?1", [user_id])?;
+			Ok(())
+		})
+		.await
+	}
+
+	async fn log_duress_event(&self, event: &DuressEvent) -> Result<(), StoreError> {
+		let event = event.clone();
+		self.call(move |conn| {
+			conn.execute(
+				"INSERT INTO duress_events
+				(id, user_id, duress_type, message, timestamp, latitude, longitude, accuracy,
+				additional_data, status, test, created_at)
+				VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)",
+				params![
+					event.id,
+					event.user_id,
+					event.duress_type.as_str(),
+					event.message,
+					event.timestamp,
+					event.location.map(|l| l.latitude),
+					event.location.map(|l| l.longitude),
+					event.location.and_then(|l| l.accuracy),
+					event.additional_data.to_string(),
+					event.status.as_str(),
+					event.test,
+					event.created_at
+				],
+			)?;
+			Ok(())
+		})
+		.await
+	}
+
+	async fn update_duress_event(&self, event: &DuressEvent) -> Result<(), StoreError> {
+		let event = event.clone();
+		self.call(move |conn| {
+			conn.execute(
+				"UPDATE duress_events SET status = ?2 WHERE id = ?1",
+				params![event.id, event.status.as_str()],
+			)?;
+			Ok(())
+		})
+		.await
+	}
+
+	async fn get_user_duress_events(
+		&self,
+		user_id: &str,
+		from: Option<DateTime<Utc>>,
+		to: Option<DateTime<Utc>>,
+	) -> Result<Vec<DuressEvent>, StoreError> {
+		let user_id = user_id.to_string();
+		self.call(move |conn| {
+			let mut stmt = conn.prepare(
+				"SELECT * FROM duress_events
+				WHERE user_id = ?1
+				AND (?2 IS NULL OR created_at >= ?2)
+				AND (?3 IS NULL OR created_at <= ?3)
+				ORDER BY created_at",
+			)?;
+			let events = stmt
+				.query_map(params![user_id, from, to], duress_event_from_row)?
+				.collect::<Result<Vec<_>, _>>()?;
+			Ok(events)
+		})
+		.await
+	}
+
+	async fn save_delivery(&self, delivery: &Delivery) -> Result<(), StoreError> {
+		let delivery = delivery.clone();
+		self.call(move |conn| {
+			conn.execute(
+				"INSERT OR REPLACE INTO deliveries
+				(id, event_id, recipient_id, channel, kind, status, error, created_at)
+				VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+				params![
+					delivery.id,
+					delivery.event_id,
+					delivery.recipient_id,
+					delivery.channel,
+					delivery.kind.as_str(),
+					delivery.status.as_str(),
+					delivery.error,
+					delivery.created_at
+				],
+			)?;
+			Ok(())
+		})
+		.await
+	}
+
+	async fn get_event_deliveries(&self, event_id: &str) -> Result<Vec<Delivery>, StoreError> {
+		let event_id = event_id.to_string();
+		self.call(move |conn| {
+			let mut stmt =
+				conn.prepare("SELECT * FROM deliveries WHERE event_id = ?1 ORDER BY created_at")?;
+			let deliveries = stmt
+				.query_map([event_id], delivery_from_row)?
+				.collect::<Result<Vec<_>, _>>()?;
+			Ok(deliveries)
+		})
+		.await
+	}
+
+	async fn enqueue_job(&self, job: &Job) -> Result<bool, StoreError> {
+		let alert = serde_json::to_string(&job.alert)
+			.map_err(|err| rusqlite::Error::ToSqlConversionFailure(Box::new(err)))?;
+		let job = job.clone();
+		self.call(move |conn| {
+			let inserted = conn.execute(
+				"INSERT OR IGNORE INTO jobs
+				(key, recipient_id, channel, alert, attempts, next_attempt_at, last_error, dead,
+				created_at)
+				VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+				params![
+					job.key,
+					job.recipient_id,
+					job.channel,
+					alert,
+					job.attempts,
+					job.next_attempt_at,
+					job.last_error,
+					job.dead,
+					job.created_at
+				],
+			)?;
+			Ok(inserted == 1)
+		})
+		.await
+	}
+
+	async fn get_due_jobs(&self, now: DateTime<Utc>, limit: usize) -> Result<Vec<Job>, StoreError> {
+		self.call(move |conn| {
+			let mut stmt = conn.prepare(
+				"SELECT * FROM jobs WHERE dead = 0 AND next_attempt_at <= ?1
+				ORDER BY next_attempt_at LIMIT ?2",
+			)?;
+			let jobs = stmt
+				.query_map(params![now, limit], job_from_row)?
+				.collect::<Result<Vec<_>, _>>()?;
+			Ok(jobs)
+		})
+		.await
+	}
+
+	async fn claim_job(
+		&self,
+		key: &str,
+		expected: DateTime<Utc>,
+		until: DateTime<Utc>,
+	) -> Result<bool, StoreError> {
+		let key = key.to_string();
+		self.call(move |conn| {
+			let claimed = conn.execute(
+				"UPDATE jobs SET next_attempt_at = ?3 WHERE key = ?1 AND next_attempt_at = ?2",
+				params![key, expected, until],
+			)?;
+			Ok(claimed == 1)
+		})
+		.await
+	}
+
+	async fn update_job(&self, job: &Job) -> Result<(), StoreError> {
+		let job = job.clone();
+		self.call(move |conn| {
+			conn.execute(
+				"UPDATE jobs SET attempts = ?2, next_attempt_at = ?3, last_error = ?4, dead = ?5
+				WHERE key = ?1",
+				params![
+					job.key,
+					job.attempts,
+					job.next_attempt_at,
+					job.last_error,
+					job.dead
+				],
+			)?;
+			Ok(())
+		})
+		.await
+	}
+
+	async fn delete_job(&self, key: &str) -> Result<(), StoreError> {
+		let key = key.to_string();
+		self.call(move |conn| {
+			conn.execute("DELETE FROM jobs WHERE key = ?1", [key])?;
+			Ok(())
+		})
+		.await
+	}
+
+	async fn get_dead_jobs(&self, limit: usize) -> Result<Vec<Job>, StoreError> {
+		self.call(move |conn| {
+			let mut stmt =
+				conn.prepare("SELECT * FROM jobs WHERE dead = 1 ORDER BY created_at LIMIT ?1")?;
+			let jobs = stmt
+				.query_map([limit], job_from_row)?
+				.collect::<Result<Vec<_>, _>>()?;
+			Ok(jobs)
+		})
+		.await
+	}
+
+	async fn save_push_subscription(
+		&self,
+		subscription: &PushSubscription,
+	) -> Result<(), StoreError> {
+		let subscription = subscription.clone();
+		self.call(move |conn| {
+			conn.execute(
+				"INSERT OR REPLACE INTO push_subscriptions
+				(user_id, id, endpoint, p256dh, auth, created_at)
+				VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+				params![
+					subscription.user_id,
+					subscription.id,
+					subscription.endpoint,
+					subscription.p256dh,
+					subscription.auth,
+					subscription.created_at
+				],
+			)?;
+			Ok(())
+		})
+		.await
+	}
+
+	async fn get_push_subscriptions(
+		&self,
+		user_id: &str,
+	) -> Result<Vec<PushSubscription>, StoreError> {
+		let user_id = user_id.to_string();
+		self.call(move |conn| {
+			let mut stmt = conn.prepare(
+				"SELECT * FROM push_subscriptions WHERE user_id = ?1 ORDER BY created_at",
+			)?;
+			let subscriptions = stmt
+				.query_map([user_id], push_subscription_from_row)?
+				.collect::<Result<Vec<_>, _>>()?;
+			Ok(subscriptions)
+		})
+		.await
+	}
+
+	async fn delete_push_subscription(&self, user_id: &str, id: &str) -> Result<(), StoreError> {
+		let user_id = user_id.to_string();
+		let id = id.to_string();
+		self.call(move |conn| {
+			conn.execute(
+				"DELETE FROM push_subscriptions WHERE user_id = ?1 AND id = ?2",
+				params![user_id, id],
+			)?;
+			Ok(())
+		})
+		.await
+	}
+
+	async fn save_checkin(&self, checkin: &Checkin) -> Result<(), StoreError> {
+		let checkin = checkin.clone();
+		self.call(move |conn| {
+			conn.execute(
+				"INSERT INTO checkins
+				(id, user_id, latitude, longitude, accuracy, timestamp, created_at)
+				VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+				params![
+					checkin.id,
+					checkin.user_id,
+					checkin.location.latitude,
+					checkin.location.longitude,
+					checkin.location.accuracy,
+					checkin.timestamp,
+					checkin.created_at
+				],
+			)?;
+			Ok(())
+		})
+		.await
+	}
+
+	async fn get_last_checkin(&self, user_id: &str) -> Result<Option<Checkin>, StoreError> {
+		let user_id = user_id.to_string();
+		self.call(move |conn| {
+			let checkin = conn
+				.query_row(
+					"SELECT * FROM checkins WHERE user_id = ?1 ORDER BY created_at DESC LIMIT 1",
+					[user_id],
+					checkin_from_row,
+				)
+				.optional()?;
+			Ok(checkin)
+		})
+		.await
+	}
+
+	async fn save_user_location(&self, location: &UserLocation) -> Result<(), StoreError> {
+		let location = location.clone();
+		self.call(move |conn| {
+			conn.execute(
+				"INSERT OR REPLACE INTO user_locations
+				(user_id, cell, latitude, longitude, accuracy, updated_at)
+				VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+				params![
+					location.user_id,
+					location.cell,
+					location.location.latitude,
+					location.location.longitude,
+					location.location.accuracy,
+					location.updated_at
+				],
+			)?;
+			Ok(())
+		})
+		.await
+	}
+
+	async fn get_user_location(&self, user_id: &str) -> Result<Option<UserLocation>, StoreError> {
+		let user_id = user_id.to_string();
+		self.call(move |conn| {
+			let location = conn
+				.query_row(
+					"SELECT * FROM user_locations WHERE user_id = ?1",
+					[user_id],
+					user_location_from_row,
+				)
+				.optional()?;
+			Ok(location)
+		})
+		.await
+	}
+
+	async fn get_user_locations_in_cells(
+		&self,
+		cells: &[String],
+	) -> Result<Vec<UserLocation>, StoreError> {
+		let cells = cells.to_vec();
+		self.call(move |conn| {
+			let mut stmt = conn.prepare("SELECT * FROM user_locations WHERE cell = ?1")?;
+			let mut locations = Vec::new();
+			for cell in cells {
+				locations.extend(
+					stmt.query_map([cell], user_location_from_row)?
+						.collect::<Result<Vec<_>, _>>()?,
+				);
+			}
+			Ok(locations)
+		})
+		.await
+	}
+
+	async fn save_test_mode(&self, test_mode: &TestMode) -> Result<(), StoreError> {
+		let test_mode = test_mode.clone();
+		self.call(move |conn| {
+			conn.execute(
+				"INSERT OR REPLACE INTO test_modes (user_id, audience, started_at, expires_at)
+				VALUES (?1, ?2, ?3, ?4)",
+				params![
+					test_mode.user_id,
+					test_mode.audience.as_str(),
+					test_mode.started_at,
+					test_mode.expires_at
+				],
+			)?;
+			Ok(())
+		})
+		.await
+	}
+
+	async fn get_test_mode(&self, user_id: &str) -> Result<Option<TestMode>, StoreError> {
+		let user_id = user_id.to_string();
+		self.call(move |conn| {
+			let test_mode = conn
+				.query_row(
+					"SELECT * FROM test_modes WHERE user_id = ?1",
+					[user_id],
+					|row| {
+						let audience: String = row.get("audience")?;
+						Ok(TestMode {
+							user_id: row.get("user_id")?,
+							audience: TestAudience::parse(&audience).unwrap_or_default(),
+							started_at: row.get("started_at")?,
+							expires_at: row.get("expires_at")?,
+						})
+					},
+				)
+				.optional()?;
+			Ok(test_mode)
+		})
+		.await
+	}
+
+	async fn delete_test_mode(&self, user_id: &str) -> Result<(), StoreError> {
+		let user_id = user_id.to_string();
+		self.call(move |conn| {
+			conn.execute("DELETE FROM test_modes WHERE user_id = ?1", [user_id])?;
+			Ok(())
+		})
+		.await
+	}
+
+	async fn get_user_preferences(
+		&self,
+		user_id: &str,
+	) -> Result<Option<UserPreferences>, StoreError> {
+		let user_id = user_id.to_string();
+		self.call(move |conn| {
+			let preferences = conn
+				.query_row(
+					"SELECT * FROM user_preferences WHERE user_id = ?1",
+					[user_id],
+					preferences_from_row,
+				)
+				.optional()?;
+			Ok(preferences)
+		})
+		.await
+	}
+
+	async fn update_user_preferences(
+		&self,
+		user_id: &str,
+		preferences: &UserPreferences,
+	) -> Result<(), StoreError> {
+		let user_id = user_id.to_string();
+		let preferences = preferences.clone();
+		self.call(move |conn| {
+			conn.execute(
+				"INSERT OR REPLACE INTO user_preferences
+				(user_id, version, broadcast_duress, receive_duress_broadcasts, channel_push,
+				channel_sms, channel_email, channel_webhook, quiet_start, quiet_end,
+				quiet_utc_offset_minutes, quiet_duress_overrides, proximity_radius_m,
+				checkin_interval_minutes, contact_email, contact_webhook_url)
+				VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16)",
+				params![
+					user_id,
+					preferences.version,
+					preferences.broadcast_duress,
+					preferences.receive_duress_broadcasts,
+					preferences.channels.push,
+					preferences.channels.sms,
+					preferences.channels.email,
+					preferences.channels.webhook,
+					preferences.quiet_hours.map(|q| q.start),
+					preferences.quiet_hours.map(|q| q.end),
+					preferences.quiet_hours.map_or(0, |q| q.utc_offset_minutes),
+					preferences.quiet_hours.is_none_or(|q| q.duress_overrides),
+					preferences.proximity_radius_m,
+					preferences.checkin_interval_minutes,
+					preferences.contacts.email,
+					preferences.contacts.webhook_url
+				],
+			)?;
+			Ok(())
+		})
+		.await
+	}
+}
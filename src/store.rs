@@ -0,0 +1,274 @@
+// store.rs
+use std::fmt;
+use std::sync::Arc;
+
+use actix_web::error::BlockingError;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use tracing::info;
+
+use crate::block_db::Block;
+use crate::checkin_db::Checkin;
+use crate::db::{Invite, Session, User};
+use crate::delivery_db::{Delivery, Job};
+use crate::duress_db::{DuressEvent, TestMode, UserPreferences};
+use crate::dynamo_store::DynamoStore;
+use crate::follow_db::{Follow, FollowCursor, FollowPage, FollowRequest};
+use crate::location_db::UserLocation;
+use crate::login_db::LoginAttempts;
+use crate::memory_store::MemoryStore;
+use crate::push_db::PushSubscription;
+use crate::sqlite_store::SqliteStore;
+
+#[derive(Debug)]
+pub enum StoreError {
+	DynamoDb(Box<aws_sdk_dynamodb::Error>),
+	Sqlite(rusqlite::Error),
+	// A blocking store call panicked or could not be scheduled
+	Blocking(BlockingError),
+}
+
+impl fmt::Display for StoreError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			StoreError::DynamoDb(err) => write!(f, "DynamoDB error: {}", err),
+			StoreError::Sqlite(err) => write!(f, "SQLite error: {}", err),
+			StoreError::Blocking(err) => write!(f, "Blocking store call failed: {}", err),
+		}
+	}
+}
+
+impl std::error::Error for StoreError {
+	fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+		match self {
+			StoreError::DynamoDb(err) => Some(err.as_ref()),
+			StoreError::Sqlite(err) => Some(err),
+			StoreError::Blocking(err) => Some(err),
+		}
+	}
+}
+
+impl From<aws_sdk_dynamodb::Error> for StoreError {
+	fn from(err: aws_sdk_dynamodb::Error) -> Self {
+		StoreError::DynamoDb(Box::new(err))
+	}
+}
+
+impl From<rusqlite::Error> for StoreError {
+	fn from(err: rusqlite::Error) -> Self {
+		StoreError::Sqlite(err)
+	}
+}
+
+impl From<BlockingError> for StoreError {
+	fn from(err: BlockingError) -> Self {
+		StoreError::Blocking(err)
+	}
+}
+
+// Persistence used by the handlers. Injected as `web::Data<dyn Store>` so the
+// API can run against DynamoDB in production, SQLite for self-hosted
+// deployments and in memory for local work.
+#[async_trait]
+pub trait Store: Send + Sync {
+	// Users
+	async fn save_user(&self, user: &User) -> Result<(), StoreError>;
+	async fn get_user(&self, user_id: &str) -> Result<Option<User>, StoreError>;
+	// Users who registered with the given invite code
+	async fn get_users_by_invite(&self, code: &str) -> Result<Vec<User>, StoreError>;
+	// Set `suspended_at` on a user, and nothing else. Returns false if the
+	// user does not exist or is already suspended.
+	async fn suspend_user(
+		&self,
+		user_id: &str,
+		suspended_at: DateTime<Utc>,
+	) -> Result<bool, StoreError>;
+	// Clear `suspended_at`. Returns false if the user does not exist.
+	async fn unsuspend_user(&self, user_id: &str) -> Result<bool, StoreError>;
+
+	// Handles. Reserving one fails, returning false, if another user holds it.
+	async fn reserve_handle(&self, handle: &str, user_id: &str) -> Result<bool, StoreError>;
+	async fn release_handle(&self, handle: &str) -> Result<(), StoreError>;
+
+	// Sessions
+	async fn save_session(&self, session: &Session) -> Result<(), StoreError>;
+	async fn get_session(&self, session_id: &str) -> Result<Option<Session>, StoreError>;
+	// Log a user out everywhere
+	async fn delete_user_sessions(&self, user_id: &str) -> Result<(), StoreError>;
+	async fn delete_session(&self, session_id: &str) -> Result<(), StoreError>;
+
+	// Invites
+	async fn save_invite(&self, invite: &Invite) -> Result<(), StoreError>;
+	async fn get_invite(&self, code: &str) -> Result<Option<Invite>, StoreError>;
+	async fn get_user_invites(&self, user_id: &str) -> Result<Vec<Invite>, StoreError>;
+	// Rewrite every field of an invite but `invite_count`, which only
+	// `create_invited_user` may change. Unset optional fields are cleared.
+	async fn update_invite(&self, invite: &Invite) -> Result<(), StoreError>;
+	// Mark an invite revoked without touching anything else on it
+	async fn revoke_invite(&self, code: &str) -> Result<(), StoreError>;
+	// Save a new user and count them against their invite, all or nothing.
+	// Writes nothing and returns false if the invite is revoked, expired at
+	// `now` or used up.
+	async fn create_invited_user(
+		&self,
+		user: &User,
+		now: DateTime<Utc>,
+	) -> Result<bool, StoreError>;
+
+	// Follows
+	async fn add_follow(&self, follower_id: &str, followed_id: &str) -> Result<(), StoreError>;
+	async fn remove_follow(&self, follower_id: &str, followed_id: &str) -> Result<(), StoreError>;
+	async fn get_follows(&self, followed_id: &str) -> Result<Vec<Follow>, StoreError>;
+	// Follows made by `follower_id`, i.e. the users they follow
+	async fn get_following(&self, follower_id: &str) -> Result<Vec<Follow>, StoreError>;
+	// Up to `limit` followers of `followed_id` after `after`, ordered by follower
+	async fn get_followers_page(
+		&self,
+		followed_id: &str,
+		after: Option<&FollowCursor>,
+		limit: usize,
+	) -> Result<FollowPage, StoreError>;
+	// Up to `limit` follows made by `follower_id` after `after`, ordered by
+	// followed user
+	async fn get_following_page(
+		&self,
+		follower_id: &str,
+		after: Option<&FollowCursor>,
+		limit: usize,
+	) -> Result<FollowPage, StoreError>;
+	async fn count_followers(&self, followed_id: &str) -> Result<usize, StoreError>;
+	async fn count_following(&self, follower_id: &str) -> Result<usize, StoreError>;
+
+	// Follow requests awaiting approval
+	async fn save_follow_request(&self, request: &FollowRequest) -> Result<(), StoreError>;
+	async fn get_follow_request(
+		&self,
+		follower_id: &str,
+		followed_id: &str,
+	) -> Result<Option<FollowRequest>, StoreError>;
+	// Pending requests to follow `followed_id`
+	async fn get_follow_requests(
+		&self,
+		followed_id: &str,
+	) -> Result<Vec<FollowRequest>, StoreError>;
+	async fn delete_follow_request(
+		&self,
+		follower_id: &str,
+		followed_id: &str,
+	) -> Result<(), StoreError>;
+
+	// Blocks
+	async fn save_block(&self, block: &Block) -> Result<(), StoreError>;
+	async fn get_block(
+		&self,
+		blocker_id: &str,
+		blocked_id: &str,
+	) -> Result<Option<Block>, StoreError>;
+	// Every user `blocker_id` has blocked
+	async fn get_blocks(&self, blocker_id: &str) -> Result<Vec<Block>, StoreError>;
+	async fn delete_block(&self, blocker_id: &str, blocked_id: &str) -> Result<(), StoreError>;
+
+	// Failed logins
+	async fn get_login_attempts(&self, user_id: &str) -> Result<Option<LoginAttempts>, StoreError>;
+	// Count a failed login at `now`, starting a new window if the last one has
+	// ended, and return the updated attempts
+	async fn record_login_failure(
+		&self,
+		user_id: &str,
+		now: DateTime<Utc>,
+	) -> Result<LoginAttempts, StoreError>;
+	async fn clear_login_attempts(&self, user_id: &str) -> Result<(), StoreError>;
+
+	// Duress events
+	async fn log_duress_event(&self, event: &DuressEvent) -> Result<(), StoreError>;
+	async fn update_duress_event(&self, event: &DuressEvent) -> Result<(), StoreError>;
+	// Events recorded between `from` and `to` (inclusive, either optional), oldest first
+	async fn get_user_duress_events(
+		&self,
+		user_id: &str,
+		from: Option<DateTime<Utc>>,
+		to: Option<DateTime<Utc>>,
+	) -> Result<Vec<DuressEvent>, StoreError>;
+
+	// Alert deliveries. Saving a delivery replaces any with the same ID.
+	async fn save_delivery(&self, delivery: &Delivery) -> Result<(), StoreError>;
+	async fn get_event_deliveries(&self, event_id: &str) -> Result<Vec<Delivery>, StoreError>;
+
+	// Delivery job queue
+	// Add a job unless one with the same key exists; returns whether it was added
+	async fn enqueue_job(&self, job: &Job) -> Result<bool, StoreError>;
+	// Live jobs whose next attempt is due by `now`
+	async fn get_due_jobs(&self, now: DateTime<Utc>, limit: usize) -> Result<Vec<Job>, StoreError>;
+	// Push a job's next attempt from `expected` to `until` unless another
+	// worker already has; returns whether this caller claimed it
+	async fn claim_job(
+		&self,
+		key: &str,
+		expected: DateTime<Utc>,
+		until: DateTime<Utc>,
+	) -> Result<bool, StoreError>;
+	async fn update_job(&self, job: &Job) -> Result<(), StoreError>;
+	async fn delete_job(&self, key: &str) -> Result<(), StoreError>;
+	// Jobs that ran out of attempts
+	async fn get_dead_jobs(&self, limit: usize) -> Result<Vec<Job>, StoreError>;
+
+	// Push subscriptions. Saving replaces any with the same user and ID.
+	async fn save_push_subscription(
+		&self,
+		subscription: &PushSubscription,
+	) -> Result<(), StoreError>;
+	async fn get_push_subscriptions(
+		&self,
+		user_id: &str,
+	) -> Result<Vec<PushSubscription>, StoreError>;
+	async fn delete_push_subscription(&self, user_id: &str, id: &str) -> Result<(), StoreError>;
+
+	// Check-ins
+	async fn save_checkin(&self, checkin: &Checkin) -> Result<(), StoreError>;
+	async fn get_last_checkin(&self, user_id: &str) -> Result<Option<Checkin>, StoreError>;
+
+	// Location index. Each user has one location, replaced on every save.
+	async fn save_user_location(&self, location: &UserLocation) -> Result<(), StoreError>;
+	async fn get_user_location(&self, user_id: &str) -> Result<Option<UserLocation>, StoreError>;
+	async fn get_user_locations_in_cells(
+		&self,
+		cells: &[String],
+	) -> Result<Vec<UserLocation>, StoreError>;
+
+	// Test mode
+	async fn save_test_mode(&self, test_mode: &TestMode) -> Result<(), StoreError>;
+	async fn get_test_mode(&self, user_id: &str) -> Result<Option<TestMode>, StoreError>;
+	async fn delete_test_mode(&self, user_id: &str) -> Result<(), StoreError>;
+
+	// Preferences
+	async fn get_user_preferences(
+		&self,
+		user_id: &str,
+	) -> Result<Option<UserPreferences>, StoreError>;
+	async fn update_user_preferences(
+		&self,
+		user_id: &str,
+		preferences: &UserPreferences,
+	) -> Result<(), StoreError>;
+}
+
+// Select the storage backend from the STORE_BACKEND environment variable.
+// Defaults to DynamoDB; "sqlite" opens the database at SQLITE_PATH and
+// "memory" keeps everything in process.
+pub async fn from_env() -> Arc<dyn Store> {
+	let backend = std::env::var("STORE_BACKEND").unwrap_or_else(|_| "dynamodb".to_string());
+	info!("Using {} store backend", backend);
+
+	match backend.as_str() {
+		"dynamodb" => Arc::new(DynamoStore::from_env().await),
+		"sqlite" => {
+			let path = std::env::var("SQLITE_PATH").unwrap_or_else(|_| "cherubgyre.db".to_string());
+			match SqliteStore::open(&path) {
+				Ok(store) => Arc::new(store),
+				Err(err) => panic!("Failed to open SQLite database {}: {}", path, err),
+			}
+		}
+		"memory" => Arc::new(MemoryStore::new()),
+		other => panic!("Unknown STORE_BACKEND: {}", other),
+	}
+}
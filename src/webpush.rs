@@ -0,0 +1,195 @@
+// webpush.rs
+use aes_gcm::aead::{Aead, KeyInit};
+use aes_gcm::{Aes128Gcm, Nonce};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chrono::{DateTime, Duration, Utc};
+use hkdf::Hkdf;
+use lazy_static::lazy_static;
+use p256::ecdsa::signature::Signer;
+use p256::ecdsa::{Signature, SigningKey};
+use p256::elliptic_curve::sec1::ToEncodedPoint;
+use p256::{PublicKey, SecretKey};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use serde_json::json;
+use sha2::Sha256;
+use tracing::warn;
+
+// Record size advertised in the aes128gcm header. Push messages are a
+// single record, so this only has to exceed the payload.
+const RECORD_SIZE: u32 = 4096;
+
+// Push services refuse messages over 4096 bytes. The header (salt, record
+// size, key length, sender key), the record delimiter and the AES-GCM tag
+// leave this much for the payload.
+pub const MAX_PAYLOAD_BYTES: usize = 4096 - (16 + 4 + 1 + 65) - 1 - 16;
+
+// How long a VAPID token is valid for; push services reject more than 24h
+const VAPID_TOKEN_HOURS: i64 = 12;
+
+lazy_static! {
+	// Key that signs VAPID tokens. Browsers tie each subscription to the public
+	// half, so set VAPID_PRIVATE_KEY anywhere subscriptions must survive a
+	// restart.
+	pub static ref VAPID_KEY: VapidKey = match std::env::var("VAPID_PRIVATE_KEY") {
+		Ok(key) if !key.is_empty() => {
+			VapidKey::from_base64(&key).expect("VAPID_PRIVATE_KEY is not a base64url P-256 key")
+		}
+		_ => {
+			warn!("VAPID_PRIVATE_KEY is not set; using a random per-process VAPID key");
+			VapidKey::generate()
+		}
+	};
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum PushKeyError {
+	// Not valid base64url
+	Encoding,
+	// Not a point on P-256, or an auth secret of the wrong length
+	Key,
+}
+
+impl std::fmt::Display for PushKeyError {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			PushKeyError::Encoding => write!(f, "key is not base64url"),
+			PushKeyError::Key => write!(f, "key is not a valid P-256 key"),
+		}
+	}
+}
+
+impl std::error::Error for PushKeyError {}
+
+fn decode(value: &str) -> Result<Vec<u8>, PushKeyError> {
+	// Browsers send unpadded base64url, but some libraries pad it
+	URL_SAFE_NO_PAD
+		.decode(value.trim_end_matches('='))
+		.map_err(|_| PushKeyError::Encoding)
+}
+
+// The application server key pair identifying us to push services (RFC 8292)
+pub struct VapidKey {
+	secret: SecretKey,
+}
+
+impl VapidKey {
+	pub fn generate() -> Self {
+		Self {
+			secret: SecretKey::random(&mut OsRng),
+		}
+	}
+
+	// Load a raw 32 byte private key, base64url encoded as by most Web Push
+	// tooling
+	pub fn from_base64(key: &str) -> Result<Self, PushKeyError> {
+		let secret = SecretKey::from_slice(&decode(key)?).map_err(|_| PushKeyError::Key)?;
+		Ok(Self { secret })
+	}
+
+	// Uncompressed public key, base64url encoded. Clients pass this to
+	// `pushManager.subscribe` as the `applicationServerKey`.
+	pub fn public_key(&self) -> String {
+		URL_SAFE_NO_PAD.encode(self.secret.public_key().to_encoded_point(false).as_bytes())
+	}
+
+	// `Authorization` header value for a request to `endpoint`
+	pub fn authorization(
+		&self,
+		endpoint: &reqwest::Url,
+		subject: Option<&str>,
+		now: DateTime<Utc>,
+	) -> String {
+		let mut claims = json!({
+			"aud": endpoint.origin().ascii_serialization(),
+			"exp": (now + Duration::hours(VAPID_TOKEN_HOURS)).timestamp(),
+		});
+		if let Some(subject) = subject {
+			claims["sub"] = json!(subject);
+		}
+
+		let signing_input = format!(
+			"{}.{}",
+			URL_SAFE_NO_PAD.encode(json!({"typ": "JWT", "alg": "ES256"}).to_string()),
+			URL_SAFE_NO_PAD.encode(claims.to_string())
+		);
+		let signature: Signature = SigningKey::from(&self.secret).sign(signing_input.as_bytes());
+
+		format!(
+			"vapid t={}.{}, k={}",
+			signing_input,
+			URL_SAFE_NO_PAD.encode(signature.to_bytes()),
+			self.public_key()
+		)
+	}
+}
+
+// A browser's subscription keys: its P-256 public key and auth secret
+pub struct SubscriptionKeys {
+	public_key: PublicKey,
+	auth: [u8; 16],
+}
+
+impl SubscriptionKeys {
+	// Parse the base64url `p256dh` and `auth` values from a PushSubscription
+	pub fn from_base64(p256dh: &str, auth: &str) -> Result<Self, PushKeyError> {
+		let public_key =
+			PublicKey::from_sec1_bytes(&decode(p256dh)?).map_err(|_| PushKeyError::Key)?;
+		let auth = decode(auth)?.try_into().map_err(|_| PushKeyError::Key)?;
+		Ok(Self { public_key, auth })
+	}
+}
+
+// Encrypt a push message body for a subscription with the aes128gcm content
+// coding (RFC 8291), using a fresh key pair and salt
+pub fn encrypt(keys: &SubscriptionKeys, plaintext: &[u8]) -> Vec<u8> {
+	let mut salt = [0u8; 16];
+	OsRng.fill_bytes(&mut salt);
+	encrypt_with(keys, plaintext, &SecretKey::random(&mut OsRng), salt)
+}
+
+// `encrypt` with the sender key pair and salt supplied
+pub fn encrypt_with(
+	keys: &SubscriptionKeys,
+	plaintext: &[u8],
+	sender: &SecretKey,
+	salt: [u8; 16],
+) -> Vec<u8> {
+	let sender_public = sender.public_key().to_encoded_point(false);
+	let receiver_public = keys.public_key.to_encoded_point(false);
+	let shared =
+		p256::ecdh::diffie_hellman(sender.to_nonzero_scalar(), keys.public_key.as_affine());
+
+	// Mix the auth secret and both public keys into the ECDH secret
+	let mut key_info = b"WebPush: info\0".to_vec();
+	key_info.extend_from_slice(receiver_public.as_bytes());
+	key_info.extend_from_slice(sender_public.as_bytes());
+	let mut ikm = [0u8; 32];
+	Hkdf::<Sha256>::new(Some(&keys.auth), shared.raw_secret_bytes())
+		.expand(&key_info, &mut ikm)
+		.expect("32 bytes is a valid HKDF-SHA256 length");
+
+	let prk = Hkdf::<Sha256>::new(Some(&salt), &ikm);
+	let mut cek = [0u8; 16];
+	let mut nonce = [0u8; 12];
+	prk.expand(b"Content-Encoding: aes128gcm\0", &mut cek)
+		.expect("16 bytes is a valid HKDF-SHA256 length");
+	prk.expand(b"Content-Encoding: nonce\0", &mut nonce)
+		.expect("12 bytes is a valid HKDF-SHA256 length");
+
+	// A single record, marked as the last with a 0x02 delimiter
+	let mut record = plaintext.to_vec();
+	record.push(2);
+	let ciphertext = Aes128Gcm::new_from_slice(&cek)
+		.expect("16 bytes is a valid AES-128 key")
+		.encrypt(Nonce::from_slice(&nonce), record.as_slice())
+		.expect("AES-GCM encryption of an in-memory buffer cannot fail");
+
+	let mut body = salt.to_vec();
+	body.extend_from_slice(&RECORD_SIZE.to_be_bytes());
+	body.push(sender_public.len() as u8);
+	body.extend_from_slice(sender_public.as_bytes());
+	body.extend_from_slice(&ciphertext);
+	body
+}
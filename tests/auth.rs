@@ -0,0 +1,50 @@
+mod common;
+
+use actix_web::test;
+use common::{bearer, init_app, memory_store, register, seed_invite};
+
+#[actix_web::test]
+async fn user_routes_require_matching_token() {
+	let store = memory_store();
+	seed_invite(&store, "invite-1", "invitor").await;
+	let app = init_app(store).await;
+
+	let (alice, alice_token) = register(&app, "invite-1", "1234", "4321").await;
+	let (bob, _) = register(&app, "invite-1", "5678", "8765").await;
+
+	let req = test::TestRequest::get()
+		.uri(&format!("/users/{}/preferences", alice))
+		.to_request();
+	assert_eq!(test::call_service(&app, req).await.status(), 401);
+
+	let req = test::TestRequest::get()
+		.uri(&format!("/users/{}/preferences", alice))
+		.insert_header(bearer(&alice_token))
+		.to_request();
+	assert_eq!(test::call_service(&app, req).await.status(), 200);
+
+	let req = test::TestRequest::get()
+		.uri(&format!("/users/{}/preferences", bob))
+		.insert_header(bearer(&alice_token))
+		.to_request();
+	assert_eq!(test::call_service(&app, req).await.status(), 403);
+
+	let tampered = format!("{}x", alice_token);
+	let req = test::TestRequest::get()
+		.uri(&format!("/users/{}/preferences", alice))
+		.insert_header(bearer(&tampered))
+		.to_request();
+	assert_eq!(test::call_service(&app, req).await.status(), 401);
+
+	// Logging out revokes the token
+	let req = test::TestRequest::post()
+		.uri("/logout")
+		.insert_header(bearer(&alice_token))
+		.to_request();
+	assert_eq!(test::call_service(&app, req).await.status(), 200);
+	let req = test::TestRequest::get()
+		.uri(&format!("/users/{}/preferences", alice))
+		.insert_header(bearer(&alice_token))
+		.to_request();
+	assert_eq!(test::call_service(&app, req).await.status(), 401);
+}
@@ -0,0 +1,145 @@
+mod common;
+
+use actix_web::http::header;
+use actix_web::test;
+use cherubgyre::avatar::Identicon;
+use common::{bearer, init_app, memory_store, register, seed_invite};
+use sha2::{Digest, Sha256};
+
+#[actix_web::test]
+async fn identicons_are_deterministic_and_symmetric() {
+	let svg = Identicon::new("seraph-lagos-spiral").svg();
+	assert_eq!(svg, Identicon::new("seraph-lagos-spiral").svg());
+	assert_ne!(svg, Identicon::new("cherub-accra-arc").svg());
+	assert!(svg.starts_with("<svg xmlns=\"http://www.w3.org/2000/svg\""));
+
+	// Every cell on the left has its mirror image on the right
+	let cells: Vec<(u32, u32)> = svg
+		.split("<rect x=\"")
+		.skip(1)
+		.map(|rect| {
+			let (x, rest) = rect.split_once(".5\" y=\"").unwrap();
+			let (y, _) = rest.split_once(".5\"").unwrap();
+			(x.parse().unwrap(), y.parse().unwrap())
+		})
+		.collect();
+	for (x, y) in &cells {
+		assert!(cells.contains(&(4 - x, *y)));
+	}
+
+	let png = Identicon::new("seraph-lagos-spiral").png();
+	assert_eq!(&png[..8], b"\x89PNG\r\n\x1a\n");
+	assert_eq!(&png[12..16], b"IHDR");
+	assert_eq!(u32::from_be_bytes(png[16..20].try_into().unwrap()), 240);
+	assert!(png.ends_with(&[0, 0, 0, 0, b'I', b'E', b'N', b'D', 0xae, 0x42, 0x60, 0x82]));
+}
+
+#[actix_web::test]
+async fn avatars_are_public_and_cacheable() {
+	let store = memory_store();
+	seed_invite(&store, "invite-1", "invitor").await;
+	let app = init_app(store.clone()).await;
+	let (user_id, token) = register(&app, "invite-1", "1234", "4321").await;
+
+	// No token needed
+	let req = test::TestRequest::get()
+		.uri(&format!("/users/{}/avatar", user_id))
+		.to_request();
+	let resp = test::call_service(&app, req).await;
+	assert_eq!(resp.status(), 200);
+	assert_eq!(
+		resp.headers().get(header::CONTENT_TYPE).unwrap(),
+		"image/svg+xml"
+	);
+	assert!(resp
+		.headers()
+		.get(header::CACHE_CONTROL)
+		.unwrap()
+		.to_str()
+		.unwrap()
+		.contains("max-age"));
+	let etag = resp.headers().get(header::ETAG).unwrap().clone();
+	let body = test::read_body(resp).await;
+	let handle = store
+		.get_user(&user_id)
+		.await
+		.unwrap()
+		.unwrap()
+		.handle
+		.unwrap();
+	assert_eq!(body, Identicon::new(&handle).svg());
+	assert!(!std::str::from_utf8(&body).unwrap().contains(&user_id));
+
+	let req = test::TestRequest::get()
+		.uri(&format!("/users/{}/avatar", user_id))
+		.insert_header((header::IF_NONE_MATCH, etag.clone()))
+		.to_request();
+	assert_eq!(test::call_service(&app, req).await.status(), 304);
+
+	let req = test::TestRequest::get()
+		.uri(&format!("/users/{}/avatar?format=png", user_id))
+		.to_request();
+	let resp = test::call_service(&app, req).await;
+	assert_eq!(
+		resp.headers().get(header::CONTENT_TYPE).unwrap(),
+		"image/png"
+	);
+	assert_ne!(resp.headers().get(header::ETAG).unwrap(), &etag);
+
+	// A new handle is a new face
+	let req = test::TestRequest::post()
+		.uri(&format!("/users/{}/handle", user_id))
+		.insert_header(bearer(&token))
+		.to_request();
+	assert_eq!(test::call_service(&app, req).await.status(), 200);
+	let req = test::TestRequest::get()
+		.uri(&format!("/users/{}/avatar", user_id))
+		.insert_header((header::IF_NONE_MATCH, etag))
+		.to_request();
+	assert_eq!(test::call_service(&app, req).await.status(), 200);
+}
+
+#[actix_web::test]
+async fn avatars_do_not_give_away_handles_or_which_users_exist() {
+	let store = memory_store();
+	seed_invite(&store, "invite-1", "invitor").await;
+	let app = init_app(store.clone()).await;
+	let (user_id, _) = register(&app, "invite-1", "1234", "4321").await;
+	let handle = store
+		.get_user(&user_id)
+		.await
+		.unwrap()
+		.unwrap()
+		.handle
+		.unwrap();
+
+	let req = test::TestRequest::get()
+		.uri(&format!("/users/{}/avatar", user_id))
+		.to_request();
+	let resp = test::call_service(&app, req).await;
+	let etag = resp.headers().get(header::ETAG).unwrap().to_str().unwrap();
+	// Not something anyone could work out from the handle alone
+	let unkeyed = Sha256::digest(format!("cherubgyre-avatar:{}", handle).as_bytes());
+	let unkeyed: String = unkeyed[..8]
+		.iter()
+		.map(|byte| format!("{:02x}", byte))
+		.collect();
+	assert!(!etag.contains(&unkeyed));
+
+	// An unknown ID looks like any other user
+	let req = test::TestRequest::get()
+		.uri("/users/nobody/avatar")
+		.to_request();
+	let resp = test::call_service(&app, req).await;
+	assert_eq!(resp.status(), 200);
+	assert_eq!(
+		resp.headers().get(header::CONTENT_TYPE).unwrap(),
+		"image/svg+xml"
+	);
+	assert!(resp.headers().contains_key(header::ETAG));
+	assert!(resp.headers().contains_key(header::CACHE_CONTROL));
+	assert_eq!(
+		test::read_body(resp).await,
+		Identicon::new("nobody").svg().as_bytes()
+	);
+}
@@ -0,0 +1,210 @@
+mod common;
+
+use std::sync::{Arc, Mutex};
+
+use actix_web::test;
+use async_trait::async_trait;
+use chrono::Utc;
+use cherubgyre::duress_db::Location;
+use cherubgyre::notify::{Alert, Notifier, Notifiers, NotifyError, Recipient};
+use cherubgyre::queue;
+use common::{bearer, follow, init_app_with_notifiers, memory_store, register, seed_invite};
+use serde_json::{json, Value};
+
+// Records who every alert went to
+#[derive(Clone, Default)]
+struct RecordingNotifier {
+	recipients: Arc<Mutex<Vec<String>>>,
+}
+
+#[async_trait]
+impl Notifier for RecordingNotifier {
+	fn channel(&self) -> &'static str {
+		"recording"
+	}
+
+	fn reaches(&self, _recipient: &Recipient) -> bool {
+		true
+	}
+
+	async fn send(&self, recipient: &Recipient, _alert: &Alert) -> Result<(), NotifyError> {
+		self.recipients
+			.lock()
+			.unwrap()
+			.push(recipient.user_id.clone());
+		Ok(())
+	}
+}
+
+const HERE: Location = Location {
+	latitude: 51.5007,
+	longitude: -0.1246,
+	accuracy: Some(5.0),
+};
+
+#[actix_web::test]
+async fn blocked_users_lose_follows_alerts_and_the_map_without_being_told() {
+	let store = memory_store();
+	seed_invite(&store, "invite-1", "invitor").await;
+	let recorder = RecordingNotifier::default();
+	let notifiers = Arc::new(Notifiers::new(vec![Box::new(recorder.clone())]));
+	let app = init_app_with_notifiers(store.clone(), notifiers.clone()).await;
+
+	let (user_id, token) = register(&app, "invite-1", "1234", "4321").await;
+	let (ex, ex_token) = register(&app, "invite-1", "1111", "2222").await;
+	let (stranger, stranger_token) = register(&app, "invite-1", "3333", "4444").await;
+	let (neighbour, neighbour_token) = register(&app, "invite-1", "5555", "6666").await;
+	follow(&app, &ex, &ex_token, &user_id, &token).await;
+	follow(&app, &user_id, &token, &ex, &ex_token).await;
+	for (id, token) in [
+		(&user_id, &token),
+		(&ex, &ex_token),
+		(&stranger, &stranger_token),
+		(&neighbour, &neighbour_token),
+	] {
+		let req = test::TestRequest::post()
+			.uri(&format!("/users/{}/checkin", id))
+			.insert_header(bearer(token))
+			.set_json(json!({"location": HERE, "timestamp": Utc::now()}))
+			.to_request();
+		assert_eq!(test::call_service(&app, req).await.status(), 200);
+	}
+
+	for blocked in [&ex, &stranger] {
+		let req = test::TestRequest::post()
+			.uri(&format!("/users/{}/blocks", user_id))
+			.insert_header(bearer(&token))
+			.set_json(json!({"user_id": blocked}))
+			.to_request();
+		assert_eq!(test::call_service(&app, req).await.status(), 200);
+	}
+	let req = test::TestRequest::post()
+		.uri(&format!("/users/{}/blocks", user_id))
+		.insert_header(bearer(&token))
+		.set_json(json!({"user_id": user_id}))
+		.to_request();
+	assert_eq!(test::call_service(&app, req).await.status(), 400);
+
+	// Both follows are gone
+	assert!(store.get_follows(&user_id).await.unwrap().is_empty());
+	assert!(store.get_following(&user_id).await.unwrap().is_empty());
+
+	// Asking to follow again looks like it worked, but nothing reaches the user
+	let req = test::TestRequest::post()
+		.uri(&format!("/users/{}/follow", ex))
+		.insert_header(bearer(&ex_token))
+		.set_json(json!({"user_id": user_id}))
+		.to_request();
+	let resp = test::call_service(&app, req).await;
+	assert_eq!(resp.status(), 202);
+	assert_eq!(test::read_body(resp).await, "Follow request sent");
+	assert!(store
+		.get_follow_requests(&user_id)
+		.await
+		.unwrap()
+		.is_empty());
+	// The blocker has to unblock first
+	let req = test::TestRequest::post()
+		.uri(&format!("/users/{}/follow", user_id))
+		.insert_header(bearer(&token))
+		.set_json(json!({"user_id": ex}))
+		.to_request();
+	assert_eq!(test::call_service(&app, req).await.status(), 409);
+
+	// A stale follow the block missed still does not show the user on the map
+	store.add_follow(&ex, &user_id).await.unwrap();
+	let req = test::TestRequest::get()
+		.uri(&format!("/users/{}/map", ex))
+		.insert_header(bearer(&ex_token))
+		.to_request();
+	let map_info: Value = test::call_and_read_body_json(&app, req).await;
+	assert!(map_info.as_array().unwrap().is_empty());
+
+	// Only the neighbour hears about a duress alert
+	let req = test::TestRequest::post()
+		.uri(&format!("/users/{}/duress", user_id))
+		.insert_header(bearer(&token))
+		.set_json(json!({
+			"duress_type": "assault",
+			"message": "help",
+			"timestamp": Utc::now(),
+			"location": HERE
+		}))
+		.to_request();
+	assert_eq!(test::call_service(&app, req).await.status(), 200);
+	queue::process_due_jobs(store.as_ref(), &notifiers, Utc::now())
+		.await
+		.unwrap();
+	assert_eq!(
+		*recorder.recipients.lock().unwrap(),
+		vec![neighbour.clone()]
+	);
+
+	let req = test::TestRequest::get()
+		.uri(&format!("/users/{}/blocks", user_id))
+		.insert_header(bearer(&token))
+		.to_request();
+	let blocks: Value = test::call_and_read_body_json(&app, req).await;
+	assert_eq!(blocks.as_array().unwrap().len(), 2);
+	let req = test::TestRequest::delete()
+		.uri(&format!("/users/{}/blocks/{}", user_id, stranger))
+		.insert_header(bearer(&token))
+		.to_request();
+	assert_eq!(test::call_service(&app, req).await.status(), 200);
+	assert!(store
+		.get_block(&user_id, &stranger)
+		.await
+		.unwrap()
+		.is_none());
+	assert!(store.get_block(&user_id, &ex).await.unwrap().is_some());
+}
+
+#[actix_web::test]
+async fn blocked_users_cannot_register_with_the_blockers_invites() {
+	let store = memory_store();
+	seed_invite(&store, "invite-1", "invitor").await;
+	let notifiers = Arc::new(Notifiers::default());
+	let app = init_app_with_notifiers(store.clone(), notifiers).await;
+
+	let (user_id, token) = register(&app, "invite-1", "1234", "4321").await;
+	let (ex, ex_token) = register(&app, "invite-1", "1111", "2222").await;
+	let (_, friend_token) = register(&app, "invite-1", "3333", "4444").await;
+	seed_invite(&store, "from-user", &user_id).await;
+
+	let req = test::TestRequest::post()
+		.uri(&format!("/users/{}/blocks", user_id))
+		.insert_header(bearer(&token))
+		.set_json(json!({"user_id": ex}))
+		.to_request();
+	assert_eq!(test::call_service(&app, req).await.status(), 200);
+
+	let register_as = |token: &str| {
+		test::TestRequest::post()
+			.uri("/register")
+			.insert_header(bearer(token))
+			.set_json(
+				json!({"invite_code": "from-user", "normal_pin": "5555", "duress_pin": "6666"}),
+			)
+			.to_request()
+	};
+	// Told the code is no good, not that they are blocked
+	let resp = test::call_service(&app, register_as(&ex_token)).await;
+	assert_eq!(resp.status(), 400);
+	assert_eq!(
+		test::read_body(resp).await,
+		"Invalid invite code".as_bytes()
+	);
+	assert_eq!(
+		store
+			.get_invite("from-user")
+			.await
+			.unwrap()
+			.unwrap()
+			.invite_count,
+		0
+	);
+
+	// Anyone else signed in can still use it
+	let resp = test::call_service(&app, register_as(&friend_token)).await;
+	assert_eq!(resp.status(), 200);
+}
@@ -0,0 +1,115 @@
+#![allow(dead_code)]
+
+use std::sync::Arc;
+
+use actix_web::body::MessageBody;
+use actix_web::dev::{Service, ServiceResponse};
+use actix_web::{test, web, App};
+use chrono::Utc;
+use cherubgyre::configure;
+use cherubgyre::db::Invite;
+use cherubgyre::memory_store::MemoryStore;
+use cherubgyre::notify::Notifiers;
+use cherubgyre::store::Store;
+use serde_json::{json, Value};
+
+pub fn memory_store() -> Arc<dyn Store> {
+	Arc::new(MemoryStore::new())
+}
+
+pub async fn init_app(
+	store: Arc<dyn Store>,
+) -> impl Service<
+	actix_http::Request,
+	Response = ServiceResponse<impl MessageBody>,
+	Error = actix_web::Error,
+> {
+	init_app_with_notifiers(store, Arc::new(Notifiers::default())).await
+}
+
+pub async fn init_app_with_notifiers(
+	store: Arc<dyn Store>,
+	notifiers: Arc<Notifiers>,
+) -> impl Service<
+	actix_http::Request,
+	Response = ServiceResponse<impl MessageBody>,
+	Error = actix_web::Error,
+> {
+	test::init_service(
+		App::new()
+			.app_data(web::Data::from(store))
+			.app_data(web::Data::from(notifiers))
+			.configure(configure),
+	)
+	.await
+}
+
+pub async fn seed_invite(store: &Arc<dyn Store>, code: &str, invitor_id: &str) {
+	store
+		.save_invite(&Invite {
+			code: code.to_string(),
+			invitor_id: invitor_id.to_string(),
+			invite_count: 0,
+			created_at: Utc::now(),
+			expires_at: None,
+			max_uses: None,
+			revoked: false,
+		})
+		.await
+		.unwrap();
+}
+
+// Register a user through the API, returning (user_id, bearer token)
+pub async fn register<S, B>(
+	app: &S,
+	invite_code: &str,
+	normal_pin: &str,
+	duress_pin: &str,
+) -> (String, String)
+where
+	S: Service<actix_http::Request, Response = ServiceResponse<B>, Error = actix_web::Error>,
+	B: MessageBody,
+{
+	let req = test::TestRequest::post()
+		.uri("/register")
+		.set_json(
+			json!({"invite_code": invite_code, "normal_pin": normal_pin, "duress_pin": duress_pin}),
+		)
+		.to_request();
+	let body: Value = test::call_and_read_body_json(app, req).await;
+	(
+		body["id"].as_str().unwrap().to_string(),
+		body["token"].as_str().unwrap().to_string(),
+	)
+}
+
+// Have the follower ask to follow the followed user, and that user approve it
+pub async fn follow<S, B>(
+	app: &S,
+	follower_id: &str,
+	follower_token: &str,
+	followed_id: &str,
+	followed_token: &str,
+) where
+	S: Service<actix_http::Request, Response = ServiceResponse<B>, Error = actix_web::Error>,
+	B: MessageBody,
+{
+	let req = test::TestRequest::post()
+		.uri(&format!("/users/{}/follow", follower_id))
+		.insert_header(bearer(follower_token))
+		.set_json(json!({"user_id": followed_id}))
+		.to_request();
+	assert_eq!(test::call_service(app, req).await.status(), 202);
+	let req = test::TestRequest::post()
+		.uri(&format!(
+			"/users/{}/follow-requests/{}/approve",
+			followed_id, follower_id
+		))
+		.insert_header(bearer(followed_token))
+		.to_request();
+	assert_eq!(test::call_service(app, req).await.status(), 200);
+}
+
+pub fn bearer(token: &str) -> (&'static str, String) {
+	("Authorization", format!("Bearer {}", token))
+}
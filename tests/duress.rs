@@ -0,0 +1,297 @@
+mod common;
+
+use std::sync::Arc;
+
+use actix_web::test;
+use chrono::Utc;
+use cherubgyre::duress_db::{DuressEvent, DuressStatus, DuressType};
+use cherubgyre::store::Store;
+use common::{bearer, follow, init_app, memory_store, register, seed_invite};
+use serde_json::{json, Value};
+
+#[actix_web::test]
+async fn cancelling_duress_requires_the_normal_pin() {
+	let store = memory_store();
+	seed_invite(&store, "invite-1", "invitor").await;
+	let app = init_app(store.clone()).await;
+	let (user_id, token) = register(&app, "invite-1", "1234", "4321").await;
+
+	let req = test::TestRequest::post()
+		.uri(&format!("/users/{}/duress", user_id))
+		.insert_header(bearer(&token))
+		.set_json(json!({
+			"duress_type": "assault",
+			"message": "help",
+			"timestamp": "2024-10-05T11:57:33Z",
+			"additional_data": {}
+		}))
+		.to_request();
+	assert_eq!(test::call_service(&app, req).await.status(), 200);
+
+	let cancel = |pin: &str| {
+		test::TestRequest::post()
+			.uri(&format!("/users/{}/duress/cancel", user_id))
+			.insert_header(bearer(&token))
+			.set_json(json!({"normal_pin": pin, "confirm": true}))
+			.to_request()
+	};
+
+	assert_eq!(test::call_service(&app, cancel("0000")).await.status(), 401);
+
+	// The duress PIN looks like it worked but leaves the event active
+	let resp = test::call_service(&app, cancel("4321")).await;
+	assert_eq!(resp.status(), 200);
+	let events = store
+		.get_user_duress_events(&user_id, None, None)
+		.await
+		.unwrap();
+	assert_eq!(events[0].status, DuressStatus::Active);
+
+	let resp = test::call_service(&app, cancel("1234")).await;
+	assert_eq!(resp.status(), 200);
+	assert_eq!(
+		wait_for_status(&store, &user_id, DuressStatus::Cancelled).await,
+		DuressStatus::Cancelled
+	);
+}
+
+// The normal PIN ends events in the background; wait for the first event to
+// reach `status`, returning the status it ends up with
+async fn wait_for_status(
+	store: &Arc<dyn Store>,
+	user_id: &str,
+	status: DuressStatus,
+) -> DuressStatus {
+	let mut current = DuressStatus::Active;
+	for _ in 0..50 {
+		let events = store
+			.get_user_duress_events(user_id, None, None)
+			.await
+			.unwrap();
+		current = events[0].status;
+		if current == status {
+			break;
+		}
+		actix_web::rt::time::sleep(std::time::Duration::from_millis(20)).await;
+	}
+	current
+}
+
+#[actix_web::test]
+async fn resolving_with_the_duress_pin_looks_the_same() {
+	let store = memory_store();
+	seed_invite(&store, "invite-1", "invitor").await;
+	let app = init_app(store.clone()).await;
+	let (user_id, token) = register(&app, "invite-1", "1234", "4321").await;
+
+	let req = test::TestRequest::post()
+		.uri(&format!("/users/{}/duress", user_id))
+		.insert_header(bearer(&token))
+		.set_json(json!({
+			"duress_type": "assault",
+			"message": "help",
+			"timestamp": "2024-10-05T11:57:33Z",
+			"additional_data": {}
+		}))
+		.to_request();
+	assert_eq!(test::call_service(&app, req).await.status(), 200);
+	let event_id = store
+		.get_user_duress_events(&user_id, None, None)
+		.await
+		.unwrap()[0]
+		.id
+		.clone();
+
+	let resolve = |event_id: &str, pin: &str| {
+		test::TestRequest::post()
+			.uri(&format!("/users/{}/duress/{}/resolve", user_id, event_id))
+			.insert_header(bearer(&token))
+			.set_json(json!({"normal_pin": pin}))
+			.to_request()
+	};
+
+	// Either PIN gets the same answers
+	for pin in ["4321", "1234"] {
+		let resp = test::call_service(&app, resolve("no-such-event", pin)).await;
+		assert_eq!(resp.status(), 404);
+	}
+	let resp = test::call_service(&app, resolve(&event_id, "4321")).await;
+	assert_eq!(resp.status(), 200);
+	assert_eq!(
+		test::read_body(resp).await,
+		"Duress event resolved".as_bytes()
+	);
+	actix_web::rt::time::sleep(std::time::Duration::from_millis(50)).await;
+	let events = store
+		.get_user_duress_events(&user_id, None, None)
+		.await
+		.unwrap();
+	assert_eq!(events[0].status, DuressStatus::Active);
+
+	let resp = test::call_service(&app, resolve(&event_id, "1234")).await;
+	assert_eq!(resp.status(), 200);
+	assert_eq!(
+		test::read_body(resp).await,
+		"Duress event resolved".as_bytes()
+	);
+	assert_eq!(
+		wait_for_status(&store, &user_id, DuressStatus::Resolved).await,
+		DuressStatus::Resolved
+	);
+}
+
+#[actix_web::test]
+async fn duress_during_test_mode_is_marked_as_test() {
+	let store = memory_store();
+	seed_invite(&store, "invite-1", "invitor").await;
+	let app = init_app(store.clone()).await;
+	let (user_id, token) = register(&app, "invite-1", "1234", "4321").await;
+
+	let trigger = || {
+		test::TestRequest::post()
+			.uri(&format!("/users/{}/duress", user_id))
+			.insert_header(bearer(&token))
+			.set_json(json!({
+				"duress_type": "assault",
+				"message": "help",
+				"timestamp": "2024-10-05T11:57:33Z",
+				"additional_data": {}
+			}))
+			.to_request()
+	};
+	let test_mode = |req: test::TestRequest| {
+		req.uri(&format!("/users/{}/test-mode", user_id))
+			.insert_header(bearer(&token))
+			.to_request()
+	};
+
+	let req = test_mode(test::TestRequest::post().set_json(json!({"minutes": 5})));
+	assert_eq!(test::call_service(&app, req).await.status(), 200);
+	let status: serde_json::Value =
+		test::call_and_read_body_json(&app, test_mode(test::TestRequest::get())).await;
+	assert_eq!(status["active"], true);
+
+	assert_eq!(test::call_service(&app, trigger()).await.status(), 200);
+
+	let req = test_mode(test::TestRequest::delete());
+	assert_eq!(test::call_service(&app, req).await.status(), 200);
+	assert_eq!(test::call_service(&app, trigger()).await.status(), 200);
+
+	let events = store
+		.get_user_duress_events(&user_id, None, None)
+		.await
+		.unwrap();
+	assert!(events[0].test);
+	assert!(!events[1].test);
+}
+
+#[actix_web::test]
+async fn duress_history_is_filtered_paged_and_shared_with_followers() {
+	let store = memory_store();
+	seed_invite(&store, "invite-1", "invitor").await;
+	let app = init_app(store.clone()).await;
+	let (user_id, token) = register(&app, "invite-1", "1234", "4321").await;
+	let (follower_id, follower_token) = register(&app, "invite-1", "5678", "8765").await;
+
+	follow(&app, &follower_id, &follower_token, &user_id, &token).await;
+
+	for duress_type in ["assault", "medical", "assault"] {
+		let req = test::TestRequest::post()
+			.uri(&format!("/users/{}/duress", user_id))
+			.insert_header(bearer(&token))
+			.set_json(json!({
+				"duress_type": duress_type,
+				"message": "help",
+				"timestamp": "2024-10-05T11:57:33Z"
+			}))
+			.to_request();
+		assert_eq!(test::call_service(&app, req).await.status(), 200);
+	}
+	store
+		.log_duress_event(&DuressEvent::new(
+			&user_id,
+			DuressType::DuressPin,
+			"Duress PIN entered at login",
+			Utc::now(),
+		))
+		.await
+		.unwrap();
+
+	let get = |uri: String, token: &str| {
+		test::TestRequest::get()
+			.uri(&uri)
+			.insert_header(bearer(token))
+			.to_request()
+	};
+
+	let page: Value = test::call_and_read_body_json(
+		&app,
+		get(
+			format!("/users/{}/duress?duress_type=assault&limit=1", user_id),
+			&token,
+		),
+	)
+	.await;
+	assert_eq!(page["events"].as_array().unwrap().len(), 1);
+	assert_eq!(page["next_offset"], 1);
+
+	let page: Value = test::call_and_read_body_json(
+		&app,
+		get(
+			format!("/users/{}/duress?duress_type=assault&offset=1", user_id),
+			&token,
+		),
+	)
+	.await;
+	assert_eq!(page["events"].as_array().unwrap().len(), 1);
+	assert!(page["next_offset"].is_null());
+
+	// Past the end, however far
+	let page: Value = test::call_and_read_body_json(
+		&app,
+		get(
+			format!("/users/{}/duress?offset={}", user_id, usize::MAX),
+			&token,
+		),
+	)
+	.await;
+	assert!(page["events"].as_array().unwrap().is_empty());
+	assert!(page["next_offset"].is_null());
+
+	// A session opened with the duress PIN never shows duress PIN alerts
+	let req = test::TestRequest::post()
+		.uri("/login")
+		.set_json(json!({"user_id": user_id, "pin": "4321"}))
+		.to_request();
+	let login: Value = test::call_and_read_body_json(&app, req).await;
+	let duress_token = login["token"].as_str().unwrap();
+	let page: Value = test::call_and_read_body_json(
+		&app,
+		get(format!("/users/{}/duress", user_id), duress_token),
+	)
+	.await;
+	let events = page["events"].as_array().unwrap();
+	assert_eq!(events.len(), 3);
+	assert!(events
+		.iter()
+		.all(|event| event["duress_type"] != "duress_pin"));
+
+	let page: Value = test::call_and_read_body_json(
+		&app,
+		get(
+			format!(
+				"/users/{}/following/duress?duress_type=medical",
+				follower_id
+			),
+			&follower_token,
+		),
+	)
+	.await;
+	let events = page["events"].as_array().unwrap();
+	assert_eq!(events.len(), 1);
+	assert_eq!(events[0]["user_id"], user_id.as_str());
+
+	// Followers cannot read the history directly
+	let req = get(format!("/users/{}/duress", user_id), &follower_token);
+	assert_eq!(test::call_service(&app, req).await.status(), 403);
+}
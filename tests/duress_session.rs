@@ -0,0 +1,485 @@
+mod common;
+
+use std::sync::Arc;
+
+use actix_web::test;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chrono::{Duration, Utc};
+use cherubgyre::auth;
+use cherubgyre::block_db::Block;
+use cherubgyre::duress_db::{DuressEvent, DuressStatus, DuressType, TestAudience, TestMode};
+use cherubgyre::follow_db::FollowRequest;
+use cherubgyre::push_db::PushSubscription;
+use cherubgyre::store::Store;
+use common::{bearer, follow, init_app, memory_store, register, seed_invite};
+use p256::elliptic_curve::sec1::ToEncodedPoint;
+use p256::SecretKey;
+use serde_json::{json, Value};
+
+// A user with a follower, a pending follow request and a session opened with
+// the duress PIN. Nothing the session writes may stick.
+struct Coerced {
+	user_id: String,
+	// Bearer token of the duress session
+	token: String,
+	follower: String,
+	requester: String,
+}
+
+async fn coerced(store: &Arc<dyn Store>) -> Coerced {
+	seed_invite(store, "invite-1", "invitor").await;
+	let app = init_app(store.clone()).await;
+	let (user_id, token) = register(&app, "invite-1", "1234", "4321").await;
+	let (follower, follower_token) = register(&app, "invite-1", "1111", "2222").await;
+	let (requester, _) = register(&app, "invite-1", "3333", "4444").await;
+	follow(&app, &follower, &follower_token, &user_id, &token).await;
+	store
+		.save_follow_request(&FollowRequest::new(&requester, &user_id))
+		.await
+		.unwrap();
+
+	// As a duress PIN login would, without raising the alarm in the background
+	let (_, token) = auth::issue_session(store.as_ref(), &user_id, true)
+		.await
+		.unwrap();
+	Coerced {
+		user_id,
+		token,
+		follower,
+		requester,
+	}
+}
+
+async fn followers(store: &Arc<dyn Store>, user_id: &str) -> Vec<String> {
+	store
+		.get_follows(user_id)
+		.await
+		.unwrap()
+		.into_iter()
+		.map(|follow| follow.follower_id)
+		.collect()
+}
+
+async fn log_active_event(store: &Arc<dyn Store>, user_id: &str) -> String {
+	let event = DuressEvent::new(user_id, DuressType::Assault, "help", Utc::now());
+	store.log_duress_event(&event).await.unwrap();
+	event.id
+}
+
+// Ending events happens in the background; give it the chance to go wrong
+async fn event_status(store: &Arc<dyn Store>, user_id: &str) -> DuressStatus {
+	actix_web::rt::time::sleep(std::time::Duration::from_millis(50)).await;
+	store
+		.get_user_duress_events(user_id, None, None)
+		.await
+		.unwrap()[0]
+		.status
+}
+
+#[actix_web::test]
+async fn duress_sessions_cannot_follow() {
+	let store = memory_store();
+	let user = coerced(&store).await;
+	let app = init_app(store.clone()).await;
+
+	let req = test::TestRequest::post()
+		.uri(&format!("/users/{}/follow", user.user_id))
+		.insert_header(bearer(&user.token))
+		.set_json(json!({"user_id": user.requester}))
+		.to_request();
+	assert_eq!(test::call_service(&app, req).await.status(), 202);
+	assert!(store
+		.get_follow_requests(&user.requester)
+		.await
+		.unwrap()
+		.is_empty());
+}
+
+#[actix_web::test]
+async fn duress_sessions_cannot_unfollow() {
+	let store = memory_store();
+	let user = coerced(&store).await;
+	store
+		.add_follow(&user.user_id, &user.follower)
+		.await
+		.unwrap();
+	let app = init_app(store.clone()).await;
+
+	let req = test::TestRequest::post()
+		.uri(&format!("/users/{}/unfollow", user.user_id))
+		.insert_header(bearer(&user.token))
+		.set_json(json!({"user_id": user.follower}))
+		.to_request();
+	assert_eq!(test::call_service(&app, req).await.status(), 200);
+	assert_eq!(followers(&store, &user.follower).await, vec![user.user_id]);
+}
+
+#[actix_web::test]
+async fn duress_sessions_cannot_approve_follow_requests() {
+	let store = memory_store();
+	let user = coerced(&store).await;
+	let app = init_app(store.clone()).await;
+
+	let answer = |follower_id: &str| {
+		test::TestRequest::post()
+			.uri(&format!(
+				"/users/{}/follow-requests/{}/approve",
+				user.user_id, follower_id
+			))
+			.insert_header(bearer(&user.token))
+			.to_request()
+	};
+	assert_eq!(
+		test::call_service(&app, answer(&user.requester))
+			.await
+			.status(),
+		200
+	);
+	// Unknown requests answer as they always do
+	assert_eq!(
+		test::call_service(&app, answer("nobody")).await.status(),
+		404
+	);
+	assert_eq!(
+		followers(&store, &user.user_id).await,
+		vec![user.follower.clone()]
+	);
+	assert_eq!(
+		store
+			.get_follow_requests(&user.user_id)
+			.await
+			.unwrap()
+			.len(),
+		1
+	);
+}
+
+#[actix_web::test]
+async fn duress_sessions_cannot_deny_follow_requests() {
+	let store = memory_store();
+	let user = coerced(&store).await;
+	let app = init_app(store.clone()).await;
+
+	let req = test::TestRequest::post()
+		.uri(&format!(
+			"/users/{}/follow-requests/{}/deny",
+			user.user_id, user.requester
+		))
+		.insert_header(bearer(&user.token))
+		.to_request();
+	assert_eq!(test::call_service(&app, req).await.status(), 200);
+	assert_eq!(
+		store
+			.get_follow_requests(&user.user_id)
+			.await
+			.unwrap()
+			.len(),
+		1
+	);
+}
+
+#[actix_web::test]
+async fn duress_sessions_cannot_remove_followers() {
+	let store = memory_store();
+	let user = coerced(&store).await;
+	let app = init_app(store.clone()).await;
+
+	let req = test::TestRequest::delete()
+		.uri(&format!(
+			"/users/{}/followers/{}",
+			user.user_id, user.follower
+		))
+		.insert_header(bearer(&user.token))
+		.to_request();
+	assert_eq!(test::call_service(&app, req).await.status(), 200);
+	assert_eq!(followers(&store, &user.user_id).await, vec![user.follower]);
+}
+
+#[actix_web::test]
+async fn duress_sessions_cannot_re_roll_the_handle() {
+	let store = memory_store();
+	let user = coerced(&store).await;
+	let app = init_app(store.clone()).await;
+	let handle = store.get_user(&user.user_id).await.unwrap().unwrap().handle;
+
+	let req = test::TestRequest::post()
+		.uri(&format!("/users/{}/handle", user.user_id))
+		.insert_header(bearer(&user.token))
+		.to_request();
+	let body: Value = test::call_and_read_body_json(&app, req).await;
+	assert_ne!(body["handle"].as_str(), handle.as_deref());
+	assert_eq!(
+		store.get_user(&user.user_id).await.unwrap().unwrap().handle,
+		handle
+	);
+}
+
+#[actix_web::test]
+async fn duress_sessions_cannot_block() {
+	let store = memory_store();
+	let user = coerced(&store).await;
+	let app = init_app(store.clone()).await;
+
+	let req = test::TestRequest::post()
+		.uri(&format!("/users/{}/blocks", user.user_id))
+		.insert_header(bearer(&user.token))
+		.set_json(json!({"user_id": user.follower}))
+		.to_request();
+	let block: Value = test::call_and_read_body_json(&app, req).await;
+	assert_eq!(block["blocked_id"], user.follower.as_str());
+	assert!(store.get_blocks(&user.user_id).await.unwrap().is_empty());
+	assert_eq!(followers(&store, &user.user_id).await, vec![user.follower]);
+}
+
+#[actix_web::test]
+async fn duress_sessions_cannot_unblock() {
+	let store = memory_store();
+	let user = coerced(&store).await;
+	store
+		.save_block(&Block::new(&user.user_id, &user.requester))
+		.await
+		.unwrap();
+	let app = init_app(store.clone()).await;
+
+	let req = test::TestRequest::delete()
+		.uri(&format!(
+			"/users/{}/blocks/{}",
+			user.user_id, user.requester
+		))
+		.insert_header(bearer(&user.token))
+		.to_request();
+	assert_eq!(test::call_service(&app, req).await.status(), 200);
+	assert_eq!(store.get_blocks(&user.user_id).await.unwrap().len(), 1);
+}
+
+#[actix_web::test]
+async fn duress_sessions_cannot_trigger_duress() {
+	let store = memory_store();
+	let user = coerced(&store).await;
+	let app = init_app(store.clone()).await;
+
+	let req = test::TestRequest::post()
+		.uri(&format!("/users/{}/duress", user.user_id))
+		.insert_header(bearer(&user.token))
+		.set_json(json!({
+			"duress_type": "assault",
+			"message": "help",
+			"timestamp": Utc::now(),
+			"location": {"latitude": 51.5, "longitude": -0.12}
+		}))
+		.to_request();
+	let resp = test::call_service(&app, req).await;
+	assert_eq!(resp.status(), 200);
+	assert_eq!(
+		test::read_body(resp).await,
+		"Duress notification triggered".as_bytes()
+	);
+	assert!(store
+		.get_user_duress_events(&user.user_id, None, None)
+		.await
+		.unwrap()
+		.is_empty());
+	assert!(store
+		.get_user_location(&user.user_id)
+		.await
+		.unwrap()
+		.is_none());
+}
+
+#[actix_web::test]
+async fn duress_sessions_cannot_cancel_duress_even_with_the_normal_pin() {
+	let store = memory_store();
+	let user = coerced(&store).await;
+	log_active_event(&store, &user.user_id).await;
+	let app = init_app(store.clone()).await;
+
+	let req = test::TestRequest::post()
+		.uri(&format!("/users/{}/duress/cancel", user.user_id))
+		.insert_header(bearer(&user.token))
+		.set_json(json!({"normal_pin": "1234", "confirm": true}))
+		.to_request();
+	assert_eq!(test::call_service(&app, req).await.status(), 200);
+	assert_eq!(
+		event_status(&store, &user.user_id).await,
+		DuressStatus::Active
+	);
+}
+
+#[actix_web::test]
+async fn duress_sessions_cannot_resolve_duress_even_with_the_normal_pin() {
+	let store = memory_store();
+	let user = coerced(&store).await;
+	let event_id = log_active_event(&store, &user.user_id).await;
+	let app = init_app(store.clone()).await;
+
+	let req = test::TestRequest::post()
+		.uri(&format!(
+			"/users/{}/duress/{}/resolve",
+			user.user_id, event_id
+		))
+		.insert_header(bearer(&user.token))
+		.set_json(json!({"normal_pin": "1234"}))
+		.to_request();
+	assert_eq!(test::call_service(&app, req).await.status(), 200);
+	assert_eq!(
+		event_status(&store, &user.user_id).await,
+		DuressStatus::Active
+	);
+}
+
+#[actix_web::test]
+async fn duress_sessions_cannot_enable_test_mode() {
+	let store = memory_store();
+	let user = coerced(&store).await;
+	let app = init_app(store.clone()).await;
+
+	let req = test::TestRequest::post()
+		.uri(&format!("/users/{}/test-mode", user.user_id))
+		.insert_header(bearer(&user.token))
+		.set_json(json!({"minutes": 30}))
+		.to_request();
+	let resp = test::call_service(&app, req).await;
+	assert_eq!(resp.status(), 200);
+	assert_eq!(
+		test::read_body(resp).await,
+		"Test mode enabled for 30 minutes".as_bytes()
+	);
+	assert!(store.get_test_mode(&user.user_id).await.unwrap().is_none());
+}
+
+#[actix_web::test]
+async fn duress_sessions_cannot_end_test_mode() {
+	let store = memory_store();
+	let user = coerced(&store).await;
+	let now = Utc::now();
+	store
+		.save_test_mode(&TestMode {
+			user_id: user.user_id.clone(),
+			audience: TestAudience::Followers,
+			started_at: now,
+			expires_at: now + Duration::minutes(30),
+		})
+		.await
+		.unwrap();
+	let app = init_app(store.clone()).await;
+
+	let req = test::TestRequest::delete()
+		.uri(&format!("/users/{}/test-mode", user.user_id))
+		.insert_header(bearer(&user.token))
+		.to_request();
+	assert_eq!(test::call_service(&app, req).await.status(), 200);
+	assert!(store.get_test_mode(&user.user_id).await.unwrap().is_some());
+}
+
+#[actix_web::test]
+async fn duress_sessions_cannot_check_in() {
+	let store = memory_store();
+	let user = coerced(&store).await;
+	let app = init_app(store.clone()).await;
+
+	let req = test::TestRequest::post()
+		.uri(&format!("/users/{}/checkin", user.user_id))
+		.insert_header(bearer(&user.token))
+		.set_json(json!({
+			"location": {"latitude": 51.5, "longitude": -0.12},
+			"timestamp": Utc::now()
+		}))
+		.to_request();
+	assert_eq!(test::call_service(&app, req).await.status(), 200);
+	assert!(store
+		.get_last_checkin(&user.user_id)
+		.await
+		.unwrap()
+		.is_none());
+	assert!(store
+		.get_user_location(&user.user_id)
+		.await
+		.unwrap()
+		.is_none());
+}
+
+#[actix_web::test]
+async fn duress_sessions_cannot_update_preferences() {
+	let store = memory_store();
+	let user = coerced(&store).await;
+	let app = init_app(store.clone()).await;
+
+	let req = test::TestRequest::patch()
+		.uri(&format!("/users/{}/preferences", user.user_id))
+		.insert_header(bearer(&user.token))
+		.set_json(json!({"broadcast_duress": false}))
+		.to_request();
+	let preferences: Value = test::call_and_read_body_json(&app, req).await;
+	assert_eq!(preferences["broadcast_duress"], false);
+	let stored = store
+		.get_user_preferences(&user.user_id)
+		.await
+		.unwrap()
+		.unwrap_or_default();
+	assert!(stored.broadcast_duress);
+
+	// Invalid preferences are still refused
+	let req = test::TestRequest::patch()
+		.uri(&format!("/users/{}/preferences", user.user_id))
+		.insert_header(bearer(&user.token))
+		.set_json(json!({"proximity_radius_m": 0}))
+		.to_request();
+	assert_eq!(test::call_service(&app, req).await.status(), 400);
+}
+
+#[actix_web::test]
+async fn duress_sessions_cannot_add_push_subscriptions() {
+	let store = memory_store();
+	let user = coerced(&store).await;
+	let app = init_app(store.clone()).await;
+
+	let browser_key = SecretKey::random(&mut rand::rngs::OsRng);
+	let p256dh =
+		URL_SAFE_NO_PAD.encode(browser_key.public_key().to_encoded_point(false).as_bytes());
+	let req = test::TestRequest::post()
+		.uri(&format!("/users/{}/push-subscriptions", user.user_id))
+		.insert_header(bearer(&user.token))
+		.set_json(json!({
+			"endpoint": "https://93.184.216.34/push/1",
+			"keys": {"p256dh": p256dh, "auth": URL_SAFE_NO_PAD.encode([7u8; 16])}
+		}))
+		.to_request();
+	assert_eq!(test::call_service(&app, req).await.status(), 200);
+	assert!(store
+		.get_push_subscriptions(&user.user_id)
+		.await
+		.unwrap()
+		.is_empty());
+}
+
+#[actix_web::test]
+async fn duress_sessions_cannot_remove_push_subscriptions() {
+	let store = memory_store();
+	let user = coerced(&store).await;
+	let subscription = PushSubscription::new(
+		&user.user_id,
+		"https://93.184.216.34/push/1",
+		"p256dh",
+		"auth",
+	);
+	store.save_push_subscription(&subscription).await.unwrap();
+	let app = init_app(store.clone()).await;
+
+	let req = test::TestRequest::delete()
+		.uri(&format!(
+			"/users/{}/push-subscriptions/{}",
+			user.user_id, subscription.id
+		))
+		.insert_header(bearer(&user.token))
+		.to_request();
+	assert_eq!(test::call_service(&app, req).await.status(), 200);
+	assert_eq!(
+		store
+			.get_push_subscriptions(&user.user_id)
+			.await
+			.unwrap()
+			.len(),
+		1
+	);
+}
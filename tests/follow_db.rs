@@ -0,0 +1,319 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
+use aws_config::{BehaviorVersion, Region};
+use aws_sdk_dynamodb::types::AttributeValue;
+use aws_sdk_dynamodb::Client;
+use cherubgyre::dynamo_store::DynamoStore;
+use cherubgyre::follow_db;
+use cherubgyre::store::Store;
+use serde_json::{json, Map, Value};
+
+type Item = Map<String, Value>;
+// Items by (hash key, range key)
+type Table = BTreeMap<(String, String), Item>;
+
+// Hash and range key attributes of each table and index the follow code uses
+fn key_schema(table: &str, index: Option<&str>) -> (&'static str, Option<&'static str>) {
+	match (table, index) {
+		("Follows", None) => ("follower_id", Some("followed_id")),
+		("Follows", Some("followed_id-index")) => ("followed_id", Some("follower_id")),
+		("Follow", None) => ("id", None),
+		other => panic!("No key schema for {:?}", other),
+	}
+}
+
+fn string(item: &Item, name: &str) -> String {
+	item.get(name)
+		.and_then(|value| value["S"].as_str())
+		.unwrap_or_default()
+		.to_string()
+}
+
+fn key_of(table: &str, index: Option<&str>, item: &Item) -> (String, String) {
+	let (hash, range) = key_schema(table, index);
+	(
+		string(item, hash),
+		range.map(|range| string(item, range)).unwrap_or_default(),
+	)
+}
+
+// Just enough of the DynamoDB JSON protocol for follow_db, kept in memory.
+// Tables are created by the first write to them.
+#[derive(Clone, Default)]
+struct FakeDynamo {
+	tables: Arc<Mutex<BTreeMap<String, Table>>>,
+}
+
+impl FakeDynamo {
+	fn handle(&self, operation: &str, body: Value) -> Result<Value, (&'static str, String)> {
+		let table_name = body["TableName"].as_str().unwrap().to_string();
+		let names = body["ExpressionAttributeNames"].clone();
+		let values = body["ExpressionAttributeValues"].clone();
+		let mut tables = self.tables.lock().unwrap();
+		let not_found = || ("ResourceNotFoundException", table_name.clone());
+
+		match operation {
+			"PutItem" => {
+				let item = body["Item"].as_object().unwrap().clone();
+				let key = key_of(&table_name, None, &item);
+				tables
+					.entry(table_name.clone())
+					.or_default()
+					.insert(key, item);
+				Ok(json!({}))
+			}
+			"DeleteItem" => {
+				let key = key_of(&table_name, None, body["Key"].as_object().unwrap());
+				if let Some(table) = tables.get_mut(&table_name) {
+					table.remove(&key);
+				}
+				Ok(json!({}))
+			}
+			"UpdateItem" => {
+				// Only `SET <attribute> = :<value>`
+				let expression = body["UpdateExpression"].as_str().unwrap();
+				let (attribute, value) = expression
+					.strip_prefix("SET ")
+					.and_then(|rest| rest.split_once(" = "))
+					.unwrap();
+				let key = key_of(&table_name, None, body["Key"].as_object().unwrap());
+				let table = tables.get_mut(&table_name).ok_or_else(not_found)?;
+				let item = table
+					.entry(key)
+					.or_insert_with(|| body["Key"].as_object().unwrap().clone());
+				item.insert(attribute.to_string(), values[value].clone());
+				Ok(json!({}))
+			}
+			"Scan" => {
+				let table = tables.get(&table_name).ok_or_else(not_found)?;
+				// Only `attribute_not_exists(<attribute>)`
+				let missing = body["FilterExpression"].as_str().map(|filter| {
+					filter
+						.strip_prefix("attribute_not_exists(")
+						.and_then(|rest| rest.strip_suffix(')'))
+						.unwrap()
+				});
+				let items: Vec<&Item> = table
+					.values()
+					.filter(|item| missing.is_none_or(|attribute| !item.contains_key(attribute)))
+					.collect();
+				Ok(json!({"Items": items, "Count": items.len(), "ScannedCount": table.len()}))
+			}
+			"Query" => {
+				let index = body["IndexName"].as_str();
+				// Only `#key = :value`
+				let condition = body["KeyConditionExpression"].as_str().unwrap();
+				let (name, value) = condition.split_once(" = ").unwrap();
+				let attribute = names[name].as_str().unwrap();
+				let wanted = values[value]["S"].as_str().unwrap();
+				assert_eq!(attribute, key_schema(&table_name, index).0);
+
+				let empty = BTreeMap::new();
+				let table = tables.get(&table_name).unwrap_or(&empty);
+				let mut items: Vec<&Item> = table
+					.values()
+					.filter(|item| string(item, attribute) == wanted)
+					.collect();
+				items.sort_by_key(|item| key_of(&table_name, index, item));
+				if let Some(start) = body["ExclusiveStartKey"].as_object() {
+					let start = key_of(&table_name, index, start);
+					items.retain(|item| key_of(&table_name, index, item) > start);
+				}
+				let limit = body["Limit"].as_u64().map(|limit| limit as usize);
+				let more = limit.is_some_and(|limit| items.len() > limit);
+				items.truncate(limit.unwrap_or(items.len()));
+
+				let mut response = json!({"Count": items.len(), "ScannedCount": items.len()});
+				if body["Select"] != "COUNT" {
+					response["Items"] = json!(items);
+				}
+				if let (true, Some(last)) = (more, items.last()) {
+					response["LastEvaluatedKey"] = json!(last);
+				}
+				Ok(response)
+			}
+			other => panic!("Unexpected DynamoDB operation {}", other),
+		}
+	}
+}
+
+async fn start_dynamo(fake: FakeDynamo) -> String {
+	let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+	let port = listener.local_addr().unwrap().port();
+	let server = HttpServer::new(move || {
+		let fake = fake.clone();
+		App::new().default_service(web::to(move |req: HttpRequest, body: web::Bytes| {
+			let fake = fake.clone();
+			async move {
+				let operation = req
+					.headers()
+					.get("x-amz-target")
+					.and_then(|value| value.to_str().ok())
+					.and_then(|value| value.strip_prefix("DynamoDB_20120810."))
+					.unwrap_or_default()
+					.to_string();
+				let body: Value = serde_json::from_slice(&body).unwrap();
+				match fake.handle(&operation, body) {
+					Ok(response) => HttpResponse::Ok()
+						.content_type("application/x-amz-json-1.0")
+						.json(response),
+					Err((kind, message)) => HttpResponse::BadRequest()
+						.content_type("application/x-amz-json-1.0")
+						.json(json!({
+							"__type": format!("com.amazonaws.dynamodb.v20120810#{}", kind),
+							"message": message
+						})),
+				}
+			}
+		}))
+	})
+	.workers(1)
+	.listen(listener)
+	.unwrap()
+	.run();
+	actix_web::rt::spawn(server);
+	format!("http://127.0.0.1:{}", port)
+}
+
+async fn dynamo_client(fake: FakeDynamo) -> Client {
+	let config = aws_config::defaults(BehaviorVersion::latest())
+		.region(Region::new("eu-north-1"))
+		.endpoint_url(start_dynamo(fake).await)
+		.test_credentials()
+		.load()
+		.await;
+	Client::new(&config)
+}
+
+fn ids(follows: &[follow_db::Follow], pick: fn(&follow_db::Follow) -> &String) -> Vec<String> {
+	follows.iter().map(|follow| pick(follow).clone()).collect()
+}
+
+fn follower(follow: &follow_db::Follow) -> &String {
+	&follow.follower_id
+}
+
+fn followed(follow: &follow_db::Follow) -> &String {
+	&follow.followed_id
+}
+
+#[actix_web::test]
+async fn follows_are_looked_up_in_both_directions() {
+	let store = DynamoStore::new(dynamo_client(FakeDynamo::default()).await);
+
+	for (follower_id, followed_id) in [
+		("alice", "bob"),
+		("alice", "carol"),
+		("dave", "bob"),
+		// Following twice is the same follow
+		("alice", "bob"),
+	] {
+		store.add_follow(follower_id, followed_id).await.unwrap();
+	}
+
+	let following = store.get_following("alice").await.unwrap();
+	assert_eq!(ids(&following, followed), vec!["bob", "carol"]);
+	let followers = store.get_follows("bob").await.unwrap();
+	assert_eq!(ids(&followers, follower), vec!["alice", "dave"]);
+	assert_eq!(store.count_following("alice").await.unwrap(), 2);
+	assert_eq!(store.count_followers("bob").await.unwrap(), 2);
+	assert_eq!(store.count_followers("alice").await.unwrap(), 0);
+
+	// Pages carry on where the last one stopped
+	let page = store.get_followers_page("bob", None, 1).await.unwrap();
+	assert_eq!(ids(&page.follows, follower), vec!["alice"]);
+	let page = store
+		.get_followers_page("bob", page.next.as_ref(), 1)
+		.await
+		.unwrap();
+	assert_eq!(ids(&page.follows, follower), vec!["dave"]);
+	let page = store.get_following_page("alice", None, 5).await.unwrap();
+	assert_eq!(ids(&page.follows, followed), vec!["bob", "carol"]);
+	assert!(page.next.is_none());
+
+	store.remove_follow("alice", "bob").await.unwrap();
+	let following = store.get_following("alice").await.unwrap();
+	assert_eq!(ids(&following, followed), vec!["carol"]);
+	let followers = store.get_follows("bob").await.unwrap();
+	assert_eq!(ids(&followers, follower), vec!["dave"]);
+}
+
+async fn put_legacy_follow(client: &Client, attributes: &[(&str, &str)]) {
+	let mut request = client.put_item().table_name("Follow");
+	for (name, value) in attributes {
+		request = request.item(*name, AttributeValue::S(value.to_string()));
+	}
+	request.send().await.unwrap();
+}
+
+#[actix_web::test]
+async fn legacy_follows_are_migrated_once() {
+	let fake = FakeDynamo::default();
+	let client = dynamo_client(fake.clone()).await;
+	let store = DynamoStore::new(client.clone());
+
+	// Nothing to do before the legacy table exists
+	assert_eq!(follow_db::migrate_legacy_follows(&client).await.unwrap(), 0);
+
+	put_legacy_follow(
+		&client,
+		&[
+			("id", "0b7c2a5e-3f4d-4d59-9a53-0e1c54f4a001"),
+			("follower_id", "alice"),
+			("followed_id", "bob"),
+		],
+	)
+	.await;
+	// Written under the mistyped name the old read path used
+	put_legacy_follow(
+		&client,
+		&[
+			("id", "0b7c2a5e-3f4d-4d59-9a53-0e1c54f4a002"),
+			("follower`1   _id", "carol"),
+			("followed_id", "bob"),
+		],
+	)
+	.await;
+	// Half a follow is skipped
+	put_legacy_follow(
+		&client,
+		&[
+			("id", "0b7c2a5e-3f4d-4d59-9a53-0e1c54f4a003"),
+			("followed_id", "bob"),
+		],
+	)
+	.await;
+
+	assert_eq!(follow_db::migrate_legacy_follows(&client).await.unwrap(), 2);
+	let followers = store.get_follows("bob").await.unwrap();
+	assert_eq!(ids(&followers, follower), vec!["alice", "carol"]);
+	let following = store.get_following("carol").await.unwrap();
+	assert_eq!(ids(&following, followed), vec!["bob"]);
+	let migrated = fake.tables.lock().unwrap()["Follow"]
+		.values()
+		.filter(|item| item.contains_key("migrated_at"))
+		.count();
+	assert_eq!(migrated, 2);
+
+	// Running it again copies nothing, so a follow removed since stays removed
+	store.remove_follow("alice", "bob").await.unwrap();
+	assert_eq!(follow_db::migrate_legacy_follows(&client).await.unwrap(), 0);
+	let followers = store.get_follows("bob").await.unwrap();
+	assert_eq!(ids(&followers, follower), vec!["carol"]);
+
+	// Rows added to the old table later are still picked up
+	put_legacy_follow(
+		&client,
+		&[
+			("id", "0b7c2a5e-3f4d-4d59-9a53-0e1c54f4a004"),
+			("follower_id", "dave"),
+			("followed_id", "bob"),
+		],
+	)
+	.await;
+	assert_eq!(follow_db::migrate_legacy_follows(&client).await.unwrap(), 1);
+	let followers = store.get_follows("bob").await.unwrap();
+	assert_eq!(ids(&followers, follower), vec!["carol", "dave"]);
+}
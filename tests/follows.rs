@@ -0,0 +1,230 @@
+mod common;
+
+use actix_web::test;
+use common::{bearer, follow, init_app, memory_store, register, seed_invite};
+use serde_json::{json, Value};
+
+#[actix_web::test]
+async fn following_and_followers_are_listed_separately_in_pages() {
+	let store = memory_store();
+	seed_invite(&store, "invite-1", "invitor").await;
+	let app = init_app(store.clone()).await;
+
+	let (user_id, token) = register(&app, "invite-1", "1234", "4321").await;
+	let mut followers = Vec::new();
+	for pin in ["1111", "2222", "3333", "5555", "6666"] {
+		let (follower_id, follower_token) = register(&app, "invite-1", pin, "9999").await;
+		follow(&app, &follower_id, &follower_token, &user_id, &token).await;
+		followers.push((follower_id, follower_token));
+	}
+	let (followed_id, followed_token) = &followers[0];
+	follow(&app, &user_id, &token, followed_id, followed_token).await;
+	let mut followers: Vec<_> = followers.into_iter().map(|(id, _)| id).collect();
+
+	// Walk the followers two at a time
+	let mut listed = Vec::new();
+	let mut cursor: Option<String> = None;
+	loop {
+		let uri = match &cursor {
+			Some(cursor) => format!("/users/{}/followers?limit=2&cursor={}", user_id, cursor),
+			None => format!("/users/{}/followers?limit=2", user_id),
+		};
+		let req = test::TestRequest::get()
+			.uri(&uri)
+			.insert_header(bearer(&token))
+			.to_request();
+		let page: Value = test::call_and_read_body_json(&app, req).await;
+		assert_eq!(page["count"], 5);
+		let follows = page["follows"].as_array().unwrap();
+		assert!(follows.len() <= 2);
+		for follow in follows {
+			assert_eq!(follow["followed_id"], user_id.as_str());
+			listed.push(follow["follower_id"].as_str().unwrap().to_string());
+		}
+		match page["next_cursor"].as_str() {
+			Some(next) => cursor = Some(next.to_string()),
+			None => break,
+		}
+	}
+	listed.sort();
+	followers.sort();
+	assert_eq!(listed, followers);
+
+	// Following is the other direction
+	let req = test::TestRequest::get()
+		.uri(&format!("/users/{}/following", user_id))
+		.insert_header(bearer(&token))
+		.to_request();
+	let page: Value = test::call_and_read_body_json(&app, req).await;
+	assert_eq!(page["count"], 1);
+	assert_eq!(page["next_cursor"], Value::Null);
+	let follows = page["follows"].as_array().unwrap();
+	assert_eq!(follows.len(), 1);
+	assert_eq!(follows[0]["follower_id"], user_id.as_str());
+
+	for query in ["limit=0", "limit=201", "cursor=not-a-cursor"] {
+		let req = test::TestRequest::get()
+			.uri(&format!("/users/{}/followers?{}", user_id, query))
+			.insert_header(bearer(&token))
+			.to_request();
+		assert_eq!(
+			test::call_service(&app, req).await.status(),
+			400,
+			"{}",
+			query
+		);
+	}
+}
+
+#[actix_web::test]
+async fn follows_wait_for_the_followed_user_to_approve_them() {
+	let store = memory_store();
+	seed_invite(&store, "invite-1", "invitor").await;
+	let app = init_app(store.clone()).await;
+
+	let (user_id, token) = register(&app, "invite-1", "1234", "4321").await;
+	let (stranger, stranger_token) = register(&app, "invite-1", "1111", "2222").await;
+	let request_follow = || {
+		test::TestRequest::post()
+			.uri(&format!("/users/{}/follow", stranger))
+			.insert_header(bearer(&stranger_token))
+			.set_json(json!({"user_id": user_id}))
+			.to_request()
+	};
+	let answer = |action: &str| {
+		test::TestRequest::post()
+			.uri(&format!(
+				"/users/{}/follow-requests/{}/{}",
+				user_id, stranger, action
+			))
+			.insert_header(bearer(&token))
+			.to_request()
+	};
+	let follower_count = || {
+		test::TestRequest::get()
+			.uri(&format!("/users/{}/followers", user_id))
+			.insert_header(bearer(&token))
+			.to_request()
+	};
+	let map = || {
+		test::TestRequest::get()
+			.uri(&format!("/users/{}/map", stranger))
+			.insert_header(bearer(&stranger_token))
+			.to_request()
+	};
+
+	// A request is only pending: the stranger is not a follower and sees nothing
+	assert_eq!(
+		test::call_service(&app, request_follow()).await.status(),
+		202
+	);
+	assert_eq!(
+		test::call_service(&app, request_follow()).await.status(),
+		202
+	);
+	let req = test::TestRequest::get()
+		.uri(&format!("/users/{}/follow-requests", user_id))
+		.insert_header(bearer(&token))
+		.to_request();
+	let requests: Value = test::call_and_read_body_json(&app, req).await;
+	let requests = requests.as_array().unwrap();
+	assert_eq!(requests.len(), 1);
+	assert_eq!(requests[0]["follower_id"], stranger.as_str());
+	let page: Value = test::call_and_read_body_json(&app, follower_count()).await;
+	assert_eq!(page["count"], 0);
+	let map_info: Value = test::call_and_read_body_json(&app, map()).await;
+	assert!(map_info.as_array().unwrap().is_empty());
+
+	// Denied, then asked again and approved
+	assert_eq!(test::call_service(&app, answer("deny")).await.status(), 200);
+	assert_eq!(
+		test::call_service(&app, answer("approve")).await.status(),
+		404
+	);
+	assert!(store
+		.get_follow_requests(&user_id)
+		.await
+		.unwrap()
+		.is_empty());
+	assert_eq!(
+		test::call_service(&app, request_follow()).await.status(),
+		202
+	);
+	assert_eq!(
+		test::call_service(&app, answer("approve")).await.status(),
+		200
+	);
+	assert!(store
+		.get_follow_requests(&user_id)
+		.await
+		.unwrap()
+		.is_empty());
+
+	let page: Value = test::call_and_read_body_json(&app, follower_count()).await;
+	assert_eq!(page["count"], 1);
+	let map_info: Value = test::call_and_read_body_json(&app, map()).await;
+	assert_eq!(map_info[0]["user_id"], user_id.as_str());
+	assert_eq!(
+		test::call_service(&app, request_follow()).await.status(),
+		200
+	);
+
+	// Unfollowing withdraws an unanswered request too
+	let req = test::TestRequest::post()
+		.uri(&format!("/users/{}/unfollow", stranger))
+		.insert_header(bearer(&stranger_token))
+		.set_json(json!({"user_id": user_id}))
+		.to_request();
+	assert_eq!(test::call_service(&app, req).await.status(), 200);
+	assert_eq!(
+		test::call_service(&app, request_follow()).await.status(),
+		202
+	);
+	let req = test::TestRequest::post()
+		.uri(&format!("/users/{}/unfollow", stranger))
+		.insert_header(bearer(&stranger_token))
+		.set_json(json!({"user_id": user_id}))
+		.to_request();
+	assert_eq!(test::call_service(&app, req).await.status(), 200);
+	assert!(store
+		.get_follow_requests(&user_id)
+		.await
+		.unwrap()
+		.is_empty());
+	assert!(store.get_follows(&user_id).await.unwrap().is_empty());
+}
+
+#[actix_web::test]
+async fn users_can_remove_a_follower() {
+	let store = memory_store();
+	seed_invite(&store, "invite-1", "invitor").await;
+	let app = init_app(store.clone()).await;
+
+	let (user_id, token) = register(&app, "invite-1", "1234", "4321").await;
+	let (alice, alice_token) = register(&app, "invite-1", "1111", "2222").await;
+	let (bob, bob_token) = register(&app, "invite-1", "3333", "4444").await;
+	follow(&app, &alice, &alice_token, &user_id, &token).await;
+	follow(&app, &bob, &bob_token, &user_id, &token).await;
+
+	let req = test::TestRequest::delete()
+		.uri(&format!("/users/{}/followers/{}", user_id, alice))
+		.insert_header(bearer(&token))
+		.to_request();
+	assert_eq!(test::call_service(&app, req).await.status(), 200);
+	let followers: Vec<String> = store
+		.get_follows(&user_id)
+		.await
+		.unwrap()
+		.into_iter()
+		.map(|follow| follow.follower_id)
+		.collect();
+	assert_eq!(followers, vec![bob.clone()]);
+
+	// Only the followed user can remove their followers
+	let req = test::TestRequest::delete()
+		.uri(&format!("/users/{}/followers/{}", user_id, bob))
+		.insert_header(bearer(&alice_token))
+		.to_request();
+	assert_eq!(test::call_service(&app, req).await.status(), 403);
+	assert_eq!(store.get_follows(&user_id).await.unwrap().len(), 1);
+}
@@ -0,0 +1,131 @@
+mod common;
+
+use std::sync::Arc;
+
+use actix_web::test;
+use cherubgyre::handle_db;
+use cherubgyre::sqlite_store::SqliteStore;
+use cherubgyre::store::Store;
+use common::{bearer, follow, init_app, memory_store, register, seed_invite};
+use serde_json::Value;
+
+#[actix_web::test]
+async fn users_get_unique_handles_they_can_re_roll() {
+	let store = memory_store();
+	seed_invite(&store, "invite-1", "invitor").await;
+	let app = init_app(store.clone()).await;
+
+	let (user_id, token) = register(&app, "invite-1", "1234", "4321").await;
+	let (follower_id, follower_token) = register(&app, "invite-1", "1111", "2222").await;
+	let handle = store
+		.get_user(&user_id)
+		.await
+		.unwrap()
+		.unwrap()
+		.handle
+		.unwrap();
+	let follower_handle = store
+		.get_user(&follower_id)
+		.await
+		.unwrap()
+		.unwrap()
+		.handle
+		.unwrap();
+	assert_ne!(handle, follower_handle);
+	assert_eq!(handle.split('-').count(), 3);
+	// Held, so nobody else can take it
+	assert!(!store.reserve_handle(&handle, "someone").await.unwrap());
+
+	// Followers see the handle on their map
+	follow(&app, &follower_id, &follower_token, &user_id, &token).await;
+	let req = test::TestRequest::get()
+		.uri(&format!("/users/{}/map", follower_id))
+		.insert_header(bearer(&follower_token))
+		.to_request();
+	let map_info: Value = test::call_and_read_body_json(&app, req).await;
+	assert_eq!(map_info[0]["username"], handle.as_str());
+
+	let req = test::TestRequest::post()
+		.uri(&format!("/users/{}/handle", user_id))
+		.insert_header(bearer(&token))
+		.to_request();
+	let body: Value = test::call_and_read_body_json(&app, req).await;
+	let new_handle = body["handle"].as_str().unwrap();
+	assert_ne!(new_handle, handle);
+	assert_eq!(
+		store
+			.get_user(&user_id)
+			.await
+			.unwrap()
+			.unwrap()
+			.handle
+			.as_deref(),
+		Some(new_handle)
+	);
+	// The old handle is free again
+	assert!(store.reserve_handle(&handle, "someone").await.unwrap());
+}
+
+#[actix_web::test]
+async fn generated_handles_come_from_the_word_lists() {
+	let lists = [
+		include_str!("../lists/angels.txt"),
+		include_str!("../lists/cities.txt"),
+		include_str!("../lists/curls.txt"),
+	];
+	for _ in 0..100 {
+		let handle = handle_db::generate();
+		let words: Vec<_> = handle.split('-').collect();
+		assert_eq!(words.len(), 3, "{}", handle);
+		for (word, list) in words.iter().zip(lists) {
+			assert!(list.lines().any(|line| line.trim() == *word), "{}", handle);
+		}
+	}
+
+	let numbered = handle_db::generate_numbered();
+	let (_, number) = numbered.rsplit_once('-').unwrap();
+	assert!(number
+		.parse::<u32>()
+		.is_ok_and(|n| (2..10_000).contains(&n)));
+}
+
+#[actix_web::test]
+async fn a_failed_re_roll_frees_the_new_handle() {
+	let path = std::env::temp_dir().join(format!("cherubgyre-{}.db", uuid::Uuid::new_v4()));
+	let store: Arc<dyn Store> = Arc::new(SqliteStore::open(path.to_str().unwrap()).unwrap());
+	seed_invite(&store, "invite-1", "invitor").await;
+	let app = init_app(store.clone()).await;
+	let (user_id, token) = register(&app, "invite-1", "1234", "4321").await;
+	let handle = store.get_user(&user_id).await.unwrap().unwrap().handle;
+
+	// Saving the user with its new handle fails
+	let conn = rusqlite::Connection::open(&path).unwrap();
+	conn.execute_batch(
+		"CREATE TRIGGER users_read_only BEFORE INSERT ON users
+		WHEN EXISTS (SELECT 1 FROM users WHERE id = NEW.id)
+		BEGIN SELECT RAISE(ABORT, 'users are read-only'); END;",
+	)
+	.unwrap();
+
+	let req = test::TestRequest::post()
+		.uri(&format!("/users/{}/handle", user_id))
+		.insert_header(bearer(&token))
+		.to_request();
+	assert_eq!(test::call_service(&app, req).await.status(), 500);
+	assert_eq!(
+		store.get_user(&user_id).await.unwrap().unwrap().handle,
+		handle
+	);
+	// Only the old handle is still held
+	let held: Vec<String> = conn
+		.prepare("SELECT handle FROM handle_reservations WHERE user_id = ?1")
+		.unwrap()
+		.query_map([&user_id], |row| row.get(0))
+		.unwrap()
+		.collect::<Result<_, _>>()
+		.unwrap();
+	assert_eq!(held, vec![handle.unwrap()]);
+
+	drop(conn);
+	std::fs::remove_file(&path).ok();
+}
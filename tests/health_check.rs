@@ -1,6 +1,22 @@
-use cherubgyre::main;
+use std::sync::Arc;
 
-#[test]
-fn dummy_test() {
-	main()
+use actix_web::{test, web, App};
+use cherubgyre::configure;
+use cherubgyre::memory_store::MemoryStore;
+use cherubgyre::store::Store;
+
+#[actix_web::test]
+async fn health_check_works() {
+	let store: Arc<dyn Store> = Arc::new(MemoryStore::new());
+	let app = test::init_service(
+		App::new()
+			.app_data(web::Data::from(store))
+			.configure(configure),
+	)
+	.await;
+
+	let req = test::TestRequest::get().uri("/health").to_request();
+	let body = test::call_and_read_body(&app, req).await;
+
+	assert_eq!(body, "System is Live");
 }
@@ -0,0 +1,195 @@
+mod common;
+
+use actix_web::test;
+use chrono::{Duration, Utc};
+use cherubgyre::db::{Invite, User};
+use common::{bearer, init_app, memory_store, register, seed_invite};
+use serde_json::{json, Value};
+
+#[actix_web::test]
+async fn invites_expire_run_out_and_can_be_revoked() {
+	let store = memory_store();
+	seed_invite(&store, "invite-1", "invitor").await;
+	let app = init_app(store.clone()).await;
+	let (user_id, token) = register(&app, "invite-1", "1234", "4321").await;
+	let (other_id, other_token) = register(&app, "invite-1", "1111", "2222").await;
+
+	let create = |body: Value| {
+		test::TestRequest::post()
+			.uri("/invite")
+			.insert_header(bearer(&token))
+			.set_json(body)
+			.to_request()
+	};
+	let register_with = |code: &str| {
+		test::TestRequest::post()
+			.uri("/register")
+			.set_json(json!({"invite_code": code, "normal_pin": "5555", "duress_pin": "6666"}))
+			.to_request()
+	};
+
+	for body in [
+		json!({"user_id": user_id, "max_uses": 0}),
+		json!({"user_id": user_id, "single_use": true, "max_uses": 3}),
+		json!({"user_id": user_id, "expires_in_hours": 0}),
+		json!({"user_id": user_id, "expires_in_hours": 10_000}),
+	] {
+		assert_eq!(
+			test::call_service(&app, create(body.clone()))
+				.await
+				.status(),
+			400,
+			"{}",
+			body
+		);
+	}
+
+	// A single-use code works once
+	let body: Value = test::call_and_read_body_json(
+		&app,
+		create(json!({"user_id": user_id, "single_use": true})),
+	)
+	.await;
+	let single = body["invite_code"].as_str().unwrap().to_string();
+	assert_eq!(body["max_uses"], 1);
+	let expires_at: chrono::DateTime<Utc> =
+		serde_json::from_value(body["expires_at"].clone()).unwrap();
+	assert!(expires_at > Utc::now() + Duration::days(6));
+	assert_eq!(
+		test::call_service(&app, register_with(&single))
+			.await
+			.status(),
+		200
+	);
+	let resp = test::call_service(&app, register_with(&single)).await;
+	assert_eq!(resp.status(), 400);
+	assert_eq!(test::read_body(resp).await, "Invite has been used up");
+
+	// Expired codes stop working
+	store
+		.save_invite(&Invite {
+			code: "stale".to_string(),
+			invitor_id: user_id.clone(),
+			invite_count: 0,
+			created_at: Utc::now() - Duration::days(8),
+			expires_at: Some(Utc::now() - Duration::days(1)),
+			max_uses: None,
+			revoked: false,
+		})
+		.await
+		.unwrap();
+	let resp = test::call_service(&app, register_with("stale")).await;
+	assert_eq!(resp.status(), 400);
+	assert_eq!(test::read_body(resp).await, "Invite has expired");
+
+	// Only the invitor can revoke a code
+	let body: Value =
+		test::call_and_read_body_json(&app, create(json!({"user_id": user_id, "max_uses": 5})))
+			.await;
+	let shared = body["invite_code"].as_str().unwrap().to_string();
+	let revoke = |token: &str| {
+		test::TestRequest::delete()
+			.uri(&format!("/invites/{}", shared))
+			.insert_header(bearer(token))
+			.to_request()
+	};
+	assert_eq!(
+		test::call_service(&app, revoke(&other_token))
+			.await
+			.status(),
+		404
+	);
+	assert_eq!(
+		test::call_service(&app, register_with(&shared))
+			.await
+			.status(),
+		200
+	);
+	assert_eq!(test::call_service(&app, revoke(&token)).await.status(), 200);
+	let resp = test::call_service(&app, register_with(&shared)).await;
+	assert_eq!(resp.status(), 400);
+	assert_eq!(test::read_body(resp).await, "Invite has been revoked");
+
+	let req = test::TestRequest::get()
+		.uri(&format!("/users/{}/invites", user_id))
+		.insert_header(bearer(&token))
+		.to_request();
+	let invites: Value = test::call_and_read_body_json(&app, req).await;
+	let invites = invites.as_array().unwrap();
+	assert_eq!(invites.len(), 3);
+	assert_eq!(invites[0]["code"], shared.as_str());
+	assert_eq!(invites[0]["revoked"], true);
+	assert_eq!(invites[0]["invite_count"], 1);
+	assert_eq!(invites[1]["code"], single.as_str());
+	assert_eq!(invites[1]["invite_count"], 1);
+	assert_eq!(invites[2]["code"], "stale");
+
+	let req = test::TestRequest::get()
+		.uri(&format!("/users/{}/invites", other_id))
+		.insert_header(bearer(&token))
+		.to_request();
+	assert_eq!(test::call_service(&app, req).await.status(), 403);
+}
+
+#[actix_web::test]
+async fn racing_registrations_use_an_invite_once() {
+	let store = memory_store();
+	store
+		.save_invite(&Invite {
+			code: "once".to_string(),
+			invitor_id: "invitor".to_string(),
+			invite_count: 0,
+			created_at: Utc::now(),
+			expires_at: None,
+			max_uses: Some(1),
+			revoked: false,
+		})
+		.await
+		.unwrap();
+
+	let tasks: Vec<_> = (0..8)
+		.map(|i| {
+			let store = store.clone();
+			actix_web::rt::spawn(async move {
+				let user = User {
+					id: format!("user-{}", i),
+					invite_code: "once".to_string(),
+					normal_pin_hash: "normal".to_string(),
+					duress_pin_hash: "duress".to_string(),
+					handle: None,
+					admin: false,
+					suspended_at: None,
+				};
+				store.create_invited_user(&user, Utc::now()).await.unwrap()
+			})
+		})
+		.collect();
+	let mut created = 0;
+	for task in tasks {
+		if task.await.unwrap() {
+			created += 1;
+		}
+	}
+	assert_eq!(created, 1);
+	assert_eq!(
+		store
+			.get_invite("once")
+			.await
+			.unwrap()
+			.unwrap()
+			.invite_count,
+		1
+	);
+	let mut users = 0;
+	for i in 0..8 {
+		if store
+			.get_user(&format!("user-{}", i))
+			.await
+			.unwrap()
+			.is_some()
+		{
+			users += 1;
+		}
+	}
+	assert_eq!(users, 1);
+}
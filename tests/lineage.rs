@@ -0,0 +1,162 @@
+mod common;
+
+use std::collections::HashSet;
+
+use actix_web::test;
+use common::{bearer, init_app, memory_store, register, seed_invite};
+use serde_json::{json, Value};
+
+#[actix_web::test]
+async fn invite_trees_can_be_walked_and_suspended() {
+	let store = memory_store();
+	seed_invite(&store, "invite-1", "invitor").await;
+	let app = init_app(store.clone()).await;
+
+	let invite = |user_id: &str, token: &str| {
+		test::TestRequest::post()
+			.uri("/invite")
+			.insert_header(bearer(token))
+			.set_json(json!({"user_id": user_id}))
+			.to_request()
+	};
+	let get = |uri: String, token: &str| {
+		test::TestRequest::get()
+			.uri(&uri)
+			.insert_header(bearer(token))
+			.to_request()
+	};
+
+	// alice invites bob and carol, and bob invites dave
+	let (alice, alice_token) = register(&app, "invite-1", "1234", "4321").await;
+	let body: Value = test::call_and_read_body_json(&app, invite(&alice, &alice_token)).await;
+	let alice_code = body["invite_code"].as_str().unwrap().to_string();
+	let (bob, bob_token) = register(&app, &alice_code, "1111", "2222").await;
+	let (carol, _) = register(&app, &alice_code, "3333", "4444").await;
+	let body: Value = test::call_and_read_body_json(&app, invite(&bob, &bob_token)).await;
+	let bob_code = body["invite_code"].as_str().unwrap().to_string();
+	let (dave, dave_token) = register(&app, &bob_code, "5555", "6666").await;
+
+	let invitees: Value = test::call_and_read_body_json(
+		&app,
+		get(format!("/users/{}/invitees", alice), &alice_token),
+	)
+	.await;
+	let ids: HashSet<_> = invitees
+		.as_array()
+		.unwrap()
+		.iter()
+		.map(|invitee| invitee["id"].as_str().unwrap().to_string())
+		.collect();
+	assert_eq!(ids, HashSet::from([bob.clone(), carol.clone()]));
+	assert!(invitees[0]["handle"].is_string());
+
+	let lineage: Value =
+		test::call_and_read_body_json(&app, get(format!("/users/{}/lineage", dave), &dave_token))
+			.await;
+	assert_eq!(lineage["ancestors"][0]["id"], bob.as_str());
+	assert_eq!(lineage["ancestors"][1]["id"], alice.as_str());
+	assert_eq!(lineage["ancestors"].as_array().unwrap().len(), 2);
+	assert_eq!(lineage["subtree_size"], 0);
+
+	let lineage: Value =
+		test::call_and_read_body_json(&app, get(format!("/users/{}/lineage", alice), &alice_token))
+			.await;
+	assert_eq!(lineage["invitee_count"], 2);
+	assert_eq!(lineage["subtree_size"], 3);
+	assert!(lineage["ancestors"].as_array().unwrap().is_empty());
+
+	// Only admins can use the admin routes
+	let suspend = |user_id: &str, token: &str| {
+		test::TestRequest::post()
+			.uri(&format!("/admin/users/{}/suspend-subtree", user_id))
+			.insert_header(bearer(token))
+			.to_request()
+	};
+	assert_eq!(
+		test::call_service(&app, suspend(&bob, &alice_token))
+			.await
+			.status(),
+		403
+	);
+	let (admin, admin_token) = register(&app, "invite-1", "7777", "8888").await;
+	let mut user = store.get_user(&admin).await.unwrap().unwrap();
+	user.admin = true;
+	store.save_user(&user).await.unwrap();
+
+	let resp = test::call_service(
+		&app,
+		get(format!("/admin/users/{}/lineage", alice), &admin_token),
+	)
+	.await;
+	assert_eq!(resp.status(), 200);
+	let resp = test::call_service(
+		&app,
+		get("/admin/users/nobody/lineage".to_string(), &admin_token),
+	)
+	.await;
+	assert_eq!(resp.status(), 404);
+
+	// Suspending bob takes dave too, but not bob's invitor or sibling
+	let before = store.get_user(&bob).await.unwrap().unwrap();
+	let body: Value = test::call_and_read_body_json(&app, suspend(&bob, &admin_token)).await;
+	assert_eq!(body["suspended"], json!([bob, dave]));
+
+	// Their sessions are gone
+	let resp = test::call_service(&app, get(format!("/users/{}/lineage", bob), &bob_token)).await;
+	assert_eq!(resp.status(), 401);
+	// Nothing but the suspension changed
+	let after = store.get_user(&bob).await.unwrap().unwrap();
+	assert!(after.suspended_at.is_some());
+	assert_eq!(after.handle, before.handle);
+	assert_eq!(after.normal_pin_hash, before.normal_pin_hash);
+	let login = |user_id: &str, pin: &str| {
+		test::TestRequest::post()
+			.uri("/login")
+			.set_json(json!({"user_id": user_id, "pin": pin}))
+			.to_request()
+	};
+	assert_eq!(
+		test::call_service(&app, login(&dave, "5555"))
+			.await
+			.status(),
+		403
+	);
+	assert_eq!(
+		test::call_service(&app, login(&carol, "3333"))
+			.await
+			.status(),
+		200
+	);
+	let resp =
+		test::call_service(&app, get(format!("/users/{}/lineage", alice), &alice_token)).await;
+	assert_eq!(resp.status(), 200);
+
+	// Bob's invites no longer work
+	let req = test::TestRequest::post()
+		.uri("/register")
+		.set_json(json!({"invite_code": bob_code, "normal_pin": "1212", "duress_pin": "2121"}))
+		.to_request();
+	let resp = test::call_service(&app, req).await;
+	assert_eq!(resp.status(), 400);
+	assert_eq!(test::read_body(resp).await, "Invite has been revoked");
+
+	// Suspending again finds nobody new
+	let body: Value = test::call_and_read_body_json(&app, suspend(&bob, &admin_token)).await;
+	assert_eq!(body["suspended"], json!([]));
+
+	let req = test::TestRequest::post()
+		.uri(&format!("/admin/users/{}/unsuspend", dave))
+		.insert_header(bearer(&admin_token))
+		.to_request();
+	assert_eq!(test::call_service(&app, req).await.status(), 200);
+	assert_eq!(
+		test::call_service(&app, login(&dave, "5555"))
+			.await
+			.status(),
+		200
+	);
+	assert_eq!(
+		test::call_service(&app, login(&bob, "1111")).await.status(),
+		403
+	);
+}
@@ -0,0 +1,232 @@
+mod common;
+
+use std::sync::{Arc, Mutex};
+
+use actix_web::test;
+use async_trait::async_trait;
+use chrono::{Duration, Utc};
+use cherubgyre::delivery_db::DeliveryStatus;
+use cherubgyre::duress_db::DuressType;
+use cherubgyre::login_db::MAX_LOGIN_FAILURES;
+use cherubgyre::notify::{Alert, Notifier, Notifiers, NotifyError, Recipient};
+use cherubgyre::queue;
+use cherubgyre::store::Store;
+use common::{follow, init_app, init_app_with_notifiers, memory_store, register, seed_invite};
+use serde_json::{json, Value};
+
+// Records who each alert went to
+#[derive(Clone, Default)]
+struct RecordingNotifier {
+	sent: Arc<Mutex<Vec<String>>>,
+}
+
+#[async_trait]
+impl Notifier for RecordingNotifier {
+	fn channel(&self) -> &'static str {
+		"recording"
+	}
+
+	fn reaches(&self, _recipient: &Recipient) -> bool {
+		true
+	}
+
+	async fn send(&self, recipient: &Recipient, _alert: &Alert) -> Result<(), NotifyError> {
+		self.sent.lock().unwrap().push(recipient.user_id.clone());
+		Ok(())
+	}
+}
+
+fn login(user_id: &str, pin: &str) -> actix_http::Request {
+	test::TestRequest::post()
+		.uri("/login")
+		.set_json(json!({"user_id": user_id, "pin": pin}))
+		.to_request()
+}
+
+// Duress PIN logins raise the alarm in the background; wait for it
+async fn wait_for_duress(
+	store: &Arc<dyn Store>,
+	notifiers: &Notifiers,
+	recorder: &RecordingNotifier,
+	expected: usize,
+) {
+	for _ in 0..50 {
+		queue::process_due_jobs(store.as_ref(), notifiers, Utc::now())
+			.await
+			.unwrap();
+		if recorder.sent.lock().unwrap().len() >= expected {
+			break;
+		}
+		actix_web::rt::time::sleep(std::time::Duration::from_millis(20)).await;
+	}
+}
+
+#[actix_web::test]
+async fn duress_login_looks_like_normal_login() {
+	let store = memory_store();
+	seed_invite(&store, "invite-1", "invitor").await;
+	let recorder = RecordingNotifier::default();
+	let notifiers = Arc::new(Notifiers::new(vec![Box::new(recorder.clone())]));
+	let app = init_app_with_notifiers(store.clone(), notifiers.clone()).await;
+
+	let (user_id, user_token) = register(&app, "invite-1", "1234", "4321").await;
+	let (follower, follower_token) = register(&app, "invite-1", "1111", "2222").await;
+	follow(&app, &follower, &follower_token, &user_id, &user_token).await;
+
+	let mut bodies = Vec::new();
+	for pin in ["1234", "4321"] {
+		let body: Value = test::call_and_read_body_json(&app, login(&user_id, pin)).await;
+		bodies.push(body);
+	}
+	let keys = |body: &Value| {
+		body.as_object()
+			.unwrap()
+			.keys()
+			.cloned()
+			.collect::<Vec<_>>()
+	};
+	assert_eq!(keys(&bodies[0]), keys(&bodies[1]));
+	assert_eq!(bodies[0]["user_id"], bodies[1]["user_id"]);
+
+	// Only the duress PIN raised the alarm, and the follower heard about it
+	wait_for_duress(&store, &notifiers, &recorder, 1).await;
+	let events = store
+		.get_user_duress_events(&user_id, None, None)
+		.await
+		.unwrap();
+	assert_eq!(events.len(), 1);
+	assert_eq!(events[0].duress_type, DuressType::DuressPin);
+	let deliveries: Vec<_> = store
+		.get_event_deliveries(&events[0].id)
+		.await
+		.unwrap()
+		.into_iter()
+		.filter(|d| d.channel == "recording")
+		.collect();
+	assert_eq!(deliveries.len(), 1);
+	assert_eq!(deliveries[0].recipient_id, follower);
+	assert_eq!(deliveries[0].status, DeliveryStatus::Sent);
+	assert_eq!(*recorder.sent.lock().unwrap(), vec![follower]);
+
+	let resp = test::call_service(&app, login(&user_id, "0000")).await;
+	assert_eq!(resp.status(), 401);
+}
+
+#[actix_web::test]
+async fn repeated_wrong_pins_lock_logins() {
+	let store = memory_store();
+	seed_invite(&store, "invite-1", "invitor").await;
+	let recorder = RecordingNotifier::default();
+	let notifiers = Arc::new(Notifiers::new(vec![Box::new(recorder.clone())]));
+	let app = init_app_with_notifiers(store.clone(), notifiers.clone()).await;
+
+	let (user_id, user_token) = register(&app, "invite-1", "1234", "4321").await;
+	let (follower, follower_token) = register(&app, "invite-1", "1111", "2222").await;
+	follow(&app, &follower, &follower_token, &user_id, &user_token).await;
+
+	for _ in 0..MAX_LOGIN_FAILURES {
+		let resp = test::call_service(&app, login(&user_id, "0000")).await;
+		assert_eq!(resp.status(), 401);
+	}
+
+	// Even the right PIN is refused now, with the same answer as a wrong one
+	for pin in ["1234", "0000"] {
+		let resp = test::call_service(&app, login(&user_id, pin)).await;
+		assert_eq!(resp.status(), 429);
+		let retry_after: i64 = resp
+			.headers()
+			.get("Retry-After")
+			.unwrap()
+			.to_str()
+			.unwrap()
+			.parse()
+			.unwrap();
+		assert!(retry_after > 0 && retry_after <= 15 * 60);
+	}
+
+	// The duress PIN is refused too, but still raises the alarm
+	let resp = test::call_service(&app, login(&user_id, "4321")).await;
+	assert_eq!(resp.status(), 429);
+	wait_for_duress(&store, &notifiers, &recorder, 1).await;
+	let events = store
+		.get_user_duress_events(&user_id, None, None)
+		.await
+		.unwrap();
+	assert_eq!(events.len(), 1);
+	assert_eq!(events[0].duress_type, DuressType::DuressPin);
+	assert_eq!(*recorder.sent.lock().unwrap(), vec![follower.clone()]);
+
+	// Other users are unaffected
+	let resp = test::call_service(&app, login(&follower, "1111")).await;
+	assert_eq!(resp.status(), 200);
+}
+
+#[actix_web::test]
+async fn logging_in_clears_failed_attempts() {
+	let store = memory_store();
+	seed_invite(&store, "invite-1", "invitor").await;
+	let app = init_app(store.clone()).await;
+	let (user_id, _) = register(&app, "invite-1", "1234", "4321").await;
+
+	for _ in 0..MAX_LOGIN_FAILURES - 1 {
+		let resp = test::call_service(&app, login(&user_id, "0000")).await;
+		assert_eq!(resp.status(), 401);
+	}
+	let resp = test::call_service(&app, login(&user_id, "1234")).await;
+	assert_eq!(resp.status(), 200);
+	assert_eq!(store.get_login_attempts(&user_id).await.unwrap(), None);
+
+	// A window that has ended starts over
+	let long_ago = Utc::now() - Duration::hours(1);
+	for _ in 0..MAX_LOGIN_FAILURES {
+		store
+			.record_login_failure(&user_id, long_ago)
+			.await
+			.unwrap();
+	}
+	let resp = test::call_service(&app, login(&user_id, "0000")).await;
+	assert_eq!(resp.status(), 401);
+	let attempts = store.get_login_attempts(&user_id).await.unwrap().unwrap();
+	assert_eq!(attempts.failures, 1);
+	let resp = test::call_service(&app, login(&user_id, "1234")).await;
+	assert_eq!(resp.status(), 200);
+
+	// Unknown users are refused without leaving a row behind
+	let resp = test::call_service(&app, login("nobody", "0000")).await;
+	assert_eq!(resp.status(), 401);
+	assert_eq!(store.get_login_attempts("nobody").await.unwrap(), None);
+}
+
+#[actix_web::test]
+async fn suspended_users_can_still_raise_the_alarm() {
+	let store = memory_store();
+	seed_invite(&store, "invite-1", "invitor").await;
+	let recorder = RecordingNotifier::default();
+	let notifiers = Arc::new(Notifiers::new(vec![Box::new(recorder.clone())]));
+	let app = init_app_with_notifiers(store.clone(), notifiers.clone()).await;
+
+	let (user_id, user_token) = register(&app, "invite-1", "1234", "4321").await;
+	let (follower, follower_token) = register(&app, "invite-1", "1111", "2222").await;
+	follow(&app, &follower, &follower_token, &user_id, &user_token).await;
+	let mut user = store.get_user(&user_id).await.unwrap().unwrap();
+	user.suspended_at = Some(Utc::now());
+	store.save_user(&user).await.unwrap();
+
+	// Refused either way, with the same answer
+	let resp = test::call_service(&app, login(&user_id, "1234")).await;
+	assert_eq!(resp.status(), 403);
+	let normal = test::read_body(resp).await;
+	let resp = test::call_service(&app, login(&user_id, "4321")).await;
+	assert_eq!(resp.status(), 403);
+	assert_eq!(test::read_body(resp).await, normal);
+
+	// Only the duress PIN raised the alarm
+	wait_for_duress(&store, &notifiers, &recorder, 1).await;
+	let events = store
+		.get_user_duress_events(&user_id, None, None)
+		.await
+		.unwrap();
+	assert_eq!(events.len(), 1);
+	assert_eq!(events[0].duress_type, DuressType::DuressPin);
+	assert_eq!(*recorder.sent.lock().unwrap(), vec![follower]);
+}
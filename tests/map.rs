@@ -0,0 +1,118 @@
+mod common;
+
+use actix_web::test;
+use common::{bearer, follow, init_app, memory_store, register, seed_invite};
+use serde_json::{json, Value};
+
+#[actix_web::test]
+async fn map_shows_last_checkin_and_duress_of_followed_users() {
+	let store = memory_store();
+	seed_invite(&store, "invite-1", "invitor").await;
+	let app = init_app(store.clone()).await;
+	let (user_id, token) = register(&app, "invite-1", "1234", "4321").await;
+	let (follower_id, follower_token) = register(&app, "invite-1", "5678", "8765").await;
+
+	follow(&app, &follower_id, &follower_token, &user_id, &token).await;
+
+	let checkin = |latitude: f64| {
+		test::TestRequest::post()
+			.uri(&format!("/users/{}/checkin", user_id))
+			.insert_header(bearer(&token))
+			.set_json(json!({
+				"location": {"latitude": latitude, "longitude": 18.07, "accuracy": 10.0},
+				"timestamp": "2024-10-05T11:57:33Z"
+			}))
+			.to_request()
+	};
+	assert_eq!(test::call_service(&app, checkin(95.0)).await.status(), 400);
+	assert_eq!(test::call_service(&app, checkin(59.0)).await.status(), 200);
+	assert_eq!(test::call_service(&app, checkin(59.33)).await.status(), 200);
+
+	let map = || {
+		test::TestRequest::get()
+			.uri(&format!("/users/{}/map", follower_id))
+			.insert_header(bearer(&follower_token))
+			.to_request()
+	};
+	let info: Value = test::call_and_read_body_json(&app, map()).await;
+	let info = info.as_array().unwrap();
+	assert_eq!(info.len(), 1);
+	assert_eq!(info[0]["user_id"], user_id.as_str());
+	assert_eq!(info[0]["location"]["latitude"], 59.33);
+	assert_eq!(info[0]["duress"], false);
+
+	let req = test::TestRequest::post()
+		.uri(&format!("/users/{}/duress", user_id))
+		.insert_header(bearer(&token))
+		.set_json(json!({
+			"duress_type": "assault",
+			"message": "help",
+			"timestamp": "2024-10-05T11:57:33Z"
+		}))
+		.to_request();
+	assert_eq!(test::call_service(&app, req).await.status(), 200);
+
+	let info: Value = test::call_and_read_body_json(&app, map()).await;
+	assert_eq!(info[0]["duress"], true);
+}
+
+#[actix_web::test]
+async fn map_shows_where_a_real_duress_alert_was_raised() {
+	let store = memory_store();
+	seed_invite(&store, "invite-1", "invitor").await;
+	let app = init_app(store.clone()).await;
+	let (user_id, token) = register(&app, "invite-1", "1234", "4321").await;
+	let (follower_id, follower_token) = register(&app, "invite-1", "5678", "8765").await;
+
+	follow(&app, &follower_id, &follower_token, &user_id, &token).await;
+
+	let req = test::TestRequest::post()
+		.uri(&format!("/users/{}/checkin", user_id))
+		.insert_header(bearer(&token))
+		.set_json(json!({
+			"location": {"latitude": 59.33, "longitude": 18.07},
+			"timestamp": "2024-10-05T11:57:33Z"
+		}))
+		.to_request();
+	assert_eq!(test::call_service(&app, req).await.status(), 200);
+
+	let trigger = |latitude: f64| {
+		test::TestRequest::post()
+			.uri(&format!("/users/{}/duress", user_id))
+			.insert_header(bearer(&token))
+			.set_json(json!({
+				"duress_type": "assault",
+				"message": "help",
+				"timestamp": "2024-10-05T12:10:00Z",
+				"location": {"latitude": latitude, "longitude": 18.05}
+			}))
+			.to_request()
+	};
+	let test_mode = |req: test::TestRequest| {
+		req.uri(&format!("/users/{}/test-mode", user_id))
+			.insert_header(bearer(&token))
+			.to_request()
+	};
+	let map = || {
+		test::TestRequest::get()
+			.uri(&format!("/users/{}/map", follower_id))
+			.insert_header(bearer(&follower_token))
+			.to_request()
+	};
+
+	// A test alert leaves the map on the last check-in
+	let req = test_mode(test::TestRequest::post().set_json(json!({"minutes": 5})));
+	assert_eq!(test::call_service(&app, req).await.status(), 200);
+	assert_eq!(test::call_service(&app, trigger(59.20)).await.status(), 200);
+	let info: Value = test::call_and_read_body_json(&app, map()).await;
+	assert_eq!(info[0]["duress"], false);
+	assert_eq!(info[0]["location"]["latitude"], 59.33);
+
+	let req = test_mode(test::TestRequest::delete());
+	assert_eq!(test::call_service(&app, req).await.status(), 200);
+	assert_eq!(test::call_service(&app, trigger(59.31)).await.status(), 200);
+	let info: Value = test::call_and_read_body_json(&app, map()).await;
+	assert_eq!(info[0]["duress"], true);
+	assert_eq!(info[0]["location"]["latitude"], 59.31);
+	assert_eq!(info[0]["location"]["longitude"], 18.05);
+}
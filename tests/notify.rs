@@ -0,0 +1,360 @@
+mod common;
+
+use std::sync::{Arc, Mutex};
+
+use actix_web::test;
+use async_trait::async_trait;
+use chrono::{Duration, Utc};
+use cherubgyre::delivery_db::{AlertKind, DeliveryStatus};
+use cherubgyre::duress_db::{DuressEvent, DuressType};
+use cherubgyre::notify::{self, Alert, Notifier, Notifiers, NotifyError, Recipient};
+use cherubgyre::notify_backends::SinkNotifier;
+use cherubgyre::queue;
+use common::{bearer, follow, init_app_with_notifiers, memory_store, register, seed_invite};
+use serde_json::{json, Value};
+
+// Records every alert it is asked to send
+#[derive(Clone, Default)]
+struct RecordingNotifier {
+	sent: Arc<Mutex<Vec<(String, AlertKind)>>>,
+}
+
+#[async_trait]
+impl Notifier for RecordingNotifier {
+	fn channel(&self) -> &'static str {
+		"recording"
+	}
+
+	fn reaches(&self, _recipient: &Recipient) -> bool {
+		true
+	}
+
+	async fn send(&self, recipient: &Recipient, alert: &Alert) -> Result<(), NotifyError> {
+		self.sent
+			.lock()
+			.unwrap()
+			.push((recipient.user_id.clone(), alert.kind));
+		Ok(())
+	}
+}
+
+#[actix_web::test]
+async fn duress_alerts_fan_out_to_followers_who_want_them() {
+	let store = memory_store();
+	seed_invite(&store, "invite-1", "invitor").await;
+	let recorder = RecordingNotifier::default();
+	let sink_path = std::env::temp_dir().join(format!("cherubgyre-{}.jsonl", uuid::Uuid::new_v4()));
+	let notifiers = Arc::new(Notifiers::new(vec![
+		Box::new(recorder.clone()),
+		Box::new(SinkNotifier::file(&sink_path)),
+	]));
+	let app = init_app_with_notifiers(store.clone(), notifiers.clone()).await;
+
+	let (user_id, token) = register(&app, "invite-1", "1234", "4321").await;
+	let (alice, alice_token) = register(&app, "invite-1", "1111", "2222").await;
+	let (bob, bob_token) = register(&app, "invite-1", "3333", "4444").await;
+	for (follower_id, follower_token) in [(&alice, &alice_token), (&bob, &bob_token)] {
+		follow(&app, follower_id, follower_token, &user_id, &token).await;
+	}
+
+	// Bob opts out of other people's alerts
+	let req = test::TestRequest::patch()
+		.uri(&format!("/users/{}/preferences", bob))
+		.insert_header(bearer(&bob_token))
+		.set_json(json!({"receive_duress_broadcasts": false}))
+		.to_request();
+	assert_eq!(test::call_service(&app, req).await.status(), 200);
+
+	let req = test::TestRequest::post()
+		.uri(&format!("/users/{}/duress", user_id))
+		.insert_header(bearer(&token))
+		.set_json(json!({
+			"duress_type": "assault",
+			"message": "help",
+			"timestamp": "2024-10-05T11:57:33Z"
+		}))
+		.to_request();
+	assert_eq!(test::call_service(&app, req).await.status(), 200);
+	// Nothing is sent until the queue worker runs
+	assert!(recorder.sent.lock().unwrap().is_empty());
+	queue::process_due_jobs(store.as_ref(), &notifiers, Utc::now())
+		.await
+		.unwrap();
+	assert_eq!(
+		*recorder.sent.lock().unwrap(),
+		vec![(alice.clone(), AlertKind::Raised)]
+	);
+
+	let req = test::TestRequest::post()
+		.uri(&format!("/users/{}/duress/cancel", user_id))
+		.insert_header(bearer(&token))
+		.set_json(json!({"normal_pin": "1234", "confirm": true}))
+		.to_request();
+	assert_eq!(test::call_service(&app, req).await.status(), 200);
+	// The cancellation is announced in the background
+	for _ in 0..50 {
+		queue::process_due_jobs(store.as_ref(), &notifiers, Utc::now())
+			.await
+			.unwrap();
+		if recorder.sent.lock().unwrap().len() > 1 {
+			break;
+		}
+		actix_web::rt::time::sleep(std::time::Duration::from_millis(20)).await;
+	}
+	assert_eq!(
+		recorder.sent.lock().unwrap().last(),
+		Some(&(alice.clone(), AlertKind::Ended))
+	);
+
+	let events = store
+		.get_user_duress_events(&user_id, None, None)
+		.await
+		.unwrap();
+	// Alice wants push alerts too, but there is no push backend here
+	let (push, deliveries): (Vec<_>, Vec<_>) = store
+		.get_event_deliveries(&events[0].id)
+		.await
+		.unwrap()
+		.into_iter()
+		.partition(|d| d.channel == "push");
+	assert_eq!(deliveries.len(), 4);
+	assert!(deliveries
+		.iter()
+		.all(|d| d.recipient_id == alice && d.status == DeliveryStatus::Sent));
+	assert_eq!(push.len(), 2);
+	assert!(push
+		.iter()
+		.all(|d| d.recipient_id == alice && d.status == DeliveryStatus::Unavailable));
+
+	let sink = std::fs::read_to_string(&sink_path).unwrap();
+	let lines: Vec<Value> = sink
+		.lines()
+		.map(|line| serde_json::from_str(line).unwrap())
+		.collect();
+	assert_eq!(lines.len(), 2);
+	assert_eq!(lines[0]["alert"]["kind"], "raised");
+	assert_eq!(lines[1]["alert"]["status"], "cancelled");
+	std::fs::remove_file(&sink_path).ok();
+}
+
+#[actix_web::test]
+async fn self_only_tests_alert_only_the_user() {
+	let store = memory_store();
+	seed_invite(&store, "invite-1", "invitor").await;
+	let recorder = RecordingNotifier::default();
+	let notifiers = Arc::new(Notifiers::new(vec![Box::new(recorder.clone())]));
+	let app = init_app_with_notifiers(store.clone(), notifiers.clone()).await;
+
+	let (user_id, token) = register(&app, "invite-1", "1234", "4321").await;
+	let (follower_id, follower_token) = register(&app, "invite-1", "1111", "2222").await;
+	follow(&app, &follower_id, &follower_token, &user_id, &token).await;
+
+	let req = test::TestRequest::post()
+		.uri(&format!("/users/{}/test-mode", user_id))
+		.insert_header(bearer(&token))
+		.set_json(json!({"audience": "self_only"}))
+		.to_request();
+	assert_eq!(test::call_service(&app, req).await.status(), 200);
+
+	let req = test::TestRequest::post()
+		.uri(&format!("/users/{}/duress", user_id))
+		.insert_header(bearer(&token))
+		.set_json(json!({
+			"duress_type": "fire",
+			"message": "drill",
+			"timestamp": "2024-10-05T11:57:33Z"
+		}))
+		.to_request();
+	assert_eq!(test::call_service(&app, req).await.status(), 200);
+	queue::process_due_jobs(store.as_ref(), &notifiers, Utc::now())
+		.await
+		.unwrap();
+
+	assert_eq!(
+		*recorder.sent.lock().unwrap(),
+		vec![(user_id.clone(), AlertKind::Raised)]
+	);
+}
+
+// Fails every delivery
+struct FailingNotifier;
+
+#[async_trait]
+impl Notifier for FailingNotifier {
+	fn channel(&self) -> &'static str {
+		"failing"
+	}
+
+	fn reaches(&self, _recipient: &Recipient) -> bool {
+		true
+	}
+
+	async fn send(&self, _recipient: &Recipient, _alert: &Alert) -> Result<(), NotifyError> {
+		Err(NotifyError("unreachable".to_string()))
+	}
+}
+
+#[actix_web::test]
+async fn failed_deliveries_back_off_and_end_up_dead_lettered() {
+	let store = memory_store();
+	store.add_follow("follower", "victim").await.unwrap();
+	let notifiers = Notifiers::new(vec![Box::new(FailingNotifier)]);
+
+	let event = DuressEvent::new("victim", DuressType::Assault, "help", Utc::now());
+	store.log_duress_event(&event).await.unwrap();
+	notify::fan_out(store.as_ref(), &notifiers, &event, AlertKind::Raised)
+		.await
+		.unwrap();
+	// Fanning the same alert out again queues nothing new
+	let again = notify::fan_out(store.as_ref(), &notifiers, &event, AlertKind::Raised)
+		.await
+		.unwrap();
+	assert!(again.is_empty());
+
+	let mut now = Utc::now();
+	for attempt in 1..=queue::MAX_ATTEMPTS {
+		let processed = queue::process_due_jobs(store.as_ref(), &notifiers, now)
+			.await
+			.unwrap();
+		assert_eq!(processed, 1, "attempt {}", attempt);
+		// Not due again until the backoff has passed
+		assert_eq!(
+			queue::process_due_jobs(store.as_ref(), &notifiers, now)
+				.await
+				.unwrap(),
+			0
+		);
+		now += queue::backoff(attempt) + Duration::seconds(61);
+	}
+
+	let dead = store.get_dead_jobs(10).await.unwrap();
+	assert_eq!(dead.len(), 1);
+	assert_eq!(dead[0].attempts, queue::MAX_ATTEMPTS);
+	assert_eq!(dead[0].last_error.as_deref(), Some("unreachable"));
+
+	let deliveries: Vec<_> = store
+		.get_event_deliveries(&event.id)
+		.await
+		.unwrap()
+		.into_iter()
+		.filter(|d| d.channel == "failing")
+		.collect();
+	assert_eq!(deliveries.len(), 1);
+	assert_eq!(deliveries[0].status, DeliveryStatus::Failed);
+}
+
+#[actix_web::test]
+async fn channels_without_a_backend_are_recorded_as_unavailable() {
+	let store = memory_store();
+	seed_invite(&store, "invite-1", "invitor").await;
+	let recorder = RecordingNotifier::default();
+	let notifiers = Arc::new(Notifiers::new(vec![Box::new(recorder.clone())]));
+	let app = init_app_with_notifiers(store.clone(), notifiers.clone()).await;
+
+	let (user_id, token) = register(&app, "invite-1", "1234", "4321").await;
+	let (alice, alice_token) = register(&app, "invite-1", "1111", "2222").await;
+	follow(&app, &alice, &alice_token, &user_id, &token).await;
+
+	// Alice asks for SMS alerts only, which this server cannot send
+	let req = test::TestRequest::patch()
+		.uri(&format!("/users/{}/preferences", alice))
+		.insert_header(bearer(&alice_token))
+		.set_json(
+			json!({"channels": {"push": false, "sms": true, "email": false, "webhook": false}}),
+		)
+		.to_request();
+	let preferences: Value = test::call_and_read_body_json(&app, req).await;
+	assert_eq!(preferences["channels"]["sms"], true);
+
+	let event = DuressEvent::new(&user_id, DuressType::Assault, "help", Utc::now());
+	store.log_duress_event(&event).await.unwrap();
+	let deliveries = notify::fan_out(store.as_ref(), &notifiers, &event, AlertKind::Raised)
+		.await
+		.unwrap();
+	let sms: Vec<_> = deliveries.iter().filter(|d| d.channel == "sms").collect();
+	assert_eq!(sms.len(), 1);
+	assert_eq!(sms[0].recipient_id, alice);
+	assert_eq!(sms[0].status, DeliveryStatus::Unavailable);
+	assert_eq!(
+		sms[0].error.as_deref(),
+		Some("No sms backend is configured")
+	);
+	// Recorded once, and never queued
+	assert!(
+		notify::fan_out(store.as_ref(), &notifiers, &event, AlertKind::Raised)
+			.await
+			.unwrap()
+			.is_empty()
+	);
+	assert_eq!(
+		store
+			.get_event_deliveries(&event.id)
+			.await
+			.unwrap()
+			.iter()
+			.filter(|d| d.channel == "sms")
+			.count(),
+		1
+	);
+	queue::process_due_jobs(store.as_ref(), &notifiers, Utc::now())
+		.await
+		.unwrap();
+	assert!(store.get_dead_jobs(10).await.unwrap().is_empty());
+}
+
+#[actix_web::test]
+async fn quiet_hours_drop_alerts_for_good() {
+	let store = memory_store();
+	seed_invite(&store, "invite-1", "invitor").await;
+	let recorder = RecordingNotifier::default();
+	let notifiers = Arc::new(Notifiers::new(vec![Box::new(recorder.clone())]));
+	let app = init_app_with_notifiers(store.clone(), notifiers.clone()).await;
+
+	let (user_id, token) = register(&app, "invite-1", "1234", "4321").await;
+	let (alice, alice_token) = register(&app, "invite-1", "1111", "2222").await;
+	follow(&app, &alice, &alice_token, &user_id, &token).await;
+
+	// Alice is in quiet hours and lets nothing through
+	let now = Utc::now();
+	let req = test::TestRequest::patch()
+		.uri(&format!("/users/{}/preferences", alice))
+		.insert_header(bearer(&alice_token))
+		.set_json(json!({"quiet_hours": {
+			"start": (now - Duration::hours(1)).time().format("%H:%M:%S").to_string(),
+			"end": (now + Duration::hours(1)).time().format("%H:%M:%S").to_string(),
+			"duress_overrides": false
+		}}))
+		.to_request();
+	assert_eq!(test::call_service(&app, req).await.status(), 200);
+
+	let req = test::TestRequest::post()
+		.uri(&format!("/users/{}/duress", user_id))
+		.insert_header(bearer(&token))
+		.set_json(json!({
+			"duress_type": "assault",
+			"message": "help",
+			"timestamp": "2024-10-05T11:57:33Z"
+		}))
+		.to_request();
+	assert_eq!(test::call_service(&app, req).await.status(), 200);
+
+	let events = store
+		.get_user_duress_events(&user_id, None, None)
+		.await
+		.unwrap();
+	let deliveries: Vec<_> = store
+		.get_event_deliveries(&events[0].id)
+		.await
+		.unwrap()
+		.into_iter()
+		.filter(|d| d.channel == "recording")
+		.collect();
+	assert_eq!(deliveries.len(), 1);
+	assert_eq!(deliveries[0].status, DeliveryStatus::Suppressed);
+
+	// Nothing is sent once quiet hours are over either
+	queue::process_due_jobs(store.as_ref(), &notifiers, now + Duration::hours(2))
+		.await
+		.unwrap();
+	assert!(recorder.sent.lock().unwrap().is_empty());
+}
@@ -0,0 +1,108 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use actix_web::{web, App, HttpResponse, HttpServer};
+use chrono::Utc;
+use cherubgyre::delivery_db::AlertKind;
+use cherubgyre::duress_db::{DuressEvent, DuressType, UserPreferences};
+use cherubgyre::notify::{Alert, Notifier, Recipient};
+use cherubgyre::notify_backends::WebhookNotifier;
+use cherubgyre::outbound::{self, OutboundError};
+
+#[actix_web::test]
+async fn only_public_addresses_are_public() {
+	for ip in ["93.184.216.34", "2606:2800:220:1::1", "1.1.1.1"] {
+		assert!(outbound::is_public(ip.parse().unwrap()), "{}", ip);
+	}
+	for ip in [
+		"127.0.0.1",
+		"10.1.2.3",
+		"172.16.0.1",
+		"192.168.0.1",
+		"169.254.169.254",
+		"100.100.100.200",
+		"0.0.0.0",
+		"255.255.255.255",
+		"224.0.0.1",
+		"::1",
+		"::",
+		"fd00:ec2::254",
+		"fe80::1",
+		"::ffff:127.0.0.1",
+		"64:ff9b::a9fe:a9fe",
+	] {
+		assert!(!outbound::is_public(ip.parse().unwrap()), "{}", ip);
+	}
+}
+
+#[actix_web::test]
+async fn urls_must_be_http_and_public() {
+	assert!(outbound::check_url("https://93.184.216.34/hook", true)
+		.await
+		.is_ok());
+	assert!(outbound::check_url("http://93.184.216.34/hook", false)
+		.await
+		.is_ok());
+	for (url, https_only, error) in [
+		("http://93.184.216.34/hook", true, OutboundError::Scheme),
+		("ftp://93.184.216.34/hook", false, OutboundError::Scheme),
+		("not a url", false, OutboundError::Url),
+		("http://localhost:8080/hook", false, OutboundError::Address),
+		(
+			"http://169.254.169.254/latest",
+			false,
+			OutboundError::Address,
+		),
+		("https://[fe80::1]/hook", false, OutboundError::Address),
+	] {
+		assert_eq!(
+			outbound::check_url(url, https_only).await.err(),
+			Some(error),
+			"{}",
+			url
+		);
+	}
+}
+
+#[actix_web::test]
+async fn webhooks_are_not_sent_to_private_addresses() {
+	let hits = Arc::new(AtomicUsize::new(0));
+	let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+	let port = listener.local_addr().unwrap().port();
+	let counter = hits.clone();
+	let server = HttpServer::new(move || {
+		let counter = counter.clone();
+		App::new().default_service(web::to(move || {
+			counter.fetch_add(1, Ordering::SeqCst);
+			async { HttpResponse::Ok().finish() }
+		}))
+	})
+	.workers(1)
+	.listen(listener)
+	.unwrap()
+	.run();
+	actix_web::rt::spawn(server);
+
+	let mut preferences = UserPreferences::default();
+	preferences.channels.webhook = true;
+	preferences.contacts.webhook_url = Some(format!("http://127.0.0.1:{}/hook", port));
+	let recipient = Recipient {
+		user_id: "follower".to_string(),
+		preferences,
+		push_subscriptions: Vec::new(),
+	};
+	let event = DuressEvent::new("user", DuressType::Assault, "help", Utc::now());
+	let alert = Alert::new(&event, AlertKind::Raised);
+
+	assert!(WebhookNotifier::new()
+		.send(&recipient, &alert)
+		.await
+		.is_err());
+	assert_eq!(hits.load(Ordering::SeqCst), 0);
+
+	WebhookNotifier::allowing_private_addresses()
+		.send(&recipient, &alert)
+		.await
+		.unwrap();
+	assert_eq!(hits.load(Ordering::SeqCst), 1);
+}
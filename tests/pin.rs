@@ -0,0 +1,21 @@
+use cherubgyre::db::User;
+use cherubgyre::pin::{hash_pin, verify_pin, verify_unknown_user, PinMatch};
+
+#[test]
+fn verify_pin_distinguishes_normal_duress_and_wrong() {
+	let user = User {
+		id: "user".to_string(),
+		invite_code: "invite".to_string(),
+		normal_pin_hash: hash_pin("1234").unwrap(),
+		duress_pin_hash: hash_pin("4321").unwrap(),
+		handle: None,
+		admin: false,
+		suspended_at: None,
+	};
+
+	assert!(!user.normal_pin_hash.contains("1234"));
+	assert_eq!(verify_pin(&user, "1234"), PinMatch::Normal);
+	assert_eq!(verify_pin(&user, "4321"), PinMatch::Duress);
+	assert_eq!(verify_pin(&user, "0000"), PinMatch::Wrong);
+	assert_eq!(verify_unknown_user("1234"), PinMatch::Wrong);
+}
@@ -0,0 +1,115 @@
+mod common;
+
+use actix_web::test;
+use cherubgyre::duress_db::{QuietHours, UserPreferences};
+use common::{bearer, init_app, memory_store, register, seed_invite};
+use serde_json::{json, Value};
+
+#[actix_web::test]
+async fn preference_updates_merge_per_user() {
+	let store = memory_store();
+	seed_invite(&store, "invite-1", "invitor").await;
+	let app = init_app(store.clone()).await;
+	let (alice, alice_token) = register(&app, "invite-1", "1234", "4321").await;
+	let (bob, bob_token) = register(&app, "invite-1", "5678", "8765").await;
+
+	let patch = |body: Value| {
+		test::TestRequest::patch()
+			.uri(&format!("/users/{}/preferences", alice))
+			.insert_header(bearer(&alice_token))
+			.set_json(body)
+			.to_request()
+	};
+	let get = |user_id: &str, token: &str| {
+		test::TestRequest::get()
+			.uri(&format!("/users/{}/preferences", user_id))
+			.insert_header(bearer(token))
+			.to_request()
+	};
+
+	let updated: Value =
+		test::call_and_read_body_json(&app, patch(json!({"broadcast_duress": false}))).await;
+	assert_eq!(updated["broadcast_duress"], false);
+	assert_eq!(updated["receive_duress_broadcasts"], true);
+
+	// Later partial updates keep earlier ones, down to single channels
+	let body = json!({
+		"receive_duress_broadcasts": false,
+		"channels": {"email": true},
+		"quiet_hours": {"start": "22:00:00", "end": "07:00:00"}
+	});
+	test::call_service(&app, patch(body)).await;
+	let alice_preferences: Value =
+		test::call_and_read_body_json(&app, get(&alice, &alice_token)).await;
+	assert_eq!(alice_preferences["broadcast_duress"], false);
+	assert_eq!(alice_preferences["receive_duress_broadcasts"], false);
+	assert_eq!(
+		alice_preferences["channels"],
+		json!({"push": true, "sms": false, "email": true, "webhook": false})
+	);
+	assert_eq!(alice_preferences["quiet_hours"]["duress_overrides"], true);
+
+	let updated: Value =
+		test::call_and_read_body_json(&app, patch(json!({"quiet_hours": null}))).await;
+	assert!(updated["quiet_hours"].is_null());
+	assert_eq!(updated["channels"]["email"], true);
+
+	let bob_preferences: Value = test::call_and_read_body_json(&app, get(&bob, &bob_token)).await;
+	assert_eq!(
+		serde_json::from_value::<UserPreferences>(bob_preferences).unwrap(),
+		UserPreferences::default()
+	);
+
+	let resp = test::call_service(&app, patch(json!({"broadcast": false}))).await;
+	assert_eq!(resp.status(), 400);
+	let resp = test::call_service(&app, patch(json!({"proximity_radius_m": 0}))).await;
+	assert_eq!(resp.status(), 400);
+	let resp = test::call_service(&app, patch(json!({"checkin_interval_minutes": 1}))).await;
+	assert_eq!(resp.status(), 400);
+
+	// Webhooks may not point into our own network
+	for url in [
+		"http://169.254.169.254/latest/meta-data",
+		"http://localhost:8080/hook",
+		"https://10.0.0.7/hook",
+	] {
+		let resp = test::call_service(&app, patch(json!({"contacts": {"webhook_url": url}}))).await;
+		assert_eq!(resp.status(), 400, "{}", url);
+	}
+	let resp = test::call_service(
+		&app,
+		patch(json!({"contacts": {"webhook_url": "https://93.184.216.34/hook"}})),
+	)
+	.await;
+	assert_eq!(resp.status(), 200);
+}
+
+#[actix_web::test]
+async fn version_one_preferences_read_with_defaults() {
+	let preferences: UserPreferences = serde_json::from_value(json!({
+		"broadcast_duress": false,
+		"receive_duress_broadcasts": true
+	}))
+	.unwrap();
+	assert_eq!(preferences.version, 1);
+	assert!(!preferences.broadcast_duress);
+	assert!(preferences.channels.push);
+	assert!(preferences.quiet_hours.is_none());
+	assert_eq!(preferences.checkin_interval_minutes, 60);
+}
+
+#[actix_web::test]
+async fn quiet_hours_wrap_past_midnight_in_local_time() {
+	let quiet_hours = QuietHours {
+		start: "22:00:00".parse().unwrap(),
+		end: "07:00:00".parse().unwrap(),
+		utc_offset_minutes: 120,
+		duress_overrides: true,
+	};
+	let at = |time: &str| time.parse().unwrap();
+
+	assert!(quiet_hours.contains(at("2024-10-05T21:30:00Z")));
+	assert!(quiet_hours.contains(at("2024-10-05T04:00:00Z")));
+	assert!(!quiet_hours.contains(at("2024-10-05T05:00:00Z")));
+	assert!(!quiet_hours.contains(at("2024-10-05T19:00:00Z")));
+}
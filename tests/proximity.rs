@@ -0,0 +1,281 @@
+mod common;
+
+use std::sync::{Arc, Mutex};
+
+use actix_web::test;
+use async_trait::async_trait;
+use chrono::{Duration, Utc};
+use cherubgyre::delivery_db::AlertKind;
+use cherubgyre::duress_db::Location;
+use cherubgyre::location_db::{self, UserLocation};
+use cherubgyre::notify::{Alert, Notifier, Notifiers, NotifyError, Recipient};
+use cherubgyre::queue;
+use common::{bearer, follow, init_app_with_notifiers, memory_store, register, seed_invite};
+use serde_json::json;
+
+// Records every alert with who it went to
+#[derive(Clone, Default)]
+struct RecordingNotifier {
+	sent: Arc<Mutex<Vec<(String, Alert)>>>,
+}
+
+impl RecordingNotifier {
+	fn alerts_for(&self, user_id: &str) -> Vec<Alert> {
+		self.sent
+			.lock()
+			.unwrap()
+			.iter()
+			.filter(|(recipient_id, _)| recipient_id == user_id)
+			.map(|(_, alert)| alert.clone())
+			.collect()
+	}
+}
+
+#[async_trait]
+impl Notifier for RecordingNotifier {
+	fn channel(&self) -> &'static str {
+		"recording"
+	}
+
+	fn reaches(&self, _recipient: &Recipient) -> bool {
+		true
+	}
+
+	async fn send(&self, recipient: &Recipient, alert: &Alert) -> Result<(), NotifyError> {
+		self.sent
+			.lock()
+			.unwrap()
+			.push((recipient.user_id.clone(), alert.clone()));
+		Ok(())
+	}
+}
+
+// `metres` north of `from`
+fn north_of(from: Location, metres: f64) -> Location {
+	Location {
+		latitude: from.latitude + metres / 111_195.0,
+		..from
+	}
+}
+
+const ORIGIN: Location = Location {
+	latitude: 51.5007,
+	longitude: -0.1246,
+	accuracy: Some(5.0),
+};
+
+#[actix_web::test]
+async fn index_cells_cover_the_search_radius() {
+	for centre in [
+		ORIGIN,
+		Location {
+			latitude: -36.85,
+			longitude: 179.95,
+			accuracy: None,
+		},
+		Location {
+			latitude: 89.9,
+			longitude: 10.0,
+			accuracy: None,
+		},
+	] {
+		let cells = location_db::cells_within(&centre, 50_000.0);
+		for bearing in 0..36 {
+			let angle = (bearing as f64 * 10.0).to_radians();
+			let lat = centre.latitude + 0.44 * angle.cos();
+			let point = Location {
+				latitude: lat.clamp(-90.0, 90.0),
+				longitude: (centre.longitude
+					+ 0.44 * angle.sin() / lat.to_radians().cos().max(0.01)
+					+ 540.0)
+					.rem_euclid(360.0)
+					- 180.0,
+				accuracy: None,
+			};
+			if location_db::distance_m(&centre, &point) <= 50_000.0 {
+				assert!(cells.contains(&location_db::cell_of(&point, location_db::CELL_PRECISION)));
+			}
+		}
+	}
+
+	let ten_km = location_db::distance_m(&ORIGIN, &north_of(ORIGIN, 10_000.0));
+	assert!((ten_km - 10_000.0).abs() < 10.0);
+}
+
+#[actix_web::test]
+async fn duress_alerts_reach_opted_in_users_nearby_without_identifying_the_user() {
+	let store = memory_store();
+	seed_invite(&store, "invite-1", "invitor").await;
+	let recorder = RecordingNotifier::default();
+	let notifiers = Arc::new(Notifiers::new(vec![Box::new(recorder.clone())]));
+	let app = init_app_with_notifiers(store.clone(), notifiers.clone()).await;
+
+	let (victim, victim_token) = register(&app, "invite-1", "1234", "4321").await;
+	let (follower, follower_token) = register(&app, "invite-1", "1111", "2222").await;
+	let (nearby, nearby_token) = register(&app, "invite-1", "3333", "4444").await;
+	let (far, far_token) = register(&app, "invite-1", "5555", "6666").await;
+	let (opted_out, opted_out_token) = register(&app, "invite-1", "7777", "8888").await;
+	let (stale, _) = register(&app, "invite-1", "9999", "0000").await;
+
+	follow(&app, &follower, &follower_token, &victim, &victim_token).await;
+
+	// Everyone but the victim checks in; the follower is right next to them
+	for (user_id, token, metres) in [
+		(&follower, &follower_token, 100.0),
+		(&nearby, &nearby_token, 600.0),
+		(&far, &far_token, 5_000.0),
+		(&opted_out, &opted_out_token, 200.0),
+	] {
+		let req = test::TestRequest::post()
+			.uri(&format!("/users/{}/checkin", user_id))
+			.insert_header(bearer(token))
+			.set_json(json!({
+				"location": north_of(ORIGIN, metres),
+				"timestamp": Utc::now()
+			}))
+			.to_request();
+		assert_eq!(test::call_service(&app, req).await.status(), 200);
+	}
+	let req = test::TestRequest::patch()
+		.uri(&format!("/users/{}/preferences", opted_out))
+		.insert_header(bearer(&opted_out_token))
+		.set_json(json!({"receive_duress_broadcasts": false}))
+		.to_request();
+	assert_eq!(test::call_service(&app, req).await.status(), 200);
+	// Close by, but has not checked in for hours
+	let mut old = UserLocation::new(&stale, north_of(ORIGIN, 300.0));
+	old.updated_at = Utc::now() - Duration::hours(3);
+	store.save_user_location(&old).await.unwrap();
+
+	let req = test::TestRequest::post()
+		.uri(&format!("/users/{}/duress", victim))
+		.insert_header(bearer(&victim_token))
+		.set_json(json!({
+			"duress_type": "assault",
+			"message": "I'm outside Alice's flat",
+			"timestamp": Utc::now(),
+			"location": ORIGIN
+		}))
+		.to_request();
+	assert_eq!(test::call_service(&app, req).await.status(), 200);
+	queue::process_due_jobs(store.as_ref(), &notifiers, Utc::now())
+		.await
+		.unwrap();
+
+	// The follower hears everything, as before
+	let alerts = recorder.alerts_for(&follower);
+	assert_eq!(alerts.len(), 1);
+	assert_eq!(alerts[0].user_id.as_deref(), Some(victim.as_str()));
+	assert!(!alerts[0].nearby);
+	assert_eq!(alerts[0].location, Some(ORIGIN));
+
+	// The stranger nearby learns only that someone close needs help, and roughly where
+	let alerts = recorder.alerts_for(&nearby);
+	assert_eq!(alerts.len(), 1);
+	let alert = &alerts[0];
+	assert!(alert.nearby);
+	assert_eq!(alert.user_id, None);
+	assert!(alert.message.is_empty());
+	let location = alert.location.unwrap();
+	assert_ne!(location, ORIGIN);
+	assert!(location_db::distance_m(&location, &ORIGIN) < 200.0);
+	assert!(!serde_json::to_string(alert).unwrap().contains(&victim));
+
+	for user_id in [&far, &opted_out, &stale, &victim] {
+		assert!(recorder.alerts_for(user_id).is_empty(), "{}", user_id);
+	}
+
+	// The ending is anonymous to the stranger too
+	let req = test::TestRequest::post()
+		.uri(&format!("/users/{}/duress/cancel", victim))
+		.insert_header(bearer(&victim_token))
+		.set_json(json!({"normal_pin": "1234", "confirm": true}))
+		.to_request();
+	assert_eq!(test::call_service(&app, req).await.status(), 200);
+	// The cancellation is announced in the background
+	for _ in 0..50 {
+		queue::process_due_jobs(store.as_ref(), &notifiers, Utc::now())
+			.await
+			.unwrap();
+		if recorder.alerts_for(&nearby).len() > 1 {
+			break;
+		}
+		actix_web::rt::time::sleep(std::time::Duration::from_millis(20)).await;
+	}
+	let alerts = recorder.alerts_for(&nearby);
+	assert_eq!(alerts.len(), 2);
+	assert_eq!(alerts[1].kind, AlertKind::Ended);
+	assert_eq!(alerts[1].user_id, None);
+	assert_eq!(recorder.alerts_for(&follower).len(), 2);
+}
+
+#[actix_web::test]
+async fn duress_pin_logins_use_the_last_check_in_unless_broadcasts_are_off() {
+	let store = memory_store();
+	seed_invite(&store, "invite-1", "invitor").await;
+	let recorder = RecordingNotifier::default();
+	let notifiers = Arc::new(Notifiers::new(vec![Box::new(recorder.clone())]));
+	let app = init_app_with_notifiers(store.clone(), notifiers.clone()).await;
+
+	let (victim, victim_token) = register(&app, "invite-1", "1234", "4321").await;
+	let (nearby, nearby_token) = register(&app, "invite-1", "3333", "4444").await;
+	for (user_id, token, metres) in [
+		(&victim, &victim_token, 0.0),
+		(&nearby, &nearby_token, 400.0),
+	] {
+		let req = test::TestRequest::post()
+			.uri(&format!("/users/{}/checkin", user_id))
+			.insert_header(bearer(token))
+			.set_json(json!({
+				"location": north_of(ORIGIN, metres),
+				"timestamp": Utc::now()
+			}))
+			.to_request();
+		assert_eq!(test::call_service(&app, req).await.status(), 200);
+	}
+
+	let login = |pin: &'static str| {
+		test::TestRequest::post()
+			.uri("/login")
+			.set_json(json!({"user_id": victim, "pin": pin}))
+			.to_request()
+	};
+	assert_eq!(test::call_service(&app, login("4321")).await.status(), 200);
+	// The alert is raised in the background
+	for _ in 0..50 {
+		queue::process_due_jobs(store.as_ref(), &notifiers, Utc::now())
+			.await
+			.unwrap();
+		if !recorder.alerts_for(&nearby).is_empty() {
+			break;
+		}
+		actix_web::rt::time::sleep(std::time::Duration::from_millis(20)).await;
+	}
+	let alerts = recorder.alerts_for(&nearby);
+	assert_eq!(alerts.len(), 1);
+	assert!(alerts[0].nearby);
+	assert!(alerts[0].location.is_some());
+
+	// A user who does not broadcast their duress alerts only their followers
+	let req = test::TestRequest::patch()
+		.uri(&format!("/users/{}/preferences", victim))
+		.insert_header(bearer(&victim_token))
+		.set_json(json!({"broadcast_duress": false}))
+		.to_request();
+	assert_eq!(test::call_service(&app, req).await.status(), 200);
+	let req = test::TestRequest::post()
+		.uri(&format!("/users/{}/duress", victim))
+		.insert_header(bearer(&victim_token))
+		.set_json(json!({
+			"duress_type": "medical",
+			"message": "",
+			"timestamp": Utc::now(),
+			"location": ORIGIN
+		}))
+		.to_request();
+	assert_eq!(test::call_service(&app, req).await.status(), 200);
+	queue::process_due_jobs(store.as_ref(), &notifiers, Utc::now())
+		.await
+		.unwrap();
+	assert_eq!(recorder.alerts_for(&nearby).len(), 1);
+}
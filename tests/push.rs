@@ -0,0 +1,335 @@
+mod common;
+
+use std::sync::{Arc, Mutex};
+
+use actix_web::{test, web, App, HttpRequest, HttpResponse, HttpServer};
+use aes_gcm::aead::{Aead, KeyInit};
+use aes_gcm::{Aes128Gcm, Nonce};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chrono::Utc;
+use cherubgyre::delivery_db::AlertKind;
+use cherubgyre::duress_db::{DuressEvent, DuressType};
+use cherubgyre::notify::{Alert, Notifier, Notifiers, Recipient};
+use cherubgyre::notify_backends::PushNotifier;
+use cherubgyre::push_db::PushSubscription;
+use cherubgyre::queue;
+use cherubgyre::webpush::{self, SubscriptionKeys, VAPID_KEY};
+use common::{bearer, follow, init_app_with_notifiers, memory_store, register, seed_invite};
+use hkdf::Hkdf;
+use p256::ecdsa::signature::Verifier;
+use p256::ecdsa::{Signature, VerifyingKey};
+use p256::elliptic_curve::sec1::ToEncodedPoint;
+use p256::{PublicKey, SecretKey};
+use serde_json::{json, Value};
+use sha2::Sha256;
+
+fn b64(value: &str) -> Vec<u8> {
+	URL_SAFE_NO_PAD.decode(value).unwrap()
+}
+
+// A request to the mock push service: path, headers of interest and body
+type PushRequest = (String, String, String, String, Vec<u8>);
+
+#[derive(Clone, Default)]
+struct Received {
+	requests: Arc<Mutex<Vec<PushRequest>>>,
+}
+
+// A push service on a local port. Paths starting with /gone answer 410.
+async fn start_push_service(received: Received) -> String {
+	let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+	let port = listener.local_addr().unwrap().port();
+	let server = HttpServer::new(move || {
+		let received = received.clone();
+		App::new().default_service(web::to(move |req: HttpRequest, body: web::Bytes| {
+			let received = received.clone();
+			async move {
+				let header = |name: &str| {
+					req.headers()
+						.get(name)
+						.and_then(|value| value.to_str().ok())
+						.unwrap_or_default()
+						.to_string()
+				};
+				received.requests.lock().unwrap().push((
+					req.path().to_string(),
+					header("authorization"),
+					header("content-encoding"),
+					header("urgency"),
+					body.to_vec(),
+				));
+				if req.path().starts_with("/gone") {
+					HttpResponse::Gone().finish()
+				} else {
+					HttpResponse::Created().finish()
+				}
+			}
+		}))
+	})
+	.workers(1)
+	.listen(listener)
+	.unwrap()
+	.run();
+	actix_web::rt::spawn(server);
+	format!("http://127.0.0.1:{}", port)
+}
+
+// Undo RFC 8291 encryption as a browser would
+fn decrypt(receiver: &SecretKey, auth: &[u8], body: &[u8]) -> Vec<u8> {
+	let salt = &body[..16];
+	let id_len = body[20] as usize;
+	let sender_public = PublicKey::from_sec1_bytes(&body[21..21 + id_len]).unwrap();
+	let ciphertext = &body[21 + id_len..];
+
+	let shared =
+		p256::ecdh::diffie_hellman(receiver.to_nonzero_scalar(), sender_public.as_affine());
+	let mut key_info = b"WebPush: info\0".to_vec();
+	key_info.extend_from_slice(receiver.public_key().to_encoded_point(false).as_bytes());
+	key_info.extend_from_slice(sender_public.to_encoded_point(false).as_bytes());
+	let mut ikm = [0u8; 32];
+	Hkdf::<Sha256>::new(Some(auth), shared.raw_secret_bytes())
+		.expand(&key_info, &mut ikm)
+		.unwrap();
+	let prk = Hkdf::<Sha256>::new(Some(salt), &ikm);
+	let mut cek = [0u8; 16];
+	let mut nonce = [0u8; 12];
+	prk.expand(b"Content-Encoding: aes128gcm\0", &mut cek)
+		.unwrap();
+	prk.expand(b"Content-Encoding: nonce\0", &mut nonce)
+		.unwrap();
+
+	let mut record = Aes128Gcm::new_from_slice(&cek)
+		.unwrap()
+		.decrypt(Nonce::from_slice(&nonce), ciphertext)
+		.unwrap();
+	assert_eq!(record.pop(), Some(2));
+	record
+}
+
+#[actix_web::test]
+async fn encryption_matches_the_rfc_8291_example() {
+	let keys = SubscriptionKeys::from_base64(
+		"BCVxsr7N_eNgVRqvHtD0zTZsEc6-VV-JvLexhqUzORcxaOzi6-AYWXvTBHm4bjyPjs7Vd8pZGH6SRpkNtoIAiw4",
+		"BTBZMqHH6r4Tts7J_aSIgg",
+	)
+	.unwrap();
+	let sender =
+		SecretKey::from_slice(&b64("yfWPiYE-n46HLnH0KqZOF1fJJU3MYrct3AELtAQ-oRw")).unwrap();
+	let salt = b64("DGv6ra1nlYgDCS1FRnbzlw").try_into().unwrap();
+
+	let body = webpush::encrypt_with(
+		&keys,
+		b"When I grow up, I want to be a watermelon",
+		&sender,
+		salt,
+	);
+	assert_eq!(
+		URL_SAFE_NO_PAD.encode(body),
+		"DGv6ra1nlYgDCS1FRnbzlwAAEABBBP4z9KsN6nGRTbVYI_c7VJSPQTBtkgcy27mlmlMoZIIgDll6e3vCYLocInmYWAmS6TlzAC8wEqKK6PBru3jl7A_yl95bQpu6cVPTpK4Mqgkf1CXztLVBSt2Ks3oZwbuwXPXLWyouBWLVWGNWQexSgSxsj_Qulcy4a-fN"
+	);
+}
+
+#[actix_web::test]
+async fn duress_alerts_are_pushed_to_subscribed_browsers() {
+	let received = Received::default();
+	let push_service = start_push_service(received.clone()).await;
+
+	let store = memory_store();
+	seed_invite(&store, "invite-1", "invitor").await;
+	// The mock push service is plain http on loopback
+	let notifiers = Arc::new(Notifiers::new(vec![Box::new(
+		PushNotifier::new(
+			store.clone(),
+			&VAPID_KEY,
+			Some("mailto:ops@example.com".to_string()),
+		)
+		.allowing_private_addresses(),
+	)]));
+	let app = init_app_with_notifiers(store.clone(), notifiers.clone()).await;
+
+	let req = test::TestRequest::get()
+		.uri("/push/vapid-public-key")
+		.to_request();
+	let body: Value = test::call_and_read_body_json(&app, req).await;
+	assert_eq!(body["public_key"], VAPID_KEY.public_key());
+
+	let (user_id, token) = register(&app, "invite-1", "1234", "4321").await;
+	let (follower_id, follower_token) = register(&app, "invite-1", "1111", "2222").await;
+	follow(&app, &follower_id, &follower_token, &user_id, &token).await;
+
+	// The follower's browser, plus a stale subscription the service has dropped
+	let browser_key = SecretKey::random(&mut rand::rngs::OsRng);
+	let p256dh =
+		URL_SAFE_NO_PAD.encode(browser_key.public_key().to_encoded_point(false).as_bytes());
+	let auth = URL_SAFE_NO_PAD.encode([7u8; 16]);
+	// Saved directly, as the API only takes public https endpoints
+	for endpoint in ["/push/browser", "/gone/old-browser"] {
+		store
+			.save_push_subscription(&PushSubscription::new(
+				&follower_id,
+				&format!("{}{}", push_service, endpoint),
+				&p256dh,
+				&auth,
+			))
+			.await
+			.unwrap();
+	}
+
+	let subscribe = |endpoint: &str, p256dh: &str| {
+		test::TestRequest::post()
+			.uri(&format!("/users/{}/push-subscriptions", user_id))
+			.insert_header(bearer(&token))
+			.set_json(json!({
+				"endpoint": endpoint,
+				"expirationTime": null,
+				"keys": {"p256dh": p256dh, "auth": auth}
+			}))
+			.to_request()
+	};
+	assert_eq!(
+		test::call_service(&app, subscribe("https://93.184.216.34/push/1", "not-a-key"))
+			.await
+			.status(),
+		400
+	);
+	// Nothing but public https endpoints
+	for endpoint in [
+		"http://93.184.216.34/push/1",
+		"https://127.0.0.1/push/1",
+		"https://localhost/push/1",
+		"https://169.254.169.254/latest/meta-data",
+		"https://10.0.0.7/push/1",
+		"https://[::1]/push/1",
+		"https://[::ffff:192.168.1.1]/push/1",
+	] {
+		let resp = test::call_service(&app, subscribe(endpoint, &p256dh)).await;
+		assert_eq!(resp.status(), 400, "{}", endpoint);
+	}
+	assert_eq!(
+		test::call_service(&app, subscribe("https://93.184.216.34/push/1", &p256dh))
+			.await
+			.status(),
+		200
+	);
+
+	let req = test::TestRequest::post()
+		.uri(&format!("/users/{}/duress", user_id))
+		.insert_header(bearer(&token))
+		.set_json(json!({
+			"duress_type": "assault",
+			"message": "help",
+			"timestamp": "2024-10-05T11:57:33Z"
+		}))
+		.to_request();
+	assert_eq!(test::call_service(&app, req).await.status(), 200);
+	assert_eq!(
+		queue::process_due_jobs(store.as_ref(), &notifiers, Utc::now())
+			.await
+			.unwrap(),
+		1
+	);
+
+	let requests = received.requests.lock().unwrap().clone();
+	assert_eq!(requests.len(), 2);
+	let (_, authorization, encoding, urgency, body) = requests
+		.iter()
+		.find(|(path, ..)| path == "/push/browser")
+		.unwrap();
+	assert_eq!(encoding, "aes128gcm");
+	assert_eq!(urgency, "high");
+
+	// A VAPID token for the push service's origin, signed by our key
+	let (token, key) = authorization
+		.strip_prefix("vapid t=")
+		.and_then(|rest| rest.split_once(", k="))
+		.unwrap();
+	assert_eq!(key, VAPID_KEY.public_key());
+	let (signing_input, signature) = token.rsplit_once('.').unwrap();
+	VerifyingKey::from_sec1_bytes(&b64(key))
+		.unwrap()
+		.verify(
+			signing_input.as_bytes(),
+			&Signature::from_slice(&b64(signature)).unwrap(),
+		)
+		.unwrap();
+	let claims: Value =
+		serde_json::from_slice(&b64(signing_input.split('.').nth(1).unwrap())).unwrap();
+	assert_eq!(claims["aud"], push_service);
+	assert_eq!(claims["sub"], "mailto:ops@example.com");
+
+	let alert: Value = serde_json::from_slice(&decrypt(&browser_key, &[7u8; 16], body)).unwrap();
+	assert_eq!(alert["user_id"], user_id);
+	assert_eq!(alert["kind"], "raised");
+	assert_eq!(alert["message"], "help");
+
+	// The subscription the push service reported gone was dropped
+	let req = test::TestRequest::get()
+		.uri(&format!("/users/{}/push-subscriptions", follower_id))
+		.insert_header(bearer(&follower_token))
+		.to_request();
+	let subscriptions: Value = test::call_and_read_body_json(&app, req).await;
+	let subscriptions = subscriptions.as_array().unwrap();
+	assert_eq!(subscriptions.len(), 1);
+	assert_eq!(
+		subscriptions[0]["endpoint"],
+		format!("{}/push/browser", push_service)
+	);
+
+	let req = test::TestRequest::delete()
+		.uri(&format!(
+			"/users/{}/push-subscriptions/{}",
+			follower_id,
+			subscriptions[0]["id"].as_str().unwrap()
+		))
+		.insert_header(bearer(&follower_token))
+		.to_request();
+	assert_eq!(test::call_service(&app, req).await.status(), 200);
+	assert!(store
+		.get_push_subscriptions(&follower_id)
+		.await
+		.unwrap()
+		.is_empty());
+}
+
+#[actix_web::test]
+async fn long_messages_are_cut_to_fit_a_push_message() {
+	let received = Received::default();
+	let push_service = start_push_service(received.clone()).await;
+	let store = memory_store();
+	let notifier = PushNotifier::new(store.clone(), &VAPID_KEY, None).allowing_private_addresses();
+
+	let browser_key = SecretKey::random(&mut rand::rngs::OsRng);
+	store
+		.save_push_subscription(&PushSubscription::new(
+			"follower",
+			&format!("{}/push/browser", push_service),
+			&URL_SAFE_NO_PAD.encode(browser_key.public_key().to_encoded_point(false).as_bytes()),
+			&URL_SAFE_NO_PAD.encode([7u8; 16]),
+		))
+		.await
+		.unwrap();
+	let recipient = Recipient::load(store.as_ref(), "follower").await.unwrap();
+
+	// Quotes double in JSON, and each of these characters is several bytes
+	for message in [
+		"\"".repeat(3000),
+		"ü\"".repeat(1500),
+		"\u{1F6A8}\n".repeat(1000),
+	] {
+		let event = DuressEvent::new("victim", DuressType::Assault, &message, Utc::now());
+		notifier
+			.send(&recipient, &Alert::new(&event, AlertKind::Raised))
+			.await
+			.unwrap();
+
+		let (_, _, _, _, body) = received.requests.lock().unwrap().pop().unwrap();
+		assert!(body.len() <= 4096, "{} bytes", body.len());
+		let alert: Value =
+			serde_json::from_slice(&decrypt(&browser_key, &[7u8; 16], &body)).unwrap();
+		let sent = alert["message"].as_str().unwrap();
+		assert!(!sent.is_empty());
+		assert!(message.starts_with(sent));
+		assert!(sent.len() < message.len());
+	}
+}
@@ -0,0 +1,55 @@
+use std::sync::Arc;
+
+use actix_web::{test, web, App};
+use chrono::Utc;
+use cherubgyre::configure;
+use cherubgyre::db::Invite;
+use cherubgyre::memory_store::MemoryStore;
+use cherubgyre::store::Store;
+use serde_json::json;
+
+#[actix_web::test]
+async fn register_consumes_invite() {
+	let store = Arc::new(MemoryStore::new());
+	store
+		.save_invite(&Invite {
+			code: "invite-1".to_string(),
+			invitor_id: "invitor".to_string(),
+			invite_count: 0,
+			created_at: Utc::now(),
+			expires_at: None,
+			max_uses: None,
+			revoked: false,
+		})
+		.await
+		.unwrap();
+
+	let app_store: Arc<dyn Store> = store.clone();
+	let app = test::init_service(
+		App::new()
+			.app_data(web::Data::from(app_store))
+			.configure(configure),
+	)
+	.await;
+
+	let req = test::TestRequest::post()
+		.uri("/register")
+		.set_json(json!({"invite_code": "invite-1", "normal_pin": "1234", "duress_pin": "4321"}))
+		.to_request();
+	let resp = test::call_service(&app, req).await;
+	assert!(resp.status().is_success());
+	let body = test::read_body(resp).await;
+	let body = std::str::from_utf8(&body).unwrap();
+	assert!(!body.contains("pin"));
+	assert!(!body.contains("1234"));
+
+	let invite = store.get_invite("invite-1").await.unwrap().unwrap();
+	assert_eq!(invite.invite_count, 1);
+
+	let req = test::TestRequest::post()
+		.uri("/register")
+		.set_json(json!({"invite_code": "missing", "normal_pin": "1234", "duress_pin": "4321"}))
+		.to_request();
+	let resp = test::call_service(&app, req).await;
+	assert_eq!(resp.status(), 400);
+}
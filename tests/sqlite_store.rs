@@ -0,0 +1,516 @@
+use chrono::{Duration, Utc};
+use cherubgyre::block_db::Block;
+use cherubgyre::checkin_db::Checkin;
+use cherubgyre::delivery_db::{AlertKind, Job};
+use cherubgyre::follow_db::FollowRequest;
+use cherubgyre::db::{Invite, User};
+use cherubgyre::location_db::UserLocation;
+use cherubgyre::notify::Alert;
+use cherubgyre::push_db::PushSubscription;
+use cherubgyre::duress_db::{DuressEvent, DuressType, Location, QuietHours, UserPreferences};
+use cherubgyre::memory_store::MemoryStore;
+use cherubgyre::sqlite_store::SqliteStore;
+use cherubgyre::store::Store;
+use serde_json::json;
+
+fn invited_user(id: &str, invite_code: &str) -> User {
+	User {
+		id: id.to_string(),
+		invite_code: invite_code.to_string(),
+		normal_pin_hash: "normal".to_string(),
+		duress_pin_hash: "duress".to_string(),
+		handle: None,
+		admin: false,
+		suspended_at: None,
+	}
+}
+
+#[actix_web::test]
+async fn sqlite_store_round_trips() {
+	let store = SqliteStore::open_in_memory().unwrap();
+
+	store
+		.save_invite(&Invite {
+			code: "invite-1".to_string(),
+			invitor_id: "invitor".to_string(),
+			invite_count: 0,
+			created_at: Utc::now(),
+			expires_at: None,
+			max_uses: None,
+			revoked: false,
+		})
+		.await
+		.unwrap();
+	let invites = store.get_user_invites("invitor").await.unwrap();
+	assert_eq!(invites.len(), 1);
+	assert_eq!(invites[0].code, "invite-1");
+
+	store
+		.save_invite(&Invite {
+			code: "twice".to_string(),
+			invitor_id: "invitor".to_string(),
+			invite_count: 0,
+			created_at: Utc::now(),
+			expires_at: Some(Utc::now() + Duration::hours(1)),
+			max_uses: Some(2),
+			revoked: false,
+		})
+		.await
+		.unwrap();
+	let create = |id: &'static str, code: &'static str, at| {
+		let store = &store;
+		async move { store.create_invited_user(&invited_user(id, code), at).await }
+	};
+	assert!(create("u1", "twice", Utc::now()).await.unwrap());
+	assert!(!create("u2", "twice", Utc::now() + Duration::hours(2))
+		.await
+		.unwrap());
+	// A failed insert does not use up the invite
+	assert!(create("u1", "twice", Utc::now()).await.is_err());
+	assert!(create("u2", "twice", Utc::now()).await.unwrap());
+	assert!(!create("u3", "twice", Utc::now()).await.unwrap());
+	assert!(!create("u3", "missing", Utc::now()).await.unwrap());
+	assert!(store.get_user("u3").await.unwrap().is_none());
+	assert_eq!(
+		store
+			.get_invite("twice")
+			.await
+			.unwrap()
+			.unwrap()
+			.invite_count,
+		2
+	);
+	store.revoke_invite("invite-1").await.unwrap();
+	assert!(!create("u3", "invite-1", Utc::now()).await.unwrap());
+	let mut invite = store.get_invite("invite-1").await.unwrap().unwrap();
+	invite.revoked = false;
+	store.update_invite(&invite).await.unwrap();
+	assert!(create("u3", "invite-1", Utc::now()).await.unwrap());
+	// A stale copy written back does not undo the registration
+	store.update_invite(&invite).await.unwrap();
+	assert_eq!(
+		store
+			.get_invite("invite-1")
+			.await
+			.unwrap()
+			.unwrap()
+			.invite_count,
+		1
+	);
+	assert_eq!(
+		store.get_user("u3").await.unwrap().unwrap().invite_code,
+		"invite-1"
+	);
+	let invitees = store.get_users_by_invite("twice").await.unwrap();
+	assert_eq!(
+		invitees
+			.iter()
+			.map(|user| user.id.as_str())
+			.collect::<Vec<_>>(),
+		["u1", "u2"]
+	);
+	let mut user = invitees[0].clone();
+	user.admin = true;
+	user.suspended_at = Some(Utc::now());
+	store.save_user(&user).await.unwrap();
+	let user = store.get_user("u1").await.unwrap().unwrap();
+	assert!(user.admin);
+	assert!(user.suspended_at.is_some());
+	assert!(!store.suspend_user("u1", Utc::now()).await.unwrap());
+	assert!(store.unsuspend_user("u1").await.unwrap());
+	assert!(store.suspend_user("u1", Utc::now()).await.unwrap());
+	assert!(!store.suspend_user("missing", Utc::now()).await.unwrap());
+	assert!(!store.unsuspend_user("missing").await.unwrap());
+	let user = store.get_user("u1").await.unwrap().unwrap();
+	assert!(user.admin);
+	assert!(user.suspended_at.is_some());
+
+	store.add_follow("alice", "bob").await.unwrap();
+	store.add_follow("alice", "bob").await.unwrap();
+	let followers = store.get_follows("bob").await.unwrap();
+	assert_eq!(followers.len(), 1);
+	assert_eq!(followers[0].follower_id, "alice");
+	store.remove_follow("alice", "bob").await.unwrap();
+	assert!(store.get_follows("bob").await.unwrap().is_empty());
+
+	for follower in ["carol", "alice", "dave"] {
+		store.add_follow(follower, "bob").await.unwrap();
+	}
+	store.add_follow("alice", "carol").await.unwrap();
+	let page = store.get_followers_page("bob", None, 2).await.unwrap();
+	let names: Vec<_> = page
+		.follows
+		.iter()
+		.map(|f| f.follower_id.as_str())
+		.collect();
+	assert_eq!(names, ["alice", "carol"]);
+	let page = store
+		.get_followers_page("bob", page.next.as_ref(), 2)
+		.await
+		.unwrap();
+	assert_eq!(page.follows.len(), 1);
+	assert_eq!(page.follows[0].follower_id, "dave");
+	assert!(page.next.is_none());
+	assert_eq!(store.count_followers("bob").await.unwrap(), 3);
+
+	store
+		.save_follow_request(&FollowRequest::new("erin", "bob"))
+		.await
+		.unwrap();
+	let requests = store.get_follow_requests("bob").await.unwrap();
+	assert_eq!(requests.len(), 1);
+	assert_eq!(requests[0].follower_id, "erin");
+	assert!(store
+		.get_follow_request("erin", "bob")
+		.await
+		.unwrap()
+		.is_some());
+	store.delete_follow_request("erin", "bob").await.unwrap();
+	assert!(store
+		.get_follow_request("erin", "bob")
+		.await
+		.unwrap()
+		.is_none());
+
+	store.save_block(&Block::new("bob", "erin")).await.unwrap();
+	assert!(store.get_block("bob", "erin").await.unwrap().is_some());
+	assert!(store.get_block("erin", "bob").await.unwrap().is_none());
+	assert_eq!(store.get_blocks("bob").await.unwrap()[0].blocked_id, "erin");
+	store.delete_block("bob", "erin").await.unwrap();
+
+	assert!(store
+		.reserve_handle("cherub-lagos-arc", "bob")
+		.await
+		.unwrap());
+	assert!(!store
+		.reserve_handle("cherub-lagos-arc", "erin")
+		.await
+		.unwrap());
+	store.release_handle("cherub-lagos-arc").await.unwrap();
+	assert!(store
+		.reserve_handle("cherub-lagos-arc", "erin")
+		.await
+		.unwrap());
+	assert!(store.get_blocks("bob").await.unwrap().is_empty());
+	assert_eq!(store.count_following("alice").await.unwrap(), 2);
+	let page = store.get_following_page("alice", None, 10).await.unwrap();
+	let names: Vec<_> = page
+		.follows
+		.iter()
+		.map(|f| f.followed_id.as_str())
+		.collect();
+	assert_eq!(names, ["bob", "carol"]);
+	for (follower, followed) in [
+		("carol", "bob"),
+		("alice", "bob"),
+		("dave", "bob"),
+		("alice", "carol"),
+	] {
+		store.remove_follow(follower, followed).await.unwrap();
+	}
+
+	assert!(store.get_user_preferences("alice").await.unwrap().is_none());
+	let preferences = UserPreferences {
+		broadcast_duress: false,
+		quiet_hours: Some(QuietHours {
+			start: "22:00:00".parse().unwrap(),
+			end: "07:00:00".parse().unwrap(),
+			utc_offset_minutes: 60,
+			duress_overrides: false,
+		}),
+		proximity_radius_m: 2_500,
+		..UserPreferences::default()
+	};
+	store
+		.update_user_preferences("alice", &preferences)
+		.await
+		.unwrap();
+	let stored = store.get_user_preferences("alice").await.unwrap().unwrap();
+	assert_eq!(stored, preferences);
+}
+
+#[actix_web::test]
+async fn sqlite_migrations_are_reentrant() {
+	let path = std::env::temp_dir().join(format!("cherubgyre-{}.db", uuid::Uuid::new_v4()));
+	let path = path.to_str().unwrap();
+
+	SqliteStore::open(path)
+		.unwrap()
+		.add_follow("alice", "bob")
+		.await
+		.unwrap();
+	let reopened = SqliteStore::open(path).unwrap();
+	assert_eq!(reopened.get_follows("bob").await.unwrap().len(), 1);
+
+	std::fs::remove_file(path).unwrap();
+}
+
+#[actix_web::test]
+async fn sqlite_duress_events_keep_structure_and_filter_by_time() {
+	let store = SqliteStore::open_in_memory().unwrap();
+	let timestamp = "2024-10-05T11:57:33Z".parse().unwrap();
+
+	let mut event = DuressEvent::new("alice", DuressType::Medical, "fell | hurt", timestamp);
+	event.location = Some(Location {
+		latitude: 59.33,
+		longitude: 18.07,
+		accuracy: Some(12.5),
+	});
+	event.additional_data = json!({"battery": 12});
+	store.log_duress_event(&event).await.unwrap();
+
+	let events = store
+		.get_user_duress_events("alice", None, None)
+		.await
+		.unwrap();
+	assert_eq!(events.len(), 1);
+	assert_eq!(events[0].duress_type, DuressType::Medical);
+	assert_eq!(events[0].message, "fell | hurt");
+	assert_eq!(events[0].timestamp, event.timestamp);
+	assert_eq!(events[0].location, event.location);
+	assert_eq!(events[0].additional_data, json!({"battery": 12}));
+
+	let later = event.created_at + Duration::seconds(1);
+	let earlier = event.created_at - Duration::seconds(1);
+	assert!(store
+		.get_user_duress_events("alice", Some(later), None)
+		.await
+		.unwrap()
+		.is_empty());
+	assert_eq!(
+		store
+			.get_user_duress_events("alice", Some(earlier), Some(later))
+			.await
+			.unwrap()
+			.len(),
+		1
+	);
+}
+
+#[actix_web::test]
+async fn sqlite_returns_the_latest_checkin() {
+	let store = SqliteStore::open_in_memory().unwrap();
+	assert!(store.get_last_checkin("alice").await.unwrap().is_none());
+
+	let location = |latitude| Location {
+		latitude,
+		longitude: 18.07,
+		accuracy: None,
+	};
+	let mut earlier = Checkin::new("alice", location(59.0), Utc::now());
+	earlier.created_at -= Duration::minutes(5);
+	store.save_checkin(&earlier).await.unwrap();
+	store
+		.save_checkin(&Checkin::new("alice", location(59.33), Utc::now()))
+		.await
+		.unwrap();
+
+	let last = store.get_last_checkin("alice").await.unwrap().unwrap();
+	assert_eq!(last.location, location(59.33));
+}
+
+#[actix_web::test]
+async fn sqlite_jobs_are_claimed_once_and_dead_lettered() {
+	let store = SqliteStore::open_in_memory().unwrap();
+	let now = Utc::now();
+	let event = DuressEvent::new("victim", DuressType::Medical, "", now);
+	let alert = Alert::new(&event, AlertKind::Raised);
+	let mut job = Job {
+		key: Job::key_for(&alert, "alice", "webhook"),
+		recipient_id: "alice".to_string(),
+		channel: "webhook".to_string(),
+		alert,
+		attempts: 0,
+		next_attempt_at: now,
+		last_error: None,
+		dead: false,
+		created_at: now,
+	};
+
+	assert!(store.enqueue_job(&job).await.unwrap());
+	assert!(!store.enqueue_job(&job).await.unwrap());
+
+	let due = store.get_due_jobs(now, 10).await.unwrap();
+	assert_eq!(due.len(), 1);
+	assert_eq!(due[0].alert.event_id, event.id);
+	let lease = now + Duration::seconds(60);
+	assert!(store.claim_job(&job.key, now, lease).await.unwrap());
+	assert!(!store.claim_job(&job.key, now, lease).await.unwrap());
+	assert!(store.get_due_jobs(now, 10).await.unwrap().is_empty());
+
+	job.attempts = 8;
+	job.dead = true;
+	job.last_error = Some("timed out".to_string());
+	store.update_job(&job).await.unwrap();
+	assert!(store
+		.get_due_jobs(now + Duration::days(1), 10)
+		.await
+		.unwrap()
+		.is_empty());
+	let dead = store.get_dead_jobs(10).await.unwrap();
+	assert_eq!(dead.len(), 1);
+	assert_eq!(dead[0].last_error.as_deref(), Some("timed out"));
+
+	store.delete_job(&job.key).await.unwrap();
+	assert!(store.get_dead_jobs(10).await.unwrap().is_empty());
+}
+
+#[actix_web::test]
+async fn sqlite_push_subscriptions_are_keyed_by_endpoint() {
+	let store = SqliteStore::open_in_memory().unwrap();
+	let first = PushSubscription::new("alice", "https://push.example/a", "key-1", "auth-1");
+	store.save_push_subscription(&first).await.unwrap();
+	// Resubscribing the same browser replaces its keys
+	let renewed = PushSubscription::new("alice", "https://push.example/a", "key-2", "auth-2");
+	store.save_push_subscription(&renewed).await.unwrap();
+	store
+		.save_push_subscription(&PushSubscription::new(
+			"alice",
+			"https://push.example/b",
+			"key-3",
+			"auth-3",
+		))
+		.await
+		.unwrap();
+
+	let subscriptions = store.get_push_subscriptions("alice").await.unwrap();
+	assert_eq!(subscriptions.len(), 2);
+	assert!(subscriptions
+		.iter()
+		.any(|s| s.id == first.id && s.p256dh == "key-2"));
+	assert!(store
+		.get_push_subscriptions("bob")
+		.await
+		.unwrap()
+		.is_empty());
+
+	store
+		.delete_push_subscription("alice", &first.id)
+		.await
+		.unwrap();
+	assert_eq!(
+		store.get_push_subscriptions("alice").await.unwrap().len(),
+		1
+	);
+}
+
+#[actix_web::test]
+async fn sqlite_user_locations_are_found_by_cell() {
+	let store = SqliteStore::open_in_memory().unwrap();
+	let here = Location {
+		latitude: 51.5007,
+		longitude: -0.1246,
+		accuracy: None,
+	};
+	store
+		.save_user_location(&UserLocation::new("alice", here))
+		.await
+		.unwrap();
+	let moved = UserLocation::new(
+		"alice",
+		Location {
+			latitude: 48.8584,
+			longitude: 2.2945,
+			accuracy: Some(20.0),
+		},
+	);
+	store.save_user_location(&moved).await.unwrap();
+	store
+		.save_user_location(&UserLocation::new("bob", here))
+		.await
+		.unwrap();
+
+	let found = store
+		.get_user_locations_in_cells(std::slice::from_ref(&moved.cell))
+		.await
+		.unwrap();
+	assert_eq!(found.len(), 1);
+	assert_eq!(found[0].user_id, "alice");
+	assert_eq!(found[0].location.accuracy, Some(20.0));
+	let alice = store.get_user_location("alice").await.unwrap().unwrap();
+	assert_eq!(alice.cell, moved.cell);
+	assert!(store.get_user_location("carol").await.unwrap().is_none());
+}
+
+#[actix_web::test]
+async fn sqlite_login_failures_count_within_a_window() {
+	let store = SqliteStore::open_in_memory().unwrap();
+	let start = Utc::now() - Duration::hours(1);
+	for expected in 1..=3 {
+		let attempts = store
+			.record_login_failure("alice", start + Duration::minutes(expected))
+			.await
+			.unwrap();
+		assert_eq!(attempts.failures, expected as u32);
+		assert_eq!(attempts.window_start, start + Duration::minutes(1));
+	}
+	assert!(!store
+		.get_login_attempts("alice")
+		.await
+		.unwrap()
+		.unwrap()
+		.locked(start));
+
+	// Once the window has ended the count starts over
+	let now = Utc::now();
+	let attempts = store.record_login_failure("alice", now).await.unwrap();
+	assert_eq!(attempts.failures, 1);
+	assert_eq!(attempts.window_start, now);
+
+	store.clear_login_attempts("alice").await.unwrap();
+	assert!(store.get_login_attempts("alice").await.unwrap().is_none());
+}
+
+#[actix_web::test]
+async fn invite_updates_write_every_field_but_the_count() {
+	let stores: [Box<dyn Store>; 2] = [
+		Box::new(SqliteStore::open_in_memory().unwrap()),
+		Box::new(MemoryStore::new()),
+	];
+	for store in stores {
+		let created_at = Utc::now();
+		store
+			.save_invite(&Invite {
+				code: "invite-1".to_string(),
+				invitor_id: "invitor".to_string(),
+				invite_count: 0,
+				created_at,
+				expires_at: None,
+				max_uses: None,
+				revoked: false,
+			})
+			.await
+			.unwrap();
+		let expires_at = created_at + Duration::hours(1);
+		store
+			.update_invite(&Invite {
+				code: "invite-1".to_string(),
+				invitor_id: "invitor".to_string(),
+				invite_count: 5,
+				created_at,
+				expires_at: Some(expires_at),
+				max_uses: Some(3),
+				revoked: true,
+			})
+			.await
+			.unwrap();
+		let invite = store.get_invite("invite-1").await.unwrap().unwrap();
+		assert_eq!(invite.expires_at, Some(expires_at));
+		assert_eq!(invite.max_uses, Some(3));
+		assert!(invite.revoked);
+		assert_eq!(invite.invite_count, 0);
+
+		// Unset fields are cleared
+		store
+			.update_invite(&Invite {
+				expires_at: None,
+				max_uses: None,
+				..invite
+			})
+			.await
+			.unwrap();
+		let invite = store.get_invite("invite-1").await.unwrap().unwrap();
+		assert_eq!(invite.expires_at, None);
+		assert_eq!(invite.max_uses, None);
+	}
+}